@@ -0,0 +1,130 @@
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use stun_rs::codec::{
+    append_fingerprint, append_message_integrity, verify_fingerprint, verify_message_integrity,
+    Decoder, Encoder,
+};
+use stun_rs::messages::{Address, Attribute, Message, MessageClass, MessageMethod, TransactionID};
+
+const PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+
+// TURN Data method (RFC 5766)
+const DATA: u16 = 0x007;
+
+fn binding_request() -> Message {
+    Message {
+        message_class: MessageClass::Request,
+        message_method: MessageMethod::Binding,
+        transaction_id: TransactionID::from([1u8; 12]),
+        attributes: vec![Attribute::Software("stun-rs:0.1.0".to_owned())],
+    }
+}
+
+fn binding_response() -> Message {
+    Message {
+        message_class: MessageClass::SuccessResponse,
+        message_method: MessageMethod::Binding,
+        transaction_id: TransactionID::from([1u8; 12]),
+        attributes: vec![
+            Attribute::Software("stun-rs:0.1.0".to_owned()),
+            Attribute::XorMappedAddress(Address::ipv4([192, 0, 2, 1], 32853)),
+        ],
+    }
+}
+
+// an ICE connectivity check, which has MESSAGE-INTEGRITY and FINGERPRINT appended
+fn connectivity_check() -> Message {
+    Message {
+        message_class: MessageClass::Request,
+        message_method: MessageMethod::Binding,
+        transaction_id: TransactionID::from([1u8; 12]),
+        attributes: vec![
+            Attribute::UserName("evtj:h6vY".to_owned()),
+            Attribute::Priority(0x6e00_01ff),
+            Attribute::IceControlled(0x932f_f9b1_5126_3b36),
+        ],
+    }
+}
+
+// there is no DATA attribute, PADDING has the same layout of opaque bytes
+fn data_indication(size: usize) -> Message {
+    Message {
+        message_class: MessageClass::Indication,
+        message_method: MessageMethod::from(DATA),
+        transaction_id: TransactionID::from([1u8; 12]),
+        attributes: vec![Attribute::Padding(vec![0xAB; size])],
+    }
+}
+
+fn encode(message: &Message) -> BytesMut {
+    let mut bytes = BytesMut::with_capacity(1500);
+    Encoder::new().encode(message, &mut bytes).unwrap();
+    bytes
+}
+
+fn encode_authenticated(message: &Message) -> BytesMut {
+    let mut bytes = encode(message);
+    append_message_integrity(&mut bytes, PASSWORD);
+    append_fingerprint(&mut bytes);
+    bytes
+}
+
+fn bench_binding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("binding");
+    for (name, message) in &[
+        ("request", binding_request()),
+        ("response", binding_response()),
+    ] {
+        group.bench_function(BenchmarkId::new("encode", name), |b| {
+            b.iter(|| encode(black_box(message)))
+        });
+        let bytes = encode(message);
+        group.bench_function(BenchmarkId::new("decode", name), |b| {
+            b.iter(|| Decoder::new().decode(&mut black_box(&bytes[..])).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_authenticated(c: &mut Criterion) {
+    let mut group = c.benchmark_group("authenticated");
+    let message = connectivity_check();
+    group.bench_function("encode", |b| {
+        b.iter(|| encode_authenticated(black_box(&message)))
+    });
+    let bytes = encode_authenticated(&message);
+    group.bench_function("decode", |b| {
+        b.iter(|| {
+            let bytes = black_box(&bytes[..]);
+            assert!(verify_fingerprint(bytes));
+            assert!(verify_message_integrity(bytes, PASSWORD));
+            Decoder::new().decode(&mut &bytes[..]).unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn bench_data_indication(c: &mut Criterion) {
+    let mut group = c.benchmark_group("data_indication");
+    for size in &[1200, 16 * 1024, 60 * 1024] {
+        let message = data_indication(*size);
+        let bytes = encode(&message);
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_with_input(BenchmarkId::new("encode", size), &message, |b, message| {
+            b.iter(|| encode(black_box(message)))
+        });
+        group.bench_with_input(BenchmarkId::new("decode", size), &bytes, |b, bytes| {
+            b.iter(|| Decoder::new().decode(&mut black_box(&bytes[..])).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_binding,
+    bench_authenticated,
+    bench_data_indication
+);
+criterion_main!(benches);
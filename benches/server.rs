@@ -0,0 +1,102 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use stun_rs::codec::Encoder;
+use stun_rs::messages::{Address, Attribute, Message, MessageClass, MessageMethod, TransactionID};
+use stun_rs::server::{Context, ServerEngine};
+
+fn binding(request: &Message, context: &Context) -> Option<Message> {
+    Some(Message {
+        message_class: MessageClass::SuccessResponse,
+        message_method: MessageMethod::Binding,
+        transaction_id: request.transaction_id,
+        attributes: vec![
+            Attribute::Software("stun-rs:0.1.0".to_owned()),
+            Attribute::XorMappedAddress(Address::from(context.source)),
+        ],
+    })
+}
+
+fn binding_request() -> Vec<u8> {
+    let message = Message {
+        message_class: MessageClass::Request,
+        message_method: MessageMethod::Binding,
+        transaction_id: TransactionID::from([1u8; 12]),
+        attributes: vec![Attribute::Software("stun-rs:0.1.0".to_owned())],
+    };
+    let mut bytes = BytesMut::new();
+    Encoder::new().encode(&message, &mut bytes).unwrap();
+    bytes.to_vec()
+}
+
+fn bench_handle_datagram(c: &mut Criterion) {
+    let source: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+    let destination: SocketAddr = "192.0.2.2:3478".parse().unwrap();
+    let mut engine = ServerEngine::new().with_handler(MessageMethod::Binding, binding);
+    let request = binding_request();
+
+    let mut group = c.benchmark_group("server");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("handle_datagram", |b| {
+        b.iter(|| {
+            engine
+                .handle_datagram(source, destination, black_box(&request))
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+// a binding request and its response over UDP on the loopback interface, one at a time
+fn bench_loopback(c: &mut Criterion) {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let server_address = server.local_addr().unwrap();
+    let stopped = Arc::new(AtomicBool::new(false));
+    let handle = {
+        let stopped = stopped.clone();
+        thread::spawn(move || {
+            let mut engine = ServerEngine::new().with_handler(MessageMethod::Binding, binding);
+            let mut buf = [0u8; 1500];
+            while !stopped.load(Ordering::Relaxed) {
+                let (size, source) = match server.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                };
+                if let Some(response) = engine.handle_datagram(source, server_address, &buf[..size])
+                {
+                    server.send_to(&response, source).unwrap();
+                }
+            }
+        })
+    };
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(server_address).unwrap();
+    let request = binding_request();
+    let mut buf = [0u8; 1500];
+
+    let mut group = c.benchmark_group("server");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("loopback_udp", |b| {
+        b.iter(|| {
+            client.send(&request).unwrap();
+            client.recv(&mut buf).unwrap()
+        })
+    });
+    group.finish();
+
+    stopped.store(true, Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+criterion_group!(benches, bench_handle_datagram, bench_loopback);
+criterion_main!(benches);
@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use stun_rs::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Decoder::new().decode(&mut &data[..]);
+    let _ = Decoder::with_classic_stun().decode(&mut &data[..]);
+});
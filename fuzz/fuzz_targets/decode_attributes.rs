@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use stun_rs::codec::{Decoder, MAGIC_COOKIE};
+
+// wraps the input in a valid header, so that every input reaches the attribute decoders
+fuzz_target!(|data: &[u8]| {
+    let body = &data[..data.len().min(0xFFFC) & !0x03];
+    let mut message = Vec::with_capacity(20 + body.len());
+    message.extend_from_slice(&0x0001u16.to_be_bytes());
+    message.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    message.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    message.extend_from_slice(&[0x42; 12]);
+    message.extend_from_slice(body);
+    let _ = Decoder::new().decode(&mut &message[..]);
+});
@@ -0,0 +1,31 @@
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+
+use stun_rs::codec::{Decoder, Encoder};
+use stun_rs::messages::Attribute;
+
+// whatever decodes, encodes into bytes which decode into the same message
+fuzz_target!(|data: &[u8]| {
+    let decoder = Decoder::with_classic_stun();
+    let message = match decoder.decode(&mut &data[..]) {
+        Ok(message) => message,
+        Err(_) => return,
+    };
+    // the value of unrecognized attributes is not kept, so they cannot be encoded again
+    if message
+        .attributes
+        .iter()
+        .any(|attribute| matches!(attribute, Attribute::UnRecognized { .. }))
+    {
+        return;
+    }
+    let mut bytes = BytesMut::new();
+    Encoder::new()
+        .encode(&message, &mut bytes)
+        .expect("a decoded message encodes");
+    let decoded = decoder
+        .decode(&mut &bytes[..])
+        .expect("an encoded message decodes");
+    assert_eq!(decoded, message);
+});
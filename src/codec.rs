@@ -1,14 +1,26 @@
+pub use custom::*;
 pub use decoder::*;
 pub use encoder::*;
+pub use integrity::*;
 
 use crate::codec::error::CodecError;
 
 mod attributes;
 
+mod custom;
+
 mod decoder;
 
 mod encoder;
 
+mod integrity;
+
+#[cfg(test)]
+mod round_trip;
+
+#[cfg(test)]
+mod test_vectors;
+
 pub mod error;
 
 pub type Result<T> = std::result::Result<T, CodecError>;
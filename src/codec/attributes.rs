@@ -7,8 +7,23 @@ use crate::messages::{Address, Attribute, IPKind};
 use super::Result;
 
 pub fn decode_attribute(buf: &mut dyn Buf, transaction_id: &[u8; 12]) -> Result<Attribute> {
+    if buf.remaining() < 4 {
+        return Err(CodecError::insufficient_bytes(
+            "decode attribute header",
+            4,
+            buf.remaining(),
+        ));
+    }
     let attribute_type = buf.get_u16();
     let attribute_value_size = buf.get_u16() as usize;
+    // the value decoders below may still need more bytes for padding or a fixed size value
+    if buf.remaining() < attribute_value_size {
+        return Err(CodecError::insufficient_bytes(
+            "decode attribute value",
+            attribute_value_size,
+            buf.remaining(),
+        ));
+    }
 
     match attribute_type {
         // Comprehension-required range (0x0000-0x7FFF):
@@ -19,41 +34,29 @@ pub fn decode_attribute(buf: &mut dyn Buf, transaction_id: &[u8; 12]) -> Result<
         }
         // MAPPED-ADDRESS
         0x0001 => decode_mapped_address(buf, attribute_value_size),
-        // (Reserved; was RESPONSE-ADDRESS)
-        0x0002 => {
-            buf.advance(attribute_value_size);
-            Ok(Attribute::UnRecognized {
-                kind: attribute_type,
-            })
-        }
-        // (Reserved; was CHANGE-ADDRESS)
-        0x0003 => {
-            buf.advance(attribute_value_size);
-            Ok(Attribute::UnRecognized {
-                kind: attribute_type,
-            })
-        }
-        //(Reserved; was SOURCE-ADDRESS)
-        0x0004 => {
-            buf.advance(attribute_value_size);
-            Ok(Attribute::UnRecognized {
-                kind: attribute_type,
-            })
-        }
-        // (Reserved; was CHANGED-ADDRESS)
-        0x0005 => {
-            buf.advance(attribute_value_size);
-            Ok(Attribute::UnRecognized {
-                kind: attribute_type,
-            })
-        }
+        // RESPONSE-ADDRESS (RFC 3489, reserved since RFC 5389)
+        0x0002 => Ok(Attribute::ResponseAddress(decode_address(
+            buf,
+            attribute_value_size,
+        )?)),
+        // CHANGE-REQUEST (RFC 5780, was CHANGE-ADDRESS)
+        0x0003 => decode_change_request(buf, attribute_value_size),
+        // SOURCE-ADDRESS (RFC 3489, reserved since RFC 5389)
+        0x0004 => Ok(Attribute::SourceAddress(decode_address(
+            buf,
+            attribute_value_size,
+        )?)),
+        // CHANGED-ADDRESS (RFC 3489, reserved since RFC 5389)
+        0x0005 => Ok(Attribute::ChangedAddress(decode_address(
+            buf,
+            attribute_value_size,
+        )?)),
         // USERNAME
-        0x0006 => {
-            buf.advance(attribute_value_size);
-            Ok(Attribute::UnRecognized {
-                kind: attribute_type,
-            })
-        }
+        0x0006 => Ok(Attribute::UserName(decode_string(
+            buf,
+            attribute_value_size,
+            "decode UserName",
+        )?)),
         // (Reserved; was PASSWORD)
         0x0007 => {
             buf.advance(attribute_value_size);
@@ -62,74 +65,134 @@ pub fn decode_attribute(buf: &mut dyn Buf, transaction_id: &[u8; 12]) -> Result<
             })
         }
         // MESSAGE-INTEGRITY
-        0x0008 => {
-            buf.advance(attribute_value_size);
-            Ok(Attribute::UnRecognized {
-                kind: attribute_type,
-            })
-        }
+        0x0008 => decode_message_integrity(buf, attribute_value_size),
         // ERROR-CODE
-        0x0009 => {
-            buf.advance(attribute_value_size);
-            Ok(Attribute::UnRecognized {
-                kind: attribute_type,
-            })
-        }
+        0x0009 => decode_error_code(buf, attribute_value_size),
         // UNKNOWN-ATTRIBUTES
-        0x000A => {
-            buf.advance(attribute_value_size);
-            Ok(Attribute::UnRecognized {
-                kind: attribute_type,
-            })
-        }
-        // (Reserved; was REFLECTED-FROM)
-        0x000B => {
-            buf.advance(attribute_value_size);
-            Ok(Attribute::UnRecognized {
-                kind: attribute_type,
-            })
-        }
+        0x000A => decode_unknown_attributes(buf, attribute_value_size),
+        // REFLECTED-FROM (RFC 3489, reserved since RFC 5389)
+        0x000B => Ok(Attribute::ReflectedFrom(decode_address(
+            buf,
+            attribute_value_size,
+        )?)),
+        // CHANNEL-NUMBER
+        0x000C => decode_channel_number(buf, attribute_value_size),
+        // LIFETIME
+        0x000D => decode_lifetime(buf, attribute_value_size),
+        // XOR-PEER-ADDRESS
+        0x0012 => Ok(Attribute::XorPeerAddress(decode_xor_address(
+            buf,
+            attribute_value_size,
+            transaction_id,
+            "decode XorPeerAddress",
+        )?)),
+        // DATA
+        0x0013 => Ok(Attribute::Data(decode_bytes(
+            buf,
+            attribute_value_size,
+            "decode Data",
+        )?)),
         // REALM
-        0x0014 => {
-            buf.advance(attribute_value_size);
-            Ok(Attribute::UnRecognized {
-                kind: attribute_type,
-            })
-        }
+        0x0014 => Ok(Attribute::Realm(decode_string(
+            buf,
+            attribute_value_size,
+            "decode Realm",
+        )?)),
         // NONCE
-        0x0015 => {
-            buf.advance(attribute_value_size);
-            Ok(Attribute::UnRecognized {
-                kind: attribute_type,
-            })
-        }
+        0x0015 => Ok(Attribute::Nonce(decode_string(
+            buf,
+            attribute_value_size,
+            "decode Nonce",
+        )?)),
         // XOR-MAPPED-ADDRESS
         0x0020 => decode_xor_mapped_address(buf, attribute_value_size, transaction_id),
-
-        // Comprehension-optional range (0x8000-0xFFFF)
-        0x8022 => {
-            let mut bytes = vec![0u8; attribute_value_size];
-            buf.copy_to_slice(bytes.as_mut());
-            let padding = 4 - attribute_value_size % 4;
-            if (padding != 4) {
-                buf.advance(padding);
+        // XOR-RELAYED-ADDRESS
+        0x0016 => Ok(Attribute::XorRelayedAddress(decode_xor_address(
+            buf,
+            attribute_value_size,
+            transaction_id,
+            "decode XorRelayedAddress",
+        )?)),
+        // REQUESTED-ADDRESS-FAMILY
+        0x0017 => Ok(Attribute::RequestedAddressFamily(decode_address_family(
+            buf,
+            attribute_value_size,
+        )?)),
+        // EVEN-PORT
+        0x0018 => decode_even_port(buf, attribute_value_size),
+        // REQUESTED-TRANSPORT
+        0x0019 => decode_requested_transport(buf, attribute_value_size),
+        // DONT-FRAGMENT
+        0x001A => {
+            if attribute_value_size != 0 {
+                return Err(CodecError::unexpected(&format!(
+                    "Invalid DontFragment size {}",
+                    attribute_value_size
+                )));
             }
-            Ok(Attribute::Software(String::from_utf8(bytes)?))
+            Ok(Attribute::DontFragment)
         }
-        //ALTERNATE-SERVER
-        0x8023 => {
-            buf.advance(attribute_value_size);
-            Ok(Attribute::UnRecognized {
-                kind: attribute_type,
-            })
+        // RESERVATION-TOKEN
+        0x0022 => decode_reservation_token(buf, attribute_value_size),
+        // PRIORITY
+        0x0024 => decode_priority(buf, attribute_value_size),
+        // USE-CANDIDATE
+        0x0025 => {
+            if attribute_value_size != 0 {
+                return Err(CodecError::unexpected(&format!(
+                    "Invalid UseCandidate size {}",
+                    attribute_value_size
+                )));
+            }
+            Ok(Attribute::UseCandidate)
         }
+        // PADDING
+        0x0026 => decode_padding(buf, attribute_value_size),
+        // RESPONSE-PORT
+        0x0027 => decode_response_port(buf, attribute_value_size),
+        // CONNECTION-ID
+        0x002A => decode_connection_id(buf, attribute_value_size),
+
+        // Comprehension-optional range (0x8000-0xFFFF)
+        // ADDITIONAL-ADDRESS-FAMILY
+        0x8000 => Ok(Attribute::AdditionalAddressFamily(decode_address_family(
+            buf,
+            attribute_value_size,
+        )?)),
+        // ADDRESS-ERROR-CODE
+        0x8001 => decode_address_error_code(buf, attribute_value_size),
+        0x8022 => Ok(Attribute::Software(decode_string(
+            buf,
+            attribute_value_size,
+            "decode Software",
+        )?)),
+        // RESPONSE-ORIGIN
+        0x802B => Ok(Attribute::ResponseOrigin(decode_address(
+            buf,
+            attribute_value_size,
+        )?)),
+        // OTHER-ADDRESS
+        0x802C => Ok(Attribute::OtherAddress(decode_address(
+            buf,
+            attribute_value_size,
+        )?)),
+        //ALTERNATE-SERVER
+        0x8023 => Ok(Attribute::AlternateServer(decode_address(
+            buf,
+            attribute_value_size,
+        )?)),
+        // ICE-CONTROLLED
+        0x8029 => Ok(Attribute::IceControlled(decode_tie_breaker(
+            buf,
+            attribute_value_size,
+        )?)),
+        // ICE-CONTROLLING
+        0x802A => Ok(Attribute::IceControlling(decode_tie_breaker(
+            buf,
+            attribute_value_size,
+        )?)),
         //FINGERPRINT
-        0x8028 => {
-            buf.advance(attribute_value_size);
-            Ok(Attribute::UnRecognized {
-                kind: attribute_type,
-            })
-        }
+        0x8028 => decode_fingerprint(buf, attribute_value_size),
         _ => {
             buf.advance(attribute_value_size);
             Ok(Attribute::UnRecognized {
@@ -139,12 +202,67 @@ pub fn decode_attribute(buf: &mut dyn Buf, transaction_id: &[u8; 12]) -> Result<
     }
 }
 
+/// Whether `decode_attribute` decodes the attribute into its own variant, rather than
+/// `Attribute::UnRecognized`.
+pub fn is_builtin(kind: u16) -> bool {
+    matches!(
+        kind,
+        0x0001..=0x0006
+            | 0x0008..=0x000D
+            | 0x0012..=0x001A
+            | 0x0020
+            | 0x0022
+            | 0x0024..=0x0027
+            | 0x002A
+            | 0x8000
+            | 0x8001
+            | 0x8022
+            | 0x8023
+            | 0x8028..=0x802C
+    )
+}
+
+/// Whether the attribute has the MAPPED-ADDRESS format.
+pub fn is_address(kind: u16) -> bool {
+    matches!(
+        kind,
+        0x0001
+            | 0x0002
+            | 0x0004
+            | 0x0005
+            | 0x000B
+            | 0x0012
+            | 0x0016
+            | 0x0020
+            | 0x8023
+            | 0x802B
+            | 0x802C
+    )
+}
+
+/// The longest value RFC 5389 allows for the string attributes.
+pub fn max_value_length(kind: u16) -> Option<usize> {
+    match kind {
+        // USERNAME, less than 513 bytes
+        0x0006 => Some(512),
+        // ERROR-CODE, a reason phrase of less than 128 characters, up to 763 bytes
+        0x0009 => Some(4 + 763),
+        // REALM, NONCE and SOFTWARE, less than 128 characters
+        0x0014 | 0x0015 | 0x8022 => Some(763),
+        _ => None,
+    }
+}
+
+/// Encodes the attribute with its header and padding, returns its size.
+///
+/// Unrecognized attributes have no value to encode, and a custom attribute of a built-in type
+/// would decode as the built-in one, both are errors.
 pub fn encode_attribute(
     attribute: &Attribute,
     buf: &mut dyn BufMut,
     transaction_id: &[u8; 12],
-) -> usize {
-    match attribute {
+) -> Result<usize> {
+    let size = match attribute {
         Attribute::XorMappedAddress(address) => {
             buf.put_u16(0x0020);
             let value_size = if address.ip_kind == IPKind::IPv4 {
@@ -182,125 +300,762 @@ pub fn encode_attribute(
             encode_mapped_address(attribute, buf);
             4 + value_size as usize
         }
-        _ => panic!("encoding not supported!"),
-    }
+        Attribute::ConnectionId(connection_id) => {
+            buf.put_u16(0x002A);
+            buf.put_u16(4);
+            buf.put_u32(*connection_id);
+            8
+        }
+        Attribute::ChannelNumber(channel) => {
+            buf.put_u16(0x000C);
+            buf.put_u16(4);
+            buf.put_u16(*channel);
+            // RFFU
+            buf.put_u16(0);
+            8
+        }
+        Attribute::Lifetime(seconds) => {
+            buf.put_u16(0x000D);
+            buf.put_u16(4);
+            buf.put_u32(*seconds);
+            8
+        }
+        Attribute::XorPeerAddress(address) => {
+            buf.put_u16(0x0012);
+            buf.put_u16(address_value_size(address));
+            4 + encode_xor_address(address, buf, transaction_id)
+        }
+        Attribute::Data(data) => encode_bytes(0x0013, data, buf),
+        Attribute::XorRelayedAddress(address) => {
+            buf.put_u16(0x0016);
+            buf.put_u16(address_value_size(address));
+            4 + encode_xor_address(address, buf, transaction_id)
+        }
+        Attribute::RequestedTransport(protocol) => {
+            buf.put_u16(0x0019);
+            buf.put_u16(4);
+            buf.put_u8(*protocol);
+            // RFFU
+            buf.put_u8(0);
+            buf.put_u16(0);
+            8
+        }
+        Attribute::DontFragment => {
+            buf.put_u16(0x001A);
+            buf.put_u16(0);
+            4
+        }
+        Attribute::RequestedAddressFamily(ip_kind) => {
+            buf.put_u16(0x0017);
+            buf.put_u16(4);
+            encode_address_family(ip_kind, buf);
+            8
+        }
+        Attribute::AdditionalAddressFamily(ip_kind) => {
+            buf.put_u16(0x8000);
+            buf.put_u16(4);
+            encode_address_family(ip_kind, buf);
+            8
+        }
+        Attribute::AddressErrorCode {
+            ip_kind,
+            code,
+            reason,
+        } => {
+            let bytes = reason.as_bytes();
+            buf.put_u16(0x8001);
+            buf.put_u16(4 + bytes.len() as u16);
+            buf.put_u8(address_family_code(ip_kind));
+            buf.put_u8(0);
+            buf.put_u8(((code / 100) & 0x07) as u8);
+            buf.put_u8((code % 100) as u8);
+            buf.put_slice(bytes);
+            let padding = padding_size(bytes.len());
+            for _ in 0..padding {
+                buf.put_u8(0x00)
+            }
+            8 + bytes.len() + padding
+        }
+        Attribute::EvenPort(reserve) => {
+            buf.put_u16(0x0018);
+            buf.put_u16(1);
+            buf.put_u8(if *reserve { 0x80 } else { 0x00 });
+            // padding
+            buf.put_u8(0);
+            buf.put_u16(0);
+            8
+        }
+        Attribute::ReservationToken(token) => {
+            buf.put_u16(0x0022);
+            buf.put_u16(8);
+            buf.put_slice(token);
+            12
+        }
+        Attribute::ChangeRequest {
+            change_ip,
+            change_port,
+        } => {
+            buf.put_u16(0x0003);
+            buf.put_u16(4);
+            let mut flags = 0u32;
+            if *change_ip {
+                flags |= 0x04;
+            }
+            if *change_port {
+                flags |= 0x02;
+            }
+            buf.put_u32(flags);
+            8
+        }
+        Attribute::ResponsePort(port) => {
+            buf.put_u16(0x0027);
+            buf.put_u16(4);
+            buf.put_u16(*port);
+            buf.put_u16(0);
+            8
+        }
+        Attribute::Padding(bytes) => {
+            buf.put_u16(0x0026);
+            buf.put_u16(bytes.len() as u16);
+            buf.put_slice(bytes);
+            let padding = padding_size(bytes.len());
+            for _ in 0..padding {
+                buf.put_u8(0x00)
+            }
+            4 + bytes.len() + padding
+        }
+        Attribute::UserName(username) => {
+            let bytes = username.as_bytes();
+            buf.put_u16(0x0006);
+            buf.put_u16(bytes.len() as u16);
+            buf.put_slice(bytes);
+            let padding = padding_size(bytes.len());
+            for _ in 0..padding {
+                buf.put_u8(0x00)
+            }
+            4 + bytes.len() + padding
+        }
+        Attribute::Realm(realm) => encode_string(0x0014, realm, buf),
+        Attribute::Nonce(nonce) => encode_string(0x0015, nonce, buf),
+        Attribute::MessageIntegrity(hmac) => {
+            buf.put_u16(0x0008);
+            buf.put_u16(20);
+            buf.put_slice(hmac);
+            24
+        }
+        Attribute::FingerPrint(crc) => {
+            buf.put_u16(0x8028);
+            buf.put_u16(4);
+            buf.put_u32(*crc);
+            8
+        }
+        Attribute::ErrorCode { code, reason } => {
+            let bytes = reason.as_bytes();
+            buf.put_u16(0x0009);
+            buf.put_u16(4 + bytes.len() as u16);
+            buf.put_u16(0);
+            buf.put_u8(((code / 100) & 0x07) as u8);
+            buf.put_u8((code % 100) as u8);
+            buf.put_slice(bytes);
+            let padding = padding_size(bytes.len());
+            for _ in 0..padding {
+                buf.put_u8(0x00)
+            }
+            8 + bytes.len() + padding
+        }
+        Attribute::Priority(priority) => {
+            buf.put_u16(0x0024);
+            buf.put_u16(4);
+            buf.put_u32(*priority);
+            8
+        }
+        Attribute::UseCandidate => {
+            buf.put_u16(0x0025);
+            buf.put_u16(0);
+            4
+        }
+        Attribute::IceControlled(tie_breaker) => {
+            buf.put_u16(0x8029);
+            buf.put_u16(8);
+            buf.put_u64(*tie_breaker);
+            12
+        }
+        Attribute::IceControlling(tie_breaker) => {
+            buf.put_u16(0x802A);
+            buf.put_u16(8);
+            buf.put_u64(*tie_breaker);
+            12
+        }
+        Attribute::ResponseAddress(address) => {
+            buf.put_u16(0x0002);
+            buf.put_u16(address_value_size(address));
+            4 + encode_address(address, buf)
+        }
+        Attribute::SourceAddress(address) => {
+            buf.put_u16(0x0004);
+            buf.put_u16(address_value_size(address));
+            4 + encode_address(address, buf)
+        }
+        Attribute::ChangedAddress(address) => {
+            buf.put_u16(0x0005);
+            buf.put_u16(address_value_size(address));
+            4 + encode_address(address, buf)
+        }
+        Attribute::ReflectedFrom(address) => {
+            buf.put_u16(0x000B);
+            buf.put_u16(address_value_size(address));
+            4 + encode_address(address, buf)
+        }
+        Attribute::ResponseOrigin(address) => {
+            buf.put_u16(0x802B);
+            buf.put_u16(address_value_size(address));
+            4 + encode_address(address, buf)
+        }
+        Attribute::OtherAddress(address) => {
+            buf.put_u16(0x802C);
+            buf.put_u16(address_value_size(address));
+            4 + encode_address(address, buf)
+        }
+        Attribute::AlternateServer(address) => {
+            buf.put_u16(0x8023);
+            buf.put_u16(address_value_size(address));
+            4 + encode_address(address, buf)
+        }
+        Attribute::Custom(attribute) if is_builtin(attribute.kind()) => {
+            return Err(CodecError::unexpected(&format!(
+                "custom attribute 0x{:04X} is encoded by the built-in codec",
+                attribute.kind()
+            )));
+        }
+        Attribute::Custom(attribute) => {
+            encode_bytes(attribute.kind(), &attribute.encode_value(), buf)
+        }
+        Attribute::UnknownAttributes(kinds) => {
+            buf.put_u16(0x000A);
+            buf.put_u16(2 * kinds.len() as u16);
+            for kind in kinds {
+                buf.put_u16(*kind);
+            }
+            let padding = padding_size(2 * kinds.len());
+            for _ in 0..padding {
+                buf.put_u8(0x00)
+            }
+            4 + 2 * kinds.len() + padding
+        }
+        Attribute::UnRecognized { kind } => {
+            return Err(CodecError::unexpected(&format!(
+                "unrecognized attribute 0x{:04X} cannot be encoded",
+                kind
+            )));
+        }
+    };
+    Ok(size)
 }
 
 fn decode_mapped_address(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
-    if buf.remaining() < size {
+    Ok(Attribute::MappedAddress(decode_address(buf, size)?))
+}
+
+fn encode_mapped_address(v: &Attribute, buf: &mut dyn BufMut) -> usize {
+    match v {
+        Attribute::MappedAddress(address) => encode_address(address, buf),
+        _ => panic!("Should never be here!"),
+    }
+}
+
+// decodes the value of the attributes sharing the MAPPED-ADDRESS format
+fn decode_address(buf: &mut dyn Buf, size: usize) -> Result<Address> {
+    let (ip_kind, port) = decode_address_header(buf, size, "decode mapped address")?;
+    let mut address = vec![0; ip_length(&ip_kind)];
+    buf.copy_to_slice(address.as_mut());
+    Ok(Address {
+        address,
+        port,
+        ip_kind,
+    })
+}
+
+// decodes the family and the port, and makes sure the address of that family follows
+fn decode_address_header(buf: &mut dyn Buf, size: usize, when: &str) -> Result<(IPKind, u16)> {
+    if buf.remaining() < size.max(4) {
         return Err(CodecError::insufficient_bytes(
-            "decode mapped address",
-            size,
+            when,
+            size.max(4),
             buf.remaining(),
         ));
     }
     if buf.get_u8() != 0x00 {
-        return Err(CodecError::unexpected("Invalid MappedAddress Codec!"));
-    }
-    // TODO: validate size
-    match buf.get_u8() {
-        0x01 => {
-            let port = buf.get_u16();
-            let mut address = vec![0; 4];
-            buf.copy_to_slice(address.as_mut());
-            Ok(Attribute::MappedAddress(Address {
-                address,
-                port,
-                ip_kind: IPKind::IPv4,
-            }))
-        }
-        0x02 => {
-            let port = buf.get_u16();
-            let mut address = vec![0; 16];
-            buf.copy_to_slice(address.as_mut());
-            Ok(Attribute::MappedAddress(Address {
-                address,
-                port,
-                ip_kind: IPKind::IPv6,
-            }))
-        }
-        v => Err(CodecError::unexpected(&format!("Invalid ip type {}", v))),
+        return Err(CodecError::unexpected(&format!(
+            "Invalid first byte when {}",
+            when
+        )));
+    }
+    // addresses longer than their family are accepted unless DecoderOptions says otherwise
+    let ip_kind = match buf.get_u8() {
+        0x01 => IPKind::IPv4,
+        0x02 => IPKind::IPv6,
+        v => return Err(CodecError::unexpected(&format!("Invalid ip type {}", v))),
+    };
+    let port = buf.get_u16();
+    if buf.remaining() < ip_length(&ip_kind) {
+        return Err(CodecError::insufficient_bytes(
+            when,
+            ip_length(&ip_kind),
+            buf.remaining(),
+        ));
     }
+    Ok((ip_kind, port))
 }
 
-fn encode_mapped_address(v: &Attribute, buf: &mut dyn BufMut) -> usize {
-    match v {
-        Attribute::MappedAddress(Address {
-            address,
-            port,
-            ip_kind,
-        }) if ip_kind == &IPKind::IPv4 => {
+fn ip_length(ip_kind: &IPKind) -> usize {
+    match ip_kind {
+        IPKind::IPv4 => 4,
+        IPKind::IPv6 => 16,
+    }
+}
+
+fn encode_address(address: &Address, buf: &mut dyn BufMut) -> usize {
+    match address.ip_kind {
+        IPKind::IPv4 => {
             buf.put_u8(0);
             buf.put_u8(0x01);
-            buf.put_u16(*port);
-            for i in 0..4 {
-                buf.put_u8(address[i]);
-            }
+            buf.put_u16(address.port);
+            buf.put_slice(&address.address[..4]);
             8
         }
-        Attribute::MappedAddress(Address {
-            address,
-            port,
-            ip_kind,
-        }) if ip_kind == &IPKind::IPv6 => {
+        IPKind::IPv6 => {
             buf.put_u8(0);
             buf.put_u8(0x02);
-            buf.put_u16(*port);
-            for i in 0..16 {
-                buf.put_u8(address[i]);
-            }
+            buf.put_u16(address.port);
+            buf.put_slice(&address.address[..16]);
             20
         }
-        _ => panic!("Should never be here!".to_owned()),
     }
 }
 
-fn decode_xor_mapped_address(
-    buf: &mut dyn Buf,
-    size: usize,
-    transaction_id: &[u8; 12],
-) -> Result<Attribute> {
+fn address_value_size(address: &Address) -> u16 {
+    match address.ip_kind {
+        IPKind::IPv4 => 8,
+        IPKind::IPv6 => 20,
+    }
+}
+
+fn decode_change_request(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if size != 4 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid ChangeRequest size {}",
+            size
+        )));
+    }
     if buf.remaining() < size {
         return Err(CodecError::insufficient_bytes(
-            "decode XorMappedAddress",
+            "decode ChangeRequest",
             size,
             buf.remaining(),
         ));
     }
-    if buf.get_u8() != 0x00 {
-        return Err(CodecError::unexpected("Invalid XorMappedAddress Codec!"));
-    }
-    // TODO: validate size
-    match buf.get_u8() {
-        0x01 => {
-            let port = buf.get_u16() ^ ((MAGIC_COOKIE >> 16) as u16);
-            let mut address = vec![0; 4];
-            for i in 0..4 {
-                address[i] = buf.get_u8() ^ ((MAGIC_COOKIE >> ((4 - i as u32 - 1) * 8)) as u8);
-            }
-            Ok(Attribute::XorMappedAddress(Address {
-                address,
-                port,
-                ip_kind: IPKind::IPv4,
-            }))
-        }
-        0x02 => {
-            let port = buf.get_u16() ^ ((MAGIC_COOKIE >> 16) as u16);
-            let mut address = vec![0; 16];
-            for i in 0..4 {
-                address[i] = buf.get_u8() ^ ((MAGIC_COOKIE >> ((4 - i as u32 - 1) * 8)) as u8);
-            }
-            for i in 0..12 {
-                address[i + 4] = buf.get_u8() ^ (transaction_id[i]);
-            }
-            Ok(Attribute::XorMappedAddress(Address {
-                address,
-                port,
-                ip_kind: IPKind::IPv6,
-            }))
-        }
-        v => Err(CodecError::unexpected(&format!("Invalid ip type {}", v))),
+    let flags = buf.get_u32();
+    Ok(Attribute::ChangeRequest {
+        change_ip: flags & 0x04 != 0,
+        change_port: flags & 0x02 != 0,
+    })
+}
+
+fn decode_response_port(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if size != 4 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid ResponsePort size {}",
+            size
+        )));
+    }
+    if buf.remaining() < size {
+        return Err(CodecError::insufficient_bytes(
+            "decode ResponsePort",
+            size,
+            buf.remaining(),
+        ));
+    }
+    let port = buf.get_u16();
+    buf.advance(2);
+    Ok(Attribute::ResponsePort(port))
+}
+
+fn decode_padding(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    let padding = padding_size(size);
+    if buf.remaining() < size + padding {
+        return Err(CodecError::insufficient_bytes(
+            "decode Padding",
+            size + padding,
+            buf.remaining(),
+        ));
+    }
+    let mut bytes = vec![0u8; size];
+    buf.copy_to_slice(bytes.as_mut());
+    buf.advance(padding);
+    Ok(Attribute::Padding(bytes))
+}
+
+fn decode_unknown_attributes(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if !size.is_multiple_of(2) {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid UnknownAttributes size {}",
+            size
+        )));
+    }
+    let padding = padding_size(size);
+    if buf.remaining() < size + padding {
+        return Err(CodecError::insufficient_bytes(
+            "decode UnknownAttributes",
+            size + padding,
+            buf.remaining(),
+        ));
     }
+    let kinds = (0..size / 2).map(|_| buf.get_u16()).collect();
+    buf.advance(padding);
+    Ok(Attribute::UnknownAttributes(kinds))
+}
+
+fn decode_connection_id(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if size != 4 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid ConnectionId size {}",
+            size
+        )));
+    }
+    if buf.remaining() < size {
+        return Err(CodecError::insufficient_bytes(
+            "decode ConnectionId",
+            size,
+            buf.remaining(),
+        ));
+    }
+    Ok(Attribute::ConnectionId(buf.get_u32()))
+}
+
+fn decode_channel_number(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if size != 4 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid ChannelNumber size {}",
+            size
+        )));
+    }
+    if buf.remaining() < size {
+        return Err(CodecError::insufficient_bytes(
+            "decode ChannelNumber",
+            size,
+            buf.remaining(),
+        ));
+    }
+    let channel = buf.get_u16();
+    buf.advance(2);
+    Ok(Attribute::ChannelNumber(channel))
+}
+
+fn decode_lifetime(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if size != 4 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid Lifetime size {}",
+            size
+        )));
+    }
+    if buf.remaining() < size {
+        return Err(CodecError::insufficient_bytes(
+            "decode Lifetime",
+            size,
+            buf.remaining(),
+        ));
+    }
+    Ok(Attribute::Lifetime(buf.get_u32()))
+}
+
+fn decode_requested_transport(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if size != 4 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid RequestedTransport size {}",
+            size
+        )));
+    }
+    if buf.remaining() < size {
+        return Err(CodecError::insufficient_bytes(
+            "decode RequestedTransport",
+            size,
+            buf.remaining(),
+        ));
+    }
+    let protocol = buf.get_u8();
+    buf.advance(3);
+    Ok(Attribute::RequestedTransport(protocol))
+}
+
+fn decode_even_port(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if size != 1 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid EvenPort size {}",
+            size
+        )));
+    }
+    if buf.remaining() < 4 {
+        return Err(CodecError::insufficient_bytes(
+            "decode EvenPort",
+            4,
+            buf.remaining(),
+        ));
+    }
+    let reserve = buf.get_u8() & 0x80 != 0;
+    buf.advance(3);
+    Ok(Attribute::EvenPort(reserve))
+}
+
+fn decode_reservation_token(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if size != 8 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid ReservationToken size {}",
+            size
+        )));
+    }
+    if buf.remaining() < size {
+        return Err(CodecError::insufficient_bytes(
+            "decode ReservationToken",
+            size,
+            buf.remaining(),
+        ));
+    }
+    let mut token = [0u8; 8];
+    buf.copy_to_slice(&mut token);
+    Ok(Attribute::ReservationToken(token))
+}
+
+// number of bytes needed to pad a value of the given size to a 4 bytes boundary
+pub fn padding_size(size: usize) -> usize {
+    (4 - size % 4) % 4
+}
+
+fn address_family_code(ip_kind: &IPKind) -> u8 {
+    match ip_kind {
+        IPKind::IPv4 => 0x01,
+        IPKind::IPv6 => 0x02,
+    }
+}
+
+fn decode_address_family_code(code: u8) -> Result<IPKind> {
+    match code {
+        0x01 => Ok(IPKind::IPv4),
+        0x02 => Ok(IPKind::IPv6),
+        v => Err(CodecError::unexpected(&format!(
+            "Invalid address family {}",
+            v
+        ))),
+    }
+}
+
+fn encode_address_family(ip_kind: &IPKind, buf: &mut dyn BufMut) {
+    buf.put_u8(address_family_code(ip_kind));
+    // 24 bits RFFU
+    buf.put_u8(0);
+    buf.put_u16(0);
+}
+
+fn decode_address_family(buf: &mut dyn Buf, size: usize) -> Result<IPKind> {
+    if size != 4 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid address family size {}",
+            size
+        )));
+    }
+    if buf.remaining() < size {
+        return Err(CodecError::insufficient_bytes(
+            "decode address family",
+            size,
+            buf.remaining(),
+        ));
+    }
+    let ip_kind = decode_address_family_code(buf.get_u8())?;
+    buf.advance(3);
+    Ok(ip_kind)
+}
+
+fn decode_address_error_code(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if size < 4 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid AddressErrorCode size {}",
+            size
+        )));
+    }
+    let padding = padding_size(size);
+    if buf.remaining() < size + padding {
+        return Err(CodecError::insufficient_bytes(
+            "decode AddressErrorCode",
+            size + padding,
+            buf.remaining(),
+        ));
+    }
+    let ip_kind = decode_address_family_code(buf.get_u8())?;
+    buf.advance(1);
+    let class = (buf.get_u8() & 0x07) as u32;
+    let number = decode_error_number(buf.get_u8())?;
+    let mut reason = vec![0u8; size - 4];
+    buf.copy_to_slice(reason.as_mut());
+    buf.advance(padding);
+    Ok(Attribute::AddressErrorCode {
+        ip_kind,
+        code: class * 100 + number,
+        reason: String::from_utf8(reason)?,
+    })
+}
+
+// the number of an error code is its last two decimal digits
+fn decode_error_number(number: u8) -> Result<u32> {
+    if number > 99 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid error number {}",
+            number
+        )));
+    }
+    Ok(number as u32)
+}
+
+fn encode_string(kind: u16, value: &str, buf: &mut dyn BufMut) -> usize {
+    encode_bytes(kind, value.as_bytes(), buf)
+}
+
+fn encode_bytes(kind: u16, bytes: &[u8], buf: &mut dyn BufMut) -> usize {
+    buf.put_u16(kind);
+    buf.put_u16(bytes.len() as u16);
+    buf.put_slice(bytes);
+    let padding = padding_size(bytes.len());
+    for _ in 0..padding {
+        buf.put_u8(0x00)
+    }
+    4 + bytes.len() + padding
+}
+
+fn decode_string(buf: &mut dyn Buf, size: usize, when: &str) -> Result<String> {
+    Ok(String::from_utf8(decode_bytes(buf, size, when)?)?)
+}
+
+fn decode_bytes(buf: &mut dyn Buf, size: usize, when: &str) -> Result<Vec<u8>> {
+    let padding = padding_size(size);
+    if buf.remaining() < size + padding {
+        return Err(CodecError::insufficient_bytes(
+            when,
+            size + padding,
+            buf.remaining(),
+        ));
+    }
+    let mut bytes = vec![0u8; size];
+    buf.copy_to_slice(bytes.as_mut());
+    buf.advance(padding);
+    Ok(bytes)
+}
+
+fn decode_message_integrity(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if size != 20 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid MessageIntegrity size {}",
+            size
+        )));
+    }
+    if buf.remaining() < size {
+        return Err(CodecError::insufficient_bytes(
+            "decode MessageIntegrity",
+            size,
+            buf.remaining(),
+        ));
+    }
+    let mut hmac = [0u8; 20];
+    buf.copy_to_slice(&mut hmac);
+    Ok(Attribute::MessageIntegrity(hmac))
+}
+
+fn decode_fingerprint(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if size != 4 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid FingerPrint size {}",
+            size
+        )));
+    }
+    if buf.remaining() < size {
+        return Err(CodecError::insufficient_bytes(
+            "decode FingerPrint",
+            size,
+            buf.remaining(),
+        ));
+    }
+    Ok(Attribute::FingerPrint(buf.get_u32()))
+}
+
+fn decode_error_code(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if size < 4 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid ErrorCode size {}",
+            size
+        )));
+    }
+    let padding = padding_size(size);
+    if buf.remaining() < size + padding {
+        return Err(CodecError::insufficient_bytes(
+            "decode ErrorCode",
+            size + padding,
+            buf.remaining(),
+        ));
+    }
+    buf.advance(2);
+    let class = (buf.get_u8() & 0x07) as u32;
+    let number = decode_error_number(buf.get_u8())?;
+    let mut reason = vec![0u8; size - 4];
+    buf.copy_to_slice(reason.as_mut());
+    buf.advance(padding);
+    Ok(Attribute::ErrorCode {
+        code: class * 100 + number,
+        reason: String::from_utf8(reason)?,
+    })
+}
+
+fn decode_priority(buf: &mut dyn Buf, size: usize) -> Result<Attribute> {
+    if size != 4 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid Priority size {}",
+            size
+        )));
+    }
+    if buf.remaining() < size {
+        return Err(CodecError::insufficient_bytes(
+            "decode Priority",
+            size,
+            buf.remaining(),
+        ));
+    }
+    Ok(Attribute::Priority(buf.get_u32()))
+}
+
+fn decode_tie_breaker(buf: &mut dyn Buf, size: usize) -> Result<u64> {
+    if size != 8 {
+        return Err(CodecError::unexpected(&format!(
+            "Invalid tie breaker size {}",
+            size
+        )));
+    }
+    if buf.remaining() < size {
+        return Err(CodecError::insufficient_bytes(
+            "decode tie breaker",
+            size,
+            buf.remaining(),
+        ));
+    }
+    Ok(buf.get_u64())
+}
+
+fn decode_xor_mapped_address(
+    buf: &mut dyn Buf,
+    size: usize,
+    transaction_id: &[u8; 12],
+) -> Result<Attribute> {
+    Ok(Attribute::XorMappedAddress(decode_xor_address(
+        buf,
+        size,
+        transaction_id,
+        "decode XorMappedAddress",
+    )?))
 }
 
 fn encode_xor_mapped_address(
@@ -309,44 +1064,73 @@ fn encode_xor_mapped_address(
     transaction_id: &[u8; 12],
 ) -> usize {
     match attribute {
-        Attribute::XorMappedAddress(Address {
-            address,
-            port,
-            ip_kind,
-        }) if ip_kind == &IPKind::IPv4 => {
-            buf.put_u8(0);
-            buf.put_u8(0x01);
-            buf.put_u16((*port) ^ ((MAGIC_COOKIE >> 16) as u16));
-            for i in 0..4 {
-                buf.put_u8(address[i] ^ ((MAGIC_COOKIE >> ((4 - i as u32 - 1) * 8)) as u8));
-            }
-            8
+        Attribute::XorMappedAddress(address) => encode_xor_address(address, buf, transaction_id),
+        v => panic!("Should never be here!, {:#?}", v),
+    }
+}
+
+// decodes the value of the attributes sharing the XOR-MAPPED-ADDRESS format
+fn decode_xor_address(
+    buf: &mut dyn Buf,
+    size: usize,
+    transaction_id: &[u8; 12],
+    when: &str,
+) -> Result<Address> {
+    let (ip_kind, port) = decode_address_header(buf, size, when)?;
+    let port = port ^ ((MAGIC_COOKIE >> 16) as u16);
+    let mut address = vec![0; ip_length(&ip_kind)];
+    for (i, byte) in address.iter_mut().enumerate().take(4) {
+        *byte = buf.get_u8() ^ ((MAGIC_COOKIE >> ((4 - i as u32 - 1) * 8)) as u8);
+    }
+    if ip_kind == IPKind::IPv6 {
+        for i in 0..12 {
+            address[i + 4] = buf.get_u8() ^ (transaction_id[i]);
         }
-        Attribute::XorMappedAddress(Address {
-            address,
-            port,
-            ip_kind,
-        }) if ip_kind == &IPKind::IPv6 => {
-            buf.put_u8(0);
-            buf.put_u8(0x02);
-            buf.put_u16((*port) ^ ((MAGIC_COOKIE >> 16) as u16));
-            for i in 0..4 {
-                buf.put_u8(address[i] ^ ((MAGIC_COOKIE >> ((4 - i as u32 - 1) * 8)) as u8));
-            }
-            for i in 0..12 {
-                buf.put_u8(address[i + 4] ^ (transaction_id[i]));
+    }
+    Ok(Address {
+        address,
+        port,
+        ip_kind,
+    })
+}
+
+fn encode_xor_address(address: &Address, buf: &mut dyn BufMut, transaction_id: &[u8; 12]) -> usize {
+    buf.put_u8(0);
+    buf.put_u8(address_family_code(&address.ip_kind));
+    buf.put_u16(address.port ^ ((MAGIC_COOKIE >> 16) as u16));
+    for (i, byte) in address.address.iter().enumerate().take(4) {
+        buf.put_u8(byte ^ ((MAGIC_COOKIE >> ((4 - i as u32 - 1) * 8)) as u8));
+    }
+    match address.ip_kind {
+        IPKind::IPv4 => 8,
+        IPKind::IPv6 => {
+            for (byte, id) in address.address[4..16].iter().zip(transaction_id.iter()) {
+                buf.put_u8(byte ^ id);
             }
             20
         }
-        v => panic!(format!("Should never be here!, {:#?}", v)),
     }
 }
 
+#[cfg(test)]
 mod test {
     use bytes::{Buf, BytesMut};
 
     use crate::codec::attributes::{decode_attribute, encode_attribute};
-    use crate::messages::Attribute;
+    use crate::messages::{Address, Attribute, IPKind};
+
+    // encodes the attribute into its expected size, and decodes exactly those bytes back into it
+    fn assert_round_trip(attribute: &Attribute, expected_size: usize) {
+        let transaction_id = [0x0Au8; 12];
+        let mut bytes_mut = BytesMut::new();
+        let size = encode_attribute(attribute, &mut bytes_mut, &transaction_id).unwrap();
+        assert_eq!(expected_size, size, "size of {:?}", attribute);
+        assert_eq!(bytes_mut.len(), size);
+        let mut buf = bytes_mut.bytes();
+        let decode_attribute = decode_attribute(&mut buf, &transaction_id).unwrap();
+        assert_eq!(attribute, &decode_attribute);
+        assert_eq!(0, buf.remaining());
+    }
 
     #[test]
     pub fn test_encode_decode_ipv4_mapped_address() {
@@ -382,7 +1166,7 @@ mod test {
         let size = encode_mapped_address(&attribute, &mut buf_mut);
         assert_eq!(size, 20);
         let mut bytes = buf_mut.freeze();
-        let decoded_attribute = decode_mapped_address(&mut bytes, 8).unwrap();
+        let decoded_attribute = decode_mapped_address(&mut bytes, 20).unwrap();
         assert_eq!(decoded_attribute, attribute)
     }
 
@@ -427,19 +1211,161 @@ mod test {
         let size = encode_xor_mapped_address(&attribute, &mut buf_mut, &transaction_id);
         assert_eq!(size, 20);
         let mut bytes = buf_mut.freeze();
-        let decoded_attribute = decode_xor_mapped_address(&mut bytes, 8, &transaction_id).unwrap();
+        let decoded_attribute = decode_xor_mapped_address(&mut bytes, 20, &transaction_id).unwrap();
         assert_eq!(decoded_attribute, attribute)
     }
 
     #[test]
     pub fn test_encode_decode_software() {
         let attribute = Attribute::Software("test:0.1.0".to_owned());
-        let transaction_id = [0u8; 12];
-        let mut bytes_mut = BytesMut::new();
-        let size = encode_attribute(&attribute, &mut bytes_mut, &transaction_id);
-        assert_eq!(16, size);
-        let mut buf = bytes_mut.bytes();
-        let decode_attribute = decode_attribute(&mut buf, &transaction_id).unwrap();
-        assert_eq!(attribute, decode_attribute);
+        assert_round_trip(&attribute, 16);
+    }
+
+    #[test]
+    pub fn test_encode_decode_connection_id() {
+        let attribute = Attribute::ConnectionId(0x1234_5678);
+        assert_round_trip(&attribute, 8);
+    }
+
+    #[test]
+    pub fn test_encode_decode_address_families() {
+        for attribute in &[
+            Attribute::RequestedAddressFamily(IPKind::IPv6),
+            Attribute::AdditionalAddressFamily(IPKind::IPv6),
+        ] {
+            assert_round_trip(attribute, 8);
+        }
+    }
+
+    #[test]
+    pub fn test_encode_decode_address_error_code() {
+        let attribute = Attribute::AddressErrorCode {
+            ip_kind: IPKind::IPv6,
+            code: 440,
+            reason: "Address Family not Supported".to_owned(),
+        };
+        assert_round_trip(&attribute, 36);
+    }
+
+    #[test]
+    pub fn test_encode_decode_even_port_and_reservation_token() {
+        for (attribute, expected_size) in &[
+            (Attribute::EvenPort(true), 8),
+            (Attribute::EvenPort(false), 8),
+            (Attribute::ReservationToken([7u8; 8]), 12),
+        ] {
+            assert_round_trip(attribute, *expected_size);
+        }
+    }
+
+    #[test]
+    pub fn test_encode_decode_nat_behavior_discovery_attributes() {
+        for (attribute, expected_size) in &[
+            (
+                Attribute::ChangeRequest {
+                    change_ip: true,
+                    change_port: false,
+                },
+                8,
+            ),
+            (
+                Attribute::ChangeRequest {
+                    change_ip: false,
+                    change_port: true,
+                },
+                8,
+            ),
+            (Attribute::ResponsePort(3478), 8),
+            (Attribute::Padding(vec![0xFF; 5]), 12),
+            (Attribute::Padding(vec![]), 4),
+            (
+                Attribute::ResponseOrigin(Address::ipv4([127, 0, 0, 1], 3478)),
+                12,
+            ),
+            (Attribute::OtherAddress(Address::ipv6([1u8; 16], 3479)), 24),
+        ] {
+            assert_round_trip(attribute, *expected_size);
+        }
+    }
+
+    #[test]
+    pub fn test_encode_decode_classic_stun_addresses() {
+        for attribute in &[
+            Attribute::ResponseAddress(Address::ipv4([10, 0, 0, 1], 4000)),
+            Attribute::SourceAddress(Address::ipv4([10, 0, 0, 2], 3478)),
+            Attribute::ChangedAddress(Address::ipv4([10, 0, 0, 3], 3479)),
+            Attribute::ReflectedFrom(Address::ipv4([10, 0, 0, 4], 5000)),
+        ] {
+            assert_round_trip(attribute, 12);
+        }
+    }
+
+    #[test]
+    pub fn test_encode_decode_ice_attributes() {
+        for (attribute, expected_size) in &[
+            (Attribute::Priority(0x6E00_01FF), 8),
+            (Attribute::UseCandidate, 4),
+            (Attribute::IceControlled(0x0102_0304_0506_0708), 12),
+            (Attribute::IceControlling(u64::MAX), 12),
+            (Attribute::role_conflict(), 24),
+        ] {
+            assert_round_trip(attribute, *expected_size);
+        }
+    }
+
+    #[test]
+    pub fn test_encode_decode_turn_attributes() {
+        for (attribute, expected_size) in &[
+            (Attribute::ChannelNumber(0x4001), 8),
+            (Attribute::Lifetime(600), 8),
+            (
+                Attribute::XorPeerAddress(Address::ipv4([192, 0, 2, 15], 49152)),
+                12,
+            ),
+            (
+                Attribute::XorRelayedAddress(Address::ipv6([0x20; 16], 50000)),
+                24,
+            ),
+            (Attribute::Data(b"hello".to_vec()), 12),
+            (Attribute::Data(vec![]), 4),
+            (Attribute::RequestedTransport(17), 8),
+            (Attribute::DontFragment, 4),
+        ] {
+            assert_round_trip(attribute, *expected_size);
+        }
+    }
+
+    #[test]
+    pub fn test_encode_decode_authentication_attributes() {
+        for (attribute, expected_size) in &[
+            (Attribute::UserName("evtj:h6vY".to_owned()), 16),
+            (Attribute::MessageIntegrity([0xAB; 20]), 24),
+            (Attribute::FingerPrint(0xE57A_3BCF), 8),
+        ] {
+            assert_round_trip(attribute, *expected_size);
+        }
+    }
+
+    #[test]
+    pub fn test_encode_decode_long_term_credential_attributes() {
+        for (attribute, expected_size) in &[
+            (Attribute::UserName("alice".to_owned()), 12),
+            (Attribute::Realm("example.org".to_owned()), 16),
+            (
+                Attribute::Nonce("f//499k954d6OL34oL9FSTvy64sA".to_owned()),
+                32,
+            ),
+            (Attribute::MessageIntegrity([0xAB; 20]), 24),
+            (
+                Attribute::ErrorCode {
+                    code: 401,
+                    reason: "Unauthorized".to_owned(),
+                },
+                20,
+            ),
+            (Attribute::UnknownAttributes(vec![0x001A]), 8),
+        ] {
+            assert_round_trip(attribute, *expected_size);
+        }
     }
 }
@@ -0,0 +1,146 @@
+use crate::messages::CustomAttribute;
+
+use super::Result;
+
+/// Decodes the value of a vendor attribute, registered with the decoder for the attribute type,
+/// see `Decoder::with_attribute`.
+///
+/// Encoding needs no registration, a `CustomAttribute` encodes its own value.
+pub trait AttributeCodec: Send + Sync {
+    /// Decodes the value of the attribute, without its header and padding.
+    fn decode(&self, value: &[u8]) -> Result<Box<dyn CustomAttribute>>;
+}
+
+impl<F> AttributeCodec for F
+where
+    F: Fn(&[u8]) -> Result<Box<dyn CustomAttribute>> + Send + Sync,
+{
+    fn decode(&self, value: &[u8]) -> Result<Box<dyn CustomAttribute>> {
+        self(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use crate::codec::error::CodecError;
+    use crate::codec::{Decoder, Encoder, Result};
+    use crate::messages::*;
+
+    // a vendor attribute in the comprehension-optional range
+    const BANDWIDTH: u16 = 0xC001;
+
+    #[derive(Debug, PartialEq)]
+    struct Bandwidth {
+        kbps: u32,
+    }
+
+    impl CustomAttribute for Bandwidth {
+        fn kind(&self) -> u16 {
+            BANDWIDTH
+        }
+
+        fn encode_value(&self) -> Vec<u8> {
+            self.kbps.to_be_bytes().to_vec()
+        }
+    }
+
+    fn decode_bandwidth(value: &[u8]) -> Result<Box<dyn CustomAttribute>> {
+        if value.len() != 4 {
+            return Err(CodecError::unexpected("Invalid Bandwidth size"));
+        }
+        let mut kbps = [0u8; 4];
+        kbps.copy_from_slice(value);
+        Ok(Box::new(Bandwidth {
+            kbps: u32::from_be_bytes(kbps),
+        }))
+    }
+
+    fn message(attributes: Vec<Attribute>) -> Message {
+        Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Custom(0x0F0),
+            transaction_id: TransactionID::from([1u8; 12]),
+            attributes,
+        }
+    }
+
+    #[test]
+    pub fn test_encode_decode_custom_attribute() {
+        let message = message(vec![
+            Attribute::Software("stun-rs".to_owned()),
+            Attribute::Custom(Box::new(Bandwidth { kbps: 2048 })),
+        ]);
+        let mut bytes_mut = BytesMut::new();
+        Encoder::new().encode(&message, &mut bytes_mut).unwrap();
+
+        let decoder = Decoder::new().with_attribute(BANDWIDTH, decode_bandwidth);
+        let decoded = decoder.decode(&mut &bytes_mut[..]).unwrap();
+        assert_eq!(decoded, message);
+        match &decoded.attributes[1] {
+            Attribute::Custom(attribute) => assert_eq!(
+                attribute.downcast_ref::<Bandwidth>(),
+                Some(&Bandwidth { kbps: 2048 })
+            ),
+            attribute => panic!("unexpected attribute: {:?}", attribute),
+        }
+
+        // without the codec the attribute is not recognized
+        let decoded = Decoder::new().decode(&mut &bytes_mut[..]).unwrap();
+        assert_eq!(
+            decoded.attributes[1],
+            Attribute::UnRecognized { kind: BANDWIDTH }
+        );
+    }
+
+    #[test]
+    pub fn test_custom_attribute_errors() {
+        let mut bytes_mut = BytesMut::new();
+        Encoder::new()
+            .encode(
+                &message(vec![Attribute::Padding(vec![0u8; 3])]),
+                &mut bytes_mut,
+            )
+            .unwrap();
+        // claim the PADDING attribute is a Bandwidth of the wrong size
+        bytes_mut[20..22].copy_from_slice(&BANDWIDTH.to_be_bytes());
+        let decoder = Decoder::new().with_attribute(BANDWIDTH, decode_bandwidth);
+        assert!(decoder.decode(&mut &bytes_mut[..]).is_err());
+    }
+
+    // built-in attributes take precedence, they cannot be decoded by a registered codec
+    #[test]
+    #[should_panic(expected = "attribute 0x0020 is decoded by the built-in codec")]
+    pub fn test_reject_built_in_attribute() {
+        Decoder::new().with_attribute(0x0020, decode_bandwidth);
+    }
+
+    // a custom attribute of a built-in type would not decode back into itself, neither can an
+    // unrecognized attribute be encoded, nothing is written for either
+    #[test]
+    pub fn test_encode_errors() {
+        #[derive(Debug)]
+        struct Software;
+
+        impl CustomAttribute for Software {
+            fn kind(&self) -> u16 {
+                0x8022
+            }
+
+            fn encode_value(&self) -> Vec<u8> {
+                b"stun-rs".to_vec()
+            }
+        }
+
+        for attribute in [
+            Attribute::Custom(Box::new(Software)),
+            Attribute::UnRecognized { kind: BANDWIDTH },
+        ] {
+            let mut bytes_mut = BytesMut::new();
+            let message = message(vec![Attribute::UseCandidate, attribute]);
+            assert!(Encoder::new().encode(&message, &mut bytes_mut).is_err());
+            assert!(bytes_mut.is_empty());
+        }
+    }
+}
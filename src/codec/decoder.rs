@@ -1,17 +1,108 @@
+use std::collections::HashMap;
+
 use bytes::Buf;
 
 use crate::codec::error::CodecError;
+use crate::codec::AttributeCodec;
 use crate::codec::MAGIC_COOKIE;
 use crate::messages::*;
 
-use super::attributes::decode_attribute;
+use super::attributes::{decode_attribute, is_address, is_builtin, max_value_length, padding_size};
 use super::Result;
 
-pub struct Decoder {}
+// FINGERPRINT is the last attribute of a message
+const FINGERPRINT: u16 = 0x8028;
+
+/// What the decoder tolerates besides well-formed messages.
+///
+/// The default is lenient and accepts what implementations send in the wild, e.g. the test
+/// vectors of RFC 5769 pad with spaces. Strict decoding rejects what RFC 5389 rules out.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DecoderOptions {
+    // accept classic STUN (RFC 3489) messages, which have no magic cookie
+    pub classic_stun: bool,
+    // reject padding bytes which are not zero
+    pub zero_padding: bool,
+    // reject an attribute type which shows up more than once
+    pub unique_attributes: bool,
+    // reject attributes after FINGERPRINT
+    pub fingerprint_last: bool,
+    // reject strings longer than RFC 5389 allows, e.g. 513 bytes of USERNAME
+    pub string_limits: bool,
+    // reject addresses whose length does not match their family
+    pub exact_address_length: bool,
+}
+
+impl DecoderOptions {
+    pub fn strict() -> DecoderOptions {
+        DecoderOptions {
+            classic_stun: false,
+            zero_padding: true,
+            unique_attributes: true,
+            fingerprint_last: true,
+            string_limits: true,
+            exact_address_length: true,
+        }
+    }
+
+    pub fn lenient() -> DecoderOptions {
+        DecoderOptions::default()
+    }
+
+    pub fn with_classic_stun(mut self, classic_stun: bool) -> DecoderOptions {
+        self.classic_stun = classic_stun;
+        self
+    }
+}
+
+pub struct Decoder {
+    options: DecoderOptions,
+    // codecs of vendor attributes, by attribute type
+    attributes: HashMap<u16, Box<dyn AttributeCodec>>,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new()
+    }
+}
 
 impl Decoder {
     pub fn new() -> Decoder {
-        Decoder {}
+        Decoder::with_options(DecoderOptions::lenient())
+    }
+
+    /// A decoder which also accepts classic STUN (RFC 3489) messages.
+    pub fn with_classic_stun() -> Decoder {
+        Decoder::with_options(DecoderOptions::lenient().with_classic_stun(true))
+    }
+
+    pub fn with_options(options: DecoderOptions) -> Decoder {
+        Decoder {
+            options,
+            attributes: HashMap::new(),
+        }
+    }
+
+    /// Decodes attributes of this type with the codec into `Attribute::Custom`, instead of
+    /// `Attribute::UnRecognized`.
+    ///
+    /// # Panics
+    ///
+    /// Panics when the type is one the built-in codec decodes, those always keep their own
+    /// variant.
+    pub fn with_attribute<C: AttributeCodec + 'static>(mut self, kind: u16, codec: C) -> Decoder {
+        assert!(
+            !is_builtin(kind),
+            "attribute 0x{:04X} is decoded by the built-in codec",
+            kind
+        );
+        self.attributes.insert(kind, Box::new(codec));
+        self
+    }
+
+    pub fn options(&self) -> &DecoderOptions {
+        &self.options
     }
 
     pub fn decode(&self, buf: &mut dyn Buf) -> Result<Message> {
@@ -35,7 +126,7 @@ impl Decoder {
             )));
         }
         let magic_cookie = buf.get_u32();
-        if magic_cookie != MAGIC_COOKIE {
+        if magic_cookie != MAGIC_COOKIE && !self.options.classic_stun {
             return Err(CodecError::unexpected(&format!(
                 "invalid matic cookie: {}",
                 magic_cookie
@@ -50,7 +141,10 @@ impl Decoder {
 
         let mut transaction_id_bytes: [u8; 12] = [0; 12];
         buf.copy_to_slice(&mut transaction_id_bytes);
-        let transaction_id = TransactionID::from(transaction_id_bytes);
+        let transaction_id = TransactionID {
+            magic_cookie,
+            value: transaction_id_bytes,
+        };
 
         // verify and decode body
         if buf.remaining() < message_length {
@@ -60,28 +154,99 @@ impl Decoder {
                 buf.remaining(),
             ));
         }
+        let mut body = vec![0u8; message_length];
+        buf.copy_to_slice(&mut body);
         let mut attributes: Vec<Attribute> = Vec::new();
-        let reserved = buf.remaining() - message_length;
-        while buf.remaining() > reserved {
-            let attribute = decode_attribute(buf, &transaction_id_bytes)?;
+        let mut kinds: Vec<u16> = Vec::new();
+        let mut offset = 0;
+        while offset < body.len() {
+            // the body length is a multiple of 4, so at least a header is left
+            let kind = u16::from_be_bytes([body[offset], body[offset + 1]]);
+            let length = u16::from_be_bytes([body[offset + 2], body[offset + 3]]) as usize;
+            let end = offset + 4 + length + padding_size(length);
+            if end > body.len() {
+                return Err(CodecError::attribute_overrun(
+                    kind,
+                    length,
+                    body.len() - offset - 4,
+                ));
+            }
+            self.check_attribute(kind, length, &body[offset + 4..end], &kinds)?;
+            kinds.push(kind);
+            // each attribute is decoded from its own bytes, and so cannot read into the next
+            let attribute = match self.attributes.get(&kind) {
+                Some(codec) => {
+                    Attribute::Custom(codec.decode(&body[offset + 4..offset + 4 + length])?)
+                }
+                None => decode_attribute(&mut &body[offset..end], &transaction_id_bytes)?,
+            };
             attributes.push(attribute);
+            offset = end;
         }
 
         // decode
         let msg = Message {
-            message_class: message_class,
-            message_method: message_method,
-            transaction_id: transaction_id,
-            attributes: attributes,
+            message_class,
+            message_method,
+            transaction_id,
+            attributes,
         };
         Result::Ok(msg)
     }
+
+    // the checks of the options, on the value and padding of an attribute which fits in the body
+    fn check_attribute(&self, kind: u16, length: usize, value: &[u8], kinds: &[u16]) -> Result<()> {
+        let options = &self.options;
+        if options.fingerprint_last && kinds.last() == Some(&FINGERPRINT) {
+            return Err(CodecError::unexpected(&format!(
+                "attribute 0x{:04X} after FINGERPRINT",
+                kind
+            )));
+        }
+        if options.unique_attributes && kinds.contains(&kind) {
+            return Err(CodecError::unexpected(&format!(
+                "duplicate attribute 0x{:04X}",
+                kind
+            )));
+        }
+        if options.zero_padding && value[length..].iter().any(|byte| *byte != 0) {
+            return Err(CodecError::unexpected(&format!(
+                "non-zero padding of attribute 0x{:04X}",
+                kind
+            )));
+        }
+        if options.string_limits {
+            if let Some(max) = max_value_length(kind).filter(|max| length > *max) {
+                return Err(CodecError::unexpected(&format!(
+                    "attribute 0x{:04X} of length {} is longer than {}",
+                    kind, length, max
+                )));
+            }
+        }
+        if options.exact_address_length && is_address(kind) {
+            // an unknown family is left to the attribute decoder
+            let expected = match value.get(1) {
+                Some(0x01) => 8,
+                Some(0x02) => 20,
+                _ => length,
+            };
+            if length != expected {
+                return Err(CodecError::unexpected(&format!(
+                    "Invalid address length {} of attribute 0x{:04X}",
+                    length, kind
+                )));
+            }
+        }
+        Ok(())
+    }
 }
 
+#[cfg(test)]
 mod test {
     use bytes::{Buf, BytesMut};
 
-    use crate::codec::{Decoder, Encoder};
+    use crate::codec::error::CodecError;
+    use crate::codec::{Decoder, DecoderOptions, Encoder};
     use crate::messages::*;
 
     #[test]
@@ -94,7 +259,7 @@ mod test {
             attributes: vec![Attribute::XorMappedAddress(Address::ipv4([1u8; 4], 8080))],
         };
         let mut bytes_mut = BytesMut::with_capacity(0);
-        let size = Encoder::new().encode(&message, &mut bytes_mut);
+        let size = Encoder::new().encode(&message, &mut bytes_mut).unwrap();
         assert_eq!(size, 20 + 12);
 
         let mut bytes = bytes_mut.bytes();
@@ -115,10 +280,171 @@ mod test {
             ],
         };
         let mut bytes_mut = BytesMut::with_capacity(0);
-        Encoder::new().encode(&message, &mut bytes_mut);
+        Encoder::new().encode(&message, &mut bytes_mut).unwrap();
 
         let mut bytes = bytes_mut.bytes();
         let decoded_message = Decoder::new().decode(&mut bytes).unwrap();
         assert_eq!(decoded_message, message)
     }
+
+    #[test]
+    pub fn test_decode_truncated_attributes() {
+        let message = Message {
+            message_class: MessageClass::SuccessResponse,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::from([1u8; 12]),
+            attributes: vec![
+                Attribute::XorMappedAddress(Address::ipv6([1u8; 16], 8080)),
+                Attribute::ErrorCode {
+                    code: 420,
+                    reason: "Unknown Attribute".to_owned(),
+                },
+                Attribute::Software("stun-rs".to_owned()),
+                Attribute::EvenPort(true),
+            ],
+        };
+        let mut bytes_mut = BytesMut::with_capacity(0);
+        Encoder::new().encode(&message, &mut bytes_mut).unwrap();
+
+        // cut the message at every 4 bytes boundary of the body, with the length to match
+        for length in (0..bytes_mut.len() - 20).step_by(4) {
+            let mut truncated = bytes_mut[..20 + length].to_vec();
+            truncated[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+            let _ = Decoder::new().decode(&mut &truncated[..]);
+        }
+        // a value which claims more bytes than there are
+        let mut overrun = bytes_mut[..28].to_vec();
+        overrun[2..4].copy_from_slice(&8u16.to_be_bytes());
+        overrun[22..24].copy_from_slice(&0xFFFFu16.to_be_bytes());
+        assert!(Decoder::new().decode(&mut &overrun[..]).is_err());
+    }
+
+    fn header(length: u16) -> Vec<u8> {
+        let mut bytes = vec![0x00, 0x01];
+        bytes.extend_from_slice(&length.to_be_bytes());
+        bytes.extend_from_slice(&[0x21, 0x12, 0xA4, 0x42]);
+        bytes.extend_from_slice(&[1u8; 12]);
+        bytes
+    }
+
+    #[test]
+    pub fn test_skip_padding_of_unrecognized_attributes() {
+        let mut bytes = header(16);
+        // an unknown comprehension-optional attribute of 3 bytes and its padding
+        bytes.extend_from_slice(&[0x80, 0x55, 0x00, 0x03, 0xAA, 0xAA, 0xAA, 0x00]);
+        bytes.extend_from_slice(&[0x80, 0x22, 0x00, 0x03, 0x61, 0x62, 0x63, 0x00]);
+        let message = Decoder::new().decode(&mut &bytes[..]).unwrap();
+        assert_eq!(
+            message.attributes,
+            vec![
+                Attribute::UnRecognized { kind: 0x8055 },
+                Attribute::Software("abc".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_attribute_overrun() {
+        // SOFTWARE claims 8 bytes but the body ends after 4, the bytes after the message are
+        // not part of it
+        let mut bytes = header(8);
+        bytes.extend_from_slice(&[0x80, 0x22, 0x00, 0x08, 0x61, 0x62, 0x63, 0x64]);
+        bytes.extend_from_slice(&[0x65, 0x66, 0x67, 0x68]);
+        match Decoder::new().decode(&mut &bytes[..]) {
+            Err(CodecError::AttributeOverrun {
+                kind,
+                length,
+                remaining,
+            }) => assert_eq!((kind, length, remaining), (0x8022, 8, 4)),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        // padding counts as well
+        let mut bytes = header(8);
+        bytes.extend_from_slice(&[0x80, 0x22, 0x00, 0x05, 0x61, 0x62, 0x63, 0x64]);
+        bytes.extend_from_slice(&[0x65, 0x00, 0x00, 0x00]);
+        assert!(matches!(
+            Decoder::new().decode(&mut &bytes[..]),
+            Err(CodecError::AttributeOverrun { .. })
+        ));
+    }
+
+    fn message(attributes: &[&[u8]]) -> Vec<u8> {
+        let body = attributes.concat();
+        let mut bytes = header(body.len() as u16);
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    #[test]
+    pub fn test_strict_and_lenient_options() {
+        let software = [&[0x80, 0x22, 0x02, 0xFC][..], &[0x61; 764]].concat();
+        let rejected: Vec<Vec<u8>> = vec![
+            // padding with spaces
+            message(&[&[0x80, 0x22, 0x00, 0x03, 0x61, 0x62, 0x63, 0x20]]),
+            // PRIORITY twice
+            message(&[
+                &[0x00, 0x24, 0x00, 0x04, 0, 0, 0, 1],
+                &[0x00, 0x24, 0x00, 0x04, 0, 0, 0, 2],
+            ]),
+            // SOFTWARE after FINGERPRINT
+            message(&[
+                &[0x80, 0x28, 0x00, 0x04, 0, 0, 0, 0],
+                &[0x80, 0x22, 0x00, 0x00],
+            ]),
+            // SOFTWARE of 764 bytes
+            message(&[&software]),
+            // an IPv4 MAPPED-ADDRESS of 12 bytes
+            message(&[&[
+                0x00, 0x01, 0x00, 0x0C, 0x00, 0x01, 0x1F, 0x90, 1, 2, 3, 4, 0, 0, 0, 0,
+            ]]),
+        ];
+
+        let strict = Decoder::with_options(DecoderOptions::strict());
+        for bytes in rejected {
+            assert!(Decoder::new().decode(&mut &bytes[..]).is_ok());
+            assert!(strict.decode(&mut &bytes[..]).is_err());
+        }
+
+        let mut bytes_mut = BytesMut::with_capacity(0);
+        let message = Message {
+            message_class: MessageClass::SuccessResponse,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::from([1u8; 12]),
+            attributes: vec![
+                Attribute::Software("stun-rs".to_owned()),
+                Attribute::XorMappedAddress(Address::ipv6([1u8; 16], 8080)),
+                Attribute::FingerPrint(0),
+            ],
+        };
+        Encoder::new().encode(&message, &mut bytes_mut).unwrap();
+        assert_eq!(strict.decode(&mut bytes_mut.bytes()).unwrap(), message);
+    }
+
+    #[test]
+    pub fn test_decode_classic_stun_message() {
+        let mut id = [2u8; 16];
+        id[..4].copy_from_slice(&[0xA1, 0xA2, 0xA3, 0xA4]);
+        let message = Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::classic(id),
+            attributes: vec![
+                Attribute::ResponseAddress(Address::ipv4([10, 0, 0, 1], 4000)),
+                Attribute::ChangeRequest {
+                    change_ip: true,
+                    change_port: true,
+                },
+            ],
+        };
+        let mut bytes_mut = BytesMut::with_capacity(0);
+        Encoder::new().encode(&message, &mut bytes_mut).unwrap();
+        assert_eq!(&bytes_mut[4..20], &id[..]);
+
+        assert!(Decoder::new().decode(&mut bytes_mut.bytes()).is_err());
+        let decoded_message = Decoder::with_classic_stun()
+            .decode(&mut bytes_mut.bytes())
+            .unwrap();
+        assert_eq!(decoded_message, message)
+    }
 }
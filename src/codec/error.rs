@@ -10,6 +10,12 @@ pub enum CodecError {
         required: usize,
         actual: usize,
     },
+    // an attribute with its padding does not fit in what is left of the message body
+    AttributeOverrun {
+        kind: u16,
+        length: usize,
+        remaining: usize,
+    },
     UnExpected(String),
 }
 
@@ -25,6 +31,15 @@ impl Display for CodecError {
                 "insufficient bytes when {}, required {}, actual: {}",
                 when, required, actual
             ),
+            CodecError::AttributeOverrun {
+                kind,
+                length,
+                remaining,
+            } => write!(
+                f,
+                "attribute 0x{:04X} of length {} overruns the message body, remaining: {}",
+                kind, length, remaining
+            ),
             CodecError::UnExpected(msg) => write!(f, "{}", msg),
         }
     }
@@ -46,6 +61,14 @@ impl CodecError {
         }
     }
 
+    pub fn attribute_overrun(kind: u16, length: usize, remaining: usize) -> CodecError {
+        CodecError::AttributeOverrun {
+            kind,
+            length,
+            remaining,
+        }
+    }
+
     pub fn unexpected(msg: &str) -> CodecError {
         CodecError::UnExpected(msg.to_owned())
     }
@@ -0,0 +1,168 @@
+use bytes::{BufMut, BytesMut};
+use hmac::{Hmac, Mac, NewMac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+// FINGERPRINT is the CRC-32 of the message xored with this value
+const FINGERPRINT_XOR: u32 = 0x5354_554E;
+
+/// Appends MESSAGE-INTEGRITY, the HMAC-SHA1 of the encoded message keyed with the key, to an
+/// encoded message.
+///
+/// For short-term credentials the key is the password, MESSAGE-INTEGRITY has to be appended
+/// before FINGERPRINT.
+pub fn append_message_integrity(bytes: &mut BytesMut, key: &[u8]) {
+    let hmac = message_integrity(bytes, key);
+    set_message_length(bytes, bytes.len() - 20 + 24);
+    bytes.put_u16(0x0008);
+    bytes.put_u16(20);
+    bytes.put_slice(&hmac);
+}
+
+/// The MESSAGE-INTEGRITY key of long-term credentials, MD5(username ":" realm ":" password).
+///
+/// The password has to be processed with SASLprep (RFC 4013) already.
+pub fn long_term_key(username: &str, realm: &str, password: &str) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key.copy_from_slice(&Md5::digest(
+        format!("{}:{}:{}", username, realm, password).as_bytes(),
+    ));
+    key
+}
+
+/// Appends FINGERPRINT, the CRC-32 of the encoded message, to an encoded message.
+pub fn append_fingerprint(bytes: &mut BytesMut) {
+    let crc = fingerprint(bytes);
+    set_message_length(bytes, bytes.len() - 20 + 8);
+    bytes.put_u16(0x8028);
+    bytes.put_u16(4);
+    bytes.put_u32(crc);
+}
+
+/// Verifies the MESSAGE-INTEGRITY of an encoded message, false when the message has none.
+pub fn verify_message_integrity(bytes: &[u8], key: &[u8]) -> bool {
+    match find_attribute(bytes, 0x0008) {
+        Some(offset) if offset + 24 <= bytes.len() => {
+            let mut covered = BytesMut::from(&bytes[..offset]);
+            set_message_length(&mut covered, offset - 20 + 24);
+            let mut mac = Hmac::<Sha1>::new_varkey(key).expect("HMAC accepts keys of any size");
+            mac.update(&covered);
+            mac.verify(&bytes[offset + 4..offset + 24]).is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// Verifies the FINGERPRINT of an encoded message, false when the message has none.
+pub fn verify_fingerprint(bytes: &[u8]) -> bool {
+    match find_attribute(bytes, 0x8028) {
+        Some(offset) if offset + 8 <= bytes.len() => {
+            let mut covered = BytesMut::from(&bytes[..offset]);
+            set_message_length(&mut covered, offset - 20 + 8);
+            let expected = u32::from_be_bytes([
+                bytes[offset + 4],
+                bytes[offset + 5],
+                bytes[offset + 6],
+                bytes[offset + 7],
+            ]);
+            crc32fast::hash(&covered) ^ FINGERPRINT_XOR == expected
+        }
+        _ => false,
+    }
+}
+
+// the HMAC of the message, with the message length covering the MESSAGE-INTEGRITY to append
+fn message_integrity(bytes: &BytesMut, key: &[u8]) -> [u8; 20] {
+    let mut covered = bytes.clone();
+    set_message_length(&mut covered, bytes.len() - 20 + 24);
+    let mut mac = Hmac::<Sha1>::new_varkey(key).expect("HMAC accepts keys of any size");
+    mac.update(&covered);
+    let mut hmac = [0u8; 20];
+    hmac.copy_from_slice(&mac.finalize().into_bytes());
+    hmac
+}
+
+// the CRC-32 of the message, with the message length covering the FINGERPRINT to append
+fn fingerprint(bytes: &BytesMut) -> u32 {
+    let mut covered = bytes.clone();
+    set_message_length(&mut covered, bytes.len() - 20 + 8);
+    crc32fast::hash(&covered) ^ FINGERPRINT_XOR
+}
+
+fn set_message_length(bytes: &mut BytesMut, length: usize) {
+    bytes[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+}
+
+// offset of the first attribute of the given type, walking the attributes of the message
+fn find_attribute(bytes: &[u8], kind: u16) -> Option<usize> {
+    let mut offset = 20;
+    while offset + 4 <= bytes.len() {
+        let attribute_type = u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        if attribute_type == kind {
+            return Some(offset);
+        }
+        let size = u16::from_be_bytes([bytes[offset + 2], bytes[offset + 3]]) as usize;
+        offset += 4 + size + (4 - size % 4) % 4;
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use crate::codec::integrity::*;
+    use crate::codec::{Decoder, Encoder};
+    use crate::messages::*;
+
+    fn encoded_request() -> BytesMut {
+        let message = Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::from([5u8; 12]),
+            attributes: vec![Attribute::UserName("evtj:h6vY".to_owned())],
+        };
+        let mut bytes_mut = BytesMut::new();
+        Encoder::new().encode(&message, &mut bytes_mut).unwrap();
+        bytes_mut
+    }
+
+    #[test]
+    pub fn test_append_and_verify_integrity_and_fingerprint() {
+        let mut bytes_mut = encoded_request();
+        append_message_integrity(&mut bytes_mut, b"VOkJxbRl1RmTxUk/WvJxBt");
+        append_fingerprint(&mut bytes_mut);
+        assert!(verify_message_integrity(
+            &bytes_mut,
+            b"VOkJxbRl1RmTxUk/WvJxBt"
+        ));
+        assert!(!verify_message_integrity(&bytes_mut, b"wrong password"));
+        assert!(verify_fingerprint(&bytes_mut));
+
+        let message = Decoder::new().decode(&mut &bytes_mut[..]).unwrap();
+        assert_eq!(message.attributes.len(), 3);
+        assert!(matches!(
+            message.attributes[1],
+            Attribute::MessageIntegrity(_)
+        ));
+        assert!(matches!(message.attributes[2], Attribute::FingerPrint(_)));
+    }
+
+    #[test]
+    pub fn test_detect_modified_message() {
+        let mut bytes_mut = encoded_request();
+        append_message_integrity(&mut bytes_mut, b"password");
+        append_fingerprint(&mut bytes_mut);
+        // flip a bit of the USERNAME
+        bytes_mut[24] ^= 0x01;
+        assert!(!verify_message_integrity(&bytes_mut, b"password"));
+        assert!(!verify_fingerprint(&bytes_mut));
+    }
+
+    #[test]
+    pub fn test_verify_message_without_integrity() {
+        let bytes_mut = encoded_request();
+        assert!(!verify_message_integrity(&bytes_mut, b"password"));
+        assert!(!verify_fingerprint(&bytes_mut));
+    }
+}
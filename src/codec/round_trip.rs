@@ -0,0 +1,142 @@
+// property based tests: every valid message encodes into bytes which decode into that message
+use bytes::BytesMut;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::strategy::LazyJust;
+
+use crate::codec::{Decoder, Encoder};
+use crate::messages::*;
+
+fn message_class() -> impl Strategy<Value = MessageClass> {
+    (0u8..4).prop_map(MessageClass::from)
+}
+
+fn message_method() -> impl Strategy<Value = MessageMethod> {
+    (0u16..0x1000).prop_map(MessageMethod::from)
+}
+
+fn transaction_id() -> impl Strategy<Value = TransactionID> {
+    prop_oneof![
+        any::<[u8; 12]>().prop_map(TransactionID::from),
+        any::<[u8; 16]>().prop_map(TransactionID::classic),
+    ]
+}
+
+fn ip_kind() -> impl Strategy<Value = IPKind> {
+    any::<bool>().prop_map(|ipv6| if ipv6 { IPKind::IPv6 } else { IPKind::IPv4 })
+}
+
+fn address() -> impl Strategy<Value = Address> {
+    prop_oneof![
+        (any::<[u8; 4]>(), any::<u16>()).prop_map(|(ip, port)| Address::ipv4(ip, port)),
+        (any::<[u8; 16]>(), any::<u16>()).prop_map(|(ip, port)| Address::ipv6(ip, port)),
+    ]
+}
+
+// up to 12 characters of 1 to 4 bytes each, so that every padding size shows up
+fn text() -> impl Strategy<Value = String> {
+    "\\PC{0,12}"
+}
+
+// the class is 3 bits and the number two decimal digits
+fn error_code() -> impl Strategy<Value = u32> {
+    (0u32..8, 0u32..100).prop_map(|(class, number)| class * 100 + number)
+}
+
+// every attribute the encoder supports, UnRecognized keeps no value to encode
+fn attribute() -> impl Strategy<Value = Attribute> {
+    prop_oneof![
+        address().prop_map(Attribute::MappedAddress),
+        address().prop_map(Attribute::ResponseAddress),
+        address().prop_map(Attribute::SourceAddress),
+        address().prop_map(Attribute::ChangedAddress),
+        address().prop_map(Attribute::ReflectedFrom),
+        address().prop_map(Attribute::XorMappedAddress),
+        text().prop_map(Attribute::UserName),
+        any::<[u8; 20]>().prop_map(Attribute::MessageIntegrity),
+        any::<u32>().prop_map(Attribute::FingerPrint),
+        (error_code(), text()).prop_map(|(code, reason)| Attribute::ErrorCode { code, reason }),
+        text().prop_map(Attribute::Realm),
+        text().prop_map(Attribute::Nonce),
+        vec(any::<u16>(), 0..8).prop_map(Attribute::UnknownAttributes),
+        text().prop_map(Attribute::Software),
+        address().prop_map(Attribute::AlternateServer),
+        any::<u16>().prop_map(Attribute::ChannelNumber),
+        any::<u32>().prop_map(Attribute::Lifetime),
+        address().prop_map(Attribute::XorPeerAddress),
+        vec(any::<u8>(), 0..32).prop_map(Attribute::Data),
+        address().prop_map(Attribute::XorRelayedAddress),
+        any::<u8>().prop_map(Attribute::RequestedTransport),
+        LazyJust::new(|| Attribute::DontFragment),
+        any::<u32>().prop_map(Attribute::ConnectionId),
+        ip_kind().prop_map(Attribute::RequestedAddressFamily),
+        ip_kind().prop_map(Attribute::AdditionalAddressFamily),
+        (ip_kind(), error_code(), text()).prop_map(|(ip_kind, code, reason)| {
+            Attribute::AddressErrorCode {
+                ip_kind,
+                code,
+                reason,
+            }
+        }),
+        any::<bool>().prop_map(Attribute::EvenPort),
+        any::<[u8; 8]>().prop_map(Attribute::ReservationToken),
+        (any::<bool>(), any::<bool>()).prop_map(|(change_ip, change_port)| {
+            Attribute::ChangeRequest {
+                change_ip,
+                change_port,
+            }
+        }),
+        address().prop_map(Attribute::ResponseOrigin),
+        address().prop_map(Attribute::OtherAddress),
+        any::<u16>().prop_map(Attribute::ResponsePort),
+        vec(any::<u8>(), 0..32).prop_map(Attribute::Padding),
+        any::<u32>().prop_map(Attribute::Priority),
+        LazyJust::new(|| Attribute::UseCandidate),
+        any::<u64>().prop_map(Attribute::IceControlled),
+        any::<u64>().prop_map(Attribute::IceControlling),
+    ]
+}
+
+fn message() -> impl Strategy<Value = Message> {
+    (
+        message_class(),
+        message_method(),
+        transaction_id(),
+        vec(attribute(), 0..8),
+    )
+        .prop_map(
+            |(message_class, message_method, transaction_id, attributes)| Message {
+                message_class,
+                message_method,
+                transaction_id,
+                attributes,
+            },
+        )
+}
+
+proptest! {
+    #[test]
+    fn test_encode_decode_message(message in message()) {
+        let mut bytes_mut = BytesMut::new();
+        let size = Encoder::new().encode(&message, &mut bytes_mut).unwrap();
+        prop_assert_eq!(size, bytes_mut.len());
+
+        // classic STUN transaction IDs need not start with the magic cookie
+        let decoded = Decoder::with_classic_stun().decode(&mut &bytes_mut[..]).unwrap();
+        prop_assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn test_encode_decode_attribute(attribute in attribute()) {
+        let mut bytes_mut = BytesMut::new();
+        let transaction_id = [7u8; 12];
+        let size = super::attributes::encode_attribute(&attribute, &mut bytes_mut, &transaction_id).unwrap();
+        prop_assert_eq!(size, bytes_mut.len());
+        prop_assert_eq!(size % 4, 0);
+
+        let mut bytes = &bytes_mut[..];
+        let decoded = super::attributes::decode_attribute(&mut bytes, &transaction_id).unwrap();
+        prop_assert_eq!(bytes.len(), 0);
+        prop_assert_eq!(decoded, attribute);
+    }
+}
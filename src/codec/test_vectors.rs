@@ -0,0 +1,192 @@
+// conformance with the STUN test vectors of RFC 5769
+//
+// The suite is limited to the RFC vectors. Messages of other implementations (e.g. coturn,
+// libnice, pion or browsers) belong here only as captures of their real traffic: bytes written
+// by hand would only show that the codec agrees with itself.
+use std::net::SocketAddr;
+
+use bytes::BytesMut;
+
+use crate::codec::{long_term_key, verify_fingerprint, verify_message_integrity};
+use crate::codec::{Decoder, DecoderOptions, Encoder};
+use crate::messages::*;
+
+// short-term credential password of the vectors in sections 2.1 to 2.3
+const PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+
+// RFC 5769 section 2.1, sample request
+const SAMPLE_REQUEST: &[u8] = &[
+    0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86,
+    0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x10, 0x53, 0x54, 0x55, 0x4e, 0x20, 0x74, 0x65, 0x73,
+    0x74, 0x20, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x00, 0x24, 0x00, 0x04, 0x6e, 0x00, 0x01, 0xff,
+    0x80, 0x29, 0x00, 0x08, 0x93, 0x2f, 0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36, 0x00, 0x06, 0x00, 0x09,
+    0x65, 0x76, 0x74, 0x6a, 0x3a, 0x68, 0x36, 0x76, 0x59, 0x20, 0x20, 0x20, 0x00, 0x08, 0x00, 0x14,
+    0x9a, 0xea, 0xa7, 0x0c, 0xbf, 0xd8, 0xcb, 0x56, 0x78, 0x1e, 0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49,
+    0xc1, 0xb5, 0x71, 0xa2, 0x80, 0x28, 0x00, 0x04, 0xe5, 0x7a, 0x3b, 0xcf,
+];
+
+// RFC 5769 section 2.2, sample IPv4 response
+const SAMPLE_IPV4_RESPONSE: &[u8] = &[
+    0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86,
+    0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63,
+    0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+    0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3, 0x8c, 0x74, 0x89, 0xf9,
+    0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96,
+];
+
+// RFC 5769 section 2.3, sample IPv6 response
+const SAMPLE_IPV6_RESPONSE: &[u8] = &[
+    0x01, 0x01, 0x00, 0x48, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86,
+    0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63,
+    0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa,
+    0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9, 0x00, 0x08, 0x00, 0x14,
+    0xa3, 0x82, 0x95, 0x4e, 0x4b, 0xe6, 0x7b, 0xf1, 0x17, 0x84, 0xc9, 0x7c, 0x82, 0x92, 0xc2, 0x75,
+    0xbf, 0xe3, 0xed, 0x41, 0x80, 0x28, 0x00, 0x04, 0xc8, 0xfb, 0x0b, 0x4c,
+];
+
+// RFC 5769 section 2.4, sample request with long-term authentication
+const SAMPLE_LONG_TERM_REQUEST: &[u8] = &[
+    0x00, 0x01, 0x00, 0x60, 0x21, 0x12, 0xa4, 0x42, 0x78, 0xad, 0x34, 0x33, 0xc6, 0xad, 0x72, 0xc0,
+    0x29, 0xda, 0x41, 0x2e, 0x00, 0x06, 0x00, 0x12, 0xe3, 0x83, 0x9e, 0xe3, 0x83, 0x88, 0xe3, 0x83,
+    0xaa, 0xe3, 0x83, 0x83, 0xe3, 0x82, 0xaf, 0xe3, 0x82, 0xb9, 0x00, 0x00, 0x00, 0x15, 0x00, 0x1c,
+    0x66, 0x2f, 0x2f, 0x34, 0x39, 0x39, 0x6b, 0x39, 0x35, 0x34, 0x64, 0x36, 0x4f, 0x4c, 0x33, 0x34,
+    0x6f, 0x4c, 0x39, 0x46, 0x53, 0x54, 0x76, 0x79, 0x36, 0x34, 0x73, 0x41, 0x00, 0x14, 0x00, 0x0b,
+    0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x6f, 0x72, 0x67, 0x00, 0x00, 0x08, 0x00, 0x14,
+    0xf6, 0x70, 0x24, 0x65, 0x6d, 0xd6, 0x4a, 0x3e, 0x02, 0xb8, 0xe0, 0x71, 0x2e, 0x85, 0xc9, 0xa2,
+    0x8c, 0xa8, 0x96, 0x66,
+];
+
+const TRANSACTION_ID: [u8; 12] = [
+    0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+];
+
+fn decode(vector: &[u8]) -> Message {
+    Decoder::new().decode(&mut &vector[..]).unwrap()
+}
+
+// encodes the decoded message again, the vectors pad with spaces where the encoder pads with
+// zeros, which receivers have to ignore
+fn assert_reencoded(message: &Message, vector: &[u8]) {
+    let mut bytes_mut = BytesMut::new();
+    Encoder::new().encode(message, &mut bytes_mut).unwrap();
+    assert_eq!(&bytes_mut[..], &zero_padding(vector)[..]);
+}
+
+fn zero_padding(vector: &[u8]) -> Vec<u8> {
+    let mut bytes = vector.to_vec();
+    let mut offset = 20;
+    while offset + 4 <= bytes.len() {
+        let size = u16::from_be_bytes([bytes[offset + 2], bytes[offset + 3]]) as usize;
+        let padded = size.div_ceil(4) * 4;
+        for byte in &mut bytes[offset + 4 + size..offset + 4 + padded] {
+            *byte = 0;
+        }
+        offset += 4 + padded;
+    }
+    bytes
+}
+
+fn xor_mapped_address(message: &Message) -> SocketAddr {
+    message
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            Attribute::XorMappedAddress(address) => Some(address.to_socket_addr()),
+            _ => None,
+        })
+        .unwrap()
+}
+
+#[test]
+pub fn test_sample_request() {
+    assert!(verify_message_integrity(SAMPLE_REQUEST, PASSWORD));
+    assert!(verify_fingerprint(SAMPLE_REQUEST));
+
+    let message = decode(SAMPLE_REQUEST);
+    assert_eq!(message.message_class, MessageClass::Request);
+    assert_eq!(message.message_method, MessageMethod::Binding);
+    assert_eq!(message.transaction_id, TransactionID::from(TRANSACTION_ID));
+    assert_eq!(
+        &message.attributes[..4],
+        &[
+            Attribute::Software("STUN test client".to_owned()),
+            Attribute::Priority(0x6e00_01ff),
+            Attribute::IceControlled(0x932f_f9b1_5126_3b36),
+            Attribute::UserName("evtj:h6vY".to_owned()),
+        ]
+    );
+    assert_eq!(message.attributes[5], Attribute::FingerPrint(0xe57a_3bcf));
+    assert_reencoded(&message, SAMPLE_REQUEST);
+
+    // USERNAME and SOFTWARE are padded with spaces, which only lenient decoders accept
+    let strict = Decoder::with_options(DecoderOptions::strict());
+    assert!(strict.decode(&mut &SAMPLE_REQUEST[..]).is_err());
+}
+
+#[test]
+pub fn test_sample_ipv4_response() {
+    assert!(verify_message_integrity(SAMPLE_IPV4_RESPONSE, PASSWORD));
+    assert!(verify_fingerprint(SAMPLE_IPV4_RESPONSE));
+
+    let message = decode(SAMPLE_IPV4_RESPONSE);
+    assert_eq!(message.message_class, MessageClass::SuccessResponse);
+    assert_eq!(message.transaction_id, TransactionID::from(TRANSACTION_ID));
+    assert_eq!(
+        message.attributes[0],
+        Attribute::Software("test vector".to_owned())
+    );
+    assert_eq!(
+        xor_mapped_address(&message),
+        "192.0.2.1:32853".parse().unwrap()
+    );
+    assert_reencoded(&message, SAMPLE_IPV4_RESPONSE);
+}
+
+#[test]
+pub fn test_sample_ipv6_response() {
+    assert!(verify_message_integrity(SAMPLE_IPV6_RESPONSE, PASSWORD));
+    assert!(verify_fingerprint(SAMPLE_IPV6_RESPONSE));
+
+    let message = decode(SAMPLE_IPV6_RESPONSE);
+    assert_eq!(message.message_class, MessageClass::SuccessResponse);
+    assert_eq!(
+        xor_mapped_address(&message),
+        "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            .parse()
+            .unwrap()
+    );
+    assert_reencoded(&message, SAMPLE_IPV6_RESPONSE);
+}
+
+#[test]
+pub fn test_sample_long_term_request() {
+    // the password "The<U+00AD>M<U+00AA>tr<U+2168>" after SASLprep
+    let key = long_term_key(
+        "\u{30DE}\u{30C8}\u{30EA}\u{30C3}\u{30AF}\u{30B9}",
+        "example.org",
+        "TheMatrIX",
+    );
+    assert!(verify_message_integrity(SAMPLE_LONG_TERM_REQUEST, &key));
+    assert!(!verify_message_integrity(
+        SAMPLE_LONG_TERM_REQUEST,
+        PASSWORD
+    ));
+
+    let message = decode(SAMPLE_LONG_TERM_REQUEST);
+    assert_eq!(message.message_class, MessageClass::Request);
+    assert_eq!(
+        &message.attributes[..3],
+        &[
+            Attribute::UserName("\u{30DE}\u{30C8}\u{30EA}\u{30C3}\u{30AF}\u{30B9}".to_owned()),
+            Attribute::Nonce("f//499k954d6OL34oL9FSTvy64sA".to_owned()),
+            Attribute::Realm("example.org".to_owned()),
+        ]
+    );
+    assert_reencoded(&message, SAMPLE_LONG_TERM_REQUEST);
+
+    let strict = Decoder::with_options(DecoderOptions::strict());
+    assert_eq!(
+        strict.decode(&mut &SAMPLE_LONG_TERM_REQUEST[..]).unwrap(),
+        message
+    );
+}
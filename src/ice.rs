@@ -0,0 +1,13 @@
+// Interactive Connectivity Establishment (RFC 8445)
+pub use agent::*;
+pub use candidate::*;
+pub use consent::*;
+pub use lite::*;
+
+mod agent;
+
+mod candidate;
+
+mod consent;
+
+mod lite;
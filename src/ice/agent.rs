@@ -0,0 +1,1560 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use rand::Rng;
+
+use crate::codec::{
+    append_fingerprint, append_message_integrity, verify_fingerprint, verify_message_integrity,
+    Decoder, Encoder,
+};
+use crate::ice::candidate::*;
+use crate::ice::consent::*;
+use crate::messages::*;
+use crate::transaction::{ClientTransaction, TransactionResult};
+use crate::turn::{Connection, TurnClient, TurnClientEvent};
+
+// pacing of the connectivity checks (RFC 8445 section 14.2)
+const TA: Duration = Duration::from_millis(50);
+// initial retransmission timeout of a STUN transaction
+const RTO: Duration = Duration::from_millis(250);
+// a transaction fails after this many transmissions without a response
+const MAX_TRANSMISSIONS: u32 = 7;
+// candidate pairs beyond this limit are pruned
+const MAX_PAIRS: usize = 100;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum IceMode {
+    Full,
+    // only host candidates, never sends checks and is always controlled (RFC 8445 section 2.5)
+    Lite,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum IceRole {
+    Controlling,
+    Controlled,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum IceState {
+    New,
+    Checking,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum PairState {
+    Frozen,
+    Waiting,
+    InProgress,
+    Succeeded,
+    Failed,
+}
+
+/// The username fragment and password exchanged through signaling, the password keys the
+/// MESSAGE-INTEGRITY of the checks.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Credentials {
+    pub ufrag: String,
+    pub pwd: String,
+}
+
+impl Credentials {
+    pub fn new(ufrag: &str, pwd: &str) -> Credentials {
+        Credentials {
+            ufrag: ufrag.to_owned(),
+            pwd: pwd.to_owned(),
+        }
+    }
+
+    pub fn random() -> Credentials {
+        Credentials {
+            ufrag: random_ice_chars(8),
+            pwd: random_ice_chars(24),
+        }
+    }
+}
+
+fn random_ice_chars(len: usize) -> String {
+    const ICE_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut rng = rand::thread_rng();
+    (0..len)
+        .map(|_| ICE_CHARS[rng.gen_range(0, ICE_CHARS.len())] as char)
+        .collect()
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum IceEvent {
+    CandidateGathered(Candidate),
+    // the local end-of-candidates of trickle ICE
+    GatheringComplete,
+    StateChanged(IceState),
+    Selected { local: Candidate, remote: Candidate },
+    // consent freshness (RFC 7675) of the selected pair expired, sending has to stop
+    ConsentLost,
+    // application data the remote agent sent to the relayed candidate, data sent to a host
+    // candidate arrives on its socket
+    Data { peer: SocketAddr, data: Vec<u8> },
+}
+
+/// A datagram to send from the socket bound to `source`.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Transmit {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct CandidatePair {
+    // indexes of the local and the remote candidates
+    local: usize,
+    remote: usize,
+    priority: u64,
+    state: PairState,
+    // a check of the pair succeeded
+    valid: bool,
+    // the controlled agent received USE-CANDIDATE before the pair was valid
+    nominate_on_success: bool,
+}
+
+#[derive(Debug)]
+enum TransactionKind {
+    Gather,
+    Check { pair: usize, use_candidate: bool },
+}
+
+#[derive(Debug)]
+struct Transaction {
+    kind: TransactionKind,
+    // the local address the request is sent from
+    source: SocketAddr,
+    transaction: ClientTransaction,
+}
+
+// the TURN allocation of the relayed candidate
+struct Relay {
+    client: TurnClient,
+    // the host candidate the TURN server is reached from
+    base: SocketAddr,
+    allocating: bool,
+    // the peers permissions and channels are asked for
+    peers: HashSet<SocketAddr>,
+}
+
+/// An ICE agent (RFC 8445) for a single component, without any IO.
+///
+/// The application binds the sockets of the host candidates, feeds the datagrams received on
+/// them to `handle_input`, sends what `poll_transmit` returns and calls `handle_timeout` once
+/// the instant returned by `poll_timeout` is reached. A relayed candidate is allocated with a
+/// `TurnClient` the agent drives itself, its traffic goes through the host candidate the TURN
+/// server is reached from.
+pub struct IceAgent {
+    mode: IceMode,
+    role: IceRole,
+    tie_breaker: u64,
+    local_credentials: Credentials,
+    remote_credentials: Option<Credentials>,
+    local_candidates: Vec<Candidate>,
+    remote_candidates: Vec<Candidate>,
+    pairs: Vec<CandidatePair>,
+    triggered: VecDeque<(usize, bool)>,
+    transactions: HashMap<[u8; 12], Transaction>,
+    next_check: Option<Instant>,
+    nominating: Option<usize>,
+    selected: Option<usize>,
+    consent: Option<ConsentFreshness>,
+    relay: Option<Relay>,
+    state: IceState,
+    gathering: bool,
+    // trickle ICE (RFC 8838), remote candidates keep coming until end-of-candidates
+    trickle: bool,
+    remote_end_of_candidates: bool,
+    events: VecDeque<IceEvent>,
+    transmits: VecDeque<Transmit>,
+}
+
+impl IceAgent {
+    pub fn new(mode: IceMode, role: IceRole) -> IceAgent {
+        IceAgent {
+            mode,
+            role: if mode == IceMode::Lite {
+                IceRole::Controlled
+            } else {
+                role
+            },
+            tie_breaker: rand::random(),
+            local_credentials: Credentials::random(),
+            remote_credentials: None,
+            local_candidates: vec![],
+            remote_candidates: vec![],
+            pairs: vec![],
+            triggered: VecDeque::new(),
+            transactions: HashMap::new(),
+            next_check: None,
+            nominating: None,
+            selected: None,
+            consent: None,
+            relay: None,
+            state: IceState::New,
+            gathering: false,
+            trickle: false,
+            remote_end_of_candidates: false,
+            events: VecDeque::new(),
+            transmits: VecDeque::new(),
+        }
+    }
+
+    pub fn with_credentials(mut self, credentials: Credentials) -> IceAgent {
+        self.local_credentials = credentials;
+        self
+    }
+
+    pub fn with_tie_breaker(mut self, tie_breaker: u64) -> IceAgent {
+        self.tie_breaker = tie_breaker;
+        self
+    }
+
+    /// Trickle ICE (RFC 8838): checks start with the candidates known so far, and fail only
+    /// after `set_remote_end_of_candidates` once the remote agent signals end-of-candidates.
+    pub fn with_trickle(mut self, trickle: bool) -> IceAgent {
+        self.trickle = trickle;
+        self
+    }
+
+    pub fn mode(&self) -> IceMode {
+        self.mode
+    }
+
+    pub fn role(&self) -> IceRole {
+        self.role
+    }
+
+    pub fn state(&self) -> IceState {
+        self.state
+    }
+
+    pub fn local_credentials(&self) -> &Credentials {
+        &self.local_credentials
+    }
+
+    pub fn set_remote_credentials(&mut self, credentials: Credentials) {
+        self.remote_credentials = Some(credentials);
+    }
+
+    pub fn local_candidates(&self) -> &[Candidate] {
+        &self.local_candidates
+    }
+
+    pub fn remote_candidates(&self) -> &[Candidate] {
+        &self.remote_candidates
+    }
+
+    /// The local and the remote candidates of the nominated pair.
+    pub fn selected_pair(&self) -> Option<(&Candidate, &Candidate)> {
+        self.selected.map(|index| {
+            let pair = &self.pairs[index];
+            (
+                &self.local_candidates[pair.local],
+                &self.remote_candidates[pair.remote],
+            )
+        })
+    }
+
+    /// Consent freshness of the selected pair, a lite agent does not check consent.
+    pub fn consent(&self) -> Option<&ConsentFreshness> {
+        self.consent.as_ref()
+    }
+
+    /// Adds the address of a socket bound by the application as a host candidate.
+    pub fn add_host_candidate(&mut self, address: SocketAddr) {
+        let local_preference = 65535 - self.local_candidates.len() as u16;
+        self.add_local_candidate(Candidate::host(address, local_preference));
+    }
+
+    /// Sends a Binding request from every host candidate to the STUN server, the server
+    /// reflexive candidates are reported by `IceEvent::CandidateGathered` and
+    /// `IceEvent::GatheringComplete` follows once all the requests are done.
+    pub fn gather_server_reflexive(&mut self, now: Instant, server: SocketAddr) {
+        if self.mode == IceMode::Lite {
+            self.events.push_back(IceEvent::GatheringComplete);
+            return;
+        }
+        let bases: Vec<SocketAddr> = self
+            .local_candidates
+            .iter()
+            .filter(|candidate| candidate.kind == CandidateType::Host)
+            .filter(|candidate| candidate.base.is_ipv4() == server.is_ipv4())
+            .map(|candidate| candidate.base)
+            .collect();
+        for base in bases {
+            let request = Message {
+                message_class: MessageClass::Request,
+                message_method: MessageMethod::Binding,
+                transaction_id: TransactionID::random(),
+                attributes: vec![],
+            };
+            let mut bytes = BytesMut::new();
+            Encoder::new()
+                .encode(&request, &mut bytes)
+                .expect("built-in attributes always encode");
+            append_fingerprint(&mut bytes);
+            self.start_transaction(
+                now,
+                request.transaction_id,
+                TransactionKind::Gather,
+                base,
+                server,
+                bytes.to_vec(),
+            );
+        }
+        self.gathering = true;
+        self.check_gathering_complete();
+    }
+
+    /// Allocates a relayed candidate with the TURN client, from the host candidate `base`. The
+    /// candidate is reported by `IceEvent::CandidateGathered`, and `IceEvent::GatheringComplete`
+    /// follows once the allocation is done or failed. Checks from the relayed candidate create
+    /// a permission and bind a channel for each remote candidate they are sent to.
+    pub fn gather_relayed(&mut self, now: Instant, base: SocketAddr, mut client: TurnClient) {
+        if self.mode == IceMode::Lite {
+            self.events.push_back(IceEvent::GatheringComplete);
+            return;
+        }
+        client.allocate(now);
+        self.relay = Some(Relay {
+            client,
+            base,
+            allocating: true,
+            peers: HashSet::new(),
+        });
+        self.gathering = true;
+        self.drain_relay(now);
+    }
+
+    /// Adds a local candidate the application gathered itself and pairs it with the remote
+    /// candidates, it is reported by `IceEvent::CandidateGathered` as well. Checks of a relayed
+    /// candidate added this way are transmits from its address, which the application sends
+    /// through its own TURN allocation.
+    pub fn add_local_candidate(&mut self, candidate: Candidate) {
+        self.events
+            .push_back(IceEvent::CandidateGathered(candidate.clone()));
+        self.local_candidates.push(candidate);
+        let local = self.local_candidates.len() - 1;
+        for remote in 0..self.remote_candidates.len() {
+            self.add_pair(local, remote);
+        }
+    }
+
+    /// Adds a candidate of the remote agent and pairs it with the local candidates, which can
+    /// happen while checks are in progress.
+    pub fn add_remote_candidate(&mut self, candidate: Candidate) {
+        if let Some(remote) = self
+            .remote_candidates
+            .iter()
+            .position(|remote| remote.address == candidate.address)
+        {
+            // a trickled candidate replaces the peer reflexive candidate a check already learnt,
+            // the pairs keep their state (RFC 8838 section 11)
+            if self.remote_candidates[remote].kind == CandidateType::PeerReflexive
+                && candidate.kind != CandidateType::PeerReflexive
+            {
+                self.remote_candidates[remote] = candidate;
+                for index in 0..self.pairs.len() {
+                    if self.pairs[index].remote == remote {
+                        self.pairs[index].priority =
+                            self.compute_pair_priority(self.pairs[index].local, remote);
+                    }
+                }
+            }
+            return;
+        }
+        self.remote_candidates.push(candidate);
+        let remote = self.remote_candidates.len() - 1;
+        for local in 0..self.local_candidates.len() {
+            self.add_pair(local, remote);
+        }
+    }
+
+    /// The remote agent signaled end-of-candidates, no more remote candidates are expected.
+    pub fn set_remote_end_of_candidates(&mut self) {
+        self.remote_end_of_candidates = true;
+    }
+
+    /// Sends application data on the selected pair, from the base of its local candidate or
+    /// through the TURN server for a relayed one. False when no pair is selected.
+    pub fn send(&mut self, data: &[u8]) -> bool {
+        let (source, destination) = match self.selected_pair() {
+            Some((local, remote)) => (local.base, remote.address),
+            None => return false,
+        };
+        self.transmit(source, destination, data.to_vec());
+        true
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<IceEvent> {
+        self.events.pop_front()
+    }
+
+    /// The instant `handle_timeout` should be called at.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let retransmission = self
+            .transactions
+            .values()
+            .filter_map(|t| t.transaction.poll_timeout())
+            .min();
+        let check = if self.has_checks_to_send() {
+            self.next_check
+        } else {
+            None
+        };
+        let consent = self
+            .consent
+            .as_ref()
+            .and_then(|consent| consent.poll_timeout());
+        let relay = self
+            .relay
+            .as_ref()
+            .and_then(|relay| relay.client.poll_timeout());
+        [retransmission, check, consent, relay]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        if let Some(relay) = self.relay.as_mut() {
+            relay.client.handle_timeout(now);
+            self.drain_relay(now);
+        }
+        self.retransmit(now);
+        if let Some(consent) = self.consent.as_mut() {
+            consent.handle_timeout(now);
+            self.drain_consent();
+        }
+        if self.has_checks_to_send() && self.next_check.is_none_or(|at| at <= now) {
+            if let Some((pair, use_candidate)) = self.next_pair_to_check() {
+                self.send_check(now, pair, use_candidate);
+            }
+            self.next_check = Some(now + TA);
+        }
+        self.check_failed();
+    }
+
+    /// Handles a datagram received on the socket bound to `destination`, datagrams which are not
+    /// STUN messages are ignored unless they come from the TURN server of the relayed candidate.
+    pub fn handle_input(
+        &mut self,
+        now: Instant,
+        source: SocketAddr,
+        destination: SocketAddr,
+        data: &[u8],
+    ) {
+        // responses to Binding requests sent to the same server go to their transactions
+        let from_relay = self
+            .relay
+            .as_ref()
+            .is_some_and(|relay| source == relay.client.server() && destination == relay.base)
+            && !self.is_own_response(data);
+        if from_relay {
+            if let Some(relay) = self.relay.as_mut() {
+                relay.client.handle_input(now, Connection::Control, data);
+            }
+            self.drain_relay(now);
+            return;
+        }
+        if data.len() < 20 || data[0] & 0xC0 != 0 {
+            return;
+        }
+        if let Some(consent) = self.consent.as_mut() {
+            if destination == consent.local() && consent.handle_input(now, source, data) {
+                self.drain_consent();
+                return;
+            }
+        }
+        let message = match Decoder::new().decode(&mut &data[..]) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        match (&message.message_class, &message.message_method) {
+            (MessageClass::Request, MessageMethod::Binding) => {
+                self.handle_request(now, source, destination, data, message)
+            }
+            (MessageClass::SuccessResponse, MessageMethod::Binding)
+            | (MessageClass::FailureResponse, MessageMethod::Binding) => {
+                self.handle_response(now, source, destination, data, message)
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        now: Instant,
+        source: SocketAddr,
+        destination: SocketAddr,
+        data: &[u8],
+        request: Message,
+    ) {
+        if !verify_fingerprint(data) {
+            return;
+        }
+        let prefix = format!("{}:", self.local_credentials.ufrag);
+        let username_matches = request.attributes.iter().any(|attribute| match attribute {
+            Attribute::UserName(username) => username.starts_with(&prefix),
+            _ => false,
+        });
+        if !username_matches
+            || !verify_message_integrity(data, self.local_credentials.pwd.as_bytes())
+        {
+            self.send_error(&request, source, destination, 401, "Unauthorized");
+            return;
+        }
+
+        if self.mode == IceMode::Full && self.role_conflict(&request) {
+            self.send_error(&request, source, destination, 487, "Role Conflict");
+            return;
+        }
+
+        let response = Message {
+            message_class: MessageClass::SuccessResponse,
+            message_method: MessageMethod::Binding,
+            transaction_id: request.transaction_id,
+            attributes: vec![Attribute::XorMappedAddress(Address::from(source))],
+        };
+        self.send_response(&response, source, destination);
+
+        let local = match self.local_candidates.iter().position(|candidate| {
+            matches!(candidate.kind, CandidateType::Host | CandidateType::Relayed)
+                && candidate.base == destination
+        }) {
+            Some(local) => local,
+            None => return,
+        };
+        let remote = match self
+            .remote_candidates
+            .iter()
+            .position(|candidate| candidate.address == source)
+        {
+            Some(remote) => remote,
+            None => {
+                // a peer reflexive candidate, with the priority the remote agent signaled
+                let priority = request
+                    .attributes
+                    .iter()
+                    .find_map(|attribute| match attribute {
+                        Attribute::Priority(priority) => Some(*priority),
+                        _ => None,
+                    })
+                    .unwrap_or_else(|| priority(CandidateType::PeerReflexive, 0));
+                self.remote_candidates
+                    .push(Candidate::peer_reflexive(source, source, priority));
+                self.remote_candidates.len() - 1
+            }
+        };
+        let pair = match self.find_pair(local, remote) {
+            Some(pair) => pair,
+            None => self.push_pair(local, remote),
+        };
+        self.set_state(IceState::Checking);
+
+        let use_candidate = request
+            .attributes
+            .iter()
+            .any(|attribute| attribute == &Attribute::UseCandidate);
+        if self.mode == IceMode::Lite {
+            // a lite agent never checks, the pair is valid once the full agent nominates it
+            if use_candidate && self.selected.is_none() {
+                self.pairs[pair].valid = true;
+                self.select(now, pair);
+            }
+            return;
+        }
+
+        // triggered check (RFC 8445 section 7.3.1.4)
+        match self.pairs[pair].state {
+            PairState::Succeeded | PairState::InProgress => {}
+            _ => {
+                self.pairs[pair].state = PairState::Waiting;
+                if !self.triggered.iter().any(|(p, _)| *p == pair) {
+                    self.triggered.push_back((pair, false));
+                }
+            }
+        }
+        if use_candidate && self.role == IceRole::Controlled && self.selected.is_none() {
+            if self.pairs[pair].valid {
+                self.select(now, pair);
+            } else {
+                self.pairs[pair].nominate_on_success = true;
+            }
+        }
+        if self.next_check.is_none() {
+            self.next_check = Some(now);
+        }
+    }
+
+    // resolves a role conflict (RFC 8445 section 7.3.1.1), true when the request has to be
+    // rejected with 487
+    fn role_conflict(&mut self, request: &Message) -> bool {
+        for attribute in &request.attributes {
+            match (self.role, attribute) {
+                (IceRole::Controlling, Attribute::IceControlling(tie_breaker)) => {
+                    if self.tie_breaker >= *tie_breaker {
+                        return true;
+                    }
+                    self.switch_role(IceRole::Controlled);
+                }
+                (IceRole::Controlled, Attribute::IceControlled(tie_breaker)) => {
+                    if self.tie_breaker < *tie_breaker {
+                        return true;
+                    }
+                    self.switch_role(IceRole::Controlling);
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    fn handle_response(
+        &mut self,
+        now: Instant,
+        source: SocketAddr,
+        destination: SocketAddr,
+        data: &[u8],
+        response: Message,
+    ) {
+        let transaction = match self.transactions.get_mut(&response.transaction_id.value) {
+            Some(transaction) => transaction,
+            None => return,
+        };
+        match transaction.kind {
+            TransactionKind::Gather => {
+                if !transaction.transaction.handle_input(now, source, data) {
+                    return;
+                }
+                let base = transaction.source;
+                let server = transaction.transaction.destination();
+                self.transactions.remove(&response.transaction_id.value);
+                if let Some(mapped) = mapped_address(&response) {
+                    if mapped != base
+                        && !self
+                            .local_candidates
+                            .iter()
+                            .any(|candidate| candidate.address == mapped)
+                    {
+                        let local_preference = self
+                            .local_candidates
+                            .iter()
+                            .find(|candidate| candidate.address == base)
+                            .map_or(0, |candidate| (candidate.priority >> 8) as u16);
+                        self.add_local_candidate(Candidate::server_reflexive(
+                            mapped,
+                            base,
+                            server,
+                            local_preference,
+                        ));
+                    }
+                }
+                self.check_gathering_complete();
+            }
+            TransactionKind::Check {
+                pair,
+                use_candidate,
+            } => {
+                let pwd = match &self.remote_credentials {
+                    Some(credentials) => credentials.pwd.clone(),
+                    None => return,
+                };
+                if !verify_message_integrity(data, pwd.as_bytes()) {
+                    return;
+                }
+                // a response from another address fails the check rather than being ignored as
+                // by ClientTransaction, see handle_check_response
+                self.transactions.remove(&response.transaction_id.value);
+                if self.nominating == Some(pair) {
+                    self.nominating = None;
+                }
+                self.handle_check_response(now, pair, use_candidate, source, destination, response);
+            }
+        }
+    }
+
+    fn handle_check_response(
+        &mut self,
+        now: Instant,
+        pair: usize,
+        use_candidate: bool,
+        source: SocketAddr,
+        destination: SocketAddr,
+        response: Message,
+    ) {
+        let local = self.pairs[pair].local;
+        let remote = self.pairs[pair].remote;
+        // the addresses of a check and its response must be symmetric
+        if source != self.remote_candidates[remote].address
+            || destination != self.local_candidates[local].base
+        {
+            self.pairs[pair].state = PairState::Failed;
+            return;
+        }
+        if response.message_class == MessageClass::FailureResponse {
+            let role_conflict = response.attributes.iter().any(|attribute| match attribute {
+                Attribute::ErrorCode { code, .. } => *code == 487,
+                _ => false,
+            });
+            if role_conflict {
+                let role = match self.role {
+                    IceRole::Controlling => IceRole::Controlled,
+                    IceRole::Controlled => IceRole::Controlling,
+                };
+                self.switch_role(role);
+                self.pairs[pair].state = PairState::Waiting;
+                self.triggered.push_back((pair, false));
+            } else {
+                self.pairs[pair].state = PairState::Failed;
+            }
+            return;
+        }
+
+        // a mapped address unknown locally is a peer reflexive candidate (RFC 8445 section
+        // 7.2.5.3.1), it shares the base of the pair which stays the valid pair
+        if let Some(mapped) = mapped_address(&response) {
+            if !self
+                .local_candidates
+                .iter()
+                .any(|candidate| candidate.address == mapped)
+            {
+                let priority = peer_reflexive_priority(&self.local_candidates[local]);
+                let base = self.local_candidates[local].base;
+                self.local_candidates
+                    .push(Candidate::peer_reflexive(mapped, base, priority));
+            }
+        }
+
+        self.pairs[pair].state = PairState::Succeeded;
+        self.pairs[pair].valid = true;
+        let foundation = self.pair_foundation(pair);
+        for index in 0..self.pairs.len() {
+            if self.pairs[index].state == PairState::Frozen
+                && self.pair_foundation(index) == foundation
+            {
+                self.pairs[index].state = PairState::Waiting;
+            }
+        }
+
+        if self.selected.is_some() {
+            return;
+        }
+        match self.role {
+            IceRole::Controlling if use_candidate => self.select(now, pair),
+            IceRole::Controlled if self.pairs[pair].nominate_on_success => self.select(now, pair),
+            IceRole::Controlling => self.nominate(now),
+            IceRole::Controlled => {}
+        }
+    }
+
+    // regular nomination: once no pair of a higher priority than the best valid pair is left to
+    // check, the controlling agent checks it again with USE-CANDIDATE
+    fn nominate(&mut self, now: Instant) {
+        if self.nominating.is_some() || self.selected.is_some() {
+            return;
+        }
+        let best = (0..self.pairs.len())
+            .filter(|index| self.pairs[*index].valid)
+            .max_by_key(|index| self.pairs[*index].priority);
+        let best = match best {
+            Some(best) => best,
+            None => return,
+        };
+        let pending = self.pairs.iter().any(|pair| {
+            pair.priority > self.pairs[best].priority
+                && matches!(
+                    pair.state,
+                    PairState::Frozen | PairState::Waiting | PairState::InProgress
+                )
+        });
+        if !pending {
+            self.nominating = Some(best);
+            self.send_check(now, best, true);
+        }
+    }
+
+    fn select(&mut self, now: Instant, pair: usize) {
+        self.selected = Some(pair);
+        self.triggered.clear();
+        let local = self.local_candidates[self.pairs[pair].local].clone();
+        let remote = self.remote_candidates[self.pairs[pair].remote].clone();
+        if let (IceMode::Full, Some(credentials)) = (self.mode, &self.remote_credentials) {
+            let username = format!("{}:{}", credentials.ufrag, self.local_credentials.ufrag);
+            self.consent = Some(ConsentFreshness::new(
+                now,
+                local.base,
+                remote.address,
+                &username,
+                &credentials.pwd,
+            ));
+        }
+        self.events.push_back(IceEvent::Selected { local, remote });
+        self.set_state(IceState::Completed);
+    }
+
+    fn drain_consent(&mut self) {
+        let mut transmits = vec![];
+        if let Some(consent) = self.consent.as_mut() {
+            while let Some(transmit) = consent.poll_transmit() {
+                transmits.push(transmit);
+            }
+            while let Some(ConsentEvent::Lost) = consent.poll_event() {
+                self.events.push_back(IceEvent::ConsentLost);
+            }
+        }
+        for transmit in transmits {
+            self.transmit(transmit.source, transmit.destination, transmit.data);
+        }
+    }
+
+    // sends what the TURN client has to send, and handles its events
+    fn drain_relay(&mut self, now: Instant) {
+        self.drain_relay_transmits();
+        let relay = match self.relay.as_mut() {
+            Some(relay) => relay,
+            None => return,
+        };
+        let server = relay.client.server();
+        let mut events = vec![];
+        while let Some(event) = relay.client.poll_event() {
+            events.push(event);
+        }
+        for event in events {
+            match event {
+                TurnClientEvent::Allocated { relayed, .. } => {
+                    if let Some(relay) = self.relay.as_mut() {
+                        relay.allocating = false;
+                    }
+                    let local_preference = 65535 - self.local_candidates.len() as u16;
+                    self.add_local_candidate(Candidate::relayed(
+                        relayed[0],
+                        server,
+                        local_preference,
+                    ));
+                    self.check_gathering_complete();
+                }
+                TurnClientEvent::Failed {
+                    method: MessageMethod::Allocate,
+                    ..
+                } => {
+                    if let Some(relay) = self.relay.as_mut() {
+                        relay.allocating = false;
+                    }
+                    self.check_gathering_complete();
+                }
+                // checks and their responses are STUN messages, anything else is the
+                // application's
+                TurnClientEvent::Data { peer, data } => {
+                    match self.relay.as_ref().and_then(|r| r.client.relayed_address()) {
+                        Some(relayed) if !data.is_empty() && data[0] & 0xC0 == 0 => {
+                            self.handle_input(now, peer, relayed, &data)
+                        }
+                        _ => self.events.push_back(IceEvent::Data { peer, data }),
+                    }
+                }
+                // checks to a peer without a permission time out
+                _ => {}
+            }
+        }
+    }
+
+    // what the TURN client sends to the server goes from the host candidate it is reached from
+    fn drain_relay_transmits(&mut self) {
+        if let Some(relay) = self.relay.as_mut() {
+            let (base, server) = (relay.base, relay.client.server());
+            while let Some(transmit) = relay.client.poll_transmit() {
+                self.transmits.push_back(Transmit {
+                    source: base,
+                    destination: server,
+                    data: transmit.data,
+                });
+            }
+        }
+    }
+
+    // asks the TURN server for a permission and a channel, once, before checks are relayed to
+    // the peer
+    fn relay_to(&mut self, now: Instant, peer: SocketAddr) {
+        if let Some(relay) = self.relay.as_mut() {
+            if relay.peers.insert(peer) {
+                relay.client.create_permission(now, peer);
+                relay.client.bind_channel(now, peer);
+            }
+        }
+        self.drain_relay(now);
+    }
+
+    // a transmit from the relayed candidate goes through the TURN server
+    fn transmit(&mut self, source: SocketAddr, destination: SocketAddr, data: Vec<u8>) {
+        if let Some(relay) = self.relay.as_mut() {
+            if relay.client.relayed_address() == Some(source) {
+                relay.client.send_to(destination, &data);
+                self.drain_relay_transmits();
+                return;
+            }
+        }
+        self.transmits.push_back(Transmit {
+            source,
+            destination,
+            data,
+        });
+    }
+
+    // whether the datagram is a response to a request of the agent
+    fn is_own_response(&self, data: &[u8]) -> bool {
+        if data.len() < 20 || data[0] & 0xC0 != 0 {
+            return false;
+        }
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&data[8..20]);
+        self.transactions.contains_key(&transaction_id)
+    }
+
+    fn set_state(&mut self, state: IceState) {
+        if self.state != state
+            && self.state != IceState::Completed
+            && self.state != IceState::Failed
+        {
+            self.state = state;
+            self.events.push_back(IceEvent::StateChanged(state));
+        }
+    }
+
+    fn switch_role(&mut self, role: IceRole) {
+        self.role = role;
+        for index in 0..self.pairs.len() {
+            self.pairs[index].priority =
+                self.compute_pair_priority(self.pairs[index].local, self.pairs[index].remote);
+        }
+    }
+
+    // forms a pair, unless it is pruned (RFC 8445 section 6.1.2.4)
+    fn add_pair(&mut self, local: usize, remote: usize) {
+        let local_candidate = &self.local_candidates[local];
+        let remote_candidate = &self.remote_candidates[remote];
+        // reflexive candidates are checked from their base, which is a host candidate, a
+        // relayed candidate is its own base
+        if !matches!(
+            local_candidate.kind,
+            CandidateType::Host | CandidateType::Relayed
+        ) || local_candidate.address.is_ipv4() != remote_candidate.address.is_ipv4()
+            || self.pairs.len() >= MAX_PAIRS
+            || self.find_pair(local, remote).is_some()
+        {
+            return;
+        }
+        self.push_pair(local, remote);
+    }
+
+    fn push_pair(&mut self, local: usize, remote: usize) -> usize {
+        let priority = self.compute_pair_priority(local, remote);
+        self.pairs.push(CandidatePair {
+            local,
+            remote,
+            priority,
+            state: PairState::Frozen,
+            valid: false,
+            nominate_on_success: false,
+        });
+        let pair = self.pairs.len() - 1;
+        // the first pair of a foundation starts waiting, the others stay frozen until a pair
+        // of the same foundation succeeds, a pair trickled after that starts waiting as well
+        let foundation = self.pair_foundation(pair);
+        let same_foundation: Vec<PairState> = (0..pair)
+            .filter(|index| self.pair_foundation(*index) == foundation)
+            .map(|index| self.pairs[index].state)
+            .collect();
+        if same_foundation.is_empty() || same_foundation.contains(&PairState::Succeeded) {
+            self.pairs[pair].state = PairState::Waiting;
+        }
+        pair
+    }
+
+    fn find_pair(&self, local: usize, remote: usize) -> Option<usize> {
+        let base = self.local_candidates[local].base;
+        let address = self.remote_candidates[remote].address;
+        self.pairs.iter().position(|pair| {
+            self.local_candidates[pair.local].base == base
+                && self.remote_candidates[pair.remote].address == address
+        })
+    }
+
+    fn compute_pair_priority(&self, local: usize, remote: usize) -> u64 {
+        let local = self.local_candidates[local].priority;
+        let remote = self.remote_candidates[remote].priority;
+        match self.role {
+            IceRole::Controlling => pair_priority(local, remote),
+            IceRole::Controlled => pair_priority(remote, local),
+        }
+    }
+
+    fn pair_foundation(&self, pair: usize) -> (String, String) {
+        let pair = &self.pairs[pair];
+        (
+            self.local_candidates[pair.local].foundation.clone(),
+            self.remote_candidates[pair.remote].foundation.clone(),
+        )
+    }
+
+    fn has_checks_to_send(&self) -> bool {
+        self.mode == IceMode::Full
+            && self.remote_credentials.is_some()
+            && self.selected.is_none()
+            && self.state != IceState::Failed
+            && (!self.triggered.is_empty()
+                || self.pairs.iter().any(|pair| {
+                    pair.state == PairState::Waiting || pair.state == PairState::Frozen
+                }))
+    }
+
+    // triggered checks go first, then the waiting pair of the highest priority, unfreezing one
+    // when none is waiting
+    fn next_pair_to_check(&mut self) -> Option<(usize, bool)> {
+        if let Some(check) = self.triggered.pop_front() {
+            return Some(check);
+        }
+        for state in &[PairState::Waiting, PairState::Frozen] {
+            let pair = (0..self.pairs.len())
+                .filter(|index| self.pairs[*index].state == *state)
+                .max_by_key(|index| self.pairs[*index].priority);
+            if let Some(pair) = pair {
+                return Some((pair, false));
+            }
+        }
+        None
+    }
+
+    fn send_check(&mut self, now: Instant, pair: usize, use_candidate: bool) {
+        let credentials = match &self.remote_credentials {
+            Some(credentials) => credentials.clone(),
+            None => return,
+        };
+        let local = &self.local_candidates[self.pairs[pair].local];
+        let remote = &self.remote_candidates[self.pairs[pair].remote];
+        let mut attributes = vec![
+            Attribute::UserName(format!(
+                "{}:{}",
+                credentials.ufrag, self.local_credentials.ufrag
+            )),
+            Attribute::Priority(peer_reflexive_priority(local)),
+            match self.role {
+                IceRole::Controlling => Attribute::IceControlling(self.tie_breaker),
+                IceRole::Controlled => Attribute::IceControlled(self.tie_breaker),
+            },
+        ];
+        if use_candidate {
+            attributes.push(Attribute::UseCandidate);
+        }
+        let request = Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::random(),
+            attributes,
+        };
+        let mut bytes = BytesMut::new();
+        Encoder::new()
+            .encode(&request, &mut bytes)
+            .expect("built-in attributes always encode");
+        append_message_integrity(&mut bytes, credentials.pwd.as_bytes());
+        append_fingerprint(&mut bytes);
+
+        let (source, destination) = (local.base, remote.address);
+        if local.kind == CandidateType::Relayed {
+            self.relay_to(now, destination);
+        }
+        if self.pairs[pair].state != PairState::Succeeded {
+            self.pairs[pair].state = PairState::InProgress;
+        }
+        self.set_state(IceState::Checking);
+        self.start_transaction(
+            now,
+            request.transaction_id,
+            TransactionKind::Check {
+                pair,
+                use_candidate,
+            },
+            source,
+            destination,
+            bytes.to_vec(),
+        );
+    }
+
+    fn start_transaction(
+        &mut self,
+        now: Instant,
+        transaction_id: TransactionID,
+        kind: TransactionKind,
+        source: SocketAddr,
+        destination: SocketAddr,
+        data: Vec<u8>,
+    ) {
+        let mut transaction = ClientTransaction::from_bytes(now, transaction_id, data, destination)
+            .with_rto(RTO)
+            .with_max_transmissions(MAX_TRANSMISSIONS);
+        while let Some(data) = transaction.poll_transmit() {
+            self.transmit(source, destination, data);
+        }
+        self.transactions.insert(
+            transaction_id.value,
+            Transaction {
+                kind,
+                source,
+                transaction,
+            },
+        );
+    }
+
+    fn retransmit(&mut self, now: Instant) {
+        let mut timed_out = vec![];
+        let mut transmits = vec![];
+        for (id, transaction) in self.transactions.iter_mut() {
+            transaction.transaction.handle_timeout(now);
+            while let Some(data) = transaction.transaction.poll_transmit() {
+                transmits.push((
+                    transaction.source,
+                    transaction.transaction.destination(),
+                    data,
+                ));
+            }
+            if transaction.transaction.poll_result() == Some(TransactionResult::TimedOut) {
+                timed_out.push(*id);
+            }
+        }
+        for (source, destination, data) in transmits {
+            self.transmit(source, destination, data);
+        }
+        for id in timed_out {
+            let transaction = self.transactions.remove(&id).unwrap();
+            match transaction.kind {
+                TransactionKind::Gather => self.check_gathering_complete(),
+                TransactionKind::Check { pair, .. } => {
+                    if self.nominating == Some(pair) {
+                        self.nominating = None;
+                    }
+                    if self.pairs[pair].state == PairState::InProgress {
+                        self.pairs[pair].state = PairState::Failed;
+                    }
+                }
+            }
+        }
+        if self.role == IceRole::Controlling && self.mode == IceMode::Full {
+            self.nominate(now);
+        }
+    }
+
+    fn check_gathering_complete(&mut self) {
+        let pending = self
+            .transactions
+            .values()
+            .any(|transaction| matches!(transaction.kind, TransactionKind::Gather))
+            || self.relay.as_ref().is_some_and(|relay| relay.allocating);
+        if self.gathering && !pending {
+            self.gathering = false;
+            self.events.push_back(IceEvent::GatheringComplete);
+        }
+    }
+
+    // checks are over once every pair failed and no check is in flight, and with trickle ICE no
+    // more candidates are coming
+    fn check_failed(&mut self) {
+        if self.mode != IceMode::Full || self.state != IceState::Checking || self.pairs.is_empty() {
+            return;
+        }
+        if self.gathering || (self.trickle && !self.remote_end_of_candidates) {
+            return;
+        }
+        let checking = self
+            .transactions
+            .values()
+            .any(|transaction| matches!(transaction.kind, TransactionKind::Check { .. }));
+        if !checking
+            && self
+                .pairs
+                .iter()
+                .all(|pair| pair.state == PairState::Failed)
+        {
+            self.set_state(IceState::Failed);
+        }
+    }
+
+    fn send_response(&mut self, response: &Message, source: SocketAddr, destination: SocketAddr) {
+        let mut bytes = BytesMut::new();
+        Encoder::new()
+            .encode(response, &mut bytes)
+            .expect("built-in attributes always encode");
+        append_message_integrity(&mut bytes, self.local_credentials.pwd.as_bytes());
+        append_fingerprint(&mut bytes);
+        self.transmit(destination, source, bytes.to_vec());
+    }
+
+    fn send_error(
+        &mut self,
+        request: &Message,
+        source: SocketAddr,
+        destination: SocketAddr,
+        code: u32,
+        reason: &str,
+    ) {
+        let response = Message {
+            message_class: MessageClass::FailureResponse,
+            message_method: MessageMethod::Binding,
+            transaction_id: request.transaction_id,
+            attributes: vec![Attribute::ErrorCode {
+                code,
+                reason: reason.to_owned(),
+            }],
+        };
+        if code == 401 {
+            // the request could not be authenticated, so neither can the response
+            let mut bytes = BytesMut::new();
+            Encoder::new()
+                .encode(&response, &mut bytes)
+                .expect("built-in attributes always encode");
+            append_fingerprint(&mut bytes);
+            self.transmit(destination, source, bytes.to_vec());
+        } else {
+            self.send_response(&response, source, destination);
+        }
+    }
+}
+
+// the priority a peer reflexive candidate learnt from a check of this candidate would get
+fn peer_reflexive_priority(candidate: &Candidate) -> u32 {
+    (CandidateType::PeerReflexive.preference() << 24) | (candidate.priority & 0x00FF_FFFF)
+}
+
+fn mapped_address(message: &Message) -> Option<SocketAddr> {
+    message
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            Attribute::XorMappedAddress(address) => Some(address.to_socket_addr()),
+            Attribute::MappedAddress(address) => Some(address.to_socket_addr()),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use bytes::BytesMut;
+    use tokio::net::UdpSocket;
+
+    use crate::codec::{append_fingerprint, Decoder, Encoder};
+    use crate::ice::agent::*;
+
+    async fn agent(mode: IceMode, role: IceRole) -> (IceAgent, UdpSocket) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut agent = IceAgent::new(mode, role);
+        agent.add_host_candidate(socket.local_addr().unwrap());
+        (agent, socket)
+    }
+
+    // exchanges credentials and candidates, as signaling would
+    fn signal(a: &mut IceAgent, b: &mut IceAgent) {
+        a.set_remote_credentials(b.local_credentials().clone());
+        b.set_remote_credentials(a.local_credentials().clone());
+        for candidate in b.local_candidates().to_vec() {
+            a.add_remote_candidate(candidate);
+        }
+        for candidate in a.local_candidates().to_vec() {
+            b.add_remote_candidate(candidate);
+        }
+    }
+
+    async fn flush(agent: &mut IceAgent, socket: &mut UdpSocket) {
+        while let Some(transmit) = agent.poll_transmit() {
+            assert_eq!(transmit.source, socket.local_addr().unwrap());
+            socket
+                .send_to(&transmit.data, &transmit.destination)
+                .await
+                .unwrap();
+        }
+    }
+
+    // drives both agents until they are done checking
+    async fn connect(a: &mut IceAgent, sa: &mut UdpSocket, b: &mut IceAgent, sb: &mut UdpSocket) {
+        let (local_a, local_b) = (sa.local_addr().unwrap(), sb.local_addr().unwrap());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut buf_a = [0u8; 1500];
+        let mut buf_b = [0u8; 1500];
+        loop {
+            let now = Instant::now();
+            assert!(now < deadline, "connectivity checks did not complete");
+            a.handle_timeout(now);
+            b.handle_timeout(now);
+            flush(a, sa).await;
+            flush(b, sb).await;
+            let done = |agent: &IceAgent| {
+                agent.state() == IceState::Completed || agent.state() == IceState::Failed
+            };
+            if done(a) && done(b) {
+                return;
+            }
+            let wake = [a.poll_timeout(), b.poll_timeout()]
+                .iter()
+                .flatten()
+                .min()
+                .copied()
+                .unwrap_or(now + Duration::from_millis(100));
+            tokio::select! {
+                received = sa.recv_from(&mut buf_a) => {
+                    let (size, from) = received.unwrap();
+                    a.handle_input(Instant::now(), from, local_a, &buf_a[..size]);
+                }
+                received = sb.recv_from(&mut buf_b) => {
+                    let (size, from) = received.unwrap();
+                    b.handle_input(Instant::now(), from, local_b, &buf_b[..size]);
+                }
+                _ = tokio::time::delay_until(tokio::time::Instant::from_std(wake)) => {}
+            }
+        }
+    }
+
+    fn selected(agent: &IceAgent) -> (SocketAddr, SocketAddr) {
+        let (local, remote) = agent.selected_pair().expect("a pair is selected");
+        (local.address, remote.address)
+    }
+
+    #[tokio::test]
+    pub async fn test_full_agents_over_loopback() {
+        let (mut a, mut sa) = agent(IceMode::Full, IceRole::Controlling).await;
+        let (mut b, mut sb) = agent(IceMode::Full, IceRole::Controlled).await;
+        signal(&mut a, &mut b);
+        connect(&mut a, &mut sa, &mut b, &mut sb).await;
+
+        assert_eq!(a.state(), IceState::Completed);
+        assert_eq!(b.state(), IceState::Completed);
+        let (local, remote) = selected(&a);
+        assert_eq!(selected(&b), (remote, local));
+        assert_eq!(local, sa.local_addr().unwrap());
+        assert!(a.consent().unwrap().is_granted());
+        assert_eq!(a.consent().unwrap().remote(), remote);
+
+        let events: Vec<IceEvent> = std::iter::from_fn(|| a.poll_event()).collect();
+        assert!(events.contains(&IceEvent::StateChanged(IceState::Checking)));
+        assert!(events.contains(&IceEvent::StateChanged(IceState::Completed)));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, IceEvent::Selected { .. })));
+    }
+
+    #[tokio::test]
+    pub async fn test_role_conflict_over_loopback() {
+        let (a, mut sa) = agent(IceMode::Full, IceRole::Controlling).await;
+        let (b, mut sb) = agent(IceMode::Full, IceRole::Controlling).await;
+        let mut a = a.with_tie_breaker(2);
+        let mut b = b.with_tie_breaker(1);
+        signal(&mut a, &mut b);
+        connect(&mut a, &mut sa, &mut b, &mut sb).await;
+
+        assert_eq!(a.role(), IceRole::Controlling);
+        assert_eq!(b.role(), IceRole::Controlled);
+        assert_eq!(a.state(), IceState::Completed);
+        assert_eq!(b.state(), IceState::Completed);
+    }
+
+    #[tokio::test]
+    pub async fn test_full_and_lite_agents_over_loopback() {
+        let (mut a, mut sa) = agent(IceMode::Full, IceRole::Controlling).await;
+        let (mut b, mut sb) = agent(IceMode::Lite, IceRole::Controlling).await;
+        assert_eq!(b.role(), IceRole::Controlled);
+        signal(&mut a, &mut b);
+        connect(&mut a, &mut sa, &mut b, &mut sb).await;
+
+        assert_eq!(a.state(), IceState::Completed);
+        assert_eq!(b.state(), IceState::Completed);
+        let (local, remote) = selected(&a);
+        assert_eq!(selected(&b), (remote, local));
+    }
+
+    #[test]
+    pub fn test_gather_server_reflexive_candidate() {
+        let base: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let server: SocketAddr = "192.0.2.1:3478".parse().unwrap();
+        let mapped: SocketAddr = "203.0.113.5:6000".parse().unwrap();
+        let mut agent = IceAgent::new(IceMode::Full, IceRole::Controlling);
+        agent.add_host_candidate(base);
+        let now = Instant::now();
+        agent.gather_server_reflexive(now, server);
+
+        let transmit = agent.poll_transmit().unwrap();
+        assert_eq!((transmit.source, transmit.destination), (base, server));
+        let request = Decoder::new().decode(&mut &transmit.data[..]).unwrap();
+        let response = Message {
+            message_class: MessageClass::SuccessResponse,
+            message_method: MessageMethod::Binding,
+            transaction_id: request.transaction_id,
+            attributes: vec![Attribute::XorMappedAddress(Address::from(mapped))],
+        };
+        let mut bytes = BytesMut::new();
+        Encoder::new().encode(&response, &mut bytes).unwrap();
+        append_fingerprint(&mut bytes);
+        agent.handle_input(now, server, base, &bytes);
+
+        assert!(matches!(
+            agent.poll_event(),
+            Some(IceEvent::CandidateGathered(_))
+        ));
+        match agent.poll_event() {
+            Some(IceEvent::CandidateGathered(candidate)) => {
+                assert_eq!(candidate.kind, CandidateType::ServerReflexive);
+                assert_eq!((candidate.address, candidate.base), (mapped, base));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(agent.poll_event(), Some(IceEvent::GatheringComplete));
+        assert_eq!(agent.poll_timeout(), None);
+    }
+
+    #[test]
+    pub fn test_reject_unauthenticated_check() {
+        let local: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let peer: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let mut agent = IceAgent::new(IceMode::Full, IceRole::Controlled);
+        agent.add_host_candidate(local);
+        let request = Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::random(),
+            attributes: vec![Attribute::UserName(format!(
+                "{}:peer",
+                agent.local_credentials().ufrag
+            ))],
+        };
+        let mut bytes = BytesMut::new();
+        Encoder::new().encode(&request, &mut bytes).unwrap();
+        crate::codec::append_message_integrity(&mut bytes, b"wrong password");
+        append_fingerprint(&mut bytes);
+        agent.handle_input(Instant::now(), peer, local, &bytes);
+
+        let transmit = agent.poll_transmit().unwrap();
+        let response = Decoder::new().decode(&mut &transmit.data[..]).unwrap();
+        assert_eq!(response.message_class, MessageClass::FailureResponse);
+        assert!(response.attributes.contains(&Attribute::ErrorCode {
+            code: 401,
+            reason: "Unauthorized".to_owned()
+        }));
+        assert_eq!(agent.state(), IceState::New);
+        assert!(agent.remote_candidates().is_empty());
+    }
+
+    // runs the timers of an agent whose checks get no response, for 40 simulated seconds
+    fn expire_checks(agent: &mut IceAgent, start: Instant) {
+        for second in 0..40 {
+            agent.handle_timeout(start + Duration::from_secs(second));
+            while agent.poll_transmit().is_some() {}
+        }
+    }
+
+    #[test]
+    pub fn test_trickle_fails_after_end_of_candidates() {
+        let local: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let remote = Candidate::host("10.0.0.2:5000".parse().unwrap(), 65535);
+        let start = Instant::now();
+
+        let mut agent = IceAgent::new(IceMode::Full, IceRole::Controlling);
+        agent.add_host_candidate(local);
+        agent.set_remote_credentials(Credentials::random());
+        agent.add_remote_candidate(remote.clone());
+        expire_checks(&mut agent, start);
+        assert_eq!(agent.state(), IceState::Failed);
+
+        let mut agent = IceAgent::new(IceMode::Full, IceRole::Controlling).with_trickle(true);
+        agent.add_host_candidate(local);
+        agent.set_remote_credentials(Credentials::random());
+        agent.add_remote_candidate(remote);
+        expire_checks(&mut agent, start);
+        assert_eq!(agent.state(), IceState::Checking);
+
+        // a candidate trickled after the checks started is checked as well
+        agent.add_remote_candidate(Candidate::host("10.0.0.3:5000".parse().unwrap(), 65535));
+        agent.handle_timeout(start + Duration::from_secs(40));
+        let transmit = agent.poll_transmit().unwrap();
+        assert_eq!(transmit.destination, "10.0.0.3:5000".parse().unwrap());
+
+        agent.set_remote_end_of_candidates();
+        expire_checks(&mut agent, start + Duration::from_secs(41));
+        assert_eq!(agent.state(), IceState::Failed);
+    }
+
+    #[test]
+    pub fn test_trickled_candidate_replaces_peer_reflexive() {
+        let local: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let peer: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let remote_credentials = Credentials::random();
+        let mut agent = IceAgent::new(IceMode::Full, IceRole::Controlled).with_trickle(true);
+        agent.add_host_candidate(local);
+        agent.set_remote_credentials(remote_credentials.clone());
+
+        // a check arrives before the candidate it comes from is signaled
+        let request = Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::random(),
+            attributes: vec![
+                Attribute::UserName(format!(
+                    "{}:{}",
+                    agent.local_credentials().ufrag,
+                    remote_credentials.ufrag
+                )),
+                Attribute::Priority(priority(CandidateType::PeerReflexive, 65535)),
+                Attribute::IceControlling(1),
+            ],
+        };
+        let mut bytes = BytesMut::new();
+        Encoder::new().encode(&request, &mut bytes).unwrap();
+        crate::codec::append_message_integrity(
+            &mut bytes,
+            agent.local_credentials().pwd.as_bytes(),
+        );
+        append_fingerprint(&mut bytes);
+        agent.handle_input(Instant::now(), peer, local, &bytes);
+        assert_eq!(agent.remote_candidates().len(), 1);
+        assert_eq!(
+            agent.remote_candidates()[0].kind,
+            CandidateType::PeerReflexive
+        );
+
+        agent.add_remote_candidate(Candidate::host(peer, 65535));
+        assert_eq!(agent.remote_candidates().len(), 1);
+        assert_eq!(agent.remote_candidates()[0].kind, CandidateType::Host);
+    }
+
+    #[tokio::test]
+    pub async fn test_trickle_agents_over_loopback() {
+        let (a, mut sa) = agent(IceMode::Full, IceRole::Controlling).await;
+        let (b, mut sb) = agent(IceMode::Full, IceRole::Controlled).await;
+        let mut a = a.with_trickle(true);
+        let mut b = b.with_trickle(true);
+        a.set_remote_credentials(b.local_credentials().clone());
+        b.set_remote_credentials(a.local_credentials().clone());
+        // only the candidate of the controlled agent has been trickled so far
+        for candidate in b.local_candidates().to_vec() {
+            a.add_remote_candidate(candidate);
+        }
+        connect(&mut a, &mut sa, &mut b, &mut sb).await;
+
+        assert_eq!(a.state(), IceState::Completed);
+        assert_eq!(b.state(), IceState::Completed);
+        let (local, remote) = selected(&a);
+        assert_eq!(selected(&b), (remote, local));
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+
+// the only component of a data stream, ICE is used for a single UDP flow
+pub const COMPONENT_ID: u16 = 1;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash)]
+pub enum CandidateType {
+    Host,
+    ServerReflexive,
+    PeerReflexive,
+    Relayed,
+}
+
+impl CandidateType {
+    // type preferences recommended by RFC 8445 section 5.1.2.2
+    pub fn preference(&self) -> u32 {
+        match self {
+            CandidateType::Host => 126,
+            CandidateType::PeerReflexive => 110,
+            CandidateType::ServerReflexive => 100,
+            CandidateType::Relayed => 0,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Candidate {
+    pub kind: CandidateType,
+    // candidates of the same type, base IP address and STUN server share a foundation
+    pub foundation: String,
+    pub priority: u32,
+    pub address: SocketAddr,
+    // the address the candidate sends from, the host candidate of a reflexive candidate
+    pub base: SocketAddr,
+}
+
+impl Candidate {
+    pub fn host(address: SocketAddr, local_preference: u16) -> Candidate {
+        Candidate {
+            kind: CandidateType::Host,
+            foundation: foundation(CandidateType::Host, address.ip(), None),
+            priority: priority(CandidateType::Host, local_preference),
+            address,
+            base: address,
+        }
+    }
+
+    pub fn server_reflexive(
+        address: SocketAddr,
+        base: SocketAddr,
+        server: SocketAddr,
+        local_preference: u16,
+    ) -> Candidate {
+        Candidate {
+            kind: CandidateType::ServerReflexive,
+            foundation: foundation(CandidateType::ServerReflexive, base.ip(), Some(server.ip())),
+            priority: priority(CandidateType::ServerReflexive, local_preference),
+            address,
+            base,
+        }
+    }
+
+    /// A candidate on the relayed address of a TURN allocation, it is its own base.
+    pub fn relayed(address: SocketAddr, server: SocketAddr, local_preference: u16) -> Candidate {
+        Candidate {
+            kind: CandidateType::Relayed,
+            foundation: foundation(CandidateType::Relayed, address.ip(), Some(server.ip())),
+            priority: priority(CandidateType::Relayed, local_preference),
+            address,
+            base: address,
+        }
+    }
+
+    pub fn peer_reflexive(address: SocketAddr, base: SocketAddr, priority: u32) -> Candidate {
+        Candidate {
+            kind: CandidateType::PeerReflexive,
+            foundation: foundation(CandidateType::PeerReflexive, base.ip(), None),
+            priority,
+            address,
+            base,
+        }
+    }
+
+    /// A candidate of the remote agent, learnt through signaling.
+    pub fn remote(
+        kind: CandidateType,
+        foundation: &str,
+        priority: u32,
+        address: SocketAddr,
+    ) -> Candidate {
+        Candidate {
+            kind,
+            foundation: foundation.to_owned(),
+            priority,
+            address,
+            base: address,
+        }
+    }
+}
+
+/// Priority of a candidate (RFC 8445 section 5.1.2.1).
+pub fn priority(kind: CandidateType, local_preference: u16) -> u32 {
+    (kind.preference() << 24) + ((local_preference as u32) << 8) + (256 - COMPONENT_ID as u32)
+}
+
+/// Priority of a candidate pair (RFC 8445 section 6.1.2.3), from the priorities of the
+/// candidates of the controlling and the controlled agents.
+pub fn pair_priority(controlling: u32, controlled: u32) -> u64 {
+    let (g, d) = (controlling as u64, controlled as u64);
+    (1 << 32) * g.min(d) + 2 * g.max(d) + if g > d { 1 } else { 0 }
+}
+
+fn foundation(kind: CandidateType, base: IpAddr, server: Option<IpAddr>) -> String {
+    let mut hasher = DefaultHasher::new();
+    kind.hash(&mut hasher);
+    base.hash(&mut hasher);
+    server.hash(&mut hasher);
+    format!("{:x}", hasher.finish() as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ice::candidate::*;
+
+    #[test]
+    pub fn test_candidate_priorities() {
+        assert_eq!(priority(CandidateType::Host, 65535), 2_130_706_431);
+        assert_eq!(
+            priority(CandidateType::ServerReflexive, 65535),
+            1_694_498_815
+        );
+        assert!(
+            priority(CandidateType::PeerReflexive, 0)
+                > priority(CandidateType::ServerReflexive, 65535)
+        );
+    }
+
+    #[test]
+    pub fn test_pair_priority_is_symmetric_except_tie_bit() {
+        let host = priority(CandidateType::Host, 65535);
+        let srflx = priority(CandidateType::ServerReflexive, 65535);
+        assert_eq!(pair_priority(host, srflx), pair_priority(srflx, host) + 1);
+        assert!(pair_priority(host, host) > pair_priority(host, srflx));
+    }
+
+    #[test]
+    pub fn test_foundations() {
+        let base1 = "10.0.0.1:5000".parse().unwrap();
+        let base2 = "10.0.0.1:5002".parse().unwrap();
+        let base3 = "10.0.0.2:5000".parse().unwrap();
+        let server = "1.2.3.4:3478".parse().unwrap();
+        let mapped = "5.6.7.8:6000".parse().unwrap();
+        assert_eq!(
+            Candidate::host(base1, 65535).foundation,
+            Candidate::host(base2, 65534).foundation
+        );
+        assert_ne!(
+            Candidate::host(base1, 65535).foundation,
+            Candidate::host(base3, 65535).foundation
+        );
+        assert_ne!(
+            Candidate::host(base1, 65535).foundation,
+            Candidate::server_reflexive(mapped, base1, server, 65535).foundation
+        );
+        let relayed = Candidate::relayed(mapped, server, 65535);
+        assert_eq!(relayed.base, mapped);
+        assert_eq!(relayed.priority, 16_777_215);
+        assert_ne!(
+            relayed.foundation,
+            Candidate::server_reflexive(mapped, base1, server, 65535).foundation
+        );
+    }
+}
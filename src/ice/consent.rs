@@ -0,0 +1,324 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use rand::Rng;
+
+use crate::codec::{append_fingerprint, append_message_integrity, verify_message_integrity};
+use crate::codec::{Decoder, Encoder};
+use crate::ice::agent::Transmit;
+use crate::messages::*;
+use crate::transaction::ClientTransaction;
+
+// consent expires 30 seconds after the last response (RFC 7675 section 5.1)
+const CONSENT_TIMEOUT: Duration = Duration::from_secs(30);
+// consent checks are sent every 5 seconds on average, randomized by 20% each way
+const CONSENT_INTERVAL_MS: u64 = 5000;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum ConsentEvent {
+    // no response was authenticated for 30 seconds or the remote agent refused, sending has to
+    // stop
+    Lost,
+}
+
+/// Consent freshness (RFC 7675) of a selected candidate pair, without any IO.
+///
+/// Binding requests authenticated with the ICE credentials are sent at randomized intervals,
+/// consent is lost when none of them is answered for 30 seconds.
+pub struct ConsentFreshness {
+    local: SocketAddr,
+    remote: SocketAddr,
+    // "remote ufrag:local ufrag" and the remote password, as for connectivity checks
+    username: String,
+    pwd: String,
+    expires_at: Instant,
+    next_check: Instant,
+    // the consent checks sent, kept until they are answered or time out since a response may
+    // arrive after the next check was sent
+    checks: Vec<ClientTransaction>,
+    lost: bool,
+    events: VecDeque<ConsentEvent>,
+    transmits: VecDeque<Transmit>,
+}
+
+impl ConsentFreshness {
+    /// Consent for the pair starts granted, as a connectivity check just succeeded.
+    pub fn new(
+        now: Instant,
+        local: SocketAddr,
+        remote: SocketAddr,
+        username: &str,
+        pwd: &str,
+    ) -> ConsentFreshness {
+        ConsentFreshness {
+            local,
+            remote,
+            username: username.to_owned(),
+            pwd: pwd.to_owned(),
+            expires_at: now + CONSENT_TIMEOUT,
+            next_check: now + check_interval(),
+            checks: vec![],
+            lost: false,
+            events: VecDeque::new(),
+            transmits: VecDeque::new(),
+        }
+    }
+
+    pub fn local(&self) -> SocketAddr {
+        self.local
+    }
+
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    pub fn is_granted(&self) -> bool {
+        !self.lost
+    }
+
+    /// When consent expires unless a consent check is answered before.
+    pub fn expires_at(&self) -> Instant {
+        self.expires_at
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<ConsentEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        if self.lost {
+            return None;
+        }
+        let retransmission = self
+            .checks
+            .iter()
+            .filter_map(|check| check.poll_timeout())
+            .min();
+        let timeout = self.expires_at.min(self.next_check);
+        Some(retransmission.map_or(timeout, |at| at.min(timeout)))
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        if self.lost {
+            return;
+        }
+        if now >= self.expires_at {
+            self.lose();
+            return;
+        }
+        for check in self.checks.iter_mut() {
+            check.handle_timeout(now);
+        }
+        self.drain_checks();
+        if now >= self.next_check {
+            self.send_check(now);
+            self.next_check = now + check_interval();
+        }
+    }
+
+    /// Handles a datagram received on the local address of the pair, true when it is the
+    /// response to a consent check.
+    pub fn handle_input(&mut self, now: Instant, source: SocketAddr, data: &[u8]) -> bool {
+        let message = match Decoder::new().decode(&mut &data[..]) {
+            Ok(message) => message,
+            Err(_) => return false,
+        };
+        if message.message_class != MessageClass::SuccessResponse
+            && message.message_class != MessageClass::FailureResponse
+        {
+            return false;
+        }
+        let index = match self
+            .checks
+            .iter()
+            .position(|check| check.transaction_id() == &message.transaction_id)
+        {
+            Some(index) => index,
+            None => return false,
+        };
+        // an unauthenticated response or one from another address neither refreshes nor
+        // revokes consent
+        if source != self.remote || !verify_message_integrity(data, self.pwd.as_bytes()) {
+            return true;
+        }
+        if self.lost || !self.checks[index].handle_input(now, source, data) {
+            return true;
+        }
+        self.checks.remove(index);
+        if message.message_class == MessageClass::SuccessResponse {
+            self.expires_at = now + CONSENT_TIMEOUT;
+        } else {
+            self.lose();
+        }
+        true
+    }
+
+    fn send_check(&mut self, now: Instant) {
+        let request = Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::random(),
+            attributes: vec![Attribute::UserName(self.username.clone())],
+        };
+        let mut bytes = BytesMut::new();
+        Encoder::new()
+            .encode(&request, &mut bytes)
+            .expect("built-in attributes always encode");
+        append_message_integrity(&mut bytes, self.pwd.as_bytes());
+        append_fingerprint(&mut bytes);
+        self.checks.push(ClientTransaction::from_bytes(
+            now,
+            request.transaction_id,
+            bytes.to_vec(),
+            self.remote,
+        ));
+        self.drain_checks();
+    }
+
+    // queues the transmissions of the checks, drops those that timed out
+    fn drain_checks(&mut self) {
+        for check in self.checks.iter_mut() {
+            while let Some(data) = check.poll_transmit() {
+                self.transmits.push_back(Transmit {
+                    source: self.local,
+                    destination: self.remote,
+                    data,
+                });
+            }
+        }
+        self.checks.retain(|check| !check.is_done());
+    }
+
+    fn lose(&mut self) {
+        self.lost = true;
+        self.checks.clear();
+        self.events.push_back(ConsentEvent::Lost);
+    }
+}
+
+fn check_interval() -> Duration {
+    let spread = CONSENT_INTERVAL_MS / 5;
+    Duration::from_millis(rand::thread_rng().gen_range(
+        CONSENT_INTERVAL_MS - spread,
+        CONSENT_INTERVAL_MS + spread + 1,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use bytes::BytesMut;
+
+    use crate::codec::{append_fingerprint, append_message_integrity, Decoder, Encoder};
+    use crate::ice::consent::*;
+
+    fn response(request: &[u8], pwd: &[u8], class: MessageClass) -> BytesMut {
+        let request = Decoder::new().decode(&mut &request[..]).unwrap();
+        let response = Message {
+            message_class: class,
+            message_method: MessageMethod::Binding,
+            transaction_id: request.transaction_id,
+            attributes: vec![],
+        };
+        let mut bytes = BytesMut::new();
+        Encoder::new().encode(&response, &mut bytes).unwrap();
+        append_message_integrity(&mut bytes, pwd);
+        append_fingerprint(&mut bytes);
+        bytes
+    }
+
+    fn consent(now: Instant) -> ConsentFreshness {
+        let local: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let remote: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        ConsentFreshness::new(now, local, remote, "remote:local", "password")
+    }
+
+    #[test]
+    pub fn test_answered_checks_keep_consent() {
+        let start = Instant::now();
+        let mut consent = consent(start);
+        let mut checks = vec![];
+        for millis in (0..120_000).step_by(100) {
+            let now = start + Duration::from_millis(millis);
+            consent.handle_timeout(now);
+            while let Some(transmit) = consent.poll_transmit() {
+                assert_eq!(transmit.destination, consent.remote());
+                checks.push(now);
+                let response = response(&transmit.data, b"password", MessageClass::SuccessResponse);
+                assert!(consent.handle_input(now, consent.remote(), &response));
+            }
+        }
+        assert!(consent.is_granted());
+        assert_eq!(consent.poll_event(), None);
+        for interval in checks.windows(2) {
+            let interval = interval[1] - interval[0];
+            assert!(interval >= Duration::from_secs(4) && interval <= Duration::from_millis(6100));
+        }
+    }
+
+    #[test]
+    pub fn test_consent_expires_without_responses() {
+        let start = Instant::now();
+        let mut consent = consent(start);
+        let mut now = start;
+        while let Some(timeout) = consent.poll_timeout() {
+            now = timeout;
+            consent.handle_timeout(now);
+            while let Some(transmit) = consent.poll_transmit() {
+                // an unauthenticated response does not refresh consent
+                let response = response(&transmit.data, b"wrong", MessageClass::SuccessResponse);
+                assert!(consent.handle_input(now, consent.remote(), &response));
+            }
+        }
+        assert_eq!(now, start + Duration::from_secs(30));
+        assert!(!consent.is_granted());
+        assert_eq!(consent.poll_event(), Some(ConsentEvent::Lost));
+        assert_eq!(consent.poll_event(), None);
+    }
+
+    #[test]
+    pub fn test_late_response_keeps_consent() {
+        let start = Instant::now();
+        let mut consent = consent(start);
+        consent.handle_timeout(start + Duration::from_secs(6));
+        let first = consent.poll_transmit().unwrap();
+        // the first check is retransmitted along with the second one
+        consent.handle_timeout(start + Duration::from_secs(12));
+        let transmits: Vec<_> = std::iter::from_fn(|| consent.poll_transmit()).collect();
+        assert_eq!(transmits.len(), 2);
+        assert_eq!(transmits[0].data, first.data);
+        let second = transmits[1].clone();
+        assert_ne!(first.data, second.data);
+
+        // the first check is answered only after the second one was sent
+        let now = start + Duration::from_secs(13);
+        let late = response(&first.data, b"password", MessageClass::SuccessResponse);
+        assert!(consent.handle_input(now, consent.remote(), &late));
+        assert_eq!(consent.expires_at(), now + Duration::from_secs(30));
+        let answer = response(&second.data, b"password", MessageClass::SuccessResponse);
+        assert!(consent.handle_input(now, consent.remote(), &answer));
+        assert!(consent.is_granted());
+        assert_eq!(consent.poll_event(), None);
+    }
+
+    #[test]
+    pub fn test_error_response_revokes_consent() {
+        let start = Instant::now();
+        let mut consent = consent(start);
+        consent.handle_timeout(start + Duration::from_secs(6));
+        let transmit = consent.poll_transmit().unwrap();
+        let response = response(&transmit.data, b"password", MessageClass::FailureResponse);
+        assert!(consent.handle_input(start + Duration::from_secs(6), consent.remote(), &response));
+        assert!(!consent.is_granted());
+        assert_eq!(consent.poll_event(), Some(ConsentEvent::Lost));
+        assert_eq!(consent.poll_timeout(), None);
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+
+use crate::codec::{
+    append_fingerprint, append_message_integrity, verify_fingerprint, verify_message_integrity,
+    Decoder, Encoder,
+};
+use crate::messages::*;
+use crate::server::{error_response, Action, Context, Handler, Middleware, ServerEngine};
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum LiteEvent {
+    // the full agent nominated the pair ending at `address` with USE-CANDIDATE
+    Nominated {
+        local_ufrag: String,
+        remote_ufrag: String,
+        address: SocketAddr,
+    },
+}
+
+// the credentials signaled for one session
+struct Session {
+    pwd: String,
+    remote_ufrag: String,
+}
+
+#[derive(Default)]
+struct Sessions {
+    // by local ufrag
+    credentials: HashMap<String, Session>,
+    // the last address nominated for a local ufrag
+    nominated: HashMap<String, SocketAddr>,
+    events: VecDeque<LiteEvent>,
+}
+
+/// The STUN side of an ICE-lite server (RFC 8445 section 2.5): answers the connectivity checks
+/// of full agents and reports their nominations, for any number of sessions keyed by the local
+/// username fragment.
+///
+/// Checks run through a `ServerEngine`, authentication as its middleware and the nominations
+/// in its Binding handler.
+pub struct LiteResponder {
+    sessions: Arc<Mutex<Sessions>>,
+    engine: ServerEngine,
+}
+
+impl Default for LiteResponder {
+    fn default() -> LiteResponder {
+        LiteResponder::new()
+    }
+}
+
+impl LiteResponder {
+    pub fn new() -> LiteResponder {
+        let sessions = Arc::new(Mutex::new(Sessions::default()));
+        let engine = ServerEngine::new()
+            .with_middleware(Authentication {
+                sessions: sessions.clone(),
+            })
+            .with_handler(
+                MessageMethod::Binding,
+                Check {
+                    sessions: sessions.clone(),
+                },
+            );
+        LiteResponder { sessions, engine }
+    }
+
+    /// Starts a session with the local credentials and the ufrag of the full agent, checks
+    /// are only answered when their USERNAME is "ufrag:remote_ufrag".
+    pub fn add_credentials(&mut self, ufrag: &str, pwd: &str, remote_ufrag: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = Session {
+            pwd: pwd.to_owned(),
+            remote_ufrag: remote_ufrag.to_owned(),
+        };
+        sessions.credentials.insert(ufrag.to_owned(), session);
+    }
+
+    pub fn remove_credentials(&mut self, ufrag: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.credentials.remove(ufrag);
+        sessions.nominated.remove(ufrag);
+    }
+
+    pub fn poll_event(&mut self) -> Option<LiteEvent> {
+        self.sessions.lock().unwrap().events.pop_front()
+    }
+
+    /// Handles a datagram received from `source` on `destination`, returns the response to send
+    /// back.
+    ///
+    /// Datagrams which are not Binding requests with a valid FINGERPRINT get no response, a
+    /// request without USERNAME or MESSAGE-INTEGRITY is answered with 400 and one failing
+    /// authentication with 401.
+    pub fn handle_request(
+        &mut self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        if data.len() < 20 || data[0] & 0xC0 != 0 || !verify_fingerprint(data) {
+            return None;
+        }
+        let request = Decoder::new().decode(&mut &data[..]).ok()?;
+        if request.message_class != MessageClass::Request
+            || request.message_method != MessageMethod::Binding
+        {
+            return None;
+        }
+        let context = Context {
+            source,
+            destination,
+            data,
+        };
+        let response = self.engine.handle_message(&request, &context)?;
+
+        let mut bytes = BytesMut::new();
+        Encoder::new().encode(&response, &mut bytes).ok()?;
+        // errors are not authenticated, the request could not be
+        if response.message_class == MessageClass::SuccessResponse {
+            let (local_ufrag, _) = ufrags(&request)?;
+            let sessions = self.sessions.lock().unwrap();
+            let session = sessions.credentials.get(local_ufrag)?;
+            append_message_integrity(&mut bytes, session.pwd.as_bytes());
+        }
+        append_fingerprint(&mut bytes);
+        Some(bytes.to_vec())
+    }
+}
+
+// USERNAME of a check is "local ufrag:remote ufrag" from the point of view of the server
+fn ufrags(request: &Message) -> Option<(&str, &str)> {
+    let username = request
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            Attribute::UserName(username) => Some(username),
+            _ => None,
+        })?;
+    let colon = username.find(':')?;
+    Some((&username[..colon], &username[colon + 1..]))
+}
+
+// answers checks without USERNAME and MESSAGE-INTEGRITY with 400, failing ones with 401, so are
+// those whose USERNAME does not match the signaled ufrags
+struct Authentication {
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+impl Middleware for Authentication {
+    fn before(&mut self, message: &Message, context: &Context) -> Action {
+        let has_username = message
+            .attributes
+            .iter()
+            .any(|attribute| matches!(attribute, Attribute::UserName(_)));
+        let has_integrity = message
+            .attributes
+            .iter()
+            .any(|attribute| matches!(attribute, Attribute::MessageIntegrity(_)));
+        if !has_username || !has_integrity {
+            return Action::Respond(error_response(message, 400, "Bad Request"));
+        }
+        let sessions = self.sessions.lock().unwrap();
+        let session = ufrags(message).and_then(|(local_ufrag, remote_ufrag)| {
+            sessions
+                .credentials
+                .get(local_ufrag)
+                .filter(|session| session.remote_ufrag == remote_ufrag)
+        });
+        match session {
+            Some(session) if verify_message_integrity(context.data, session.pwd.as_bytes()) => {
+                Action::Continue
+            }
+            _ => Action::Respond(error_response(message, 401, "Unauthorized")),
+        }
+    }
+}
+
+// answers authenticated checks and reports their nominations
+struct Check {
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+impl Handler for Check {
+    fn handle(&mut self, message: &Message, context: &Context) -> Option<Message> {
+        let (local_ufrag, remote_ufrag) = ufrags(message)?;
+        let use_candidate = message
+            .attributes
+            .iter()
+            .any(|attribute| attribute == &Attribute::UseCandidate);
+        let mut sessions = self.sessions.lock().unwrap();
+        if use_candidate && sessions.nominated.get(local_ufrag) != Some(&context.source) {
+            sessions
+                .nominated
+                .insert(local_ufrag.to_owned(), context.source);
+            sessions.events.push_back(LiteEvent::Nominated {
+                local_ufrag: local_ufrag.to_owned(),
+                remote_ufrag: remote_ufrag.to_owned(),
+                address: context.source,
+            });
+        }
+
+        Some(Message {
+            message_class: MessageClass::SuccessResponse,
+            message_method: MessageMethod::Binding,
+            transaction_id: message.transaction_id,
+            attributes: vec![Attribute::XorMappedAddress(Address::from(context.source))],
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use bytes::BytesMut;
+
+    use crate::codec::{
+        append_fingerprint, append_message_integrity, verify_message_integrity, Decoder, Encoder,
+    };
+    use crate::ice::lite::*;
+
+    fn check(username: &str, pwd: Option<&[u8]>, use_candidate: bool) -> (TransactionID, Vec<u8>) {
+        let mut attributes = vec![
+            Attribute::UserName(username.to_owned()),
+            Attribute::IceControlling(7),
+        ];
+        if use_candidate {
+            attributes.push(Attribute::UseCandidate);
+        }
+        let request = Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::random(),
+            attributes,
+        };
+        let mut bytes = BytesMut::new();
+        Encoder::new().encode(&request, &mut bytes).unwrap();
+        if let Some(pwd) = pwd {
+            append_message_integrity(&mut bytes, pwd);
+        }
+        append_fingerprint(&mut bytes);
+        (request.transaction_id, bytes.to_vec())
+    }
+
+    fn error_code(response: &[u8]) -> Option<u32> {
+        let response = Decoder::new().decode(&mut &response[..]).unwrap();
+        response
+            .attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                Attribute::ErrorCode { code, .. } => Some(*code),
+                _ => None,
+            })
+    }
+
+    fn addresses() -> (SocketAddr, SocketAddr) {
+        (
+            "198.51.100.7:40000".parse().unwrap(),
+            "192.0.2.2:3478".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    pub fn test_answer_authenticated_check() {
+        let (source, destination) = addresses();
+        let mut responder = LiteResponder::new();
+        responder.add_credentials("srv1", "server-password-0123456789", "peer");
+
+        let (transaction_id, request) =
+            check("srv1:peer", Some(b"server-password-0123456789"), false);
+        let response = responder
+            .handle_request(source, destination, &request)
+            .unwrap();
+        assert!(verify_message_integrity(
+            &response,
+            b"server-password-0123456789"
+        ));
+        let response = Decoder::new().decode(&mut &response[..]).unwrap();
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+        assert_eq!(response.transaction_id, transaction_id);
+        assert_eq!(
+            response.attributes[0],
+            Attribute::XorMappedAddress(Address::from(source))
+        );
+        assert_eq!(responder.poll_event(), None);
+    }
+
+    #[test]
+    pub fn test_report_nomination_once() {
+        let (source, destination) = addresses();
+        let mut responder = LiteResponder::new();
+        responder.add_credentials("srv1", "pwd", "peer");
+        for _ in 0..2 {
+            let (_, request) = check("srv1:peer", Some(b"pwd"), true);
+            assert_eq!(
+                error_code(
+                    &responder
+                        .handle_request(source, destination, &request)
+                        .unwrap()
+                ),
+                None
+            );
+        }
+        assert_eq!(
+            responder.poll_event(),
+            Some(LiteEvent::Nominated {
+                local_ufrag: "srv1".to_owned(),
+                remote_ufrag: "peer".to_owned(),
+                address: source,
+            })
+        );
+        assert_eq!(responder.poll_event(), None);
+    }
+
+    #[test]
+    pub fn test_reject_invalid_checks() {
+        let (source, destination) = addresses();
+        let mut responder = LiteResponder::new();
+        responder.add_credentials("srv1", "pwd", "peer");
+
+        let (_, wrong_pwd) = check("srv1:peer", Some(b"wrong"), true);
+        let (_, unknown_ufrag) = check("srv2:peer", Some(b"pwd"), true);
+        let (_, other_peer) = check("srv1:other", Some(b"pwd"), true);
+        let (_, no_remote_ufrag) = check("srv1:", Some(b"pwd"), true);
+        let (_, no_integrity) = check("srv1:peer", None, true);
+        for (request, code) in &[
+            (wrong_pwd, 401),
+            (unknown_ufrag, 401),
+            (other_peer, 401),
+            (no_remote_ufrag, 401),
+            (no_integrity, 400),
+        ] {
+            let response = responder
+                .handle_request(source, destination, request)
+                .unwrap();
+            assert_eq!(error_code(&response), Some(*code));
+        }
+        assert_eq!(responder.poll_event(), None);
+
+        // without FINGERPRINT the datagram is not taken as STUN
+        let (_, mut request) = check("srv1:peer", Some(b"pwd"), false);
+        request.truncate(request.len() - 8);
+        request[3] -= 8;
+        assert_eq!(
+            responder.handle_request(source, destination, &request),
+            None
+        );
+    }
+}
@@ -1,2 +1,9 @@
 pub mod codec;
+pub mod ice;
 pub mod messages;
+pub mod nat;
+pub mod server;
+#[cfg(any(test, feature = "simulator"))]
+pub mod sim;
+pub mod transaction;
+pub mod turn;
@@ -1,32 +1,56 @@
-use std::intrinsics::write_bytes;
-use std::net::SocketAddr;
-use std::slice::Iter;
-use std::thread::sleep;
-use std::time::Duration;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
 use clap::{App, Arg, ArgMatches, SubCommand};
-use futures::sink::SinkExt;
-use futures::stream::{self, StreamExt};
-use tokio::io::AsyncWriteExt;
-use tokio::io::Error;
 use tokio::net::UdpSocket;
-use tokio::prelude::*;
 
-use stun_rs::codec::error::CodecError;
-use stun_rs::codec::{Decoder, Encoder};
+use stun_rs::codec::Decoder;
 use stun_rs::messages::{Address, Attribute, Message, MessageClass, MessageMethod, TransactionID};
+use stun_rs::nat::{DiscoveryClient, DiscoveryServer, LifetimeSearch};
+use stun_rs::server::{Context, ServerEngine};
+use stun_rs::transaction::{ClientTransaction, TransactionResult};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = parse_arguments();
     let host = args.value_of("host").unwrap();
     let port: u16 = args.value_of("port").unwrap().parse().unwrap();
-    let socket = UdpSocket::bind(format!("{}:{}", host, port)).await.unwrap();
 
     match args.subcommand() {
-        ("client", Some(opts)) => start_udp_client(socket, opts.value_of("server").unwrap()).await,
-        ("server", _) => start_udp_server(socket).await,
+        ("client", Some(opts)) => {
+            let socket = UdpSocket::bind(format!("{}:{}", host, port)).await?;
+            let server = opts.value_of("server").unwrap();
+            match opts.subcommand() {
+                ("nat-type", Some(opts)) => {
+                    let search = if opts.is_present("skip-lifetime") {
+                        None
+                    } else {
+                        Some(lifetime_search(opts)?)
+                    };
+                    start_nat_type_client(socket, server, search).await
+                }
+                ("lifetime", Some(opts)) => {
+                    start_lifetime_client(socket, server, lifetime_search(opts)?).await
+                }
+                _ => start_udp_client(socket, server).await,
+            }
+        }
+        ("server", Some(opts)) if opts.is_present("alternate-host") => {
+            let primary = format!("{}:{}", host, port).parse()?;
+            let alternate_host = opts.value_of("alternate-host").unwrap();
+            let alternate_port: u16 = opts.value_of("alternate-port").unwrap().parse()?;
+            let alternate = format!("{}:{}", alternate_host, alternate_port).parse()?;
+            start_discovery_server(primary, alternate, opts.is_present("rfc3489")).await
+        }
+        ("server", Some(opts)) => {
+            let socket = UdpSocket::bind(format!("{}:{}", host, port)).await?;
+            let stun_decoder = if opts.is_present("rfc3489") {
+                Decoder::with_classic_stun()
+            } else {
+                Decoder::new()
+            };
+            start_udp_server(socket, stun_decoder).await
+        }
         (cmd, _) => {
             eprintln!("unsupported command: {}", cmd);
             println!("{}", args.usage());
@@ -65,82 +89,215 @@ fn parse_arguments() -> ArgMatches<'static> {
                         .long("server")
                         .takes_value(true)
                         .required(true),
+                )
+                .subcommand(
+                    SubCommand::with_name("nat-type")
+                        .about(
+                            "discover NAT mapping and filtering behavior and binding lifetime \
+                             (RFC 5780)",
+                        )
+                        .arg(
+                            Arg::with_name("skip-lifetime")
+                                .long("skip-lifetime")
+                                .help("skip the binding lifetime, measuring it idles for minutes"),
+                        )
+                        .arg(max_arg())
+                        .arg(resolution_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("lifetime")
+                        .about("measure how long the NAT keeps an idle UDP binding (RFC 5780)")
+                        .arg(max_arg())
+                        .arg(resolution_arg()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("server")
+                .about("run STUN server")
+                .arg(
+                    Arg::with_name("rfc3489")
+                        .long("rfc3489")
+                        .help("also answer classic STUN (RFC 3489) requests"),
+                )
+                .arg(
+                    Arg::with_name("alternate-host")
+                        .long("alternate-host")
+                        .value_name("HOST")
+                        .help("alternate HOST for NAT behavior discovery (RFC 5780)")
+                        .requires("alternate-port")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("alternate-port")
+                        .long("alternate-port")
+                        .value_name("PORT")
+                        .help("alternate PORT for NAT behavior discovery (RFC 5780)")
+                        .requires("alternate-host")
+                        .takes_value(true),
                 ),
         )
-        .subcommand(SubCommand::with_name("server").about("run STUN server"))
         .get_matches()
 }
 
+fn max_arg() -> Arg<'static, 'static> {
+    Arg::with_name("max")
+        .long("max")
+        .value_name("SECONDS")
+        .help("longest idle interval to try for the binding lifetime")
+        .default_value("600")
+        .takes_value(true)
+}
+
+fn resolution_arg() -> Arg<'static, 'static> {
+    Arg::with_name("resolution")
+        .long("resolution")
+        .value_name("SECONDS")
+        .help("stop once the binding lifetime is known within this interval")
+        .default_value("5")
+        .takes_value(true)
+}
+
+fn lifetime_search(opts: &ArgMatches) -> Result<LifetimeSearch, Box<dyn std::error::Error>> {
+    let max = Duration::from_secs(opts.value_of("max").unwrap().parse()?);
+    let resolution = Duration::from_secs(opts.value_of("resolution").unwrap().parse()?);
+    Ok(LifetimeSearch::new(Duration::from_secs(1), max, resolution))
+}
+
 async fn start_udp_client(
     mut socket: UdpSocket,
     server: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let server = resolve(server)?;
     let msg = Message {
         message_class: MessageClass::Request,
         message_method: MessageMethod::Binding,
-        transaction_id: TransactionID::from([1u8; 12]),
+        transaction_id: TransactionID::random(),
         attributes: vec![Attribute::Software("stun-rs:0.1.0".to_owned())],
     };
-    let stun_encoder = Encoder::new();
-    let stun_decoder = Decoder::new();
-    let mut bytes_mut = BytesMut::new();
-    let size = stun_encoder.encode(&msg, &mut bytes_mut);
-
-    let bytes_sent = socket.send_to(bytes_mut.bytes(), server).await.unwrap();
-    assert_eq!(bytes_sent, bytes_mut.len());
+    let mut transaction = ClientTransaction::new(Instant::now(), &msg, server)?;
+    let mut buf = [0u8; 1024];
+    // where the response came from
+    let mut from = server;
     loop {
-        let mut buf = [0u8; 1024];
-        let (bytes_recv, address) = socket.recv_from(&mut buf).await.unwrap();
-        let mut bytes = Bytes::copy_from_slice(&buf);
-        let message = stun_decoder.decode(&mut bytes).unwrap();
-        println!("receive from {}, message: {:?}", address, message);
+        while let Some(request) = transaction.poll_transmit() {
+            socket.send_to(&request, server).await?;
+        }
+        match transaction.poll_result() {
+            Some(TransactionResult::Response(message)) => {
+                println!("receive from {}, message: {:?}", from, message);
+                return Ok(());
+            }
+            Some(TransactionResult::TimedOut) => return Err("no response from server".into()),
+            None => {}
+        }
+        let timeout = match transaction.poll_timeout() {
+            Some(timeout) => tokio::time::Instant::from_std(timeout),
+            None => return Ok(()),
+        };
+        match tokio::time::timeout_at(timeout, socket.recv_from(&mut buf)).await {
+            Ok(received) => {
+                let (bytes_recv, source) = received?;
+                if transaction.handle_input(Instant::now(), source, &buf[..bytes_recv]) {
+                    from = source;
+                }
+            }
+            Err(_) => transaction.handle_timeout(Instant::now()),
+        }
+    }
+}
+
+async fn start_nat_type_client(
+    socket: UdpSocket,
+    server: &str,
+    search: Option<LifetimeSearch>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server = resolve(server)?;
+    let mut client = DiscoveryClient::new(socket, server);
+    let behavior = client.discover().await?;
+    println!("{}", behavior);
+    if let Some(search) = search {
+        println!("measuring the binding lifetime, --skip-lifetime skips it");
+        println!("{}", client.binding_lifetime(search).await?);
     }
     Ok(())
 }
 
-async fn start_udp_server(mut socket: UdpSocket) -> Result<(), Box<dyn std::error::Error>> {
-    let stun_encoder = Encoder::new();
-    let stun_decoder = Decoder::new();
+async fn start_lifetime_client(
+    socket: UdpSocket,
+    server: &str,
+    search: LifetimeSearch,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server = resolve(server)?;
+    let lifetime = DiscoveryClient::new(socket, server)
+        .binding_lifetime(search)
+        .await?;
+    println!("{}", lifetime);
+    Ok(())
+}
+
+async fn start_udp_server(
+    mut socket: UdpSocket,
+    stun_decoder: Decoder,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_address = socket.local_addr()?;
+    let mut engine = ServerEngine::new()
+        .with_decoder(stun_decoder)
+        .with_handler(MessageMethod::Binding, message_handler);
     let mut buf = [0u8; 1024];
     loop {
         let (bytes_recv, address) = socket.recv_from(&mut buf).await?;
-        let mut bytes = Bytes::copy_from_slice(&buf);
-        let message = stun_decoder.decode(&mut bytes)?;
-        match message_handler(message, address) {
-            Some(reply) => {
-                println!("sending message: {:?}", reply);
-                let mut buf = BytesMut::new();
-                Encoder::new().encode(&reply, &mut buf);
-                socket.send_to(&mut buf.bytes(), address).await?;
+        if let Some(reply) = engine.handle_datagram(address, local_address, &buf[..bytes_recv]) {
+            // a failed send only loses this response, the server keeps serving others
+            if let Err(e) = socket.send_to(&reply, address).await {
+                eprintln!("failed to send response to {}: {}", address, e);
             }
-            None => {}
         }
     }
+}
+
+async fn start_discovery_server(
+    primary: SocketAddr,
+    alternate: SocketAddr,
+    classic_stun: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server = DiscoveryServer::bind(primary, alternate)
+        .await?
+        .with_classic_stun(classic_stun)
+        .with_error_handler(|error| eprintln!("{}", error));
+    println!(
+        "NAT behavior discovery server on {} and {}",
+        server.primary_address(),
+        server.alternate_address()
+    );
+    server.run().await?;
     Ok(())
 }
 
-fn message_handler(message: Message, socket_addr: SocketAddr) -> Option<Message> {
+fn message_handler(message: &Message, context: &Context) -> Option<Message> {
     println!("receive message: {:?}", message);
-    match (message.message_class, message.message_method) {
-        (MessageClass::Request, MessageMethod::Binding) => {
-            let reply = Message {
-                message_class: MessageClass::SuccessResponse,
-                message_method: MessageMethod::Binding,
-                transaction_id: TransactionID::from([1u8; 12]),
-                attributes: vec![
-                    Attribute::Software("stun-rs:0.1.0".to_owned()),
-                    Attribute::XorMappedAddress(get_address(socket_addr)),
-                ],
-            };
-            Some(reply)
-        }
-        _ => None,
-    }
+    // classic STUN (RFC 3489) clients do not know XOR-MAPPED-ADDRESS
+    let mapped_address = if message.transaction_id.is_classic() {
+        Attribute::MappedAddress(Address::from(context.source))
+    } else {
+        Attribute::XorMappedAddress(Address::from(context.source))
+    };
+    let reply = Message {
+        message_class: MessageClass::SuccessResponse,
+        message_method: MessageMethod::Binding,
+        transaction_id: message.transaction_id,
+        attributes: vec![
+            Attribute::Software("stun-rs:0.1.0".to_owned()),
+            mapped_address,
+        ],
+    };
+    println!("sending message: {:?}", reply);
+    Some(reply)
 }
 
-fn get_address(socket_addr: SocketAddr) -> Address {
-    match socket_addr {
-        SocketAddr::V4(address) => Address::ipv4(address.ip().octets(), address.port()),
-        SocketAddr::V6(address) => Address::ipv6(address.ip().octets(), address.port()),
-    }
+fn resolve(server: &str) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    let address = server
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("cannot resolve server address: {}", server))?;
+    Ok(address)
 }
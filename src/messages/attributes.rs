@@ -1,5 +1,9 @@
+use std::any::Any;
+use std::fmt::Debug;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
 /**
-*
+
   To allow future revisions of this specification to add new attributes
   if needed, the attribute space is divided into two ranges.
   Attributes with type values between 0x0000 and 0x7FFF are
@@ -12,6 +16,15 @@
 #[derive(Eq, PartialEq, Debug)]
 pub enum Attribute {
     MappedAddress(Address),
+    // classic STUN (RFC 3489): where the response should be sent to
+    ResponseAddress(Address),
+    // classic STUN (RFC 3489): the address the response was sent from
+    SourceAddress(Address),
+    // classic STUN (RFC 3489): the address a CHANGE-REQUEST response would be sent from
+    ChangedAddress(Address),
+    // classic STUN (RFC 3489): the source of the request which caused a response sent to
+    // RESPONSE-ADDRESS
+    ReflectedFrom(Address),
     // same as MappedAddress, but bits are xored with the magic cookie
     XorMappedAddress(Address),
     // user credentials
@@ -20,15 +33,107 @@ pub enum Attribute {
     MessageIntegrity([u8; 20]),
     // crc-32 of the message
     FingerPrint(u32),
-    ErrorCode { code: u32, reason: String },
+    ErrorCode {
+        code: u32,
+        reason: String,
+    },
     Realm(String),
     Nonce(String),
     // a list of unknown attribute kinds
     UnknownAttributes(Vec<u16>),
     Software(String),
     AlternateServer(Address),
+    // the channel a ChannelBind request binds the peer to (TURN)
+    ChannelNumber(u16),
+    // the lifetime of a TURN allocation in seconds
+    Lifetime(u32),
+    // the address of a peer as seen from the relay, xored like XorMappedAddress
+    XorPeerAddress(Address),
+    // application data of Send and Data indications
+    Data(Vec<u8>),
+    // the relayed address of a TURN allocation, xored like XorMappedAddress
+    XorRelayedAddress(Address),
+    // protocol number of the relayed transport, 17 for UDP and 6 for TCP
+    RequestedTransport(u8),
+    // asks the TURN server to set the DF bit on the datagrams it relays
+    DontFragment,
+    // identifies a peer data connection of a TURN TCP allocation (RFC 6062)
+    ConnectionId(u32),
+    // address family of the relayed address a TURN client asks for
+    RequestedAddressFamily(IPKind),
+    // asks for a second relayed address of this family (dual allocation)
+    AdditionalAddressFamily(IPKind),
+    // the relayed address of this family could not be allocated
+    AddressErrorCode {
+        ip_kind: IPKind,
+        code: u32,
+        reason: String,
+    },
+    // asks for an even relayed port, the flag also reserves the next port
+    EvenPort(bool),
+    // token of a relayed port reserved by a previous allocation
+    ReservationToken([u8; 8]),
+    // asks the server to respond from its alternate IP and/or port (RFC 5780)
+    ChangeRequest {
+        change_ip: bool,
+        change_port: bool,
+    },
+    // the address and port the response was sent from
+    ResponseOrigin(Address),
+    // the alternate address and port of a NAT behavior discovery server
+    OtherAddress(Address),
+    // asks the server to send the response to this port
+    ResponsePort(u16),
+    // pads the message, used to test fragmentation behavior
+    Padding(Vec<u8>),
+    // priority of the peer reflexive candidate a connectivity check would discover (RFC 8445)
+    Priority(u32),
+    // the controlling ICE agent nominates the candidate pair of the check
+    UseCandidate,
+    // the sender is the controlled ICE agent, with its tie breaker
+    IceControlled(u64),
+    // the sender is the controlling ICE agent, with its tie breaker
+    IceControlling(u64),
+    // a vendor attribute, decoded by the codec registered for its type
+    Custom(Box<dyn CustomAttribute>),
     // unrecognized attributes
-    UnRecognized { kind: u16 },
+    UnRecognized {
+        kind: u16,
+    },
+}
+
+impl Attribute {
+    /// ERROR-CODE 487, sent when both ICE agents claim the same role (RFC 8445).
+    pub fn role_conflict() -> Attribute {
+        Attribute::ErrorCode {
+            code: 487,
+            reason: "Role Conflict".to_owned(),
+        }
+    }
+}
+
+/// A vendor attribute, decoded into its own type by the `AttributeCodec` registered with the
+/// decoder for its type, see `Decoder::with_attribute`.
+pub trait CustomAttribute: Any + Debug + Send + Sync {
+    fn kind(&self) -> u16;
+
+    /// The value of the attribute, without its header and padding.
+    fn encode_value(&self) -> Vec<u8>;
+}
+
+// custom attributes are equal when they encode to the same bytes
+impl PartialEq for dyn CustomAttribute {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind() == other.kind() && self.encode_value() == other.encode_value()
+    }
+}
+
+impl Eq for dyn CustomAttribute {}
+
+impl dyn CustomAttribute {
+    pub fn downcast_ref<T: CustomAttribute>(&self) -> Option<&T> {
+        (self as &dyn Any).downcast_ref()
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -54,10 +159,44 @@ impl Address {
             ip_kind: IPKind::IPv6,
         }
     }
+
+    pub fn to_socket_addr(&self) -> SocketAddr {
+        let ip = match self.ip_kind {
+            IPKind::IPv4 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&self.address[..4]);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            IPKind::IPv6 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&self.address[..16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+        };
+        SocketAddr::new(ip, self.port)
+    }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+impl From<SocketAddr> for Address {
+    fn from(socket_addr: SocketAddr) -> Self {
+        match socket_addr {
+            SocketAddr::V4(address) => Address::ipv4(address.ip().octets(), address.port()),
+            SocketAddr::V6(address) => Address::ipv6(address.ip().octets(), address.port()),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash)]
 pub enum IPKind {
     IPv4,
     IPv6,
 }
+
+impl From<IpAddr> for IPKind {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => IPKind::IPv4,
+            IpAddr::V6(_) => IPKind::IPv6,
+        }
+    }
+}
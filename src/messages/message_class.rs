@@ -22,11 +22,12 @@ impl MessageClass {
             MessageClass::Request => 0b00,
             MessageClass::Indication => 0b01,
             MessageClass::SuccessResponse => 0b10,
-            MessageClass::FailureResponse => 0b1,
+            MessageClass::FailureResponse => 0b11,
         }
     }
 }
 
+#[cfg(test)]
 mod test {
     #[test]
     fn can_deserialize_message_class_correctly() {
@@ -36,4 +37,18 @@ mod test {
         assert_eq!(MessageClass::from(0b10), MessageClass::SuccessResponse);
         assert_eq!(MessageClass::from(0b11), MessageClass::FailureResponse);
     }
+
+    #[test]
+    fn can_serialize_message_class_correctly() {
+        use super::*;
+        for class in &[
+            MessageClass::Request,
+            MessageClass::Indication,
+            MessageClass::SuccessResponse,
+            MessageClass::FailureResponse,
+        ] {
+            assert_eq!(&MessageClass::from(class.value()), class);
+        }
+        assert_eq!(MessageClass::FailureResponse.value(), 0b11);
+    }
 }
@@ -1,6 +1,17 @@
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum MessageMethod {
     Binding,
+    // TURN (RFC 8656)
+    Allocate,
+    Refresh,
+    Send,
+    Data,
+    CreatePermission,
+    ChannelBind,
+    // TURN over TCP to peers (RFC 6062)
+    Connect,
+    ConnectionBind,
+    ConnectionAttempt,
     Custom(u16),
 }
 
@@ -8,25 +19,71 @@ impl MessageMethod {
     pub fn from(value: u16) -> MessageMethod {
         // make sure the value is at most 12 bits length
         if value & 0xFFF != value {
-            panic!(format!("invalid method value: {}", value))
+            panic!("invalid method value: {}", value)
         }
         match value {
             1 => MessageMethod::Binding,
+            0x0003 => MessageMethod::Allocate,
+            0x0004 => MessageMethod::Refresh,
+            0x0006 => MessageMethod::Send,
+            0x0007 => MessageMethod::Data,
+            0x0008 => MessageMethod::CreatePermission,
+            0x0009 => MessageMethod::ChannelBind,
+            0x000A => MessageMethod::Connect,
+            0x000B => MessageMethod::ConnectionBind,
+            0x000C => MessageMethod::ConnectionAttempt,
             v => MessageMethod::Custom(v),
         }
     }
     pub fn value(&self) -> u16 {
         match self {
             MessageMethod::Binding => 0x0001,
+            MessageMethod::Allocate => 0x0003,
+            MessageMethod::Refresh => 0x0004,
+            MessageMethod::Send => 0x0006,
+            MessageMethod::Data => 0x0007,
+            MessageMethod::CreatePermission => 0x0008,
+            MessageMethod::ChannelBind => 0x0009,
+            MessageMethod::Connect => 0x000A,
+            MessageMethod::ConnectionBind => 0x000B,
+            MessageMethod::ConnectionAttempt => 0x000C,
             MessageMethod::Custom(v) => *v,
         }
     }
 }
 
+#[cfg(test)]
 mod test {
     #[test]
     fn can_deserialize_binding_method() {
         use super::*;
         assert_eq!(MessageMethod::from(1), MessageMethod::Binding);
     }
+
+    #[test]
+    fn can_deserialize_turn_methods() {
+        use super::*;
+        for value in 0x0003..=0x0009 {
+            if value == 0x0005 {
+                // reserved, was SharedSecret
+                assert_eq!(MessageMethod::from(value), MessageMethod::Custom(value));
+            } else {
+                assert_eq!(MessageMethod::from(value).value(), value);
+            }
+        }
+        assert_eq!(MessageMethod::from(0x0003), MessageMethod::Allocate);
+        assert_eq!(MessageMethod::from(0x0009), MessageMethod::ChannelBind);
+    }
+
+    #[test]
+    fn can_deserialize_tcp_relay_methods() {
+        use super::*;
+        assert_eq!(MessageMethod::from(0x000A), MessageMethod::Connect);
+        assert_eq!(MessageMethod::from(0x000B), MessageMethod::ConnectionBind);
+        assert_eq!(
+            MessageMethod::from(0x000C),
+            MessageMethod::ConnectionAttempt
+        );
+        assert_eq!(MessageMethod::ConnectionAttempt.value(), 0x000C);
+    }
 }
@@ -1,14 +1,40 @@
-#[derive(PartialEq, Eq, Debug)]
+use crate::codec::MAGIC_COOKIE;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub struct TransactionID {
+    // the magic cookie, or the first 32 bits of a classic STUN (RFC 3489) transaction id
+    pub magic_cookie: u32,
     pub value: [u8; 12],
 }
 
 impl TransactionID {
     pub fn from(value: [u8; 12]) -> TransactionID {
-        TransactionID { value }
+        TransactionID {
+            magic_cookie: MAGIC_COOKIE,
+            value,
+        }
+    }
+
+    pub fn random() -> TransactionID {
+        TransactionID::from(rand::random())
+    }
+
+    /// A 128 bits transaction id of classic STUN (RFC 3489), which has no magic cookie.
+    pub fn classic(value: [u8; 16]) -> TransactionID {
+        let mut id = [0u8; 12];
+        id.copy_from_slice(&value[4..]);
+        TransactionID {
+            magic_cookie: u32::from_be_bytes([value[0], value[1], value[2], value[3]]),
+            value: id,
+        }
+    }
+
+    pub fn is_classic(&self) -> bool {
+        self.magic_cookie != MAGIC_COOKIE
     }
 }
 
+#[cfg(test)]
 mod test {
     #[test]
     fn test_transaction_id_equality_check() {
@@ -17,4 +43,22 @@ mod test {
         let id2 = TransactionID::from([1; 12]);
         assert_eq!(id1, id2)
     }
+
+    #[test]
+    fn test_random_transaction_ids_differ() {
+        use super::*;
+        assert_ne!(TransactionID::random(), TransactionID::random())
+    }
+
+    #[test]
+    fn test_classic_transaction_id() {
+        use super::*;
+        let mut value = [3u8; 16];
+        value[..4].copy_from_slice(&[0x01, 0x02, 0x03, 0x04]);
+        let id = TransactionID::classic(value);
+        assert!(id.is_classic());
+        assert_eq!(id.magic_cookie, 0x01020304);
+        assert_eq!(id.value, [3u8; 12]);
+        assert!(!TransactionID::from([3u8; 12]).is_classic());
+    }
 }
@@ -0,0 +1,7 @@
+// NAT behavior discovery (RFC 5780)
+pub use client::*;
+pub use server::*;
+
+mod client;
+
+mod server;
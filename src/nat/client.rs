@@ -0,0 +1,740 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::net::UdpSocket;
+use tokio::time::{delay_for, timeout};
+
+use crate::codec::{Decoder, Encoder};
+use crate::messages::*;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum MappingBehavior {
+    // the mapped address is the local address, there is no NAT
+    NoNat,
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum FilteringBehavior {
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+}
+
+/// NAT types as labelled by the classic STUN (RFC 3489).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ClassicNatType {
+    OpenInternet,
+    SymmetricUdpFirewall,
+    FullCone,
+    RestrictedCone,
+    PortRestrictedCone,
+    Symmetric,
+}
+
+impl Display for ClassicNatType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            ClassicNatType::OpenInternet => "open internet",
+            ClassicNatType::SymmetricUdpFirewall => "symmetric UDP firewall",
+            ClassicNatType::FullCone => "full cone",
+            ClassicNatType::RestrictedCone => "restricted cone",
+            ClassicNatType::PortRestrictedCone => "port-restricted cone",
+            ClassicNatType::Symmetric => "symmetric",
+        };
+        write!(f, "{}", label)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct NatBehavior {
+    pub local_address: SocketAddr,
+    pub mapped_address: SocketAddr,
+    pub mapping: MappingBehavior,
+    pub filtering: FilteringBehavior,
+    pub hairpinning: bool,
+}
+
+impl NatBehavior {
+    pub fn classic_type(&self) -> ClassicNatType {
+        match (self.mapping, self.filtering) {
+            (MappingBehavior::NoNat, FilteringBehavior::EndpointIndependent) => {
+                ClassicNatType::OpenInternet
+            }
+            (MappingBehavior::NoNat, _) => ClassicNatType::SymmetricUdpFirewall,
+            (MappingBehavior::EndpointIndependent, FilteringBehavior::EndpointIndependent) => {
+                ClassicNatType::FullCone
+            }
+            (MappingBehavior::EndpointIndependent, FilteringBehavior::AddressDependent) => {
+                ClassicNatType::RestrictedCone
+            }
+            (MappingBehavior::EndpointIndependent, FilteringBehavior::AddressAndPortDependent) => {
+                ClassicNatType::PortRestrictedCone
+            }
+            _ => ClassicNatType::Symmetric,
+        }
+    }
+}
+
+impl Display for NatBehavior {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "local address: {}", self.local_address)?;
+        writeln!(f, "mapped address: {}", self.mapped_address)?;
+        writeln!(f, "mapping behavior: {:?}", self.mapping)?;
+        writeln!(f, "filtering behavior: {:?}", self.filtering)?;
+        writeln!(f, "hairpinning: {}", self.hairpinning)?;
+        write!(f, "classic NAT type: {}", self.classic_type())
+    }
+}
+
+/// How long a NAT keeps an idle UDP binding: still alive after `alive`, expired after
+/// `expired`, which is None when the binding outlived the longest interval tried.
+#[derive(Debug, Eq, PartialEq)]
+pub struct BindingLifetime {
+    pub alive: Duration,
+    pub expired: Option<Duration>,
+}
+
+impl Display for BindingLifetime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.expired {
+            Some(expired) => write!(
+                f,
+                "binding lifetime: between {:?} and {:?}",
+                self.alive, expired
+            ),
+            None => write!(f, "binding lifetime: more than {:?}", self.alive),
+        }
+    }
+}
+
+/// Chooses the idle intervals to probe a binding lifetime with: intervals are doubled until the
+/// binding expires, then the lifetime is binary searched down to the resolution.
+pub struct LifetimeSearch {
+    initial: Duration,
+    max: Duration,
+    resolution: Duration,
+    alive: Duration,
+    expired: Option<Duration>,
+}
+
+impl LifetimeSearch {
+    pub fn new(initial: Duration, max: Duration, resolution: Duration) -> LifetimeSearch {
+        LifetimeSearch {
+            initial,
+            max,
+            resolution,
+            alive: Duration::from_secs(0),
+            expired: None,
+        }
+    }
+
+    /// The next interval to probe, None when the search is done.
+    pub fn next_interval(&self) -> Option<Duration> {
+        match self.expired {
+            None if self.alive >= self.max => None,
+            None if self.alive < self.initial => Some(self.initial.min(self.max)),
+            None => Some((self.alive * 2).min(self.max)),
+            Some(expired) if expired - self.alive <= self.resolution => None,
+            Some(expired) => Some((self.alive + expired) / 2),
+        }
+    }
+
+    pub fn record(&mut self, interval: Duration, alive: bool) {
+        if alive {
+            self.alive = self.alive.max(interval);
+        } else {
+            self.expired = Some(
+                self.expired
+                    .map_or(interval, |expired| expired.min(interval)),
+            );
+        }
+    }
+
+    pub fn lifetime(&self) -> BindingLifetime {
+        BindingLifetime {
+            alive: self.alive,
+            expired: self.expired,
+        }
+    }
+}
+
+/// Runs the NAT behavior discovery tests (RFC 5780) against a discovery capable server.
+pub struct DiscoveryClient {
+    socket: UdpSocket,
+    server: SocketAddr,
+    // how long to wait for a response before retransmitting
+    timeout: Duration,
+    retransmissions: u32,
+}
+
+impl DiscoveryClient {
+    pub fn new(socket: UdpSocket, server: SocketAddr) -> DiscoveryClient {
+        DiscoveryClient {
+            socket,
+            server,
+            timeout: Duration::from_millis(500),
+            retransmissions: 3,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration, retransmissions: u32) -> DiscoveryClient {
+        self.timeout = timeout;
+        self.retransmissions = retransmissions;
+        self
+    }
+
+    /// Runs the mapping, filtering and hairpinning tests. The binding lifetime takes minutes to
+    /// measure, it is left to `binding_lifetime`.
+    pub async fn discover(&mut self) -> io::Result<NatBehavior> {
+        let local_address = self.local_address()?;
+
+        // test I: the mapped address and the alternate address of the server
+        let (response, _) = self.binding(self.server, vec![]).await?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::TimedOut, "no response from the server")
+        })?;
+        let mapped_address = mapped_address(&response).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "no mapped address in response")
+        })?;
+        let other_address = other_address(&response).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the server does not support NAT behavior discovery",
+            )
+        })?;
+
+        let mapping = if mapped_address == local_address {
+            MappingBehavior::NoNat
+        } else {
+            self.mapping_behavior(mapped_address, other_address).await?
+        };
+        let filtering = self.filtering_behavior(other_address).await?;
+        let hairpinning = self.hairpinning(mapped_address).await?;
+        Ok(NatBehavior {
+            local_address,
+            mapped_address,
+            mapping,
+            filtering,
+            hairpinning,
+        })
+    }
+
+    /// Measures how long the NAT keeps an idle binding (RFC 5780 section 4.6), the server must
+    /// support RESPONSE-PORT.
+    ///
+    /// For every interval a binding is created, left idle for the interval, and then a second
+    /// socket asks the server to respond to the port of the binding.
+    pub async fn binding_lifetime(
+        &mut self,
+        mut search: LifetimeSearch,
+    ) -> io::Result<BindingLifetime> {
+        let local_address = self.local_address()?;
+        let mut socket = UdpSocket::bind(SocketAddr::new(local_address.ip(), 0)).await?;
+        while let Some(interval) = search.next_interval() {
+            let mapped_address = self.mapped_address(self.server).await?;
+            delay_for(interval).await;
+            let request = Message {
+                message_class: MessageClass::Request,
+                message_method: MessageMethod::Binding,
+                transaction_id: TransactionID::random(),
+                attributes: vec![Attribute::ResponsePort(mapped_address.port())],
+            };
+            let alive = self.send_from(&mut socket, self.server, &request).await?;
+            search.record(interval, alive);
+        }
+        Ok(search.lifetime())
+    }
+
+    async fn mapping_behavior(
+        &mut self,
+        mapped_address: SocketAddr,
+        other_address: SocketAddr,
+    ) -> io::Result<MappingBehavior> {
+        // test II: the alternate IP address and the primary port
+        let alternate_ip = SocketAddr::new(other_address.ip(), self.server.port());
+        let mapped_address2 = self.mapped_address(alternate_ip).await?;
+        if mapped_address2 == mapped_address {
+            return Ok(MappingBehavior::EndpointIndependent);
+        }
+        // test III: the alternate IP address and port
+        let mapped_address3 = self.mapped_address(other_address).await?;
+        if mapped_address3 == mapped_address2 {
+            Ok(MappingBehavior::AddressDependent)
+        } else {
+            Ok(MappingBehavior::AddressAndPortDependent)
+        }
+    }
+
+    async fn filtering_behavior(
+        &mut self,
+        other_address: SocketAddr,
+    ) -> io::Result<FilteringBehavior> {
+        // test II: the response comes from the alternate IP address and port
+        let change_address = Attribute::ChangeRequest {
+            change_ip: true,
+            change_port: true,
+        };
+        if self.changed_binding(change_address, other_address).await? {
+            return Ok(FilteringBehavior::EndpointIndependent);
+        }
+        // test III: the response comes from the primary IP address and the alternate port
+        let change_port = Attribute::ChangeRequest {
+            change_ip: false,
+            change_port: true,
+        };
+        let alternate_port = SocketAddr::new(self.server.ip(), other_address.port());
+        if self.changed_binding(change_port, alternate_port).await? {
+            Ok(FilteringBehavior::AddressDependent)
+        } else {
+            Ok(FilteringBehavior::AddressAndPortDependent)
+        }
+    }
+
+    // whether the response to the CHANGE-REQUEST arrives. A response from anywhere but the
+    // expected address means the server ignored the CHANGE-REQUEST, which says nothing about
+    // the filtering.
+    async fn changed_binding(
+        &mut self,
+        change_request: Attribute,
+        expected: SocketAddr,
+    ) -> io::Result<bool> {
+        let (response, source) = match self.binding(self.server, vec![change_request]).await? {
+            Some(received) => received,
+            None => return Ok(false),
+        };
+        if source == expected || response_origin(&response) == Some(expected) {
+            Ok(true)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "response from {} instead of {}, the server ignored CHANGE-REQUEST",
+                    source, expected
+                ),
+            ))
+        }
+    }
+
+    // sends a request from a second socket to the mapped address of the first one
+    async fn hairpinning(&mut self, mapped_address: SocketAddr) -> io::Result<bool> {
+        let local_address = self.local_address()?;
+        let mut socket = UdpSocket::bind(SocketAddr::new(local_address.ip(), 0)).await?;
+        let request = Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::random(),
+            attributes: vec![],
+        };
+        self.send_from(&mut socket, mapped_address, &request).await
+    }
+
+    // sends a message from another socket, true when a message of the same transaction arrives
+    // at the client socket
+    async fn send_from(
+        &mut self,
+        socket: &mut UdpSocket,
+        destination: SocketAddr,
+        message: &Message,
+    ) -> io::Result<bool> {
+        let mut bytes_mut = BytesMut::new();
+        encode(message, &mut bytes_mut)?;
+        for _ in 0..=self.retransmissions {
+            socket.send_to(bytes_mut.bytes(), destination).await?;
+            if self.receive(&message.transaction_id).await?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn mapped_address(&mut self, server: SocketAddr) -> io::Result<SocketAddr> {
+        self.binding(server, vec![])
+            .await?
+            .and_then(|(response, _)| mapped_address(&response))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no mapped address from {}", server),
+                )
+            })
+    }
+
+    // sends a binding request, the response and where it came from, None when it times out
+    async fn binding(
+        &mut self,
+        server: SocketAddr,
+        attributes: Vec<Attribute>,
+    ) -> io::Result<Option<(Message, SocketAddr)>> {
+        let request = Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::random(),
+            attributes,
+        };
+        let mut bytes_mut = BytesMut::new();
+        encode(&request, &mut bytes_mut)?;
+        for _ in 0..=self.retransmissions {
+            self.socket.send_to(bytes_mut.bytes(), server).await?;
+            if let Some(response) = self.receive(&request.transaction_id).await? {
+                return Ok(Some(response));
+            }
+        }
+        Ok(None)
+    }
+
+    // waits for a message of the transaction, other datagrams are dropped
+    async fn receive(
+        &mut self,
+        transaction_id: &TransactionID,
+    ) -> io::Result<Option<(Message, SocketAddr)>> {
+        let socket = &mut self.socket;
+        let receiving = async {
+            let decoder = Decoder::new();
+            let mut buf = [0u8; 2048];
+            loop {
+                let (bytes_recv, source) = socket.recv_from(&mut buf).await?;
+                let mut bytes = Bytes::copy_from_slice(&buf[..bytes_recv]);
+                match decoder.decode(&mut bytes) {
+                    Ok(message) if &message.transaction_id == transaction_id => {
+                        return Ok((message, source));
+                    }
+                    _ => continue,
+                }
+            }
+        };
+        match timeout(self.timeout, receiving).await {
+            Ok(message) => message.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    // the local address used to reach the server, the unspecified address the socket may be
+    // bound to is resolved by the routing table
+    fn local_address(&self) -> io::Result<SocketAddr> {
+        let local_address = self.socket.local_addr()?;
+        if !local_address.ip().is_unspecified() {
+            return Ok(local_address);
+        }
+        let probe = std::net::UdpSocket::bind(SocketAddr::new(local_address.ip(), 0))?;
+        probe.connect(self.server)?;
+        Ok(SocketAddr::new(
+            probe.local_addr()?.ip(),
+            local_address.port(),
+        ))
+    }
+}
+
+fn mapped_address(message: &Message) -> Option<SocketAddr> {
+    message
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            Attribute::XorMappedAddress(address) => Some(address.to_socket_addr()),
+            _ => None,
+        })
+        .or_else(|| {
+            message
+                .attributes
+                .iter()
+                .find_map(|attribute| match attribute {
+                    Attribute::MappedAddress(address) => Some(address.to_socket_addr()),
+                    _ => None,
+                })
+        })
+}
+
+fn response_origin(message: &Message) -> Option<SocketAddr> {
+    message
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            Attribute::ResponseOrigin(address) => Some(address.to_socket_addr()),
+            _ => None,
+        })
+}
+
+fn other_address(message: &Message) -> Option<SocketAddr> {
+    message
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            Attribute::OtherAddress(address) => Some(address.to_socket_addr()),
+            _ => None,
+        })
+}
+
+fn encode(message: &Message, buf: &mut BytesMut) -> io::Result<usize> {
+    Encoder::new()
+        .encode(message, buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use bytes::{Bytes, BytesMut};
+    use tokio::net::UdpSocket;
+
+    use crate::codec::{Decoder, Encoder};
+    use crate::messages::*;
+    use crate::nat::client::{
+        BindingLifetime, ClassicNatType, DiscoveryClient, FilteringBehavior, LifetimeSearch,
+        MappingBehavior, NatBehavior,
+    };
+    use crate::nat::DiscoveryServer;
+
+    fn behavior(mapping: MappingBehavior, filtering: FilteringBehavior) -> NatBehavior {
+        let address: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        NatBehavior {
+            local_address: address,
+            mapped_address: address,
+            mapping,
+            filtering,
+            hairpinning: false,
+        }
+    }
+
+    #[test]
+    pub fn test_classic_nat_types() {
+        use FilteringBehavior as F;
+        use MappingBehavior as M;
+        let cases = [
+            (
+                M::NoNat,
+                F::EndpointIndependent,
+                ClassicNatType::OpenInternet,
+            ),
+            (
+                M::NoNat,
+                F::AddressDependent,
+                ClassicNatType::SymmetricUdpFirewall,
+            ),
+            (
+                M::EndpointIndependent,
+                F::EndpointIndependent,
+                ClassicNatType::FullCone,
+            ),
+            (
+                M::EndpointIndependent,
+                F::AddressDependent,
+                ClassicNatType::RestrictedCone,
+            ),
+            (
+                M::EndpointIndependent,
+                F::AddressAndPortDependent,
+                ClassicNatType::PortRestrictedCone,
+            ),
+            (
+                M::AddressDependent,
+                F::AddressDependent,
+                ClassicNatType::Symmetric,
+            ),
+            (
+                M::AddressAndPortDependent,
+                F::EndpointIndependent,
+                ClassicNatType::Symmetric,
+            ),
+        ];
+        for (mapping, filtering, expected) in cases.iter() {
+            assert_eq!(behavior(*mapping, *filtering).classic_type(), *expected);
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_discover_without_nat_over_loopback() {
+        let server = DiscoveryServer::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.2:0".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let primary = server.primary_address();
+        tokio::spawn(server.run());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local_address = socket.local_addr().unwrap();
+        let mut client = DiscoveryClient::new(socket, primary);
+        let behavior = client.discover().await.unwrap();
+        assert_eq!(behavior.mapped_address, local_address);
+        assert_eq!(behavior.mapping, MappingBehavior::NoNat);
+        assert_eq!(behavior.filtering, FilteringBehavior::EndpointIndependent);
+        assert!(behavior.hairpinning);
+        assert_eq!(behavior.classic_type(), ClassicNatType::OpenInternet);
+    }
+
+    #[tokio::test]
+    pub async fn test_discover_fails_without_alternate_address() {
+        // a plain STUN server does not answer with OTHER-ADDRESS
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_address = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (bytes_recv, source) = server.recv_from(&mut buf).await.unwrap();
+            let mut bytes = Bytes::copy_from_slice(&buf[..bytes_recv]);
+            let request = Decoder::new().decode(&mut bytes).unwrap();
+            let response = Message {
+                message_class: MessageClass::SuccessResponse,
+                message_method: MessageMethod::Binding,
+                transaction_id: request.transaction_id,
+                attributes: vec![Attribute::XorMappedAddress(Address::from(source))],
+            };
+            let mut bytes_mut = BytesMut::new();
+            Encoder::new().encode(&response, &mut bytes_mut).unwrap();
+            server.send_to(&bytes_mut, source).await.unwrap();
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = DiscoveryClient::new(socket, server_address);
+        let error = client.discover().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    pub async fn test_discover_fails_when_change_request_is_ignored() {
+        // a server with an alternate address which answers every request from its primary one
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_address = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (bytes_recv, source) = server.recv_from(&mut buf).await.unwrap();
+                let mut bytes = Bytes::copy_from_slice(&buf[..bytes_recv]);
+                let request = Decoder::new().decode(&mut bytes).unwrap();
+                let response = Message {
+                    message_class: MessageClass::SuccessResponse,
+                    message_method: MessageMethod::Binding,
+                    transaction_id: request.transaction_id,
+                    attributes: vec![
+                        Attribute::XorMappedAddress(Address::from(source)),
+                        Attribute::ResponseOrigin(Address::from(server_address)),
+                        Attribute::OtherAddress(Address::ipv4([127, 0, 0, 2], 3479)),
+                    ],
+                };
+                let mut bytes_mut = BytesMut::new();
+                Encoder::new().encode(&response, &mut bytes_mut).unwrap();
+                server.send_to(&bytes_mut, source).await.unwrap();
+            }
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = DiscoveryClient::new(socket, server_address);
+        let error = client.discover().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("CHANGE-REQUEST"), "{}", error);
+    }
+
+    #[test]
+    pub fn test_lifetime_search_doubles_then_bisects() {
+        let seconds = Duration::from_secs;
+        let mut search = LifetimeSearch::new(seconds(10), seconds(300), seconds(5));
+        let mut intervals = vec![];
+        while let Some(interval) = search.next_interval() {
+            intervals.push(interval.as_secs());
+            // the binding lives for 45 seconds
+            search.record(interval, interval < seconds(45));
+        }
+        assert_eq!(intervals, vec![10, 20, 40, 80, 60, 50, 45]);
+        assert_eq!(
+            search.lifetime(),
+            BindingLifetime {
+                alive: seconds(40),
+                expired: Some(seconds(45)),
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_lifetime_search_stops_at_max() {
+        let seconds = Duration::from_secs;
+        let mut search = LifetimeSearch::new(seconds(10), seconds(30), seconds(5));
+        let mut intervals = vec![];
+        while let Some(interval) = search.next_interval() {
+            intervals.push(interval.as_secs());
+            search.record(interval, true);
+        }
+        assert_eq!(intervals, vec![10, 20, 30]);
+        assert_eq!(search.lifetime().expired, None);
+    }
+
+    #[tokio::test]
+    pub async fn test_binding_lifetime_without_nat_over_loopback() {
+        let server = DiscoveryServer::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.2:0".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let primary = server.primary_address();
+        tokio::spawn(server.run());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = DiscoveryClient::new(socket, primary);
+        let millis = Duration::from_millis;
+        let search = LifetimeSearch::new(millis(10), millis(40), millis(10));
+        let lifetime = client.binding_lifetime(search).await.unwrap();
+        assert_eq!(
+            lifetime,
+            BindingLifetime {
+                alive: millis(40),
+                expired: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_binding_lifetime_of_expiring_binding() {
+        // a server that stops answering to RESPONSE-PORT once the binding of the port has been
+        // idle for 60ms, as if a NAT had dropped it
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_address = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut last_binding = Instant::now();
+            let mut buf = [0u8; 1024];
+            loop {
+                let (bytes_recv, source) = server.recv_from(&mut buf).await.unwrap();
+                let mut bytes = Bytes::copy_from_slice(&buf[..bytes_recv]);
+                let request = Decoder::new().decode(&mut bytes).unwrap();
+                let response_port = request.attributes.iter().find_map(|a| match a {
+                    Attribute::ResponsePort(port) => Some(*port),
+                    _ => None,
+                });
+                let destination = match response_port {
+                    None => {
+                        last_binding = Instant::now();
+                        source
+                    }
+                    Some(_) if last_binding.elapsed() >= Duration::from_millis(60) => continue,
+                    Some(port) => SocketAddr::new(source.ip(), port),
+                };
+                let response = Message {
+                    message_class: MessageClass::SuccessResponse,
+                    message_method: MessageMethod::Binding,
+                    transaction_id: request.transaction_id,
+                    attributes: vec![Attribute::XorMappedAddress(Address::from(source))],
+                };
+                let mut bytes_mut = BytesMut::new();
+                Encoder::new().encode(&response, &mut bytes_mut).unwrap();
+                server.send_to(&bytes_mut, destination).await.unwrap();
+            }
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let millis = Duration::from_millis;
+        let mut client = DiscoveryClient::new(socket, server_address).with_timeout(millis(50), 0);
+        let search = LifetimeSearch::new(millis(20), millis(500), millis(10));
+        let lifetime = client.binding_lifetime(search).await.unwrap();
+        let expired = lifetime.expired.unwrap();
+        assert!(lifetime.alive < millis(60), "{}", lifetime);
+        assert!(expired > millis(40), "{}", lifetime);
+        assert!(expired - lifetime.alive <= millis(10), "{}", lifetime);
+    }
+}
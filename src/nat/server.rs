@@ -0,0 +1,463 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::SocketAddr;
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::future::join_all;
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+
+use crate::codec::{Decoder, Encoder};
+use crate::messages::*;
+use crate::server::error_response;
+
+// bits of a socket index, flipping them selects the socket on the other port and/or IP
+const CHANGE_PORT: usize = 0b01;
+const CHANGE_IP: usize = 0b10;
+
+/// A datagram the discovery server failed to receive or to send. The server goes on serving.
+#[derive(Debug)]
+pub enum ServeError {
+    /// Receiving on the local address failed, e.g. for an ICMP port unreachable of an earlier
+    /// reply.
+    Receive { local: SocketAddr, error: io::Error },
+    /// Sending a reply to the destination failed.
+    Send {
+        destination: SocketAddr,
+        error: io::Error,
+    },
+}
+
+impl Display for ServeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ServeError::Receive { local, error } => {
+                write!(f, "receive on {} failed: {}", local, error)
+            }
+            ServeError::Send { destination, error } => {
+                write!(f, "send to {} failed: {}", destination, error)
+            }
+        }
+    }
+}
+
+/// A NAT behavior discovery server (RFC 5780).
+///
+/// It listens on every combination of a primary and an alternate IP address and port, the
+/// sockets are indexed so that the lower bit selects the alternate port and the higher bit
+/// selects the alternate IP address.
+pub struct DiscoveryServer {
+    addresses: [SocketAddr; 4],
+    sockets: Vec<UdpSocket>,
+    classic_stun: bool,
+    error_handler: Box<dyn Fn(&ServeError) + Send + Sync>,
+}
+
+impl DiscoveryServer {
+    /// Binds the four sockets, ports of 0 are resolved once on the primary IP address and then
+    /// reused on the alternate one.
+    pub async fn bind(primary: SocketAddr, alternate: SocketAddr) -> io::Result<DiscoveryServer> {
+        let primary_socket = UdpSocket::bind(primary).await?;
+        let primary_port = primary_socket.local_addr()?.port();
+        let alternate_port_socket =
+            UdpSocket::bind(SocketAddr::new(primary.ip(), alternate.port())).await?;
+        let alternate_port = alternate_port_socket.local_addr()?.port();
+        let alternate_ip_socket =
+            UdpSocket::bind(SocketAddr::new(alternate.ip(), primary_port)).await?;
+        let alternate_socket =
+            UdpSocket::bind(SocketAddr::new(alternate.ip(), alternate_port)).await?;
+
+        let sockets = vec![
+            primary_socket,
+            alternate_port_socket,
+            alternate_ip_socket,
+            alternate_socket,
+        ];
+        let mut addresses = [primary; 4];
+        for (address, socket) in addresses.iter_mut().zip(sockets.iter()) {
+            *address = socket.local_addr()?;
+        }
+        Ok(DiscoveryServer {
+            addresses,
+            sockets,
+            classic_stun: false,
+            error_handler: Box::new(|_| {}),
+        })
+    }
+
+    /// Also answers classic STUN (RFC 3489) requests, with MAPPED-ADDRESS, SOURCE-ADDRESS and
+    /// CHANGED-ADDRESS.
+    pub fn with_classic_stun(mut self, classic_stun: bool) -> DiscoveryServer {
+        self.classic_stun = classic_stun;
+        self
+    }
+
+    /// Calls the handler with each datagram the server fails to receive or to send, e.g. to log
+    /// it. Errors are ignored by default.
+    pub fn with_error_handler<F>(mut self, handler: F) -> DiscoveryServer
+    where
+        F: Fn(&ServeError) + Send + Sync + 'static,
+    {
+        self.error_handler = Box::new(handler);
+        self
+    }
+
+    pub fn primary_address(&self) -> SocketAddr {
+        self.addresses[0]
+    }
+
+    pub fn alternate_address(&self) -> SocketAddr {
+        self.addresses[CHANGE_IP | CHANGE_PORT]
+    }
+
+    /// Serves requests on all four sockets. Failing to receive or to send a datagram goes to the
+    /// error handler and does not stop the server, a client must not be able to take it down.
+    pub async fn run(self) -> io::Result<()> {
+        let addresses = self.addresses;
+        let classic_stun = self.classic_stun;
+        let error_handler = &self.error_handler;
+        let mut receivers = Vec::new();
+        let mut senders = Vec::new();
+        for socket in self.sockets {
+            let (receiver, sender) = socket.split();
+            receivers.push(receiver);
+            senders.push(sender);
+        }
+        let senders = Mutex::new(senders);
+        let serving = receivers.into_iter().enumerate().map(|(index, receiver)| {
+            let stun_decoder = if classic_stun {
+                Decoder::with_classic_stun()
+            } else {
+                Decoder::new()
+            };
+            serve(
+                index,
+                receiver,
+                stun_decoder,
+                &senders,
+                &addresses,
+                error_handler,
+            )
+        });
+        join_all(serving).await;
+        Ok(())
+    }
+}
+
+async fn serve(
+    index: usize,
+    mut receiver: RecvHalf,
+    stun_decoder: Decoder,
+    senders: &Mutex<Vec<SendHalf>>,
+    addresses: &[SocketAddr; 4],
+    error_handler: &(dyn Fn(&ServeError) + Send + Sync),
+) {
+    let stun_encoder = Encoder::new();
+    let mut buf = [0u8; 2048];
+    loop {
+        // e.g. an ICMP port unreachable of an earlier reply
+        let (bytes_recv, source) = match receiver.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(error) => {
+                error_handler(&ServeError::Receive {
+                    local: addresses[index],
+                    error,
+                });
+                continue;
+            }
+        };
+        let mut bytes = Bytes::copy_from_slice(&buf[..bytes_recv]);
+        // datagrams which are not STUN messages are dropped
+        let request = match stun_decoder.decode(&mut bytes) {
+            Ok(request) => request,
+            Err(_) => continue,
+        };
+        if let Some(reply) = discovery_reply(addresses, index, &request, bytes_recv, source) {
+            let mut reply_bytes = BytesMut::new();
+            if stun_encoder
+                .encode(&reply.message, &mut reply_bytes)
+                .is_err()
+            {
+                continue;
+            }
+            let mut senders = senders.lock().await;
+            let sent = senders[reply.from]
+                .send_to(reply_bytes.bytes(), &reply.to)
+                .await;
+            if let Err(error) = sent {
+                error_handler(&ServeError::Send {
+                    destination: reply.to,
+                    error,
+                });
+            }
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+struct DiscoveryReply {
+    // index of the socket to send the reply from
+    from: usize,
+    to: SocketAddr,
+    message: Message,
+}
+
+fn discovery_reply(
+    addresses: &[SocketAddr; 4],
+    received_on: usize,
+    request: &Message,
+    request_size: usize,
+    source: SocketAddr,
+) -> Option<DiscoveryReply> {
+    if request.message_class != MessageClass::Request
+        || request.message_method != MessageMethod::Binding
+    {
+        return None;
+    }
+
+    let mut from = received_on;
+    let mut to = source;
+    let mut padded = false;
+    for attribute in &request.attributes {
+        match attribute {
+            Attribute::ChangeRequest {
+                change_ip,
+                change_port,
+            } => {
+                if *change_ip {
+                    from ^= CHANGE_IP;
+                }
+                if *change_port {
+                    from ^= CHANGE_PORT;
+                }
+            }
+            // there is no port 0 to send to, the request is rejected where it came from
+            Attribute::ResponsePort(0) => {
+                return Some(DiscoveryReply {
+                    from: received_on,
+                    to: source,
+                    message: error_response(request, 400, "Bad Request"),
+                })
+            }
+            Attribute::ResponsePort(port) => to = SocketAddr::new(source.ip(), *port),
+            Attribute::Padding(_) => padded = true,
+            _ => {}
+        }
+    }
+
+    let origin = Address::from(addresses[from]);
+    let other = Address::from(addresses[received_on ^ (CHANGE_IP | CHANGE_PORT)]);
+    let attributes = if request.transaction_id.is_classic() {
+        vec![
+            Attribute::Software("stun-rs:0.1.0".to_owned()),
+            Attribute::MappedAddress(Address::from(source)),
+            Attribute::SourceAddress(origin),
+            Attribute::ChangedAddress(other),
+        ]
+    } else {
+        vec![
+            Attribute::Software("stun-rs:0.1.0".to_owned()),
+            Attribute::XorMappedAddress(Address::from(source)),
+            Attribute::ResponseOrigin(origin),
+            Attribute::OtherAddress(other),
+        ]
+    };
+    let mut message = Message {
+        message_class: MessageClass::SuccessResponse,
+        message_method: MessageMethod::Binding,
+        transaction_id: request.transaction_id,
+        attributes,
+    };
+    if padded {
+        // pad the response to the size of the request, so that both directions are
+        // fragmented the same way
+        let mut bytes = BytesMut::new();
+        let reply_size = Encoder::new().encode(&message, &mut bytes).ok()?;
+        let padding = request_size.saturating_sub(reply_size + 4) & !0x03;
+        message
+            .attributes
+            .push(Attribute::Padding(vec![0u8; padding]));
+    }
+    Some(DiscoveryReply { from, to, message })
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use bytes::{Buf, Bytes, BytesMut};
+    use tokio::net::UdpSocket;
+
+    use crate::codec::{Decoder, Encoder};
+    use crate::messages::*;
+    use crate::nat::server::{discovery_reply, DiscoveryServer};
+
+    fn addresses() -> [SocketAddr; 4] {
+        [
+            "127.0.0.1:3478".parse().unwrap(),
+            "127.0.0.1:3479".parse().unwrap(),
+            "127.0.0.2:3478".parse().unwrap(),
+            "127.0.0.2:3479".parse().unwrap(),
+        ]
+    }
+
+    fn binding_request(attributes: Vec<Attribute>) -> Message {
+        Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::from([7u8; 12]),
+            attributes,
+        }
+    }
+
+    #[test]
+    pub fn test_reply_from_changed_address() {
+        let addresses = addresses();
+        let source: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let request = binding_request(vec![Attribute::ChangeRequest {
+            change_ip: true,
+            change_port: false,
+        }]);
+        let reply = discovery_reply(&addresses, 1, &request, 28, source).unwrap();
+        assert_eq!(reply.from, 3);
+        assert_eq!(reply.to, source);
+        assert_eq!(reply.message.transaction_id, request.transaction_id);
+        assert!(reply
+            .message
+            .attributes
+            .contains(&Attribute::XorMappedAddress(Address::from(source))));
+        assert!(reply
+            .message
+            .attributes
+            .contains(&Attribute::ResponseOrigin(Address::from(addresses[3]))));
+        assert!(reply
+            .message
+            .attributes
+            .contains(&Attribute::OtherAddress(Address::from(addresses[2]))));
+    }
+
+    #[test]
+    pub fn test_reply_to_response_port() {
+        let source: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let request = binding_request(vec![Attribute::ResponsePort(6000)]);
+        let reply = discovery_reply(&addresses(), 0, &request, 28, source).unwrap();
+        assert_eq!(reply.from, 0);
+        assert_eq!(reply.to, "10.0.0.1:6000".parse().unwrap());
+    }
+
+    #[test]
+    pub fn test_reject_response_port_zero() {
+        let source: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let request = binding_request(vec![
+            Attribute::ChangeRequest {
+                change_ip: true,
+                change_port: true,
+            },
+            Attribute::ResponsePort(0),
+        ]);
+        let reply = discovery_reply(&addresses(), 0, &request, 36, source).unwrap();
+        assert_eq!((reply.from, reply.to), (0, source));
+        assert_eq!(reply.message.message_class, MessageClass::FailureResponse);
+        assert_eq!(
+            reply.message.attributes,
+            vec![Attribute::ErrorCode {
+                code: 400,
+                reason: "Bad Request".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    pub fn test_reply_is_padded_to_request_size() {
+        let source: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let request = binding_request(vec![Attribute::Padding(vec![0u8; 500])]);
+        let mut request_bytes = BytesMut::new();
+        let request_size = Encoder::new().encode(&request, &mut request_bytes).unwrap();
+        let reply = discovery_reply(&addresses(), 0, &request, request_size, source).unwrap();
+        let mut reply_bytes = BytesMut::new();
+        let reply_size = Encoder::new()
+            .encode(&reply.message, &mut reply_bytes)
+            .unwrap();
+        assert_eq!(reply_size, request_size);
+    }
+
+    #[test]
+    pub fn test_reply_to_classic_stun_request() {
+        let addresses = addresses();
+        let source: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let mut request = binding_request(vec![Attribute::ChangeRequest {
+            change_ip: false,
+            change_port: true,
+        }]);
+        request.transaction_id = TransactionID::classic([9u8; 16]);
+        let reply = discovery_reply(&addresses, 0, &request, 28, source).unwrap();
+        assert_eq!(reply.from, 1);
+        assert_eq!(reply.message.transaction_id, request.transaction_id);
+        assert_eq!(
+            reply.message.attributes[1..],
+            [
+                Attribute::MappedAddress(Address::from(source)),
+                Attribute::SourceAddress(Address::from(addresses[1])),
+                Attribute::ChangedAddress(Address::from(addresses[3])),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_ignore_non_binding_requests() {
+        let source: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let mut request = binding_request(vec![]);
+        request.message_class = MessageClass::Indication;
+        assert_eq!(discovery_reply(&addresses(), 0, &request, 20, source), None);
+    }
+
+    #[tokio::test]
+    pub async fn test_serve_change_request_over_loopback() {
+        let server = DiscoveryServer::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.2:0".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let primary = server.primary_address();
+        let alternate = server.alternate_address();
+        tokio::spawn(server.run());
+
+        let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 1024];
+
+        // RESPONSE-PORT 0 is rejected and the server keeps serving
+        let mut request = binding_request(vec![Attribute::ResponsePort(0)]);
+        request.transaction_id = TransactionID::from([8u8; 12]);
+        let mut bytes_mut = BytesMut::new();
+        Encoder::new().encode(&request, &mut bytes_mut).unwrap();
+        socket.send_to(bytes_mut.bytes(), primary).await.unwrap();
+        let (bytes_recv, address) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(address, primary);
+        let mut bytes = Bytes::copy_from_slice(&buf[..bytes_recv]);
+        let reply = Decoder::new().decode(&mut bytes).unwrap();
+        assert_eq!(reply.message_class, MessageClass::FailureResponse);
+        assert_eq!(reply.transaction_id, request.transaction_id);
+
+        let request = binding_request(vec![Attribute::ChangeRequest {
+            change_ip: true,
+            change_port: true,
+        }]);
+        let mut bytes_mut = BytesMut::new();
+        Encoder::new().encode(&request, &mut bytes_mut).unwrap();
+        socket.send_to(bytes_mut.bytes(), primary).await.unwrap();
+        let (bytes_recv, address) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(address, alternate);
+        let mut bytes = Bytes::copy_from_slice(&buf[..bytes_recv]);
+        let reply = Decoder::new().decode(&mut bytes).unwrap();
+        assert_eq!(reply.transaction_id, request.transaction_id);
+        assert!(reply
+            .attributes
+            .contains(&Attribute::ResponseOrigin(Address::from(alternate))));
+        assert!(reply
+            .attributes
+            .contains(&Attribute::OtherAddress(Address::from(alternate))));
+    }
+}
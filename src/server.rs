@@ -0,0 +1,315 @@
+// STUN request handling without any IO
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use bytes::BytesMut;
+
+use crate::codec::{Decoder, Encoder};
+use crate::messages::{Attribute, Message, MessageClass, MessageMethod};
+
+/// Where a datagram came from and arrived at, with its bytes for checks which need the encoded
+/// message such as MESSAGE-INTEGRITY.
+pub struct Context<'a> {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub data: &'a [u8],
+}
+
+/// Handles the requests and indications of one method, the returned message is sent back for
+/// requests.
+pub trait Handler: Send {
+    fn handle(&mut self, message: &Message, context: &Context) -> Option<Message>;
+}
+
+impl<F> Handler for F
+where
+    F: FnMut(&Message, &Context) -> Option<Message> + Send,
+{
+    fn handle(&mut self, message: &Message, context: &Context) -> Option<Message> {
+        self(message, context)
+    }
+}
+
+pub enum Action {
+    // pass the message on to the next middleware and the handler
+    Continue,
+    // answer with this message instead of the handler, e.g. 401 from authentication
+    Respond(Message),
+    // neither answer nor handle the message, e.g. over a rate limit
+    Drop,
+}
+
+/// Hooks around the handlers, e.g. authentication, logging or rate limiting.
+pub trait Middleware: Send {
+    fn before(&mut self, _message: &Message, _context: &Context) -> Action {
+        Action::Continue
+    }
+
+    fn after(&mut self, _message: &Message, _response: &mut Message, _context: &Context) {}
+}
+
+/// Decodes datagrams, runs them through the middleware and the handler registered for their
+/// method and encodes the responses.
+///
+/// Requests of a method without a handler are answered with 400, datagrams which cannot be
+/// decoded and responses are dropped.
+#[derive(Default)]
+pub struct ServerEngine {
+    decoder: Decoder,
+    // keyed by the method value, so that custom methods are found whatever variant they use
+    handlers: HashMap<u16, Box<dyn Handler>>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl ServerEngine {
+    pub fn new() -> ServerEngine {
+        ServerEngine::default()
+    }
+
+    pub fn with_decoder(mut self, decoder: Decoder) -> ServerEngine {
+        self.decoder = decoder;
+        self
+    }
+
+    pub fn with_handler<H: Handler + 'static>(
+        mut self,
+        method: MessageMethod,
+        handler: H,
+    ) -> ServerEngine {
+        self.handlers.insert(method.value(), Box::new(handler));
+        self
+    }
+
+    /// Middleware runs in the order it is added, `before` hooks first to last and `after`
+    /// hooks last to first.
+    pub fn with_middleware<M: Middleware + 'static>(mut self, middleware: M) -> ServerEngine {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Handles a datagram received from `source` on `destination`, returns the encoded response
+    /// to send back to `source`.
+    pub fn handle_datagram(
+        &mut self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        let message = self.decoder.decode(&mut &data[..]).ok()?;
+        let context = Context {
+            source,
+            destination,
+            data,
+        };
+        let response = self.handle_message(&message, &context)?;
+        let mut bytes = BytesMut::new();
+        // a response the handlers built with attributes which cannot be encoded is dropped
+        Encoder::new().encode(&response, &mut bytes).ok()?;
+        Some(bytes.to_vec())
+    }
+
+    /// Handles a decoded message, returns the response to send back.
+    pub fn handle_message(&mut self, message: &Message, context: &Context) -> Option<Message> {
+        let is_request = match message.message_class {
+            MessageClass::Request => true,
+            MessageClass::Indication => false,
+            MessageClass::SuccessResponse | MessageClass::FailureResponse => return None,
+        };
+
+        let mut ran = 0;
+        let mut response = None;
+        for middleware in self.middleware.iter_mut() {
+            ran += 1;
+            match middleware.before(message, context) {
+                Action::Continue => {}
+                Action::Respond(message) => {
+                    response = Some(message);
+                    break;
+                }
+                Action::Drop => return None,
+            }
+        }
+        if response.is_none() {
+            response = match self.handlers.get_mut(&message.message_method.value()) {
+                Some(handler) => handler.handle(message, context),
+                None => Some(error_response(message, 400, "Bad Request")),
+            };
+        }
+
+        let mut response = response.filter(|_| is_request)?;
+        for middleware in self.middleware[..ran].iter_mut().rev() {
+            middleware.after(message, &mut response, context);
+        }
+        Some(response)
+    }
+}
+
+/// An error response to the request, with the same method and transaction ID.
+pub fn error_response(request: &Message, code: u32, reason: &str) -> Message {
+    Message {
+        message_class: MessageClass::FailureResponse,
+        message_method: request.message_method,
+        transaction_id: request.transaction_id,
+        attributes: vec![Attribute::ErrorCode {
+            code,
+            reason: reason.to_owned(),
+        }],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use bytes::BytesMut;
+
+    use crate::codec::{Decoder, Encoder};
+    use crate::messages::*;
+    use crate::server::*;
+
+    fn encode(class: MessageClass, method: MessageMethod) -> Vec<u8> {
+        let message = Message {
+            message_class: class,
+            message_method: method,
+            transaction_id: TransactionID::from([9u8; 12]),
+            attributes: vec![],
+        };
+        let mut bytes = BytesMut::new();
+        Encoder::new().encode(&message, &mut bytes).unwrap();
+        bytes.to_vec()
+    }
+
+    fn decode(data: Option<Vec<u8>>) -> Message {
+        Decoder::new().decode(&mut &data.unwrap()[..]).unwrap()
+    }
+
+    fn binding(request: &Message, context: &Context) -> Option<Message> {
+        Some(Message {
+            message_class: MessageClass::SuccessResponse,
+            message_method: MessageMethod::Binding,
+            transaction_id: request.transaction_id,
+            attributes: vec![Attribute::XorMappedAddress(Address::from(context.source))],
+        })
+    }
+
+    fn addresses() -> (SocketAddr, SocketAddr) {
+        (
+            "192.0.2.1:4000".parse().unwrap(),
+            "192.0.2.2:3478".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    pub fn test_dispatch_by_method() {
+        let (source, destination) = addresses();
+        let mut engine = ServerEngine::new()
+            .with_handler(MessageMethod::Binding, binding)
+            .with_handler(
+                MessageMethod::Custom(0x0F0),
+                |request: &Message, _: &Context| Some(error_response(request, 600, "Custom")),
+            );
+
+        let request = encode(MessageClass::Request, MessageMethod::Binding);
+        let response = decode(engine.handle_datagram(source, destination, &request));
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+        assert_eq!(response.transaction_id, TransactionID::from([9u8; 12]));
+        assert_eq!(
+            response.attributes,
+            vec![Attribute::XorMappedAddress(Address::from(source))]
+        );
+
+        let request = encode(MessageClass::Request, MessageMethod::Custom(0x0F0));
+        let response = decode(engine.handle_datagram(source, destination, &request));
+        assert_eq!(response.message_method, MessageMethod::Custom(0x0F0));
+        assert_eq!(
+            response.attributes,
+            vec![Attribute::ErrorCode {
+                code: 600,
+                reason: "Custom".to_owned()
+            }]
+        );
+
+        // indications are handled but not answered, datagrams which are not STUN are dropped
+        let indication = encode(MessageClass::Indication, MessageMethod::Binding);
+        assert_eq!(
+            engine.handle_datagram(source, destination, &indication),
+            None
+        );
+        assert_eq!(
+            engine.handle_datagram(source, destination, b"garbage"),
+            None
+        );
+    }
+
+    #[test]
+    pub fn test_unsupported_method_is_bad_request() {
+        let (source, destination) = addresses();
+        let mut engine = ServerEngine::new().with_handler(MessageMethod::Binding, binding);
+        let request = encode(MessageClass::Request, MessageMethod::Connect);
+        let response = decode(engine.handle_datagram(source, destination, &request));
+        assert_eq!(response.message_class, MessageClass::FailureResponse);
+        assert_eq!(response.message_method, MessageMethod::Connect);
+        assert_eq!(response.transaction_id, TransactionID::from([9u8; 12]));
+        assert_eq!(
+            response.attributes,
+            vec![Attribute::ErrorCode {
+                code: 400,
+                reason: "Bad Request".to_owned()
+            }]
+        );
+    }
+
+    // rejects every other request and tags the responses it let through
+    struct EveryOther {
+        seen: usize,
+    }
+
+    impl Middleware for EveryOther {
+        fn before(&mut self, message: &Message, _: &Context) -> Action {
+            self.seen += 1;
+            if self.seen.is_multiple_of(2) {
+                Action::Respond(error_response(message, 401, "Unauthorized"))
+            } else {
+                Action::Continue
+            }
+        }
+
+        fn after(&mut self, _: &Message, response: &mut Message, _: &Context) {
+            response
+                .attributes
+                .push(Attribute::Software("every-other".to_owned()));
+        }
+    }
+
+    struct DropAll;
+
+    impl Middleware for DropAll {
+        fn before(&mut self, _: &Message, _: &Context) -> Action {
+            Action::Drop
+        }
+    }
+
+    #[test]
+    pub fn test_middleware_hooks() {
+        let (source, destination) = addresses();
+        let mut engine = ServerEngine::new()
+            .with_handler(MessageMethod::Binding, binding)
+            .with_middleware(EveryOther { seen: 0 });
+        let request = encode(MessageClass::Request, MessageMethod::Binding);
+
+        let response = decode(engine.handle_datagram(source, destination, &request));
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+        assert_eq!(
+            response.attributes[1],
+            Attribute::Software("every-other".to_owned())
+        );
+        let response = decode(engine.handle_datagram(source, destination, &request));
+        assert_eq!(response.message_class, MessageClass::FailureResponse);
+        assert_eq!(response.attributes.len(), 2);
+
+        let mut engine = ServerEngine::new()
+            .with_handler(MessageMethod::Binding, binding)
+            .with_middleware(DropAll);
+        assert_eq!(engine.handle_datagram(source, destination, &request), None);
+    }
+}
@@ -0,0 +1,959 @@
+// deterministic in-memory network with NAT emulation, to drive the sans-IO components in tests
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::nat::{FilteringBehavior, MappingBehavior};
+
+/// A UDP datagram in flight or delivered.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Datagram {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+/// Delay, jitter and loss of the datagrams a host sends, jitter reorders datagrams.
+#[derive(Debug, Clone, Copy)]
+pub struct Link {
+    pub delay: Duration,
+    pub jitter: Duration,
+    // probability in [0, 1] that a datagram is lost
+    pub loss: f64,
+}
+
+impl Default for Link {
+    fn default() -> Link {
+        Link {
+            delay: Duration::from_millis(10),
+            jitter: Duration::from_millis(0),
+            loss: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NatConfig {
+    pub mapping: MappingBehavior,
+    pub filtering: FilteringBehavior,
+    pub hairpinning: bool,
+    // mappings expire when no datagram goes out through them for this long
+    pub mapping_lifetime: Option<Duration>,
+}
+
+impl NatConfig {
+    pub fn full_cone() -> NatConfig {
+        NatConfig::new(
+            MappingBehavior::EndpointIndependent,
+            FilteringBehavior::EndpointIndependent,
+        )
+    }
+
+    pub fn restricted_cone() -> NatConfig {
+        NatConfig::new(
+            MappingBehavior::EndpointIndependent,
+            FilteringBehavior::AddressDependent,
+        )
+    }
+
+    pub fn port_restricted_cone() -> NatConfig {
+        NatConfig::new(
+            MappingBehavior::EndpointIndependent,
+            FilteringBehavior::AddressAndPortDependent,
+        )
+    }
+
+    pub fn symmetric() -> NatConfig {
+        NatConfig::new(
+            MappingBehavior::AddressAndPortDependent,
+            FilteringBehavior::AddressAndPortDependent,
+        )
+    }
+
+    pub fn new(mapping: MappingBehavior, filtering: FilteringBehavior) -> NatConfig {
+        NatConfig {
+            mapping,
+            filtering,
+            hairpinning: false,
+            mapping_lifetime: None,
+        }
+    }
+
+    pub fn with_hairpinning(mut self, hairpinning: bool) -> NatConfig {
+        self.hairpinning = hairpinning;
+        self
+    }
+
+    pub fn with_mapping_lifetime(mut self, lifetime: Duration) -> NatConfig {
+        self.mapping_lifetime = Some(lifetime);
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Mapping {
+    internal: SocketAddr,
+    // the part of the destination the mapping is specific to, per the mapping behavior
+    remote: Option<SocketAddr>,
+    external: SocketAddr,
+    // destinations the internal endpoint sent to, which the filtering checks against
+    contacted: HashSet<SocketAddr>,
+    last_outbound: Instant,
+}
+
+#[derive(Debug)]
+struct Nat {
+    config: NatConfig,
+    public_ip: IpAddr,
+    mappings: Vec<Mapping>,
+    next_port: u16,
+}
+
+impl Nat {
+    // the external address of an outbound datagram, creating or refreshing the mapping
+    fn outbound(
+        &mut self,
+        now: Instant,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> SocketAddr {
+        self.expire(now);
+        let remote = match self.config.mapping {
+            MappingBehavior::NoNat | MappingBehavior::EndpointIndependent => None,
+            MappingBehavior::AddressDependent => Some(SocketAddr::new(destination.ip(), 0)),
+            MappingBehavior::AddressAndPortDependent => Some(destination),
+        };
+        let index = match self
+            .mappings
+            .iter()
+            .position(|mapping| mapping.internal == source && mapping.remote == remote)
+        {
+            Some(index) => index,
+            None => {
+                let external = match self.config.mapping {
+                    MappingBehavior::NoNat => source,
+                    _ => {
+                        self.next_port = self.next_port.wrapping_add(1).max(1024);
+                        SocketAddr::new(self.public_ip, self.next_port)
+                    }
+                };
+                self.mappings.push(Mapping {
+                    internal: source,
+                    remote,
+                    external,
+                    contacted: HashSet::new(),
+                    last_outbound: now,
+                });
+                self.mappings.len() - 1
+            }
+        };
+        let mapping = &mut self.mappings[index];
+        mapping.contacted.insert(destination);
+        mapping.last_outbound = now;
+        mapping.external
+    }
+
+    // the internal address of an inbound datagram, none when no mapping lets it through
+    fn inbound(
+        &mut self,
+        now: Instant,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> Option<SocketAddr> {
+        self.expire(now);
+        let filtering = self.config.filtering;
+        self.mappings
+            .iter()
+            .filter(|mapping| mapping.external == destination)
+            .find(|mapping| match filtering {
+                FilteringBehavior::EndpointIndependent => true,
+                FilteringBehavior::AddressDependent => mapping
+                    .contacted
+                    .iter()
+                    .any(|contacted| contacted.ip() == source.ip()),
+                FilteringBehavior::AddressAndPortDependent => mapping.contacted.contains(&source),
+            })
+            .map(|mapping| mapping.internal)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        if let Some(lifetime) = self.config.mapping_lifetime {
+            self.mappings
+                .retain(|mapping| now.duration_since(mapping.last_outbound) < lifetime);
+        }
+    }
+}
+
+/// A NAT added to the network.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct NatId(usize);
+
+/// A simulated network on a virtual clock: hosts on the public internet or behind NATs, which
+/// exchange datagrams over lossy links.
+///
+/// Hosts are IP addresses, any port of a host can send and receive. Every random decision comes
+/// from the seed, so a run is reproducible.
+pub struct Network {
+    now: Instant,
+    rng: StdRng,
+    default_link: Link,
+    links: HashMap<IpAddr, Link>,
+    public_hosts: HashSet<IpAddr>,
+    // private host to the NAT it is behind
+    private_hosts: HashMap<IpAddr, usize>,
+    nats: Vec<Nat>,
+    // datagrams in flight, by arrival time and send order
+    in_flight: BTreeMap<(Instant, u64), Datagram>,
+    sent: u64,
+    delivered: HashMap<SocketAddr, VecDeque<Datagram>>,
+}
+
+impl Network {
+    pub fn new(seed: u64) -> Network {
+        Network {
+            now: Instant::now(),
+            rng: StdRng::seed_from_u64(seed),
+            default_link: Link::default(),
+            links: HashMap::new(),
+            public_hosts: HashSet::new(),
+            private_hosts: HashMap::new(),
+            nats: vec![],
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            delivered: HashMap::new(),
+        }
+    }
+
+    /// The link of the hosts without their own.
+    pub fn with_link(mut self, link: Link) -> Network {
+        self.default_link = link;
+        self
+    }
+
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    pub fn add_public_host(&mut self, ip: IpAddr) {
+        self.public_hosts.insert(ip);
+    }
+
+    /// Adds a NAT with the public IP address its mappings use.
+    pub fn add_nat(&mut self, public_ip: IpAddr, config: NatConfig) -> NatId {
+        self.nats.push(Nat {
+            config,
+            public_ip,
+            mappings: vec![],
+            next_port: 49151,
+        });
+        NatId(self.nats.len() - 1)
+    }
+
+    pub fn add_private_host(&mut self, ip: IpAddr, nat: NatId) {
+        self.private_hosts.insert(ip, nat.0);
+    }
+
+    pub fn set_link(&mut self, host: IpAddr, link: Link) {
+        self.links.insert(host, link);
+    }
+
+    /// Sends a datagram from a host, it is translated by the NATs on the way and dropped when
+    /// lost, filtered or unroutable.
+    pub fn send(&mut self, datagram: Datagram) {
+        let link = *self
+            .links
+            .get(&datagram.source.ip())
+            .unwrap_or(&self.default_link);
+        if link.loss > 0.0 && self.rng.gen::<f64>() < link.loss {
+            return;
+        }
+        let jitter = link.jitter.as_micros() as u64;
+        let delay = link.delay
+            + Duration::from_micros(if jitter > 0 {
+                self.rng.gen_range(0, jitter + 1)
+            } else {
+                0
+            });
+        if let Some(datagram) = self.route(datagram) {
+            self.sent += 1;
+            self.in_flight
+                .insert((self.now + delay, self.sent), datagram);
+        }
+    }
+
+    /// When the next datagram in flight arrives.
+    pub fn next_arrival(&self) -> Option<Instant> {
+        self.in_flight.keys().next().map(|(arrival, _)| *arrival)
+    }
+
+    /// Moves the clock forward, delivering the datagrams arriving until then.
+    pub fn advance_to(&mut self, now: Instant) {
+        if now <= self.now {
+            return;
+        }
+        self.now = now;
+        while let Some(key) = self.in_flight.keys().next().copied() {
+            if key.0 > now {
+                break;
+            }
+            let datagram = self.in_flight.remove(&key).unwrap();
+            self.delivered
+                .entry(datagram.destination)
+                .or_default()
+                .push_back(datagram);
+        }
+    }
+
+    pub fn advance(&mut self, duration: Duration) {
+        self.advance_to(self.now + duration);
+    }
+
+    /// The next datagram delivered to the address, with the source as seen by the receiver.
+    pub fn receive(&mut self, address: SocketAddr) -> Option<Datagram> {
+        self.delivered.get_mut(&address)?.pop_front()
+    }
+
+    fn route(&mut self, datagram: Datagram) -> Option<Datagram> {
+        let now = self.now;
+        let Datagram {
+            mut source,
+            mut destination,
+            data,
+        } = datagram;
+        let source_nat = self.private_hosts.get(&source.ip()).copied();
+        let destination_nat = self.private_hosts.get(&destination.ip()).copied();
+        if let Some(nat) = source_nat {
+            // hosts behind the same NAT reach each other directly
+            if destination_nat == Some(nat) {
+                return Some(Datagram {
+                    source,
+                    destination,
+                    data,
+                });
+            }
+            source = self.nats[nat].outbound(now, source, destination);
+            if destination.ip() == self.nats[nat].public_ip && !self.nats[nat].config.hairpinning {
+                return None;
+            }
+        } else if !self.public_hosts.contains(&source.ip()) {
+            return None;
+        }
+
+        if self.public_hosts.contains(&destination.ip()) {
+            return Some(Datagram {
+                source,
+                destination,
+                data,
+            });
+        }
+        let nat = self
+            .nats
+            .iter()
+            .position(|nat| nat.public_ip == destination.ip())?;
+        destination = self.nats[nat].inbound(now, source, destination)?;
+        Some(Datagram {
+            source,
+            destination,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, SocketAddr};
+    use std::time::Duration;
+
+    use crate::ice::{CandidateType, IceAgent, IceEvent, IceMode, IceRole, IceState};
+    use crate::messages::*;
+    use crate::server::{Context, ServerEngine};
+    use crate::sim::*;
+    use crate::transaction::{ClientTransaction, TransactionResult};
+    use crate::turn::{
+        Connection, FiveTuple, Transport, TurnClient, TurnClientEvent, TurnServer, TurnServerEvent,
+    };
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn send(network: &mut Network, source: &str, destination: &str) {
+        network.send(Datagram {
+            source: addr(source),
+            destination: addr(destination),
+            data: vec![1, 2, 3],
+        });
+    }
+
+    // the source the receiver sees, none when the datagram does not arrive
+    fn observed(network: &mut Network, destination: &str) -> Option<SocketAddr> {
+        network.advance(Duration::from_secs(1));
+        network
+            .receive(addr(destination))
+            .map(|datagram| datagram.source)
+    }
+
+    fn nat_network(config: NatConfig) -> Network {
+        let mut network = Network::new(1);
+        let nat = network.add_nat(ip("203.0.113.1"), config);
+        network.add_private_host(ip("10.0.0.1"), nat);
+        network.add_private_host(ip("10.0.0.2"), nat);
+        network.add_public_host(ip("192.0.2.1"));
+        network.add_public_host(ip("192.0.2.2"));
+        network
+    }
+
+    #[test]
+    pub fn test_mapping_behaviors() {
+        for (config, same_ip_other_port, other_ip) in &[
+            (NatConfig::full_cone(), true, true),
+            (
+                NatConfig::new(
+                    MappingBehavior::AddressDependent,
+                    FilteringBehavior::EndpointIndependent,
+                ),
+                true,
+                false,
+            ),
+            (NatConfig::symmetric(), false, false),
+        ] {
+            let mut network = nat_network(*config);
+            send(&mut network, "10.0.0.1:5000", "192.0.2.1:3478");
+            let mapped = observed(&mut network, "192.0.2.1:3478").unwrap();
+            assert_eq!(mapped.ip(), ip("203.0.113.1"));
+            send(&mut network, "10.0.0.1:5000", "192.0.2.1:3479");
+            let mapped2 = observed(&mut network, "192.0.2.1:3479").unwrap();
+            assert_eq!(mapped == mapped2, *same_ip_other_port);
+            send(&mut network, "10.0.0.1:5000", "192.0.2.2:3478");
+            let mapped3 = observed(&mut network, "192.0.2.2:3478").unwrap();
+            assert_eq!(mapped == mapped3, *other_ip);
+        }
+    }
+
+    #[test]
+    pub fn test_filtering_behaviors() {
+        for (config, same_ip_other_port, other_ip) in &[
+            (NatConfig::full_cone(), true, true),
+            (NatConfig::restricted_cone(), true, false),
+            (NatConfig::port_restricted_cone(), false, false),
+        ] {
+            let mut network = nat_network(*config);
+            send(&mut network, "10.0.0.1:5000", "192.0.2.1:3478");
+            let mapped = observed(&mut network, "192.0.2.1:3478")
+                .unwrap()
+                .to_string();
+            send(&mut network, "192.0.2.1:3478", &mapped);
+            assert!(observed(&mut network, "10.0.0.1:5000").is_some());
+            send(&mut network, "192.0.2.1:3479", &mapped);
+            assert_eq!(
+                observed(&mut network, "10.0.0.1:5000").is_some(),
+                *same_ip_other_port
+            );
+            send(&mut network, "192.0.2.2:3478", &mapped);
+            assert_eq!(observed(&mut network, "10.0.0.1:5000").is_some(), *other_ip);
+        }
+    }
+
+    #[test]
+    pub fn test_hairpinning_and_mapping_lifetime() {
+        for hairpinning in &[true, false] {
+            let config = NatConfig::full_cone()
+                .with_hairpinning(*hairpinning)
+                .with_mapping_lifetime(Duration::from_secs(30));
+            let mut network = nat_network(config);
+            send(&mut network, "10.0.0.1:5000", "192.0.2.1:3478");
+            let mapped = observed(&mut network, "192.0.2.1:3478")
+                .unwrap()
+                .to_string();
+            send(&mut network, "10.0.0.2:6000", &mapped);
+            assert_eq!(
+                observed(&mut network, "10.0.0.1:5000").is_some(),
+                *hairpinning
+            );
+
+            network.advance(Duration::from_secs(30));
+            send(&mut network, "192.0.2.1:3478", &mapped);
+            assert_eq!(observed(&mut network, "10.0.0.1:5000"), None);
+        }
+    }
+
+    fn binding(request: &Message, context: &Context) -> Option<Message> {
+        Some(Message {
+            message_class: MessageClass::SuccessResponse,
+            message_method: MessageMethod::Binding,
+            transaction_id: request.transaction_id,
+            attributes: vec![Attribute::XorMappedAddress(Address::from(context.source))],
+        })
+    }
+
+    // a Binding transaction from behind a NAT to a server engine over a lossy link
+    fn lossy_binding(seed: u64) -> (Option<SocketAddr>, Duration) {
+        let mut network = nat_network(NatConfig::port_restricted_cone()).with_link(Link {
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(30),
+            loss: 0.5,
+        });
+        let (client, server) = (addr("10.0.0.1:5000"), addr("192.0.2.1:3478"));
+        let mut engine = ServerEngine::new().with_handler(MessageMethod::Binding, binding);
+        let request = Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::from([seed as u8; 12]),
+            attributes: vec![],
+        };
+        let start = network.now();
+        let mut transaction = ClientTransaction::new(start, &request, server).unwrap();
+        loop {
+            while let Some(data) = transaction.poll_transmit() {
+                network.send(Datagram {
+                    source: client,
+                    destination: server,
+                    data,
+                });
+            }
+            while let Some(datagram) = network.receive(server) {
+                if let Some(data) = engine.handle_datagram(datagram.source, server, &datagram.data)
+                {
+                    network.send(Datagram {
+                        source: server,
+                        destination: datagram.source,
+                        data,
+                    });
+                }
+            }
+            while let Some(datagram) = network.receive(client) {
+                transaction.handle_input(network.now(), datagram.source, &datagram.data);
+            }
+            match transaction.poll_result() {
+                Some(TransactionResult::Response(response)) => {
+                    let mapped = match &response.attributes[0] {
+                        Attribute::XorMappedAddress(address) => address.to_socket_addr(),
+                        _ => panic!("unexpected response {:?}", response),
+                    };
+                    return (Some(mapped), network.now() - start);
+                }
+                Some(TransactionResult::TimedOut) => return (None, network.now() - start),
+                None => {}
+            }
+            let next = [transaction.poll_timeout(), network.next_arrival()]
+                .iter()
+                .flatten()
+                .min()
+                .copied()
+                .unwrap();
+            network.advance_to(next);
+            transaction.handle_timeout(network.now());
+        }
+    }
+
+    #[test]
+    pub fn test_transaction_over_lossy_link_is_reproducible() {
+        let (mapped, elapsed) = lossy_binding(7);
+        assert_eq!(mapped.unwrap().ip(), ip("203.0.113.1"));
+        assert_eq!(lossy_binding(7), (mapped, elapsed));
+    }
+
+    // two full ICE agents, each behind a NAT, with a STUN and a TURN server on the internet
+    struct IceNetwork {
+        network: Network,
+        engine: ServerEngine,
+        turn: TurnServer,
+        // the relayed addresses the TURN server allocated
+        relayed: Vec<SocketAddr>,
+        a: IceAgent,
+        b: IceAgent,
+        host_a: SocketAddr,
+        host_b: SocketAddr,
+        // how many local candidates of each agent were signaled
+        signaled: (usize, usize),
+        // application data a received through the relay, and b on its host candidate
+        a_data: Vec<Vec<u8>>,
+        b_data: Vec<Vec<u8>>,
+    }
+
+    const STUN_SERVER: &str = "192.0.2.1:3478";
+    const TURN_SERVER: &str = "192.0.2.2:3478";
+
+    impl IceNetwork {
+        // with `relay`, the agent behind the first NAT also gathers a relayed candidate
+        fn new(a_nat: NatConfig, b_nat: NatConfig, relay: bool) -> IceNetwork {
+            let mut network = Network::new(3);
+            let nat_a = network.add_nat(ip("203.0.113.1"), a_nat);
+            let nat_b = network.add_nat(ip("198.51.100.1"), b_nat);
+            network.add_private_host(ip("10.0.0.1"), nat_a);
+            network.add_private_host(ip("10.1.0.1"), nat_b);
+            network.add_public_host(ip("192.0.2.1"));
+            network.add_public_host(ip("192.0.2.2"));
+            let engine = ServerEngine::new().with_handler(MessageMethod::Binding, binding);
+            let turn = TurnServer::new("example.org")
+                .with_user("alice", "secret")
+                .with_relay_ip(ip("192.0.2.2"));
+
+            let (host_a, host_b) = (addr("10.0.0.1:5000"), addr("10.1.0.1:5000"));
+            let mut a = IceAgent::new(IceMode::Full, IceRole::Controlling);
+            let mut b = IceAgent::new(IceMode::Full, IceRole::Controlled);
+            a.add_host_candidate(host_a);
+            b.add_host_candidate(host_b);
+            a.gather_server_reflexive(network.now(), addr(STUN_SERVER));
+            b.gather_server_reflexive(network.now(), addr(STUN_SERVER));
+            if relay {
+                let client = TurnClient::new(addr(TURN_SERVER), "alice", "secret");
+                a.gather_relayed(network.now(), host_a, client);
+            }
+            a.set_remote_credentials(b.local_credentials().clone());
+            b.set_remote_credentials(a.local_credentials().clone());
+            IceNetwork {
+                network,
+                engine,
+                turn,
+                relayed: vec![],
+                a,
+                b,
+                host_a,
+                host_b,
+                signaled: (0, 0),
+                a_data: vec![],
+                b_data: vec![],
+            }
+        }
+
+        // moves the datagrams between the agents and the servers for the duration
+        fn run(&mut self, duration: Duration) {
+            let deadline = self.network.now() + duration;
+            let (stun_server, turn_server) = (addr(STUN_SERVER), addr(TURN_SERVER));
+            while self.network.now() < deadline {
+                let now = self.network.now();
+                // trickle the candidates gathered so far, as signaling would
+                let a_candidates = self.a.local_candidates()[self.signaled.0..].to_vec();
+                let b_candidates = self.b.local_candidates()[self.signaled.1..].to_vec();
+                self.signaled = (
+                    self.a.local_candidates().len(),
+                    self.b.local_candidates().len(),
+                );
+                for candidate in a_candidates {
+                    self.b.add_remote_candidate(candidate);
+                }
+                for candidate in b_candidates {
+                    self.a.add_remote_candidate(candidate);
+                }
+
+                self.turn.handle_timeout(now);
+                for agent in [&mut self.a, &mut self.b].iter_mut() {
+                    agent.handle_timeout(now);
+                    while let Some(transmit) = agent.poll_transmit() {
+                        self.network.send(Datagram {
+                            source: transmit.source,
+                            destination: transmit.destination,
+                            data: transmit.data,
+                        });
+                    }
+                }
+                while let Some(datagram) = self.network.receive(stun_server) {
+                    if let Some(data) =
+                        self.engine
+                            .handle_datagram(datagram.source, stun_server, &datagram.data)
+                    {
+                        self.network.send(Datagram {
+                            source: stun_server,
+                            destination: datagram.source,
+                            data,
+                        });
+                    }
+                }
+                while let Some(datagram) = self.network.receive(turn_server) {
+                    let connection = FiveTuple {
+                        client: datagram.source,
+                        server: turn_server,
+                        transport: Transport::Udp,
+                    };
+                    self.turn
+                        .handle_client_input(now, connection, &datagram.data);
+                }
+                for relayed in self.relayed.iter() {
+                    while let Some(datagram) = self.network.receive(*relayed) {
+                        self.turn
+                            .handle_peer_input(now, *relayed, datagram.source, &datagram.data);
+                    }
+                }
+                while let Some(transmit) = self.turn.poll_transmit() {
+                    self.network.send(Datagram {
+                        source: transmit.source,
+                        destination: transmit.destination,
+                        data: transmit.data,
+                    });
+                }
+                while let Some(event) = self.turn.poll_event() {
+                    if let TurnServerEvent::Allocated { relayed, .. } = event {
+                        self.relayed.push(relayed);
+                    }
+                }
+                while let Some(datagram) = self.network.receive(self.host_a) {
+                    self.a
+                        .handle_input(now, datagram.source, self.host_a, &datagram.data);
+                }
+                while let Some(event) = self.a.poll_event() {
+                    if let IceEvent::Data { data, .. } = event {
+                        self.a_data.push(data);
+                    }
+                }
+                while let Some(datagram) = self.network.receive(self.host_b) {
+                    if datagram.data[0] & 0xC0 != 0 {
+                        self.b_data.push(datagram.data);
+                    } else {
+                        self.b
+                            .handle_input(now, datagram.source, self.host_b, &datagram.data);
+                    }
+                }
+
+                let next = [
+                    self.a.poll_timeout(),
+                    self.b.poll_timeout(),
+                    self.turn.poll_timeout(),
+                    self.network.next_arrival(),
+                ]
+                .iter()
+                .flatten()
+                .min()
+                .copied()
+                .unwrap_or(deadline);
+                self.network
+                    .advance_to(next.max(now + Duration::from_millis(1)).min(deadline));
+            }
+        }
+    }
+
+    // the state the agents end in when each is behind a NAT of the given configuration
+    fn ice_behind_nats(a_nat: NatConfig, b_nat: NatConfig) -> IceState {
+        let mut ice = IceNetwork::new(a_nat, b_nat, false);
+        ice.run(Duration::from_secs(60));
+        if ice.a.state() == IceState::Completed {
+            assert_eq!(ice.b.state(), IceState::Completed);
+            let (local, remote) = ice.a.selected_pair().unwrap();
+            let (b_local, b_remote) = ice.b.selected_pair().unwrap();
+            assert_eq!((local.base, b_local.base), (ice.host_a, ice.host_b));
+            assert!(remote.address.ip() == ip("198.51.100.1"));
+            assert!(b_remote.address.ip() == ip("203.0.113.1"));
+        }
+        ice.a.state()
+    }
+
+    #[test]
+    pub fn test_ice_through_relayed_candidate() {
+        // no pair without the relay gets through two symmetric NATs, the first agent can only
+        // be reached on its relayed candidate
+        let mut ice = IceNetwork::new(NatConfig::symmetric(), NatConfig::symmetric(), true);
+        ice.run(Duration::from_secs(60));
+        assert_eq!(ice.a.state(), IceState::Completed);
+        assert_eq!(ice.b.state(), IceState::Completed);
+        let relayed = ice.relayed[0];
+        assert_eq!(relayed.ip(), ip("192.0.2.2"));
+        let (local, remote) = ice.a.selected_pair().unwrap();
+        assert_eq!(
+            (local.kind, local.address),
+            (CandidateType::Relayed, relayed)
+        );
+        assert_eq!(remote.address.ip(), ip("198.51.100.1"));
+        let (b_local, b_remote) = ice.b.selected_pair().unwrap();
+        assert_eq!((b_local.base, b_remote.address), (ice.host_b, relayed));
+
+        // application data goes through the relay both ways
+        assert!(ice.a.send(b"hi"));
+        assert!(ice.b.send(b"hello"));
+        ice.run(Duration::from_secs(1));
+        assert_eq!(ice.a_data, vec![b"hello".to_vec()]);
+        assert_eq!(ice.b_data, vec![b"hi".to_vec()]);
+    }
+
+    #[test]
+    pub fn test_ice_across_nats() {
+        assert_eq!(
+            ice_behind_nats(NatConfig::full_cone(), NatConfig::full_cone()),
+            IceState::Completed
+        );
+        assert_eq!(
+            ice_behind_nats(
+                NatConfig::port_restricted_cone(),
+                NatConfig::port_restricted_cone()
+            ),
+            IceState::Completed
+        );
+        assert_eq!(
+            ice_behind_nats(NatConfig::symmetric(), NatConfig::full_cone()),
+            IceState::Completed
+        );
+        // without a relay, two symmetric NATs cannot be traversed
+        assert_eq!(
+            ice_behind_nats(NatConfig::symmetric(), NatConfig::symmetric()),
+            IceState::Failed
+        );
+    }
+
+    // a TURN client and a peer, each behind a symmetric NAT, with the TURN server between them
+    struct TurnRelay {
+        network: Network,
+        server: TurnServer,
+        client: TurnClient,
+        host: SocketAddr,
+        events: Vec<TurnClientEvent>,
+    }
+
+    impl TurnRelay {
+        fn new() -> TurnRelay {
+            let mut network = Network::new(5);
+            let nat_a = network.add_nat(ip("203.0.113.1"), NatConfig::symmetric());
+            let nat_b = network.add_nat(ip("198.51.100.1"), NatConfig::symmetric());
+            network.add_private_host(ip("10.0.0.1"), nat_a);
+            network.add_private_host(ip("10.1.0.1"), nat_b);
+            network.add_public_host(ip("192.0.2.1"));
+            let server = TurnServer::new("example.org")
+                .with_user("alice", "secret")
+                .with_relay_ip(ip("192.0.2.1"));
+            let client = TurnClient::new(addr("192.0.2.1:3478"), "alice", "secret");
+            TurnRelay {
+                network,
+                server,
+                client,
+                host: addr("10.0.0.1:5000"),
+                events: vec![],
+            }
+        }
+
+        // moves the datagrams between the client, the server and its relayed addresses
+        fn run(&mut self, duration: Duration) {
+            let deadline = self.network.now() + duration;
+            let server = self.client.server();
+            while self.network.now() < deadline {
+                let now = self.network.now();
+                self.client.handle_timeout(now);
+                self.server.handle_timeout(now);
+                while let Some(transmit) = self.client.poll_transmit() {
+                    self.network.send(Datagram {
+                        source: self.host,
+                        destination: server,
+                        data: transmit.data,
+                    });
+                }
+                while let Some(datagram) = self.network.receive(server) {
+                    let connection = FiveTuple {
+                        client: datagram.source,
+                        server,
+                        transport: Transport::Udp,
+                    };
+                    self.server
+                        .handle_client_input(now, connection, &datagram.data);
+                }
+                if let Some(relayed) = self.client.relayed_address() {
+                    while let Some(datagram) = self.network.receive(relayed) {
+                        self.server.handle_peer_input(
+                            now,
+                            relayed,
+                            datagram.source,
+                            &datagram.data,
+                        );
+                    }
+                }
+                while let Some(transmit) = self.server.poll_transmit() {
+                    self.network.send(Datagram {
+                        source: transmit.source,
+                        destination: transmit.destination,
+                        data: transmit.data,
+                    });
+                }
+                while let Some(datagram) = self.network.receive(self.host) {
+                    self.client
+                        .handle_input(now, Connection::Control, &datagram.data);
+                }
+                while let Some(event) = self.client.poll_event() {
+                    self.events.push(event);
+                }
+                let next = [
+                    self.client.poll_timeout(),
+                    self.server.poll_timeout(),
+                    self.network.next_arrival(),
+                ]
+                .iter()
+                .flatten()
+                .min()
+                .copied()
+                .unwrap_or(deadline);
+                self.network
+                    .advance_to(next.max(now + Duration::from_millis(1)).min(deadline));
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_turn_relays_across_symmetric_nats() {
+        let mut relay = TurnRelay::new();
+        let peer = addr("10.1.0.1:6000");
+        relay.client.allocate(relay.network.now());
+        relay.run(Duration::from_secs(1));
+        let relayed = relay.client.relayed_address().unwrap();
+        assert_eq!(relayed.ip(), ip("192.0.2.1"));
+        assert_eq!(
+            relay.client.mapped_address().map(|mapped| mapped.ip()),
+            Some(ip("203.0.113.1"))
+        );
+
+        // the permission covers any port of the peer's NAT
+        let permitted = addr("198.51.100.1:1");
+        relay
+            .client
+            .create_permission(relay.network.now(), permitted);
+        relay.run(Duration::from_secs(1));
+        assert!(relay
+            .events
+            .contains(&TurnClientEvent::PermissionCreated(permitted)));
+
+        relay.network.send(Datagram {
+            source: peer,
+            destination: relayed,
+            data: b"ping".to_vec(),
+        });
+        relay.run(Duration::from_secs(1));
+        let mapped = match relay.events.pop() {
+            Some(TurnClientEvent::Data { peer, data }) => {
+                assert_eq!(data, b"ping".to_vec());
+                peer
+            }
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(mapped.ip(), ip("198.51.100.1"));
+
+        // the peer's NAT lets the answer in, it comes from the address the peer sent to
+        relay.client.send_to(mapped, b"pong");
+        relay.run(Duration::from_secs(1));
+        let datagram = relay.network.receive(peer).unwrap();
+        assert_eq!(
+            (datagram.source, datagram.data),
+            (relayed, b"pong".to_vec())
+        );
+
+        // the allocation and the permission outlive their lifetimes through refreshes
+        relay.run(Duration::from_secs(1200));
+        relay.network.send(Datagram {
+            source: peer,
+            destination: relayed,
+            data: b"ping".to_vec(),
+        });
+        relay.run(Duration::from_secs(1));
+        assert_eq!(
+            relay.events.pop(),
+            Some(TurnClientEvent::Data {
+                peer: mapped,
+                data: b"ping".to_vec()
+            })
+        );
+        assert!(!relay
+            .events
+            .iter()
+            .any(|event| matches!(event, TurnClientEvent::Failed { .. })));
+    }
+}
@@ -0,0 +1,350 @@
+// STUN transactions without any IO (RFC 5389 section 7.2.1)
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+
+use crate::codec::{Decoder, Encoder, Result};
+use crate::messages::{Message, MessageClass, TransactionID};
+
+// initial retransmission timeout recommended by RFC 5389 section 7.2.1
+const RTO: Duration = Duration::from_millis(500);
+// Rc, the number of transmissions of a request
+const MAX_TRANSMISSIONS: u32 = 7;
+// Rm, after the last transmission the client waits Rm times the initial RTO for a response
+const LAST_WAIT_FACTOR: u32 = 16;
+// Ti, requests over reliable transports time out after this (RFC 5389 section 7.2.2)
+const RELIABLE_TIMEOUT: Duration = Duration::from_millis(39500);
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum TransactionResult {
+    // a success or an error response
+    Response(Message),
+    TimedOut,
+}
+
+/// A client transaction, retransmitting a request until its response arrives or it times out.
+///
+/// The caller sends what `poll_transmit` returns to `destination`, feeds received datagrams to
+/// `handle_input`, calls `handle_timeout` once the instant of `poll_timeout` is reached and
+/// takes the outcome from `poll_result`.
+#[derive(Debug)]
+pub struct ClientTransaction {
+    transaction_id: TransactionID,
+    destination: SocketAddr,
+    data: Vec<u8>,
+    initial_rto: Duration,
+    rto: Duration,
+    max_transmissions: u32,
+    transmissions: u32,
+    sent_at: Instant,
+    deadline: Option<Instant>,
+    transmit: bool,
+    result: Option<TransactionResult>,
+    rtt: Option<Duration>,
+}
+
+impl ClientTransaction {
+    /// Starts a transaction for the request, which is sent right away, fails when the request
+    /// cannot be encoded.
+    pub fn new(
+        now: Instant,
+        request: &Message,
+        destination: SocketAddr,
+    ) -> Result<ClientTransaction> {
+        let mut bytes = BytesMut::new();
+        Encoder::new().encode(request, &mut bytes)?;
+        Ok(ClientTransaction::from_bytes(
+            now,
+            request.transaction_id,
+            bytes.to_vec(),
+            destination,
+        ))
+    }
+
+    /// Starts a transaction for an encoded request, e.g. one protected with MESSAGE-INTEGRITY.
+    pub fn from_bytes(
+        now: Instant,
+        transaction_id: TransactionID,
+        data: Vec<u8>,
+        destination: SocketAddr,
+    ) -> ClientTransaction {
+        let mut transaction = ClientTransaction {
+            transaction_id,
+            destination,
+            data,
+            initial_rto: RTO,
+            rto: RTO,
+            max_transmissions: MAX_TRANSMISSIONS,
+            transmissions: 1,
+            sent_at: now,
+            deadline: None,
+            transmit: true,
+            result: None,
+            rtt: None,
+        };
+        transaction.arm();
+        transaction
+    }
+
+    /// Changes the initial retransmission timeout, e.g. from an RTT estimate of the server.
+    pub fn with_rto(mut self, rto: Duration) -> ClientTransaction {
+        self.initial_rto = rto;
+        self.rto = rto;
+        self.arm();
+        self
+    }
+
+    pub fn with_max_transmissions(mut self, max_transmissions: u32) -> ClientTransaction {
+        self.max_transmissions = max_transmissions.max(1);
+        self.arm();
+        self
+    }
+
+    /// The request is sent once over a reliable transport such as TCP, the transaction times
+    /// out after 39.5 seconds without a response.
+    pub fn with_reliable_transport(self) -> ClientTransaction {
+        self.with_rto(RELIABLE_TIMEOUT / LAST_WAIT_FACTOR)
+            .with_max_transmissions(1)
+    }
+
+    pub fn transaction_id(&self) -> &TransactionID {
+        &self.transaction_id
+    }
+
+    pub fn destination(&self) -> SocketAddr {
+        self.destination
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.deadline.is_none()
+    }
+
+    /// The request to send to `destination`, when it is due.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        if self.transmit {
+            self.transmit = false;
+            Some(self.data.clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn poll_result(&mut self) -> Option<TransactionResult> {
+        self.result.take()
+    }
+
+    /// The round trip time of the response, None when there is none yet or the request was
+    /// retransmitted, as it is unknown which transmission was answered.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        match self.deadline {
+            Some(deadline) if deadline <= now => {}
+            _ => return,
+        }
+        if self.transmissions < self.max_transmissions {
+            // the timeout doubles after every transmission
+            self.transmissions += 1;
+            self.rto *= 2;
+            self.sent_at = now;
+            self.transmit = true;
+            self.arm();
+        } else {
+            self.deadline = None;
+            self.result = Some(TransactionResult::TimedOut);
+        }
+    }
+
+    // the last transmission waits Rm times the initial RTO instead of the doubled RTO
+    fn arm(&mut self) {
+        let timeout = if self.transmissions == self.max_transmissions {
+            self.initial_rto * LAST_WAIT_FACTOR
+        } else {
+            self.rto
+        };
+        self.deadline = Some(self.sent_at + timeout);
+    }
+
+    /// Handles a datagram received from `source`, true when it is the response of this
+    /// transaction.
+    ///
+    /// Responses only count when they come from `destination`, so that an off-path attacker
+    /// who learned the transaction ID cannot answer from anywhere else.
+    pub fn handle_input(&mut self, now: Instant, source: SocketAddr, data: &[u8]) -> bool {
+        if self.is_done() || source != self.destination {
+            return false;
+        }
+        let decoder = if self.transaction_id.is_classic() {
+            Decoder::with_classic_stun()
+        } else {
+            Decoder::new()
+        };
+        let message = match decoder.decode(&mut &data[..]) {
+            Ok(message) => message,
+            Err(_) => return false,
+        };
+        let is_response = message.message_class == MessageClass::SuccessResponse
+            || message.message_class == MessageClass::FailureResponse;
+        if !is_response || message.transaction_id != self.transaction_id {
+            return false;
+        }
+        if self.transmissions == 1 {
+            self.rtt = Some(now - self.sent_at);
+        }
+        self.deadline = None;
+        self.transmit = false;
+        self.result = Some(TransactionResult::Response(message));
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use bytes::BytesMut;
+
+    use crate::codec::Encoder;
+    use crate::messages::*;
+    use crate::transaction::*;
+
+    fn request() -> Message {
+        Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Binding,
+            transaction_id: TransactionID::from([3u8; 12]),
+            attributes: vec![],
+        }
+    }
+
+    fn response(class: MessageClass, transaction_id: TransactionID) -> Vec<u8> {
+        let response = Message {
+            message_class: class,
+            message_method: MessageMethod::Binding,
+            transaction_id,
+            attributes: vec![Attribute::XorMappedAddress(Address::ipv4(
+                [192, 0, 2, 1],
+                4000,
+            ))],
+        };
+        let mut bytes = BytesMut::new();
+        Encoder::new().encode(&response, &mut bytes).unwrap();
+        bytes.to_vec()
+    }
+
+    #[test]
+    pub fn test_retransmissions_until_timeout() {
+        let server: SocketAddr = "192.0.2.10:3478".parse().unwrap();
+        let start = Instant::now();
+        let mut transaction = ClientTransaction::new(start, &request(), server).unwrap();
+        let mut sent = vec![];
+        let mut now = start;
+        loop {
+            while transaction.poll_transmit().is_some() {
+                sent.push(now - start);
+            }
+            match transaction.poll_timeout() {
+                Some(timeout) => now = timeout,
+                None => break,
+            }
+            transaction.handle_timeout(now);
+        }
+        // RFC 5389 section 7.2.1: sent at 0, 500, 1500, ..., 31500ms, timed out at 39500ms
+        let expected: Vec<Duration> = [0, 500, 1500, 3500, 7500, 15500, 31500]
+            .iter()
+            .map(|millis| Duration::from_millis(*millis))
+            .collect();
+        assert_eq!(sent, expected);
+        assert_eq!(now - start, Duration::from_millis(39500));
+        assert_eq!(transaction.poll_result(), Some(TransactionResult::TimedOut));
+        assert!(transaction.is_done());
+    }
+
+    #[test]
+    pub fn test_complete_on_response() {
+        let server: SocketAddr = "192.0.2.10:3478".parse().unwrap();
+        let start = Instant::now();
+        let mut transaction = ClientTransaction::new(start, &request(), server).unwrap();
+        assert!(transaction.poll_transmit().is_some());
+        assert_eq!(transaction.poll_transmit(), None);
+
+        // responses of other transactions and datagrams that are no STUN are not taken
+        let other = response(
+            MessageClass::SuccessResponse,
+            TransactionID::from([4u8; 12]),
+        );
+        assert!(!transaction.handle_input(start, server, &other));
+        assert!(!transaction.handle_input(start, server, b"not a STUN message"));
+        assert_eq!(transaction.poll_result(), None);
+
+        let error = response(
+            MessageClass::FailureResponse,
+            TransactionID::from([3u8; 12]),
+        );
+        // the response has to come from the server
+        let elsewhere: SocketAddr = "192.0.2.11:3478".parse().unwrap();
+        assert!(!transaction.handle_input(start, elsewhere, &error));
+        assert_eq!(transaction.poll_result(), None);
+
+        let now = start + Duration::from_millis(80);
+        assert!(transaction.handle_input(now, server, &error));
+        match transaction.poll_result() {
+            Some(TransactionResult::Response(message)) => {
+                assert_eq!(message.message_class, MessageClass::FailureResponse)
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(transaction.poll_timeout(), None);
+        assert_eq!(transaction.rtt(), Some(Duration::from_millis(80)));
+        // a retransmitted response is not taken twice
+        assert!(!transaction.handle_input(now, server, &error));
+    }
+
+    #[test]
+    pub fn test_custom_rto_and_transmissions() {
+        let server: SocketAddr = "192.0.2.10:3478".parse().unwrap();
+        let start = Instant::now();
+        let mut transaction = ClientTransaction::new(start, &request(), server)
+            .unwrap()
+            .with_rto(Duration::from_millis(100))
+            .with_max_transmissions(2);
+        assert_eq!(
+            transaction.poll_timeout(),
+            Some(start + Duration::from_millis(100))
+        );
+        assert!(transaction.poll_transmit().is_some());
+        transaction.handle_timeout(start + Duration::from_millis(100));
+        assert!(transaction.poll_transmit().is_some());
+        // the last transmission waits 16 times the initial RTO
+        assert_eq!(
+            transaction.poll_timeout(),
+            Some(start + Duration::from_millis(1700))
+        );
+        transaction.handle_timeout(start + Duration::from_millis(1700));
+        assert_eq!(transaction.poll_transmit(), None);
+        assert_eq!(transaction.poll_result(), Some(TransactionResult::TimedOut));
+    }
+
+    #[test]
+    pub fn test_reliable_transport_is_not_retransmitted() {
+        let server: SocketAddr = "192.0.2.10:3478".parse().unwrap();
+        let start = Instant::now();
+        let mut transaction = ClientTransaction::new(start, &request(), server)
+            .unwrap()
+            .with_reliable_transport();
+        assert!(transaction.poll_transmit().is_some());
+        let timeout = start + Duration::from_millis(39500);
+        assert_eq!(transaction.poll_timeout(), Some(timeout));
+        transaction.handle_timeout(timeout);
+        assert_eq!(transaction.poll_transmit(), None);
+        assert_eq!(transaction.poll_result(), Some(TransactionResult::TimedOut));
+    }
+}
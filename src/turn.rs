@@ -0,0 +1,13 @@
+// Traversal Using Relays around NAT (RFC 8656), with TCP relaying to peers (RFC 6062)
+pub use client::*;
+pub use filter::*;
+pub use server::*;
+pub use transport::*;
+
+mod client;
+
+mod filter;
+
+mod server;
+
+mod transport;
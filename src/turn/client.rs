@@ -0,0 +1,1214 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+
+use crate::codec::{append_message_integrity, long_term_key, verify_message_integrity};
+use crate::codec::{Decoder, Encoder};
+use crate::messages::*;
+use crate::transaction::{ClientTransaction, TransactionResult};
+use crate::turn::transport::*;
+
+// allocations are refreshed this long before they expire
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+// permissions last 5 minutes and channels 10 minutes, they are refreshed a minute earlier
+const PERMISSION_REFRESH: Duration = Duration::from_secs(240);
+const CHANNEL_REFRESH: Duration = Duration::from_secs(540);
+
+/// The connection to the TURN server a message is sent or received on: the UDP socket or the
+/// TCP control connection, or the RFC 6062 data connection of a connection ID.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash)]
+pub enum Connection {
+    Control,
+    Data(u32),
+}
+
+/// Bytes to send to the TURN server on the connection.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct ClientTransmit {
+    pub connection: Connection,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum TurnError {
+    Response { code: u32, reason: String },
+    TimedOut,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum TurnClientEvent {
+    // one relayed address, or two for a dual allocation, with the error of the additional
+    // address family when only the first one could be allocated, and the token of the port
+    // reserved after the relayed one
+    Allocated {
+        relayed: Vec<SocketAddr>,
+        mapped: SocketAddr,
+        lifetime: Duration,
+        additional_error: Option<TurnError>,
+        reservation_token: Option<[u8; 8]>,
+    },
+    // the allocation was deleted with release
+    Released,
+    PermissionCreated(SocketAddr),
+    ChannelBound {
+        peer: SocketAddr,
+        channel: u16,
+    },
+    // data a peer sent to the relayed address
+    Data {
+        peer: SocketAddr,
+        data: Vec<u8>,
+    },
+    // RFC 6062: the server connected to the peer, open a data connection and bind it
+    Connected {
+        peer: SocketAddr,
+        connection_id: u32,
+    },
+    // RFC 6062: a peer connected to the relayed address, bind a data connection to accept it
+    ConnectionAttempt {
+        peer: SocketAddr,
+        connection_id: u32,
+    },
+    // RFC 6062: the data connection carries the bytes of the peer connection from now on
+    ConnectionBound {
+        peer: SocketAddr,
+        connection_id: u32,
+    },
+    // a request failed, for Allocate and Refresh the allocation is gone
+    Failed {
+        method: MessageMethod,
+        peer: Option<SocketAddr>,
+        error: TurnError,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Request {
+    Allocate,
+    // the lifetime to ask for, 0 deletes the allocation
+    Refresh(Option<u32>),
+    CreatePermission(SocketAddr),
+    ChannelBind(SocketAddr, u16),
+    Connect(SocketAddr),
+    ConnectionBind(SocketAddr, u32),
+}
+
+impl Request {
+    fn method(&self) -> MessageMethod {
+        match self {
+            Request::Allocate => MessageMethod::Allocate,
+            Request::Refresh(_) => MessageMethod::Refresh,
+            Request::CreatePermission(_) => MessageMethod::CreatePermission,
+            Request::ChannelBind(_, _) => MessageMethod::ChannelBind,
+            Request::Connect(_) => MessageMethod::Connect,
+            Request::ConnectionBind(_, _) => MessageMethod::ConnectionBind,
+        }
+    }
+
+    fn peer(&self) -> Option<SocketAddr> {
+        match self {
+            Request::Allocate | Request::Refresh(_) => None,
+            Request::CreatePermission(peer)
+            | Request::ChannelBind(peer, _)
+            | Request::Connect(peer)
+            | Request::ConnectionBind(peer, _) => Some(*peer),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Pending {
+    request: Request,
+    connection: Connection,
+    transaction: ClientTransaction,
+    // sent again with new credentials after a 401 or 438
+    retried: bool,
+}
+
+#[derive(Debug)]
+struct Allocation {
+    relayed: Vec<SocketAddr>,
+    mapped: SocketAddr,
+    refresh_at: Instant,
+}
+
+#[derive(Debug)]
+struct Permission {
+    peer: SocketAddr,
+    // created by the server, refreshes are not reported
+    confirmed: bool,
+    refresh_at: Instant,
+}
+
+#[derive(Debug)]
+struct Binding {
+    channel: u16,
+    // ChannelData is only sent once the server confirmed the channel
+    confirmed: bool,
+    refresh_at: Instant,
+}
+
+/// A TURN client (RFC 8656), with TCP relaying to peers (RFC 6062), without any IO.
+///
+/// The application sends what `poll_transmit` returns to the server on its connection, feeds
+/// what it receives to `handle_input`, and calls `handle_timeout` once the instant of
+/// `poll_timeout` is reached. Allocations, permissions and channels are refreshed until the
+/// allocation is released.
+pub struct TurnClient {
+    username: String,
+    password: String,
+    server: SocketAddr,
+    transport: Transport,
+    lifetime: Option<Duration>,
+    address_family: Option<IPKind>,
+    dual_stack: bool,
+    even_port: Option<bool>,
+    reservation_token: Option<[u8; 8]>,
+    realm: Option<String>,
+    nonce: Option<String>,
+    key: Option<[u8; 16]>,
+    allocation: Option<Allocation>,
+    permissions: HashMap<IpAddr, Permission>,
+    channels: HashMap<SocketAddr, Binding>,
+    next_channel: u16,
+    // peers of the RFC 6062 connection IDs
+    connections: HashMap<u32, SocketAddr>,
+    pending: Vec<Pending>,
+    transmits: VecDeque<ClientTransmit>,
+    events: VecDeque<TurnClientEvent>,
+}
+
+impl TurnClient {
+    pub fn new(server: SocketAddr, username: &str, password: &str) -> TurnClient {
+        TurnClient {
+            username: username.to_owned(),
+            password: password.to_owned(),
+            server,
+            transport: Transport::Udp,
+            lifetime: None,
+            address_family: None,
+            dual_stack: false,
+            even_port: None,
+            reservation_token: None,
+            realm: None,
+            nonce: None,
+            key: None,
+            allocation: None,
+            permissions: HashMap::new(),
+            channels: HashMap::new(),
+            next_channel: MIN_CHANNEL,
+            connections: HashMap::new(),
+            pending: vec![],
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// The transport of the relayed address, UDP by default. TCP relaying (RFC 6062) needs a
+    /// TCP control connection to the server.
+    pub fn with_transport(mut self, transport: Transport) -> TurnClient {
+        self.transport = transport;
+        self
+    }
+
+    /// The lifetime to ask for, the server picks one when there is none.
+    pub fn with_lifetime(mut self, lifetime: Duration) -> TurnClient {
+        self.lifetime = Some(lifetime);
+        self
+    }
+
+    /// The address family of the relayed address, the server picks IPv4 when there is none.
+    pub fn with_address_family(mut self, family: IPKind) -> TurnClient {
+        self.address_family = Some(family);
+        self.dual_stack = false;
+        self
+    }
+
+    /// Asks for an IPv6 relayed address in addition to the IPv4 one.
+    pub fn with_dual_stack(mut self) -> TurnClient {
+        self.dual_stack = true;
+        self.address_family = None;
+        self
+    }
+
+    /// Asks for an even relayed port, as RTP wants. With `reserve` the server keeps the next
+    /// port for about 30 seconds, the Allocated event has the token to allocate it with.
+    pub fn with_even_port(mut self, reserve: bool) -> TurnClient {
+        self.even_port = Some(reserve);
+        self
+    }
+
+    /// Allocates the port another allocation reserved, with the token of its Allocated event.
+    /// The token replaces EVEN-PORT and the address families, the reserved port has its own.
+    pub fn with_reservation_token(mut self, token: [u8; 8]) -> TurnClient {
+        self.reservation_token = Some(token);
+        self
+    }
+
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// The first relayed address, the IPv4 one of a dual allocation.
+    pub fn relayed_address(&self) -> Option<SocketAddr> {
+        self.relayed_addresses().first().copied()
+    }
+
+    pub fn relayed_addresses(&self) -> &[SocketAddr] {
+        self.allocation
+            .as_ref()
+            .map_or(&[], |allocation| &allocation.relayed[..])
+    }
+
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        self.allocation.as_ref().map(|allocation| allocation.mapped)
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<ClientTransmit> {
+        self.transmits.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<TurnClientEvent> {
+        self.events.pop_front()
+    }
+
+    /// The instant `handle_timeout` should be called at.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let transactions = self
+            .pending
+            .iter()
+            .filter_map(|pending| pending.transaction.poll_timeout());
+        let refreshes = self
+            .allocation
+            .iter()
+            .map(|allocation| allocation.refresh_at)
+            .chain(
+                self.permissions
+                    .values()
+                    .map(|permission| permission.refresh_at),
+            )
+            .chain(self.channels.values().map(|binding| binding.refresh_at));
+        transactions.chain(refreshes).min()
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        for pending in self.pending.iter_mut() {
+            pending.transaction.handle_timeout(now);
+            while let Some(transmit) = pending.transaction.poll_transmit() {
+                self.transmits.push_back(ClientTransmit {
+                    connection: pending.connection,
+                    data: transmit,
+                });
+            }
+        }
+        self.complete_transactions(now);
+
+        let lifetime = self.lifetime.map(|lifetime| lifetime.as_secs() as u32);
+        let refresh = match self.allocation.as_mut() {
+            Some(allocation) if allocation.refresh_at <= now => {
+                // refreshed again once the response tells the new lifetime
+                allocation.refresh_at = now + REFRESH_MARGIN;
+                true
+            }
+            _ => false,
+        };
+        if refresh {
+            self.send_request(now, Request::Refresh(lifetime), Connection::Control, false);
+        }
+        let mut requests = vec![];
+        for permission in self.permissions.values_mut() {
+            if permission.refresh_at <= now {
+                permission.refresh_at = now + PERMISSION_REFRESH;
+                requests.push(Request::CreatePermission(permission.peer));
+            }
+        }
+        for (peer, binding) in self.channels.iter_mut() {
+            if binding.refresh_at <= now {
+                binding.refresh_at = now + CHANNEL_REFRESH;
+                requests.push(Request::ChannelBind(*peer, binding.channel));
+            }
+        }
+        for request in requests {
+            self.send_request(now, request, Connection::Control, false);
+        }
+    }
+
+    /// Asks the server for an allocation.
+    pub fn allocate(&mut self, now: Instant) {
+        self.send_request(now, Request::Allocate, Connection::Control, false);
+    }
+
+    /// Deletes the allocation.
+    pub fn release(&mut self, now: Instant) {
+        self.send_request(now, Request::Refresh(Some(0)), Connection::Control, false);
+    }
+
+    /// Allows the peer IP address to send to the relayed address, refreshed until the
+    /// allocation is released.
+    pub fn create_permission(&mut self, now: Instant, peer: SocketAddr) {
+        let confirmed = self
+            .permissions
+            .get(&peer.ip())
+            .is_some_and(|permission| permission.confirmed);
+        self.permissions.insert(
+            peer.ip(),
+            Permission {
+                peer,
+                confirmed,
+                refresh_at: now + PERMISSION_REFRESH,
+            },
+        );
+        self.send_request(
+            now,
+            Request::CreatePermission(peer),
+            Connection::Control,
+            false,
+        );
+    }
+
+    /// Binds a channel to the peer, data to and from the peer goes through it once it is
+    /// bound.
+    pub fn bind_channel(&mut self, now: Instant, peer: SocketAddr) {
+        let channel = match self.channels.get(&peer) {
+            Some(binding) => binding.channel,
+            None => {
+                let channel = self.next_channel;
+                self.next_channel = if channel == MAX_CHANNEL {
+                    MIN_CHANNEL
+                } else {
+                    channel + 1
+                };
+                channel
+            }
+        };
+        let confirmed = self.channels.get(&peer).is_some_and(|b| b.confirmed);
+        self.channels.insert(
+            peer,
+            Binding {
+                channel,
+                confirmed,
+                refresh_at: now + CHANNEL_REFRESH,
+            },
+        );
+        self.send_request(
+            now,
+            Request::ChannelBind(peer, channel),
+            Connection::Control,
+            false,
+        );
+    }
+
+    /// Sends data to the peer through the relay, through its channel when one is bound.
+    pub fn send_to(&mut self, peer: SocketAddr, data: &[u8]) {
+        let data = match self.channels.get(&peer) {
+            Some(binding) if binding.confirmed => ChannelData {
+                channel: binding.channel,
+                data: data.to_vec(),
+            }
+            .encode(),
+            _ => {
+                let indication = Message {
+                    message_class: MessageClass::Indication,
+                    message_method: MessageMethod::Send,
+                    transaction_id: TransactionID::random(),
+                    attributes: vec![
+                        Attribute::XorPeerAddress(Address::from(peer)),
+                        Attribute::Data(data.to_vec()),
+                    ],
+                };
+                let mut bytes = BytesMut::new();
+                Encoder::new()
+                    .encode(&indication, &mut bytes)
+                    .expect("built-in attributes always encode");
+                bytes.to_vec()
+            }
+        };
+        self.transmits.push_back(ClientTransmit {
+            connection: Connection::Control,
+            data,
+        });
+    }
+
+    /// RFC 6062: asks the server to connect to the peer, `Connected` tells the connection ID to
+    /// bind a data connection to.
+    pub fn connect(&mut self, now: Instant, peer: SocketAddr) {
+        self.send_request(now, Request::Connect(peer), Connection::Control, false);
+    }
+
+    /// RFC 6062: binds a new data connection to the peer connection of the connection ID, the
+    /// ConnectionBind request is sent on `Connection::Data`.
+    pub fn bind_connection(&mut self, now: Instant, connection_id: u32) {
+        if let Some(peer) = self.connections.get(&connection_id) {
+            let request = Request::ConnectionBind(*peer, connection_id);
+            self.send_request(now, request, Connection::Data(connection_id), false);
+        }
+    }
+
+    /// Handles a message received from the server on the connection, TCP streams are split
+    /// with `frame_length` first.
+    pub fn handle_input(&mut self, now: Instant, connection: Connection, data: &[u8]) {
+        if is_channel_data(data) {
+            if let Some(message) = ChannelData::decode(data) {
+                let peer = self
+                    .channels
+                    .iter()
+                    .find(|(_, binding)| binding.channel == message.channel)
+                    .map(|(peer, _)| *peer);
+                if let Some(peer) = peer {
+                    self.events.push_back(TurnClientEvent::Data {
+                        peer,
+                        data: message.data,
+                    });
+                }
+            }
+            return;
+        }
+        let message = match Decoder::new().decode(&mut &data[..]) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        match message.message_class {
+            MessageClass::SuccessResponse | MessageClass::FailureResponse => {
+                // success responses have to be protected with the key of the credentials
+                if message.message_class == MessageClass::SuccessResponse
+                    && !self
+                        .key
+                        .is_some_and(|key| verify_message_integrity(data, &key))
+                {
+                    return;
+                }
+                let pending = self
+                    .pending
+                    .iter_mut()
+                    .filter(|pending| pending.connection == connection);
+                for pending in pending {
+                    if pending.transaction.handle_input(now, self.server, data) {
+                        break;
+                    }
+                }
+                self.complete_transactions(now);
+            }
+            MessageClass::Indication if connection == Connection::Control => {
+                self.handle_indication(message)
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_indication(&mut self, message: Message) {
+        let mut peer = None;
+        let mut data = None;
+        let mut connection_id = None;
+        for attribute in message.attributes {
+            match attribute {
+                Attribute::XorPeerAddress(address) => peer = Some(address.to_socket_addr()),
+                Attribute::Data(bytes) => data = Some(bytes),
+                Attribute::ConnectionId(id) => connection_id = Some(id),
+                _ => {}
+            }
+        }
+        match (message.message_method, peer, data, connection_id) {
+            (MessageMethod::Data, Some(peer), Some(data), _) => {
+                self.events.push_back(TurnClientEvent::Data { peer, data })
+            }
+            (MessageMethod::ConnectionAttempt, Some(peer), _, Some(connection_id)) => {
+                self.connections.insert(connection_id, peer);
+                self.events.push_back(TurnClientEvent::ConnectionAttempt {
+                    peer,
+                    connection_id,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn complete_transactions(&mut self, now: Instant) {
+        let mut index = 0;
+        while index < self.pending.len() {
+            match self.pending[index].transaction.poll_result() {
+                Some(result) => {
+                    let pending = self.pending.remove(index);
+                    self.complete(now, pending, result);
+                }
+                None => index += 1,
+            }
+        }
+    }
+
+    fn complete(&mut self, now: Instant, pending: Pending, result: TransactionResult) {
+        let response = match result {
+            TransactionResult::Response(response) => response,
+            TransactionResult::TimedOut => {
+                self.fail(&pending.request, TurnError::TimedOut);
+                return;
+            }
+        };
+        if response.message_class == MessageClass::FailureResponse {
+            let (code, reason) = error_code(&response);
+            // a challenge or a stale nonce, sent again once with the realm and the nonce given
+            if (code == 401 || code == 438) && !pending.retried && self.take_challenge(&response) {
+                self.send_request(now, pending.request, pending.connection, true);
+            } else {
+                self.fail(&pending.request, TurnError::Response { code, reason });
+            }
+            return;
+        }
+
+        match pending.request {
+            Request::Allocate => {
+                let mut relayed = vec![];
+                let mut mapped = None;
+                let mut lifetime = None;
+                let mut additional_error = None;
+                let mut reservation_token = None;
+                for attribute in &response.attributes {
+                    match attribute {
+                        Attribute::XorRelayedAddress(address) => {
+                            relayed.push(address.to_socket_addr())
+                        }
+                        Attribute::ReservationToken(token) => reservation_token = Some(*token),
+                        Attribute::AddressErrorCode { code, reason, .. } => {
+                            additional_error = Some(TurnError::Response {
+                                code: *code,
+                                reason: reason.clone(),
+                            })
+                        }
+                        Attribute::XorMappedAddress(address) => {
+                            mapped = Some(address.to_socket_addr())
+                        }
+                        Attribute::Lifetime(seconds) => {
+                            lifetime = Some(Duration::from_secs(*seconds as u64))
+                        }
+                        _ => {}
+                    }
+                }
+                if let (Some(mapped), Some(lifetime), false) =
+                    (mapped, lifetime, relayed.is_empty())
+                {
+                    self.allocation = Some(Allocation {
+                        relayed: relayed.clone(),
+                        mapped,
+                        refresh_at: refresh_at(now, lifetime),
+                    });
+                    self.events.push_back(TurnClientEvent::Allocated {
+                        relayed,
+                        mapped,
+                        lifetime,
+                        additional_error,
+                        reservation_token,
+                    });
+                }
+            }
+            Request::Refresh(Some(0)) => {
+                self.allocation = None;
+                self.permissions.clear();
+                self.channels.clear();
+                self.connections.clear();
+                self.events.push_back(TurnClientEvent::Released);
+            }
+            Request::Refresh(_) => {
+                let lifetime = response
+                    .attributes
+                    .iter()
+                    .find_map(|attribute| match attribute {
+                        Attribute::Lifetime(seconds) => Some(Duration::from_secs(*seconds as u64)),
+                        _ => None,
+                    });
+                if let (Some(allocation), Some(lifetime)) = (self.allocation.as_mut(), lifetime) {
+                    allocation.refresh_at = refresh_at(now, lifetime);
+                }
+            }
+            Request::CreatePermission(peer) => {
+                if let Some(permission) = self.permissions.get_mut(&peer.ip()) {
+                    if !permission.confirmed {
+                        permission.confirmed = true;
+                        self.events
+                            .push_back(TurnClientEvent::PermissionCreated(peer));
+                    }
+                }
+            }
+            Request::ChannelBind(peer, channel) => {
+                if let Some(binding) = self.channels.get_mut(&peer) {
+                    if !binding.confirmed {
+                        binding.confirmed = true;
+                        self.events
+                            .push_back(TurnClientEvent::ChannelBound { peer, channel });
+                    }
+                }
+            }
+            Request::Connect(peer) => {
+                let connection_id =
+                    response
+                        .attributes
+                        .iter()
+                        .find_map(|attribute| match attribute {
+                            Attribute::ConnectionId(id) => Some(*id),
+                            _ => None,
+                        });
+                if let Some(connection_id) = connection_id {
+                    self.connections.insert(connection_id, peer);
+                    self.events.push_back(TurnClientEvent::Connected {
+                        peer,
+                        connection_id,
+                    });
+                }
+            }
+            Request::ConnectionBind(peer, connection_id) => {
+                self.events.push_back(TurnClientEvent::ConnectionBound {
+                    peer,
+                    connection_id,
+                })
+            }
+        }
+    }
+
+    fn fail(&mut self, request: &Request, error: TurnError) {
+        match request {
+            Request::Allocate | Request::Refresh(_) => {
+                self.allocation = None;
+                self.permissions.clear();
+                self.channels.clear();
+            }
+            Request::CreatePermission(peer) => {
+                self.permissions.remove(&peer.ip());
+            }
+            Request::ChannelBind(peer, _) => {
+                self.channels.remove(peer);
+            }
+            Request::Connect(_) => {}
+            Request::ConnectionBind(_, connection_id) => {
+                self.connections.remove(connection_id);
+            }
+        }
+        self.events.push_back(TurnClientEvent::Failed {
+            method: request.method(),
+            peer: request.peer(),
+            error,
+        });
+    }
+
+    // takes the realm and the nonce of a 401 or 438 response, false when they are missing
+    fn take_challenge(&mut self, response: &Message) -> bool {
+        for attribute in &response.attributes {
+            match attribute {
+                Attribute::Realm(realm) => self.realm = Some(realm.clone()),
+                Attribute::Nonce(nonce) => self.nonce = Some(nonce.clone()),
+                _ => {}
+            }
+        }
+        match (&self.realm, &self.nonce) {
+            (Some(realm), Some(_)) => {
+                self.key = Some(long_term_key(&self.username, realm, &self.password));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn send_request(
+        &mut self,
+        now: Instant,
+        request: Request,
+        connection: Connection,
+        retried: bool,
+    ) {
+        let mut attributes = match request {
+            Request::Allocate => {
+                let mut attributes = vec![Attribute::RequestedTransport(self.transport.protocol())];
+                if let Some(lifetime) = self.lifetime {
+                    attributes.push(Attribute::Lifetime(lifetime.as_secs() as u32));
+                }
+                if let Some(token) = self.reservation_token {
+                    attributes.push(Attribute::ReservationToken(token));
+                } else {
+                    if let Some(family) = self.address_family {
+                        attributes.push(Attribute::RequestedAddressFamily(family));
+                    }
+                    if self.dual_stack {
+                        attributes.push(Attribute::AdditionalAddressFamily(IPKind::IPv6));
+                    }
+                    if let Some(reserve) = self.even_port {
+                        attributes.push(Attribute::EvenPort(reserve));
+                    }
+                }
+                attributes
+            }
+            Request::Refresh(lifetime) => lifetime.into_iter().map(Attribute::Lifetime).collect(),
+            Request::CreatePermission(peer) | Request::Connect(peer) => {
+                vec![Attribute::XorPeerAddress(Address::from(peer))]
+            }
+            Request::ChannelBind(peer, channel) => vec![
+                Attribute::ChannelNumber(channel),
+                Attribute::XorPeerAddress(Address::from(peer)),
+            ],
+            Request::ConnectionBind(_, connection_id) => {
+                vec![Attribute::ConnectionId(connection_id)]
+            }
+        };
+        if let (Some(realm), Some(nonce)) = (&self.realm, &self.nonce) {
+            attributes.push(Attribute::UserName(self.username.clone()));
+            attributes.push(Attribute::Realm(realm.clone()));
+            attributes.push(Attribute::Nonce(nonce.clone()));
+        }
+        let message = Message {
+            message_class: MessageClass::Request,
+            message_method: request.method(),
+            transaction_id: TransactionID::random(),
+            attributes,
+        };
+        let mut bytes = BytesMut::new();
+        Encoder::new()
+            .encode(&message, &mut bytes)
+            .expect("built-in attributes always encode");
+        if let Some(key) = self.key {
+            append_message_integrity(&mut bytes, &key);
+        }
+
+        let mut transaction =
+            ClientTransaction::from_bytes(now, message.transaction_id, bytes.to_vec(), self.server);
+        if self.transport == Transport::Tcp || connection != Connection::Control {
+            transaction = transaction.with_reliable_transport();
+        }
+        while let Some(data) = transaction.poll_transmit() {
+            self.transmits
+                .push_back(ClientTransmit { connection, data });
+        }
+        self.pending.push(Pending {
+            request,
+            connection,
+            transaction,
+            retried,
+        });
+    }
+}
+
+fn error_code(response: &Message) -> (u32, String) {
+    response
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            Attribute::ErrorCode { code, reason } => Some((*code, reason.clone())),
+            _ => None,
+        })
+        .unwrap_or((400, String::new()))
+}
+
+// a minute before the allocation expires, or half way for short lifetimes
+fn refresh_at(now: Instant, lifetime: Duration) -> Instant {
+    if lifetime > 2 * REFRESH_MARGIN {
+        now + lifetime - REFRESH_MARGIN
+    } else {
+        now + lifetime / 2
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use crate::messages::{IPKind, MessageMethod};
+    use crate::turn::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    // a client and a server passing their messages to each other, what the server relays to
+    // peers is kept
+    struct Relay {
+        now: Instant,
+        client: TurnClient,
+        server: TurnServer,
+        transport: Transport,
+        // the connection IDs of the client ports of data connections
+        data_ports: HashMap<u16, u32>,
+        to_peers: Vec<Transmit>,
+        server_events: Vec<TurnServerEvent>,
+    }
+
+    impl Relay {
+        fn new(transport: Transport, password: &str) -> Relay {
+            let server = TurnServer::new("example.org")
+                .with_user("alice", "secret")
+                .with_relay_ip("192.0.2.1".parse().unwrap());
+            let client = TurnClient::new(addr("192.0.2.1:3478"), "alice", password)
+                .with_transport(transport);
+            Relay {
+                now: Instant::now(),
+                client,
+                server,
+                transport,
+                data_ports: HashMap::new(),
+                to_peers: vec![],
+                server_events: vec![],
+            }
+        }
+
+        // the control connection uses port 5000, data connections the next free ones
+        fn five_tuple(&mut self, connection: Connection) -> FiveTuple {
+            let port = match connection {
+                Connection::Control => 5000,
+                Connection::Data(id) => match self.data_ports.iter().find(|(_, c)| **c == id) {
+                    Some((port, _)) => *port,
+                    None => {
+                        let port = 5001 + self.data_ports.len() as u16;
+                        self.data_ports.insert(port, id);
+                        port
+                    }
+                },
+            };
+            FiveTuple {
+                client: SocketAddr::new("198.51.100.1".parse().unwrap(), port),
+                server: self.client.server(),
+                transport: self.transport,
+            }
+        }
+
+        fn deliver(&mut self) {
+            loop {
+                if let Some(transmit) = self.client.poll_transmit() {
+                    let connection = self.five_tuple(transmit.connection);
+                    self.server
+                        .handle_client_input(self.now, connection, &transmit.data);
+                } else if let Some(transmit) = self.server.poll_transmit() {
+                    if transmit.source != self.client.server() {
+                        self.to_peers.push(transmit);
+                        continue;
+                    }
+                    let connection = match self.data_ports.get(&transmit.destination.port()) {
+                        Some(id) => Connection::Data(*id),
+                        None => Connection::Control,
+                    };
+                    self.client
+                        .handle_input(self.now, connection, &transmit.data);
+                } else if let Some(event) = self.server.poll_event() {
+                    self.server_events.push(event);
+                } else {
+                    break;
+                }
+            }
+        }
+
+        fn events(&mut self) -> Vec<TurnClientEvent> {
+            std::iter::from_fn(|| self.client.poll_event()).collect()
+        }
+
+        // runs the timers of both sides until the instant
+        fn advance_to(&mut self, end: Instant) {
+            loop {
+                let next = [self.client.poll_timeout(), self.server.poll_timeout()]
+                    .iter()
+                    .flatten()
+                    .min()
+                    .copied();
+                match next {
+                    Some(next) if next <= end => self.now = self.now.max(next),
+                    _ => break,
+                }
+                self.client.handle_timeout(self.now);
+                self.server.handle_timeout(self.now);
+                self.deliver();
+            }
+            self.now = end;
+        }
+
+        fn allocate(&mut self) -> SocketAddr {
+            self.client.allocate(self.now);
+            self.deliver();
+            match self.events()[..] {
+                [TurnClientEvent::Allocated {
+                    ref relayed,
+                    mapped,
+                    ..
+                }] => {
+                    assert_eq!(mapped, addr("198.51.100.1:5000"));
+                    assert_eq!(relayed.len(), 1);
+                    relayed[0]
+                }
+                ref events => panic!("unexpected events {:?}", events),
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_allocate_and_relay_udp() {
+        let mut relay = Relay::new(Transport::Udp, "secret");
+        let relayed = relay.allocate();
+        assert_eq!(relay.client.relayed_address(), Some(relayed));
+        let peer = addr("203.0.113.5:7000");
+
+        relay.client.create_permission(relay.now, peer);
+        relay.deliver();
+        assert_eq!(
+            relay.events(),
+            vec![TurnClientEvent::PermissionCreated(peer)]
+        );
+
+        // Send and Data indications until a channel is bound
+        relay.client.send_to(peer, b"ping");
+        relay.deliver();
+        relay
+            .server
+            .handle_peer_input(relay.now, relayed, peer, b"pong");
+        relay.deliver();
+        assert_eq!(relay.to_peers.pop().unwrap().data, b"ping".to_vec());
+        assert_eq!(
+            relay.events(),
+            vec![TurnClientEvent::Data {
+                peer,
+                data: b"pong".to_vec()
+            }]
+        );
+
+        relay.client.bind_channel(relay.now, peer);
+        relay.deliver();
+        assert_eq!(
+            relay.events(),
+            vec![TurnClientEvent::ChannelBound {
+                peer,
+                channel: 0x4000
+            }]
+        );
+        relay.client.send_to(peer, b"ping");
+        let transmit = relay.client.poll_transmit().unwrap();
+        assert_eq!(transmit.data[..2], [0x40, 0x00]);
+        let control = relay.five_tuple(Connection::Control);
+        relay
+            .server
+            .handle_client_input(relay.now, control, &transmit.data);
+        relay
+            .server
+            .handle_peer_input(relay.now, relayed, peer, b"pong");
+        relay.deliver();
+        assert_eq!(relay.to_peers.pop().unwrap().data, b"ping".to_vec());
+        assert_eq!(
+            relay.events(),
+            vec![TurnClientEvent::Data {
+                peer,
+                data: b"pong".to_vec()
+            }]
+        );
+    }
+
+    #[test]
+    pub fn test_refresh_until_released() {
+        let mut relay = Relay::new(Transport::Udp, "secret");
+        let relayed = relay.allocate();
+        let peer = addr("203.0.113.5:7000");
+        relay.client.create_permission(relay.now, peer);
+        relay.client.bind_channel(relay.now, peer);
+        relay.deliver();
+        relay.events();
+
+        // the allocation, the permission and the channel outlive their lifetimes, nonces going
+        // stale on the way
+        let start = relay.now;
+        relay.advance_to(start + Duration::from_secs(3600));
+        assert_eq!(relay.events(), vec![]);
+        assert_eq!(relay.server.allocations(), 1);
+        relay
+            .server
+            .handle_peer_input(relay.now, relayed, peer, b"pong");
+        relay.deliver();
+        assert_eq!(
+            relay.events(),
+            vec![TurnClientEvent::Data {
+                peer,
+                data: b"pong".to_vec()
+            }]
+        );
+
+        relay.client.release(relay.now);
+        relay.deliver();
+        assert_eq!(relay.events(), vec![TurnClientEvent::Released]);
+        assert_eq!(relay.server.allocations(), 0);
+        assert_eq!(relay.client.relayed_address(), None);
+        assert_eq!(relay.client.poll_timeout(), None);
+    }
+
+    #[test]
+    pub fn test_wrong_password_fails_allocation() {
+        let mut relay = Relay::new(Transport::Udp, "wrong");
+        relay.client.allocate(relay.now);
+        relay.deliver();
+        assert_eq!(
+            relay.events(),
+            vec![TurnClientEvent::Failed {
+                method: MessageMethod::Allocate,
+                peer: None,
+                error: TurnError::Response {
+                    code: 401,
+                    reason: "Unauthorized".to_owned()
+                }
+            }]
+        );
+        assert_eq!(relay.server.allocations(), 0);
+    }
+
+    #[test]
+    pub fn test_connect_and_accept_over_tcp() {
+        let mut relay = Relay::new(Transport::Tcp, "secret");
+        let relayed = relay.allocate();
+        let peer = addr("203.0.113.5:443");
+
+        relay.client.connect(relay.now, peer);
+        relay.deliver();
+        assert_eq!(
+            relay.server_events.pop(),
+            Some(TurnServerEvent::Connect { relayed, peer })
+        );
+        relay
+            .server
+            .handle_peer_connected(relay.now, relayed, peer, true);
+        relay.deliver();
+        let connection_id = match relay.events()[..] {
+            [TurnClientEvent::Connected {
+                peer: connected,
+                connection_id,
+            }] if connected == peer => connection_id,
+            ref events => panic!("unexpected events {:?}", events),
+        };
+
+        relay.client.bind_connection(relay.now, connection_id);
+        relay.deliver();
+        assert_eq!(
+            relay.events(),
+            vec![TurnClientEvent::ConnectionBound {
+                peer,
+                connection_id
+            }]
+        );
+        let data = relay.five_tuple(Connection::Data(connection_id));
+        assert_eq!(
+            relay.server_events.pop(),
+            Some(TurnServerEvent::Bridge {
+                connection: data,
+                relayed,
+                peer
+            })
+        );
+
+        // a peer connecting to the relayed address, it has the permission Connect installed
+        let other = addr("203.0.113.5:40000");
+        assert!(relay
+            .server
+            .handle_peer_connection(relay.now, relayed, other));
+        relay.deliver();
+        let connection_id = match relay.events()[..] {
+            [TurnClientEvent::ConnectionAttempt {
+                peer,
+                connection_id,
+            }] if peer == other => connection_id,
+            ref events => panic!("unexpected events {:?}", events),
+        };
+        relay.client.bind_connection(relay.now, connection_id);
+        relay.deliver();
+        assert_eq!(
+            relay.events(),
+            vec![TurnClientEvent::ConnectionBound {
+                peer: other,
+                connection_id
+            }]
+        );
+    }
+
+    #[test]
+    pub fn test_ipv6_and_dual_stack_allocations() {
+        // an IPv6 relayed address from a server without one fails the allocation
+        let mut relay = Relay::new(Transport::Udp, "secret");
+        relay.client = TurnClient::new(addr("192.0.2.1:3478"), "alice", "secret")
+            .with_address_family(IPKind::IPv6);
+        relay.client.allocate(relay.now);
+        relay.deliver();
+        assert_eq!(
+            relay.events(),
+            vec![TurnClientEvent::Failed {
+                method: MessageMethod::Allocate,
+                peer: None,
+                error: TurnError::Response {
+                    code: 440,
+                    reason: "Address Family not Supported".to_owned()
+                }
+            }]
+        );
+
+        // a dual allocation falls back to the IPv4 relayed address
+        let mut relay = Relay::new(Transport::Udp, "secret");
+        relay.client = TurnClient::new(addr("192.0.2.1:3478"), "alice", "secret").with_dual_stack();
+        relay.client.allocate(relay.now);
+        relay.deliver();
+        match &relay.events()[..] {
+            [TurnClientEvent::Allocated {
+                relayed,
+                additional_error: Some(TurnError::Response { code: 440, .. }),
+                ..
+            }] => assert_eq!(relayed, &vec![addr("192.0.2.1:49152")]),
+            events => panic!("unexpected events {:?}", events),
+        }
+
+        let mut relay = Relay::new(Transport::Udp, "secret");
+        relay.server = TurnServer::new("example.org")
+            .with_user("alice", "secret")
+            .with_relay_ip("192.0.2.1".parse().unwrap())
+            .with_relay_ip("2001:db8::1".parse().unwrap());
+        relay.client = TurnClient::new(addr("192.0.2.1:3478"), "alice", "secret").with_dual_stack();
+        relay.client.allocate(relay.now);
+        relay.deliver();
+        let relayed = vec![addr("192.0.2.1:49152"), addr("[2001:db8::1]:49153")];
+        match &relay.events()[..] {
+            [TurnClientEvent::Allocated {
+                relayed: addresses,
+                additional_error: None,
+                ..
+            }] => assert_eq!(addresses, &relayed),
+            events => panic!("unexpected events {:?}", events),
+        }
+        assert_eq!(relay.client.relayed_addresses(), &relayed[..]);
+
+        // IPv6 peers are reached from the IPv6 relayed address
+        let peer = addr("[2001:db8::5]:7000");
+        relay.client.create_permission(relay.now, peer);
+        relay.deliver();
+        relay.client.send_to(peer, b"ping");
+        relay.deliver();
+        let transmit = relay.to_peers.pop().unwrap();
+        assert_eq!((transmit.source, transmit.destination), (relayed[1], peer));
+        assert_eq!(
+            relay.events(),
+            vec![TurnClientEvent::PermissionCreated(peer)]
+        );
+    }
+
+    #[test]
+    pub fn test_reserve_and_use_the_next_port() {
+        let mut relay = Relay::new(Transport::Udp, "secret");
+        relay.client =
+            TurnClient::new(addr("192.0.2.1:3478"), "alice", "secret").with_even_port(true);
+        relay.client.allocate(relay.now);
+        relay.deliver();
+        let token = match &relay.events()[..] {
+            [TurnClientEvent::Allocated {
+                relayed,
+                reservation_token: Some(token),
+                ..
+            }] => {
+                assert_eq!(relayed, &vec![addr("192.0.2.1:49152")]);
+                *token
+            }
+            events => panic!("unexpected events {:?}", events),
+        };
+        relay.client.release(relay.now);
+        relay.deliver();
+        assert_eq!(relay.events(), vec![TurnClientEvent::Released]);
+
+        // the RTCP allocation gets the reserved odd port
+        relay.client = TurnClient::new(addr("192.0.2.1:3478"), "alice", "secret")
+            .with_reservation_token(token);
+        let relayed = relay.allocate();
+        assert_eq!(relayed, addr("192.0.2.1:49153"));
+    }
+}
@@ -0,0 +1,220 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// A block of IP addresses, like 10.0.0.0/8.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct IpRange {
+    ip: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// The addresses sharing the first `prefix_len` bits with `ip`, None when the prefix is
+    /// longer than the address.
+    pub fn new(ip: IpAddr, prefix_len: u8) -> Option<IpRange> {
+        let bits = if ip.is_ipv4() { 32 } else { 128 };
+        if prefix_len > bits {
+            return None;
+        }
+        Some(IpRange { ip, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.ip, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                prefix_matches(&range.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                prefix_matches(&range.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(range: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let (bytes, bits) = ((prefix_len / 8) as usize, prefix_len % 8);
+    if range[..bytes] != ip[..bytes] {
+        return false;
+    }
+    bits == 0 || (range[bytes] ^ ip[bytes]) >> (8 - bits) == 0
+}
+
+/// Which peers a TURN server relays to, so that clients cannot reach the services of the
+/// relay's own network through it.
+///
+/// By default loopback, unspecified, link-local, private (RFC 1918 and IPv6 unique local),
+/// multicast and broadcast addresses are denied, IPv4-mapped IPv6 addresses by their IPv4
+/// address. So are NAT64 (64:ff9b::/96), 6to4 (2002::/16) and IPv4-compatible (::a.b.c.d)
+/// addresses, which reach the IPv4 address they embed. More ranges are denied with
+/// `with_denied`, `with_allowed` makes exceptions.
+#[derive(Debug, Clone)]
+pub struct PeerFilter {
+    deny_special: bool,
+    denied: Vec<IpRange>,
+    allowed: Vec<IpRange>,
+}
+
+impl Default for PeerFilter {
+    fn default() -> PeerFilter {
+        PeerFilter::new()
+    }
+}
+
+impl PeerFilter {
+    pub fn new() -> PeerFilter {
+        PeerFilter {
+            deny_special: true,
+            denied: vec![],
+            allowed: vec![],
+        }
+    }
+
+    /// A filter denying nothing but the ranges added with `with_denied`.
+    pub fn allow_all() -> PeerFilter {
+        PeerFilter {
+            deny_special: false,
+            denied: vec![],
+            allowed: vec![],
+        }
+    }
+
+    pub fn with_denied(mut self, range: IpRange) -> PeerFilter {
+        self.denied.push(range);
+        self
+    }
+
+    /// Allows the range even when it is denied otherwise, e.g. a private network the relay
+    /// serves on purpose.
+    pub fn with_allowed(mut self, range: IpRange) -> PeerFilter {
+        self.allowed.push(range);
+        self
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        if self.allowed.iter().any(|range| range.contains(ip)) {
+            return true;
+        }
+        let denied = (self.deny_special && is_special(ip))
+            || self.denied.iter().any(|range| range.contains(ip));
+        !denied
+    }
+}
+
+fn is_special(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_link_local()
+                || ip.is_private()
+                || ip.is_multicast()
+                || ip.is_broadcast()
+                // "this network" (RFC 1122)
+                || ip.octets()[0] == 0
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = embedded_ipv4(ip) {
+                return is_special(IpAddr::V4(v4));
+            }
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local fc00::/7 and link-local fe80::/10
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+// the IPv4 address of NAT64 (RFC 6052), 6to4 (RFC 3056) and IPv4-compatible addresses
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let (segments, octets) = (ip.segments(), ip.octets());
+    let ipv4 =
+        |at: usize| Ipv4Addr::new(octets[at], octets[at + 1], octets[at + 2], octets[at + 3]);
+    match segments {
+        [0x0064, 0xff9b, 0, 0, 0, 0, _, _] | [0, 0, 0, 0, 0, 0, _, _] => Some(ipv4(12)),
+        [0x2002, ..] => Some(ipv4(2)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use crate::turn::filter::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    pub fn test_ip_range_contains() {
+        let range = IpRange::new(ip("100.64.0.0"), 10).unwrap();
+        assert!(range.contains(ip("100.64.0.1")));
+        assert!(range.contains(ip("100.127.255.255")));
+        assert!(!range.contains(ip("100.128.0.0")));
+        assert!(!range.contains(ip("::ffff:100.64.0.1")));
+        assert!(IpRange::new(ip("0.0.0.0"), 0)
+            .unwrap()
+            .contains(ip("8.8.8.8")));
+        assert!(IpRange::new(ip("2001:db8::"), 32)
+            .unwrap()
+            .contains(ip("2001:db8:1::1")));
+        assert_eq!(IpRange::new(ip("10.0.0.0"), 33), None);
+    }
+
+    #[test]
+    pub fn test_deny_internal_peers() {
+        let filter = PeerFilter::new();
+        for denied in [
+            "127.0.0.1",
+            "0.0.0.0",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            // NAT64, 6to4 and IPv4-compatible addresses of 127.0.0.1 and 10.0.0.1
+            "64:ff9b::7f00:1",
+            "64:ff9b::a00:1",
+            "2002:7f00:1::",
+            "2002:a00:1::1",
+            "::127.0.0.1",
+            "::10.0.0.1",
+        ] {
+            assert!(!filter.is_allowed(ip(denied)), "{} is allowed", denied);
+        }
+        for allowed in [
+            "203.0.113.5",
+            "172.32.0.1",
+            "2001:db8::5",
+            "64:ff9b::cb00:7105",
+            "2002:cb00:7105::1",
+            "::203.0.113.5",
+        ] {
+            assert!(filter.is_allowed(ip(allowed)), "{} is denied", allowed);
+        }
+
+        let filter = PeerFilter::new()
+            .with_denied(IpRange::new(ip("203.0.113.0"), 24).unwrap())
+            .with_allowed(IpRange::new(ip("10.1.0.0"), 16).unwrap());
+        assert!(!filter.is_allowed(ip("203.0.113.5")));
+        assert!(filter.is_allowed(ip("10.1.2.3")));
+        assert!(!filter.is_allowed(ip("10.2.0.1")));
+        assert!(PeerFilter::allow_all().is_allowed(ip("127.0.0.1")));
+    }
+}
@@ -0,0 +1,2267 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
+
+use crate::codec::{append_message_integrity, long_term_key, verify_message_integrity};
+use crate::codec::{Decoder, Encoder};
+use crate::messages::*;
+use crate::server::error_response;
+use crate::turn::filter::PeerFilter;
+use crate::turn::transport::*;
+
+// lifetime of an allocation when the client does not ask for one (RFC 8656 section 2.2)
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(600);
+const MAX_LIFETIME: Duration = Duration::from_secs(3600);
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+const NONCE_LIFETIME: Duration = Duration::from_secs(600);
+// a Connect waits this long for the peer, an accepted peer connection for its ConnectionBind
+// (RFC 6062 section 5.2 and 5.3)
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+// the port after an even one is kept this long for the RESERVATION-TOKEN (RFC 8656 section 7.2)
+const RESERVATION_LIFETIME: Duration = Duration::from_secs(30);
+
+/// A connection of a client to the TURN server. TCP connections are told apart by it as well,
+/// so it identifies both the control and the data connections of RFC 6062.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash)]
+pub struct FiveTuple {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub transport: Transport,
+}
+
+/// Bytes to send from `source`, the server address of a client connection or a relayed address.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Transmit {
+    pub transport: Transport,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum TurnServerEvent {
+    // bind a socket on the relayed address, or listen on it for TCP allocations
+    Allocated {
+        relayed: SocketAddr,
+        transport: Transport,
+    },
+    // close the socket of the relayed address
+    Released {
+        relayed: SocketAddr,
+        transport: Transport,
+    },
+    // RFC 6062: connect from the relayed address to the peer, then call handle_peer_connected
+    Connect {
+        relayed: SocketAddr,
+        peer: SocketAddr,
+    },
+    // RFC 6062: from now on the bytes of the data connection and of the peer connection are
+    // forwarded unchanged, once the transmits queued before this event are sent
+    Bridge {
+        connection: FiveTuple,
+        relayed: SocketAddr,
+        peer: SocketAddr,
+    },
+    // RFC 6062: close the connection between the relayed address and the peer
+    ClosePeer {
+        relayed: SocketAddr,
+        peer: SocketAddr,
+    },
+}
+
+#[derive(Debug)]
+struct Channel {
+    peer: SocketAddr,
+    expires: Instant,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum ConnectionState {
+    // the IO layer connects to the peer for the Connect request of this transaction
+    Connecting(TransactionID),
+    // waits for the ConnectionBind of the client
+    Pending,
+    // bridged to a data connection
+    Bound,
+}
+
+// a TCP connection between the relayed address and a peer (RFC 6062)
+#[derive(Debug)]
+struct PeerConnection {
+    peer: SocketAddr,
+    state: ConnectionState,
+    deadline: Instant,
+}
+
+#[derive(Debug)]
+struct Allocation {
+    username: String,
+    key: [u8; 16],
+    // the Allocate request, retransmissions of it are answered again
+    transaction_id: TransactionID,
+    // one relayed address per address family, two for a dual allocation
+    relayed: Vec<SocketAddr>,
+    // the ADDRESS-ERROR-CODE of the additional address family which could not be allocated
+    address_error: Option<(IPKind, u32)>,
+    // the token of the port reserved after the relayed one, for EVEN-PORT with the R bit
+    reservation_token: Option<[u8; 8]>,
+    transport: Transport,
+    expires: Instant,
+    // permissions are installed per IP address of the peers
+    permissions: HashMap<IpAddr, Instant>,
+    channels: HashMap<u16, Channel>,
+    connections: HashMap<u32, PeerConnection>,
+    // the bytes relayed in both directions, unlimited without a bandwidth
+    rate_limit: Option<RateLimit>,
+}
+
+impl Allocation {
+    // the relayed address of the peer's address family, peers of other families are unreachable
+    fn relayed_for(&self, peer: SocketAddr) -> Option<SocketAddr> {
+        self.relayed
+            .iter()
+            .find(|relayed| relayed.is_ipv4() == peer.is_ipv4())
+            .copied()
+    }
+
+    fn has_permission(&self, now: Instant, peer: SocketAddr) -> bool {
+        self.permissions
+            .get(&peer.ip())
+            .is_some_and(|expires| *expires > now)
+    }
+
+    fn channel_of(&self, now: Instant, peer: SocketAddr) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|(_, channel)| channel.peer == peer && channel.expires > now)
+            .map(|(number, _)| *number)
+    }
+
+    // the attributes of a success response to the Allocate request
+    fn attributes(&self, lifetime: Duration, connection: &FiveTuple) -> Vec<Attribute> {
+        let mut attributes: Vec<Attribute> = self
+            .relayed
+            .iter()
+            .map(|relayed| Attribute::XorRelayedAddress(Address::from(*relayed)))
+            .collect();
+        if let Some((ip_kind, code)) = self.address_error {
+            attributes.push(Attribute::AddressErrorCode {
+                ip_kind,
+                code,
+                reason: reason_of(code).to_owned(),
+            });
+        }
+        if let Some(token) = self.reservation_token {
+            attributes.push(Attribute::ReservationToken(token));
+        }
+        attributes.push(Attribute::Lifetime(lifetime.as_secs() as u32));
+        attributes.push(Attribute::XorMappedAddress(Address::from(
+            connection.client,
+        )));
+        attributes
+    }
+
+    fn success(&self, request: &Message, attributes: Vec<Attribute>) -> Message {
+        Message {
+            message_class: MessageClass::SuccessResponse,
+            message_method: request.message_method,
+            transaction_id: request.transaction_id,
+            attributes,
+        }
+    }
+}
+
+// a token bucket of bytes, refilled at the rate and holding up to a second of them
+#[derive(Debug)]
+struct RateLimit {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimit {
+    fn new(now: Instant, bytes_per_second: u64) -> RateLimit {
+        RateLimit {
+            rate: bytes_per_second as f64,
+            tokens: bytes_per_second as f64,
+            updated: now,
+        }
+    }
+
+    // takes the bytes from the bucket, false when there are not enough of them
+    fn allow(&mut self, now: Instant, bytes: usize) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = self.updated.max(now);
+        if self.tokens < bytes as f64 {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
+    }
+}
+
+// a relayed address kept for the Allocate request with its token
+#[derive(Debug)]
+struct Reservation {
+    address: SocketAddr,
+    expires: Instant,
+}
+
+// the user a request is authenticated for
+struct User {
+    username: String,
+    key: [u8; 16],
+}
+
+/// A TURN server (RFC 8656) relaying UDP, and TCP to peers (RFC 6062), without any IO.
+///
+/// Requests are authenticated with long-term credentials. Nonces are not stored: each one
+/// carries the time it was issued at and an HMAC binding it to the client IP address, and it
+/// goes stale after 10 minutes.
+///
+/// The IO layer feeds what clients send to `handle_client_input` and what peers send to a
+/// relayed address to `handle_peer_input`, sends what `poll_transmit` returns, binds and closes
+/// the relayed sockets as `poll_event` tells and calls `handle_timeout` once the instant of
+/// `poll_timeout` is reached.
+///
+/// TCP streams are split into messages with `frame_length` before they are passed on.
+pub struct TurnServer {
+    realm: String,
+    users: HashMap<String, [u8; 16]>,
+    relay_ips: Vec<IpAddr>,
+    min_port: u16,
+    max_port: u16,
+    next_port: u16,
+    max_lifetime: Duration,
+    max_allocations: Option<usize>,
+    user_quota: Option<usize>,
+    ip_quota: Option<usize>,
+    bandwidth: Option<u64>,
+    peer_filter: PeerFilter,
+    decoder: Decoder,
+    // nonces are not stored, they carry the time they were issued at and an HMAC keyed with this
+    nonce_key: [u8; 20],
+    // the instant nonce timestamps count from, the first one a nonce is issued at
+    epoch: Option<Instant>,
+    allocations: HashMap<FiveTuple, Allocation>,
+    relayed: HashMap<SocketAddr, FiveTuple>,
+    reservations: HashMap<[u8; 8], Reservation>,
+    // the allocation of each RFC 6062 connection ID
+    connection_ids: HashMap<u32, FiveTuple>,
+    transmits: VecDeque<Transmit>,
+    events: VecDeque<TurnServerEvent>,
+}
+
+impl TurnServer {
+    pub fn new(realm: &str) -> TurnServer {
+        TurnServer {
+            realm: realm.to_owned(),
+            users: HashMap::new(),
+            relay_ips: vec![],
+            min_port: 49152,
+            max_port: 65535,
+            next_port: 49152,
+            max_lifetime: MAX_LIFETIME,
+            max_allocations: None,
+            user_quota: None,
+            ip_quota: None,
+            bandwidth: None,
+            peer_filter: PeerFilter::new(),
+            decoder: Decoder::new(),
+            nonce_key: rand::random(),
+            epoch: None,
+            allocations: HashMap::new(),
+            relayed: HashMap::new(),
+            reservations: HashMap::new(),
+            connection_ids: HashMap::new(),
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Adds a user of the long-term credentials, the password has to be processed with
+    /// SASLprep (RFC 4013) already.
+    pub fn with_user(mut self, username: &str, password: &str) -> TurnServer {
+        let key = long_term_key(username, &self.realm, password);
+        self.users.insert(username.to_owned(), key);
+        self
+    }
+
+    /// Adds an IP address the relayed addresses are allocated on, the first one of each address
+    /// family is used. Without an IPv6 one, IPv6 allocations fail with 440.
+    pub fn with_relay_ip(mut self, ip: IpAddr) -> TurnServer {
+        self.relay_ips.push(ip);
+        self
+    }
+
+    /// The ports relayed addresses are allocated from, 49152 to 65535 by default. They should
+    /// not be used by anything else on the relay IP addresses.
+    pub fn with_relay_ports(mut self, min_port: u16, max_port: u16) -> TurnServer {
+        self.min_port = min_port;
+        self.max_port = max_port.max(min_port);
+        self.next_port = min_port;
+        self
+    }
+
+    /// The longest lifetime of an allocation, one hour by default. Clients asking for less than
+    /// 10 minutes get 10 minutes.
+    pub fn with_max_lifetime(mut self, max_lifetime: Duration) -> TurnServer {
+        self.max_lifetime = max_lifetime.max(DEFAULT_LIFETIME);
+        self
+    }
+
+    /// The most allocations the server holds, further ones are refused with 508 (Insufficient
+    /// Capacity). Unlimited by default.
+    pub fn with_max_allocations(mut self, max_allocations: usize) -> TurnServer {
+        self.max_allocations = Some(max_allocations);
+        self
+    }
+
+    /// The most allocations of a user, further ones are refused with 486 (Allocation Quota
+    /// Reached). Unlimited by default.
+    pub fn with_user_quota(mut self, allocations: usize) -> TurnServer {
+        self.user_quota = Some(allocations);
+        self
+    }
+
+    /// The most allocations of the clients on an IP address, further ones are refused with 486
+    /// (Allocation Quota Reached). Unlimited by default.
+    pub fn with_ip_quota(mut self, allocations: usize) -> TurnServer {
+        self.ip_quota = Some(allocations);
+        self
+    }
+
+    /// The bytes per second an allocation relays to and from its peers, in bursts of up to a
+    /// second. What goes beyond is dropped. Unlimited by default, the bytes of bridged RFC 6062
+    /// connections are forwarded by the IO layer and not counted.
+    pub fn with_bandwidth(mut self, bytes_per_second: u64) -> TurnServer {
+        self.bandwidth = Some(bytes_per_second);
+        self
+    }
+
+    /// The peers permissions, channels and connections can be made for, others are refused
+    /// with 403 (Forbidden). `PeerFilter::new()` by default, which denies the loopback,
+    /// link-local, private and multicast addresses.
+    pub fn with_peer_filter(mut self, peer_filter: PeerFilter) -> TurnServer {
+        self.peer_filter = peer_filter;
+        self
+    }
+
+    pub fn realm(&self) -> &str {
+        &self.realm
+    }
+
+    pub fn allocations(&self) -> usize {
+        self.allocations.len()
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<TurnServerEvent> {
+        self.events.pop_front()
+    }
+
+    /// The instant `handle_timeout` should be called at.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.allocations
+            .values()
+            .flat_map(|allocation| {
+                let connections = allocation
+                    .connections
+                    .values()
+                    .filter(|connection| connection.state != ConnectionState::Bound)
+                    .map(|connection| connection.deadline);
+                std::iter::once(allocation.expires).chain(connections)
+            })
+            .chain(self.reservations.values().map(|reserved| reserved.expires))
+            .min()
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        let expired: Vec<FiveTuple> = self
+            .allocations
+            .iter()
+            .filter(|(_, allocation)| allocation.expires <= now)
+            .map(|(connection, _)| *connection)
+            .collect();
+        for connection in expired {
+            self.release(&connection);
+        }
+        self.reservations
+            .retain(|_, reserved| reserved.expires > now);
+
+        let mut failed_connects = vec![];
+        for (connection, allocation) in self.allocations.iter_mut() {
+            allocation.permissions.retain(|_, expires| *expires > now);
+            allocation
+                .channels
+                .retain(|_, channel| channel.expires > now);
+            let timed_out: Vec<u32> = allocation
+                .connections
+                .iter()
+                .filter(|(_, peer)| peer.state != ConnectionState::Bound && peer.deadline <= now)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in timed_out {
+                let peer = allocation.connections.remove(&id).unwrap();
+                self.connection_ids.remove(&id);
+                if let ConnectionState::Connecting(transaction_id) = peer.state {
+                    failed_connects.push((*connection, allocation.key, transaction_id));
+                }
+                if let Some(relayed) = allocation.relayed_for(peer.peer) {
+                    self.events.push_back(TurnServerEvent::ClosePeer {
+                        relayed,
+                        peer: peer.peer,
+                    });
+                }
+            }
+        }
+        for (connection, key, transaction_id) in failed_connects {
+            let response = connect_failure(transaction_id);
+            self.respond(&connection, &response, Some(&key));
+        }
+    }
+
+    /// Handles a STUN or ChannelData message a client sent on the connection.
+    pub fn handle_client_input(&mut self, now: Instant, connection: FiveTuple, data: &[u8]) {
+        if is_channel_data(data) {
+            if let Some(message) = ChannelData::decode(data) {
+                self.handle_channel_data(now, &connection, message);
+            }
+            return;
+        }
+        let message = match self.decoder.decode(&mut &data[..]) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        match message.message_class {
+            MessageClass::Request => self.handle_request(now, connection, data, &message),
+            MessageClass::Indication if message.message_method == MessageMethod::Send => {
+                self.handle_send(now, &connection, &message)
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles a datagram a peer sent to a UDP relayed address.
+    pub fn handle_peer_input(
+        &mut self,
+        now: Instant,
+        relayed: SocketAddr,
+        peer: SocketAddr,
+        data: &[u8],
+    ) {
+        let connection = match self.relayed.get(&relayed) {
+            Some(connection) => *connection,
+            None => return,
+        };
+        let allocation = self.allocations.get_mut(&connection).unwrap();
+        if allocation.transport != Transport::Udp || !allocation.has_permission(now, peer) {
+            return;
+        }
+        if let Some(rate_limit) = allocation.rate_limit.as_mut() {
+            if !rate_limit.allow(now, data.len()) {
+                return;
+            }
+        }
+        let bytes = match allocation.channel_of(now, peer) {
+            Some(channel) => ChannelData {
+                channel,
+                data: data.to_vec(),
+            }
+            .encode(),
+            None => encode(&Message {
+                message_class: MessageClass::Indication,
+                message_method: MessageMethod::Data,
+                transaction_id: TransactionID::random(),
+                attributes: vec![
+                    Attribute::XorPeerAddress(Address::from(peer)),
+                    Attribute::Data(data.to_vec()),
+                ],
+            })
+            .to_vec(),
+        };
+        self.send_to_client(&connection, bytes);
+    }
+
+    /// RFC 6062: the connection to the peer of a `Connect` event was established or failed.
+    pub fn handle_peer_connected(
+        &mut self,
+        now: Instant,
+        relayed: SocketAddr,
+        peer: SocketAddr,
+        connected: bool,
+    ) {
+        let connection = match self.relayed.get(&relayed) {
+            Some(connection) => *connection,
+            None => return,
+        };
+        let allocation = self.allocations.get_mut(&connection).unwrap();
+        let connecting = allocation
+            .connections
+            .iter()
+            .find_map(|(id, c)| match c.state {
+                ConnectionState::Connecting(transaction_id) if c.peer == peer => {
+                    Some((*id, transaction_id))
+                }
+                _ => None,
+            });
+        let (id, transaction_id) = match connecting {
+            Some(connecting) => connecting,
+            None => return,
+        };
+        let key = allocation.key;
+        let response = if connected {
+            let peer_connection = allocation.connections.get_mut(&id).unwrap();
+            peer_connection.state = ConnectionState::Pending;
+            peer_connection.deadline = now + CONNECTION_TIMEOUT;
+            Message {
+                message_class: MessageClass::SuccessResponse,
+                message_method: MessageMethod::Connect,
+                transaction_id,
+                attributes: vec![Attribute::ConnectionId(id)],
+            }
+        } else {
+            allocation.connections.remove(&id);
+            self.connection_ids.remove(&id);
+            connect_failure(transaction_id)
+        };
+        self.respond(&connection, &response, Some(&key));
+    }
+
+    /// RFC 6062: a peer connected to a TCP relayed address, false when the connection has to be
+    /// refused. Otherwise the client is told with a ConnectionAttempt indication.
+    pub fn handle_peer_connection(
+        &mut self,
+        now: Instant,
+        relayed: SocketAddr,
+        peer: SocketAddr,
+    ) -> bool {
+        let connection = match self.relayed.get(&relayed) {
+            Some(connection) => *connection,
+            None => return false,
+        };
+        let allocation = &self.allocations[&connection];
+        if allocation.transport != Transport::Tcp
+            || !allocation.has_permission(now, peer)
+            || allocation.connections.values().any(|c| c.peer == peer)
+        {
+            return false;
+        }
+        let id = self.add_peer_connection(&connection, peer, ConnectionState::Pending, now);
+        let indication = Message {
+            message_class: MessageClass::Indication,
+            message_method: MessageMethod::ConnectionAttempt,
+            transaction_id: TransactionID::random(),
+            attributes: vec![
+                Attribute::XorPeerAddress(Address::from(peer)),
+                Attribute::ConnectionId(id),
+            ],
+        };
+        self.respond(&connection, &indication, None);
+        true
+    }
+
+    /// RFC 6062: the connection between the relayed address and the peer was closed.
+    pub fn handle_peer_closed(&mut self, relayed: SocketAddr, peer: SocketAddr) {
+        let connection = match self.relayed.get(&relayed) {
+            Some(connection) => *connection,
+            None => return,
+        };
+        let allocation = self.allocations.get_mut(&connection).unwrap();
+        let ids: Vec<u32> = allocation
+            .connections
+            .iter()
+            .filter(|(_, c)| c.peer == peer)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            allocation.connections.remove(&id);
+            self.connection_ids.remove(&id);
+        }
+    }
+
+    /// A TCP connection of a client was closed, closing the control connection releases its
+    /// allocation.
+    pub fn handle_client_closed(&mut self, connection: FiveTuple) {
+        if self.allocations.contains_key(&connection) {
+            self.release(&connection);
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        now: Instant,
+        connection: FiveTuple,
+        data: &[u8],
+        request: &Message,
+    ) {
+        let user = match self.authenticate(now, connection.client.ip(), request, data) {
+            Ok(user) => user,
+            Err(response) => {
+                self.respond(&connection, &response, None);
+                return;
+            }
+        };
+        let response = match request.message_method {
+            MessageMethod::Allocate => Some(self.allocate(now, connection, request, &user)),
+            MessageMethod::Refresh => Some(self.refresh(now, &connection, request, &user)),
+            MessageMethod::CreatePermission => {
+                Some(self.create_permission(now, &connection, request, &user))
+            }
+            MessageMethod::ChannelBind => Some(self.channel_bind(now, &connection, request, &user)),
+            MessageMethod::Connect => self.connect(now, &connection, request, &user),
+            MessageMethod::ConnectionBind => {
+                Some(self.connection_bind(&connection, request, &user))
+            }
+            _ => Some(error_response(request, 400, "Bad Request")),
+        };
+        if let Some(response) = response {
+            self.respond(&connection, &response, Some(&user.key));
+        }
+    }
+
+    // long-term credentials (RFC 8489 section 9.2), the error response is sent unprotected
+    fn authenticate(
+        &mut self,
+        now: Instant,
+        client: IpAddr,
+        request: &Message,
+        data: &[u8],
+    ) -> Result<User, Message> {
+        let mut username = None;
+        let mut realm = None;
+        let mut nonce = None;
+        let mut integrity = false;
+        for attribute in &request.attributes {
+            match attribute {
+                Attribute::UserName(value) => username = Some(value),
+                Attribute::Realm(value) => realm = Some(value),
+                Attribute::Nonce(value) => nonce = Some(value),
+                Attribute::MessageIntegrity(_) => integrity = true,
+                _ => {}
+            }
+        }
+        if !integrity {
+            return Err(self.challenge(now, client, request, 401, "Unauthorized"));
+        }
+        let (username, realm, nonce) = match (username, realm, nonce) {
+            (Some(username), Some(realm), Some(nonce)) => (username, realm, nonce),
+            _ => return Err(error_response(request, 400, "Bad Request")),
+        };
+        if !self.is_fresh_nonce(now, client, nonce) {
+            return Err(self.challenge(now, client, request, 438, "Stale Nonce"));
+        }
+        match self.users.get(username) {
+            Some(key) if *realm == self.realm && verify_message_integrity(data, key) => Ok(User {
+                username: username.clone(),
+                key: *key,
+            }),
+            _ => Err(self.challenge(now, client, request, 401, "Unauthorized")),
+        }
+    }
+
+    // an error response with the realm and a fresh nonce to authenticate with
+    fn challenge(
+        &mut self,
+        now: Instant,
+        client: IpAddr,
+        request: &Message,
+        code: u32,
+        reason: &str,
+    ) -> Message {
+        let epoch = *self.epoch.get_or_insert(now);
+        let issued = now.saturating_duration_since(epoch).as_secs();
+        let hmac = self.nonce_mac(issued, client).finalize().into_bytes();
+        let mut nonce = format!("{:016x}", issued);
+        for byte in hmac.iter() {
+            nonce.push_str(&format!("{:02x}", byte));
+        }
+        let mut response = error_response(request, code, reason);
+        response
+            .attributes
+            .push(Attribute::Realm(self.realm.clone()));
+        response.attributes.push(Attribute::Nonce(nonce));
+        response
+    }
+
+    // whether the nonce was issued to the client IP address within NONCE_LIFETIME
+    fn is_fresh_nonce(&self, now: Instant, client: IpAddr, nonce: &str) -> bool {
+        let epoch = match self.epoch {
+            Some(epoch) => epoch,
+            None => return false,
+        };
+        if nonce.len() != 16 + 40 {
+            return false;
+        }
+        let issued = match nonce
+            .get(..16)
+            .map(|issued| u64::from_str_radix(issued, 16))
+        {
+            Some(Ok(issued)) => issued,
+            _ => return false,
+        };
+        let hmac: Option<Vec<u8>> = (16..nonce.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(nonce.get(i..i + 2)?, 16).ok())
+            .collect();
+        match hmac {
+            Some(hmac) if self.nonce_mac(issued, client).verify(&hmac).is_ok() => {
+                now.saturating_duration_since(epoch) < Duration::from_secs(issued) + NONCE_LIFETIME
+            }
+            _ => false,
+        }
+    }
+
+    fn nonce_mac(&self, issued: u64, client: IpAddr) -> Hmac<Sha1> {
+        let mut mac =
+            Hmac::<Sha1>::new_varkey(&self.nonce_key).expect("HMAC accepts keys of any size");
+        mac.update(&issued.to_be_bytes());
+        match client {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac
+    }
+
+    fn allocate(
+        &mut self,
+        now: Instant,
+        connection: FiveTuple,
+        request: &Message,
+        user: &User,
+    ) -> Message {
+        if let Some(allocation) = self.allocations.get(&connection) {
+            // a retransmission of the request is answered again
+            if allocation.transaction_id == request.transaction_id {
+                let lifetime = allocation.expires.saturating_duration_since(now);
+                return allocation.success(request, allocation.attributes(lifetime, &connection));
+            }
+            return error_response(request, 437, "Allocation Mismatch");
+        }
+
+        let mut transport = None;
+        let mut lifetime = None;
+        let mut family = None;
+        let mut additional_family = None;
+        let mut even_port = None;
+        let mut token = None;
+        for attribute in &request.attributes {
+            match attribute {
+                Attribute::RequestedTransport(protocol) => transport = Some(*protocol),
+                Attribute::Lifetime(seconds) => lifetime = Some(*seconds),
+                Attribute::RequestedAddressFamily(kind) => family = Some(*kind),
+                Attribute::AdditionalAddressFamily(kind) => additional_family = Some(*kind),
+                Attribute::EvenPort(reserve) => even_port = Some(*reserve),
+                Attribute::ReservationToken(value) => token = Some(*value),
+                Attribute::DontFragment => {
+                    let mut response = error_response(request, 420, "Unknown Attribute");
+                    response
+                        .attributes
+                        .push(Attribute::UnknownAttributes(vec![0x001A]));
+                    return response;
+                }
+                _ => {}
+            }
+        }
+        let transport = match transport.map(Transport::from_protocol) {
+            Some(Some(transport)) => transport,
+            Some(None) => return error_response(request, 442, "Unsupported Transport Protocol"),
+            None => return error_response(request, 400, "Bad Request"),
+        };
+        // TCP allocations are made over TCP control connections (RFC 6062 section 5.1)
+        if transport == Transport::Tcp && connection.transport != Transport::Tcp {
+            return error_response(request, 400, "Bad Request");
+        }
+        // a dual allocation adds an IPv6 relayed address to the IPv4 one, a reserved address
+        // already has its family (RFC 8656 section 7.2)
+        let invalid = (family.is_some() && additional_family.is_some())
+            || additional_family == Some(IPKind::IPv4)
+            || (token.is_some()
+                && (family.is_some() || additional_family.is_some() || even_port.is_some()));
+        if invalid {
+            return error_response(request, 400, "Bad Request");
+        }
+        if let Some(code) = self.over_quota(&connection, user) {
+            return error_response(request, code, reason_of(code));
+        }
+        let (mut relayed, reservation_token) = match token {
+            Some(token) => match self.reservations.remove(&token) {
+                Some(reserved) if reserved.expires > now => (vec![reserved.address], None),
+                _ => return error_response(request, 508, "Insufficient Capacity"),
+            },
+            None => match self.allocate_address(now, family.unwrap_or(IPKind::IPv4), even_port) {
+                Ok((relayed, token)) => (vec![relayed], token),
+                Err(code) => return error_response(request, code, reason_of(code)),
+            },
+        };
+        // the allocation succeeds without the additional address family
+        let mut address_error = None;
+        if let Some(additional_family) = additional_family {
+            match self.allocate_address(now, additional_family, None) {
+                Ok((address, _)) => relayed.push(address),
+                Err(code) => address_error = Some((additional_family, code)),
+            }
+        }
+
+        let lifetime = self.lifetime(lifetime);
+        let allocation = Allocation {
+            username: user.username.clone(),
+            key: user.key,
+            transaction_id: request.transaction_id,
+            relayed,
+            address_error,
+            reservation_token,
+            transport,
+            expires: now + lifetime,
+            permissions: HashMap::new(),
+            channels: HashMap::new(),
+            connections: HashMap::new(),
+            rate_limit: self
+                .bandwidth
+                .map(|bytes_per_second| RateLimit::new(now, bytes_per_second)),
+        };
+        let response = allocation.success(request, allocation.attributes(lifetime, &connection));
+        for relayed in &allocation.relayed {
+            self.relayed.insert(*relayed, connection);
+            self.events.push_back(TurnServerEvent::Allocated {
+                relayed: *relayed,
+                transport,
+            });
+        }
+        self.allocations.insert(connection, allocation);
+        response
+    }
+
+    fn refresh(
+        &mut self,
+        now: Instant,
+        connection: &FiveTuple,
+        request: &Message,
+        user: &User,
+    ) -> Message {
+        let requested = request
+            .attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                Attribute::Lifetime(seconds) => Some(*seconds),
+                _ => None,
+            });
+        let family = request
+            .attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                Attribute::RequestedAddressFamily(kind) => Some(*kind),
+                _ => None,
+            });
+        let allocation = match self.allocation_of(connection, request, user) {
+            Ok(allocation) => allocation,
+            Err(response) => return response,
+        };
+        let has_family = |kind: IPKind| {
+            allocation
+                .relayed
+                .iter()
+                .any(|relayed| IPKind::from(relayed.ip()) == kind)
+        };
+        if family.is_some_and(|kind| !has_family(kind)) {
+            return error_response(request, 443, "Peer Address Family Mismatch");
+        }
+        if requested == Some(0) {
+            let response = allocation.success(request, vec![Attribute::Lifetime(0)]);
+            self.release(connection);
+            return response;
+        }
+        let lifetime = self.lifetime(requested);
+        let allocation = self.allocations.get_mut(connection).unwrap();
+        allocation.expires = now + lifetime;
+        allocation.success(
+            request,
+            vec![Attribute::Lifetime(lifetime.as_secs() as u32)],
+        )
+    }
+
+    fn create_permission(
+        &mut self,
+        now: Instant,
+        connection: &FiveTuple,
+        request: &Message,
+        user: &User,
+    ) -> Message {
+        let peers = peer_addresses(request);
+        if peers.is_empty() {
+            return error_response(request, 400, "Bad Request");
+        }
+        if !peers
+            .iter()
+            .all(|peer| self.peer_filter.is_allowed(peer.ip()))
+        {
+            return error_response(request, 403, "Forbidden");
+        }
+        let allocation = match self.allocation_of(connection, request, user) {
+            Ok(allocation) => allocation,
+            Err(response) => return response,
+        };
+        if peers
+            .iter()
+            .any(|peer| allocation.relayed_for(*peer).is_none())
+        {
+            return error_response(request, 443, "Peer Address Family Mismatch");
+        }
+        for peer in peers {
+            allocation
+                .permissions
+                .insert(peer.ip(), now + PERMISSION_LIFETIME);
+        }
+        allocation.success(request, vec![])
+    }
+
+    fn channel_bind(
+        &mut self,
+        now: Instant,
+        connection: &FiveTuple,
+        request: &Message,
+        user: &User,
+    ) -> Message {
+        let channel = request
+            .attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                Attribute::ChannelNumber(channel) => Some(*channel),
+                _ => None,
+            });
+        let (channel, peer) = match (channel, peer_addresses(request).first()) {
+            (Some(channel), Some(peer)) if (MIN_CHANNEL..=MAX_CHANNEL).contains(&channel) => {
+                (channel, *peer)
+            }
+            _ => return error_response(request, 400, "Bad Request"),
+        };
+        if !self.peer_filter.is_allowed(peer.ip()) {
+            return error_response(request, 403, "Forbidden");
+        }
+        let allocation = match self.allocation_of(connection, request, user) {
+            Ok(allocation) => allocation,
+            Err(response) => return response,
+        };
+        if allocation.transport != Transport::Udp {
+            return error_response(request, 400, "Bad Request");
+        }
+        if allocation.relayed_for(peer).is_none() {
+            return error_response(request, 443, "Peer Address Family Mismatch");
+        }
+        // a channel stays bound to its peer, and a peer to its channel
+        let bound_elsewhere = allocation
+            .channels
+            .iter()
+            .any(|(number, bound)| (*number == channel) != (bound.peer == peer));
+        if bound_elsewhere {
+            return error_response(request, 400, "Bad Request");
+        }
+        allocation.channels.insert(
+            channel,
+            Channel {
+                peer,
+                expires: now + CHANNEL_LIFETIME,
+            },
+        );
+        allocation
+            .permissions
+            .insert(peer.ip(), now + PERMISSION_LIFETIME);
+        allocation.success(request, vec![])
+    }
+
+    // RFC 6062 section 5.2, the response waits for the connection to the peer
+    fn connect(
+        &mut self,
+        now: Instant,
+        connection: &FiveTuple,
+        request: &Message,
+        user: &User,
+    ) -> Option<Message> {
+        let peer = match peer_addresses(request).first() {
+            Some(peer) => *peer,
+            None => return Some(error_response(request, 400, "Bad Request")),
+        };
+        if !self.peer_filter.is_allowed(peer.ip()) {
+            return Some(error_response(request, 403, "Forbidden"));
+        }
+        let allocation = match self.allocation_of(connection, request, user) {
+            Ok(allocation) => allocation,
+            Err(response) => return Some(response),
+        };
+        if allocation.transport != Transport::Tcp {
+            return Some(error_response(request, 400, "Bad Request"));
+        }
+        let relayed = match allocation.relayed_for(peer) {
+            Some(relayed) => relayed,
+            None => return Some(error_response(request, 443, "Peer Address Family Mismatch")),
+        };
+        if allocation.connections.values().any(|c| c.peer == peer) {
+            return Some(error_response(request, 446, "Connection Already Exists"));
+        }
+        allocation
+            .permissions
+            .insert(peer.ip(), now + PERMISSION_LIFETIME);
+        let state = ConnectionState::Connecting(request.transaction_id);
+        self.add_peer_connection(connection, peer, state, now);
+        self.events
+            .push_back(TurnServerEvent::Connect { relayed, peer });
+        None
+    }
+
+    // RFC 6062 section 5.4, sent on a new data connection which has no allocation
+    fn connection_bind(
+        &mut self,
+        connection: &FiveTuple,
+        request: &Message,
+        user: &User,
+    ) -> Message {
+        let id = request
+            .attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                Attribute::ConnectionId(id) => Some(*id),
+                _ => None,
+            });
+        let control = match id.and_then(|id| self.connection_ids.get(&id)) {
+            Some(control) if connection.transport == Transport::Tcp => *control,
+            _ => return error_response(request, 400, "Bad Request"),
+        };
+        let allocation = self.allocations.get_mut(&control).unwrap();
+        if allocation.username != user.username {
+            return error_response(request, 441, "Wrong Credentials");
+        }
+        let peer_connection = allocation.connections.get_mut(&id.unwrap()).unwrap();
+        if peer_connection.state != ConnectionState::Pending {
+            return error_response(request, 400, "Bad Request");
+        }
+        peer_connection.state = ConnectionState::Bound;
+        let peer = peer_connection.peer;
+        if let Some(relayed) = allocation.relayed_for(peer) {
+            self.events.push_back(TurnServerEvent::Bridge {
+                connection: *connection,
+                relayed,
+                peer,
+            });
+        }
+        allocation.success(request, vec![])
+    }
+
+    fn handle_send(&mut self, now: Instant, connection: &FiveTuple, indication: &Message) {
+        let peer = peer_addresses(indication).first().copied();
+        let data = indication
+            .attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                Attribute::Data(data) => Some(data),
+                _ => None,
+            });
+        let udp = self
+            .allocations
+            .get(connection)
+            .is_some_and(|allocation| allocation.transport == Transport::Udp);
+        if !udp {
+            return;
+        }
+        if let (Some(peer), Some(data)) = (peer, data) {
+            self.relay_to_peer(now, connection, peer, data.clone());
+        }
+    }
+
+    fn handle_channel_data(&mut self, now: Instant, connection: &FiveTuple, message: ChannelData) {
+        let allocation = match self.allocations.get(connection) {
+            Some(allocation) => allocation,
+            None => return,
+        };
+        let peer = match allocation.channels.get(&message.channel) {
+            Some(channel) if channel.expires > now => channel.peer,
+            _ => return,
+        };
+        self.relay_to_peer(now, connection, peer, message.data);
+    }
+
+    // sends the data of the client from the relayed address of the peer's family, it is dropped
+    // without a permission, without such an address and beyond the bandwidth
+    fn relay_to_peer(
+        &mut self,
+        now: Instant,
+        connection: &FiveTuple,
+        peer: SocketAddr,
+        data: Vec<u8>,
+    ) {
+        let allocation = self.allocations.get_mut(connection).unwrap();
+        let relayed = match allocation.relayed_for(peer) {
+            Some(relayed) if allocation.has_permission(now, peer) => relayed,
+            _ => return,
+        };
+        if let Some(rate_limit) = allocation.rate_limit.as_mut() {
+            if !rate_limit.allow(now, data.len()) {
+                return;
+            }
+        }
+        self.transmits.push_back(Transmit {
+            transport: Transport::Udp,
+            source: relayed,
+            destination: peer,
+            data,
+        });
+    }
+
+    // the allocation of the connection, requests on it have to come from the same user
+    fn allocation_of(
+        &mut self,
+        connection: &FiveTuple,
+        request: &Message,
+        user: &User,
+    ) -> Result<&mut Allocation, Message> {
+        match self.allocations.get_mut(connection) {
+            Some(allocation) if allocation.username == user.username => Ok(allocation),
+            Some(_) => Err(error_response(request, 441, "Wrong Credentials")),
+            None => Err(error_response(request, 437, "Allocation Mismatch")),
+        }
+    }
+
+    // 508 when the server is full, 486 when the user or the client's IP address is
+    fn over_quota(&self, connection: &FiveTuple, user: &User) -> Option<u32> {
+        if self
+            .max_allocations
+            .is_some_and(|max| self.allocations.len() >= max)
+        {
+            return Some(508);
+        }
+        let user_allocations = self
+            .allocations
+            .values()
+            .filter(|allocation| allocation.username == user.username)
+            .count();
+        let ip_allocations = self
+            .allocations
+            .keys()
+            .filter(|other| other.client.ip() == connection.client.ip())
+            .count();
+        if self
+            .user_quota
+            .is_some_and(|quota| user_allocations >= quota)
+            || self.ip_quota.is_some_and(|quota| ip_allocations >= quota)
+        {
+            return Some(486);
+        }
+        None
+    }
+
+    fn lifetime(&self, requested: Option<u32>) -> Duration {
+        match requested {
+            Some(seconds) => Duration::from_secs(seconds as u64)
+                .max(DEFAULT_LIFETIME)
+                .min(self.max_lifetime),
+            None => DEFAULT_LIFETIME,
+        }
+    }
+
+    // a relayed address of the address family with the token of the port reserved after it,
+    // or the error code when there is none
+    fn allocate_address(
+        &mut self,
+        now: Instant,
+        family: IPKind,
+        even_port: Option<bool>,
+    ) -> Result<(SocketAddr, Option<[u8; 8]>), u32> {
+        let ip = match self
+            .relay_ips
+            .iter()
+            .find(|ip| IPKind::from(**ip) == family)
+        {
+            Some(ip) => *ip,
+            None => return Err(440),
+        };
+        let relayed = self.allocate_port(ip, even_port).ok_or(508u32)?;
+        if even_port != Some(true) {
+            return Ok((relayed, None));
+        }
+        let mut token: [u8; 8] = rand::random();
+        while self.reservations.contains_key(&token) {
+            token = rand::random();
+        }
+        self.reservations.insert(
+            token,
+            Reservation {
+                address: SocketAddr::new(ip, relayed.port() + 1),
+                expires: now + RESERVATION_LIFETIME,
+            },
+        );
+        Ok((relayed, Some(token)))
+    }
+
+    // the next free port of the range on the IP address, None when all of them are taken. With
+    // EVEN-PORT the port is even, and the next one is free as well when it is to be reserved.
+    fn allocate_port(&mut self, ip: IpAddr, even_port: Option<bool>) -> Option<SocketAddr> {
+        let ports = (self.max_port - self.min_port) as u32 + 1;
+        for _ in 0..ports {
+            let port = self.next_port;
+            self.next_port = if port == self.max_port {
+                self.min_port
+            } else {
+                port + 1
+            };
+            let free = match even_port {
+                None => self.is_free(SocketAddr::new(ip, port)),
+                Some(_) if port % 2 == 1 => false,
+                Some(false) => self.is_free(SocketAddr::new(ip, port)),
+                Some(true) => {
+                    port < self.max_port
+                        && self.is_free(SocketAddr::new(ip, port))
+                        && self.is_free(SocketAddr::new(ip, port + 1))
+                }
+            };
+            if free {
+                return Some(SocketAddr::new(ip, port));
+            }
+        }
+        None
+    }
+
+    fn is_free(&self, address: SocketAddr) -> bool {
+        !self.relayed.contains_key(&address)
+            && !self
+                .reservations
+                .values()
+                .any(|reserved| reserved.address == address)
+    }
+
+    fn add_peer_connection(
+        &mut self,
+        connection: &FiveTuple,
+        peer: SocketAddr,
+        state: ConnectionState,
+        now: Instant,
+    ) -> u32 {
+        let mut id = rand::random();
+        while self.connection_ids.contains_key(&id) {
+            id = rand::random();
+        }
+        self.connection_ids.insert(id, *connection);
+        self.allocations
+            .get_mut(connection)
+            .unwrap()
+            .connections
+            .insert(
+                id,
+                PeerConnection {
+                    peer,
+                    state,
+                    deadline: now + CONNECTION_TIMEOUT,
+                },
+            );
+        id
+    }
+
+    fn release(&mut self, connection: &FiveTuple) {
+        let allocation = match self.allocations.remove(connection) {
+            Some(allocation) => allocation,
+            None => return,
+        };
+        for (id, peer) in &allocation.connections {
+            self.connection_ids.remove(id);
+            if let Some(relayed) = allocation.relayed_for(peer.peer) {
+                self.events.push_back(TurnServerEvent::ClosePeer {
+                    relayed,
+                    peer: peer.peer,
+                });
+            }
+        }
+        for relayed in allocation.relayed {
+            self.relayed.remove(&relayed);
+            self.events.push_back(TurnServerEvent::Released {
+                relayed,
+                transport: allocation.transport,
+            });
+        }
+    }
+
+    // encodes the message, with MESSAGE-INTEGRITY when there is a key
+    fn respond(&mut self, connection: &FiveTuple, message: &Message, key: Option<&[u8; 16]>) {
+        let mut bytes = encode(message);
+        if let Some(key) = key {
+            append_message_integrity(&mut bytes, key);
+        }
+        self.send_to_client(connection, bytes.to_vec());
+    }
+
+    fn send_to_client(&mut self, connection: &FiveTuple, data: Vec<u8>) {
+        self.transmits.push_back(Transmit {
+            transport: connection.transport,
+            source: connection.server,
+            destination: connection.client,
+            data,
+        });
+    }
+}
+
+// the server only builds messages of built-in attributes
+fn encode(message: &Message) -> BytesMut {
+    let mut bytes = BytesMut::new();
+    Encoder::new()
+        .encode(message, &mut bytes)
+        .expect("built-in attributes always encode");
+    bytes
+}
+
+fn peer_addresses(message: &Message) -> Vec<SocketAddr> {
+    message
+        .attributes
+        .iter()
+        .filter_map(|attribute| match attribute {
+            Attribute::XorPeerAddress(address) => Some(address.to_socket_addr()),
+            _ => None,
+        })
+        .collect()
+}
+
+// the reason phrase of the error codes allocate_address and over_quota return
+fn reason_of(code: u32) -> &'static str {
+    match code {
+        440 => "Address Family not Supported",
+        486 => "Allocation Quota Reached",
+        508 => "Insufficient Capacity",
+        _ => "Error",
+    }
+}
+
+fn connect_failure(transaction_id: TransactionID) -> Message {
+    Message {
+        message_class: MessageClass::FailureResponse,
+        message_method: MessageMethod::Connect,
+        transaction_id,
+        attributes: vec![Attribute::ErrorCode {
+            code: 447,
+            reason: "Connection Timeout or Failure".to_owned(),
+        }],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use bytes::BytesMut;
+
+    use crate::codec::{append_message_integrity, long_term_key, verify_message_integrity};
+    use crate::codec::{Decoder, Encoder};
+    use crate::messages::*;
+    use crate::turn::*;
+
+    const REALM: &str = "example.org";
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn connection(client: &str, transport: Transport) -> FiveTuple {
+        FiveTuple {
+            client: addr(client),
+            server: addr("192.0.2.1:3478"),
+            transport,
+        }
+    }
+
+    fn turn_server() -> TurnServer {
+        TurnServer::new(REALM)
+            .with_user("alice", "secret")
+            .with_relay_ip("192.0.2.1".parse().unwrap())
+            .with_relay_ports(50000, 50001)
+    }
+
+    fn key() -> [u8; 16] {
+        long_term_key("alice", REALM, "secret")
+    }
+
+    fn request(method: MessageMethod, attributes: Vec<Attribute>) -> Message {
+        Message {
+            message_class: MessageClass::Request,
+            message_method: method,
+            transaction_id: TransactionID::random(),
+            attributes,
+        }
+    }
+
+    fn encode(message: &Message) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        Encoder::new().encode(message, &mut bytes).unwrap();
+        bytes
+    }
+
+    // the request authenticated as alice with the nonce
+    fn protect(mut message: Message, nonce: &str, key: &[u8; 16]) -> BytesMut {
+        message.attributes.extend(vec![
+            Attribute::UserName("alice".to_owned()),
+            Attribute::Realm(REALM.to_owned()),
+            Attribute::Nonce(nonce.to_owned()),
+        ]);
+        let mut bytes = encode(&message);
+        append_message_integrity(&mut bytes, key);
+        bytes
+    }
+
+    // the next message the server sends to the client of the connection
+    fn receive(server: &mut TurnServer, connection: FiveTuple) -> Message {
+        let transmit = server.poll_transmit().unwrap();
+        assert_eq!(transmit.transport, connection.transport);
+        assert_eq!(transmit.source, connection.server);
+        assert_eq!(transmit.destination, connection.client);
+        Decoder::new().decode(&mut &transmit.data[..]).unwrap()
+    }
+
+    fn exchange(
+        server: &mut TurnServer,
+        now: Instant,
+        connection: FiveTuple,
+        nonce: &str,
+        request: Message,
+    ) -> Message {
+        server.handle_client_input(now, connection, &protect(request, nonce, &key()));
+        receive(server, connection)
+    }
+
+    fn error_code(message: &Message) -> Option<u32> {
+        message
+            .attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                Attribute::ErrorCode { code, .. } => Some(*code),
+                _ => None,
+            })
+    }
+
+    fn nonce_of(message: &Message) -> String {
+        message
+            .attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                Attribute::Nonce(nonce) => Some(nonce.clone()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn relayed_of(message: &Message) -> SocketAddr {
+        message
+            .attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                Attribute::XorRelayedAddress(address) => Some(address.to_socket_addr()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    // the nonce the server challenges an unauthenticated request on the connection with
+    fn challenge(server: &mut TurnServer, now: Instant, connection: FiveTuple) -> String {
+        let unauthenticated = request(
+            MessageMethod::Allocate,
+            vec![Attribute::RequestedTransport(17)],
+        );
+        server.handle_client_input(now, connection, &encode(&unauthenticated));
+        nonce_of(&receive(server, connection))
+    }
+
+    // allocates for alice on the connection, returns the nonce and the relayed address
+    fn allocate(
+        server: &mut TurnServer,
+        now: Instant,
+        connection: FiveTuple,
+        transport: Transport,
+    ) -> (String, SocketAddr) {
+        let allocate = || vec![Attribute::RequestedTransport(transport.protocol())];
+        let nonce = challenge(server, now, connection);
+        let response = exchange(
+            server,
+            now,
+            connection,
+            &nonce,
+            request(MessageMethod::Allocate, allocate()),
+        );
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+        (nonce, relayed_of(&response))
+    }
+
+    #[test]
+    pub fn test_allocate_with_long_term_credentials() {
+        let now = Instant::now();
+        let mut server = turn_server();
+        let client = connection("198.51.100.1:5000", Transport::Udp);
+        let transaction_id = TransactionID::from([1u8; 12]);
+        let allocate = |transaction_id| Message {
+            message_class: MessageClass::Request,
+            message_method: MessageMethod::Allocate,
+            transaction_id,
+            attributes: vec![Attribute::RequestedTransport(17)],
+        };
+
+        // the request without credentials is challenged with the realm and a nonce
+        server.handle_client_input(now, client, &encode(&allocate(transaction_id)));
+        let challenge = receive(&mut server, client);
+        assert_eq!(challenge.message_class, MessageClass::FailureResponse);
+        assert_eq!(error_code(&challenge), Some(401));
+        assert!(challenge
+            .attributes
+            .contains(&Attribute::Realm(REALM.to_owned())));
+        let nonce = nonce_of(&challenge);
+
+        // a wrong password and an unknown nonce are rejected
+        let wrong_key = long_term_key("alice", REALM, "wrong");
+        let bytes = protect(allocate(transaction_id), &nonce, &wrong_key);
+        server.handle_client_input(now, client, &bytes);
+        assert_eq!(error_code(&receive(&mut server, client)), Some(401));
+        let stale = exchange(&mut server, now, client, "stale", allocate(transaction_id));
+        assert_eq!(error_code(&stale), Some(438));
+        assert_eq!(server.allocations(), 0);
+
+        let bytes = protect(allocate(transaction_id), &nonce, &key());
+        server.handle_client_input(now, client, &bytes);
+        let transmit = server.poll_transmit().unwrap();
+        assert!(verify_message_integrity(&transmit.data, &key()));
+        let response = Decoder::new().decode(&mut &transmit.data[..]).unwrap();
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+        let relayed = addr("192.0.2.1:50000");
+        assert_eq!(
+            response.attributes[..3],
+            [
+                Attribute::XorRelayedAddress(Address::from(relayed)),
+                Attribute::Lifetime(600),
+                Attribute::XorMappedAddress(Address::from(client.client)),
+            ]
+        );
+        assert_eq!(
+            server.poll_event(),
+            Some(TurnServerEvent::Allocated {
+                relayed,
+                transport: Transport::Udp
+            })
+        );
+
+        // a retransmission is answered again, another Allocate on the connection is refused
+        server.handle_client_input(now, client, &bytes);
+        assert_eq!(relayed_of(&receive(&mut server, client)), relayed);
+        let again = allocate(TransactionID::random());
+        let again = exchange(&mut server, now, client, &nonce, again);
+        assert_eq!(error_code(&again), Some(437));
+        assert_eq!(server.allocations(), 1);
+        assert_eq!(server.poll_event(), None);
+    }
+
+    #[test]
+    pub fn test_nonces_are_bound_to_the_client_and_expire() {
+        let now = Instant::now();
+        let mut server = turn_server();
+        let client = connection("198.51.100.1:5000", Transport::Udp);
+        let nonce = challenge(&mut server, now, client);
+        let allocate = || {
+            request(
+                MessageMethod::Allocate,
+                vec![Attribute::RequestedTransport(17)],
+            )
+        };
+
+        // the nonce of another IP address, or with a changed timestamp, is stale
+        let other = connection("198.51.100.2:5000", Transport::Udp);
+        let response = exchange(&mut server, now, other, &nonce, allocate());
+        assert_eq!(error_code(&response), Some(438));
+        let forged = format!("{:016x}{}", 600, &nonce[16..]);
+        let response = exchange(&mut server, now, client, &forged, allocate());
+        assert_eq!(error_code(&response), Some(438));
+
+        // another port of the same IP address can use it, e.g. an RFC 6062 data connection
+        let port = connection("198.51.100.1:5001", Transport::Udp);
+        let response = exchange(&mut server, now, port, &nonce, allocate());
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+
+        // it expires after 10 minutes, the 438 carries a fresh one
+        let later = now + Duration::from_secs(600);
+        let response = exchange(&mut server, later, client, &nonce, allocate());
+        assert_eq!(error_code(&response), Some(438));
+        let fresh = nonce_of(&response);
+        assert_ne!(fresh, nonce);
+        let response = exchange(&mut server, later, client, &fresh, allocate());
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+    }
+
+    #[test]
+    pub fn test_reject_invalid_allocations() {
+        let now = Instant::now();
+        let mut server = turn_server();
+        let client = connection("198.51.100.1:5000", Transport::Udp);
+        let (nonce, _) = allocate(&mut server, now, client, Transport::Udp);
+
+        for (attributes, code) in [
+            (vec![], 400),
+            (vec![Attribute::RequestedTransport(132)], 442),
+            // TCP allocations need a TCP control connection
+            (vec![Attribute::RequestedTransport(6)], 400),
+            (
+                vec![Attribute::RequestedTransport(17), Attribute::DontFragment],
+                420,
+            ),
+        ] {
+            let other = connection("198.51.100.1:5001", Transport::Udp);
+            let allocate = request(MessageMethod::Allocate, attributes);
+            let response = exchange(&mut server, now, other, &nonce, allocate);
+            assert_eq!(error_code(&response), Some(code));
+        }
+
+        // two ports are configured, the third allocation finds none
+        let second = connection("198.51.100.2:5000", Transport::Udp);
+        let allocate = || {
+            request(
+                MessageMethod::Allocate,
+                vec![Attribute::RequestedTransport(17)],
+            )
+        };
+        let nonce = challenge(&mut server, now, second);
+        let response = exchange(&mut server, now, second, &nonce, allocate());
+        assert_eq!(relayed_of(&response), addr("192.0.2.1:50001"));
+        let third = connection("198.51.100.3:5000", Transport::Udp);
+        let nonce = challenge(&mut server, now, third);
+        let response = exchange(&mut server, now, third, &nonce, allocate());
+        assert_eq!(error_code(&response), Some(508));
+    }
+
+    #[test]
+    pub fn test_relay_through_permissions_and_channels() {
+        let now = Instant::now();
+        let mut server = turn_server();
+        let client = connection("198.51.100.1:5000", Transport::Udp);
+        let (nonce, relayed) = allocate(&mut server, now, client, Transport::Udp);
+        let peer = addr("203.0.113.5:7000");
+
+        // nothing is relayed before a permission is installed
+        let send = Message {
+            message_class: MessageClass::Indication,
+            message_method: MessageMethod::Send,
+            transaction_id: TransactionID::random(),
+            attributes: vec![
+                Attribute::XorPeerAddress(Address::from(peer)),
+                Attribute::Data(b"ping".to_vec()),
+            ],
+        };
+        server.handle_client_input(now, client, &encode(&send));
+        server.handle_peer_input(now, relayed, peer, b"pong");
+        assert_eq!(server.poll_transmit(), None);
+
+        let permission = request(
+            MessageMethod::CreatePermission,
+            vec![Attribute::XorPeerAddress(Address::from(peer))],
+        );
+        let response = exchange(&mut server, now, client, &nonce, permission);
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+
+        server.handle_client_input(now, client, &encode(&send));
+        assert_eq!(
+            server.poll_transmit(),
+            Some(Transmit {
+                transport: Transport::Udp,
+                source: relayed,
+                destination: peer,
+                data: b"ping".to_vec(),
+            })
+        );
+        // the permission covers every port of the peer IP address
+        let other_port = addr("203.0.113.5:7001");
+        server.handle_peer_input(now, relayed, other_port, b"pong");
+        let data = receive(&mut server, client);
+        assert_eq!(data.message_class, MessageClass::Indication);
+        assert_eq!(data.message_method, MessageMethod::Data);
+        assert_eq!(
+            data.attributes,
+            vec![
+                Attribute::XorPeerAddress(Address::from(other_port)),
+                Attribute::Data(b"pong".to_vec()),
+            ]
+        );
+        server.handle_peer_input(now, relayed, addr("203.0.113.6:7000"), b"pong");
+        assert_eq!(server.poll_transmit(), None);
+
+        // channels carry the data without STUN headers
+        let bind = request(
+            MessageMethod::ChannelBind,
+            vec![
+                Attribute::ChannelNumber(0x4000),
+                Attribute::XorPeerAddress(Address::from(peer)),
+            ],
+        );
+        let response = exchange(&mut server, now, client, &nonce, bind);
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+        let channel_data = ChannelData {
+            channel: 0x4000,
+            data: b"ping".to_vec(),
+        };
+        server.handle_client_input(now, client, &channel_data.encode());
+        assert_eq!(server.poll_transmit().unwrap().data, b"ping".to_vec());
+        server.handle_peer_input(now, relayed, peer, b"pong");
+        let transmit = server.poll_transmit().unwrap();
+        assert_eq!(
+            ChannelData::decode(&transmit.data),
+            Some(ChannelData {
+                channel: 0x4000,
+                data: b"pong".to_vec()
+            })
+        );
+
+        // the channel cannot be bound to another peer, nor the peer to another channel
+        for (channel, peer) in &[(0x4000, other_port), (0x4001, peer)] {
+            let bind = request(
+                MessageMethod::ChannelBind,
+                vec![
+                    Attribute::ChannelNumber(*channel),
+                    Attribute::XorPeerAddress(Address::from(*peer)),
+                ],
+            );
+            let response = exchange(&mut server, now, client, &nonce, bind);
+            assert_eq!(error_code(&response), Some(400));
+        }
+
+        // permissions expire after 5 minutes unless refreshed
+        let later = now + Duration::from_secs(301);
+        server.handle_timeout(later);
+        server.handle_client_input(later, client, &channel_data.encode());
+        server.handle_client_input(later, client, &encode(&send));
+        assert_eq!(server.poll_transmit(), None);
+    }
+
+    #[test]
+    pub fn test_refresh_and_expire_allocations() {
+        let now = Instant::now();
+        let mut server = turn_server();
+        let client = connection("198.51.100.1:5000", Transport::Udp);
+        let (nonce, relayed) = allocate(&mut server, now, client, Transport::Udp);
+        server.poll_event();
+        assert_eq!(server.poll_timeout(), Some(now + Duration::from_secs(600)));
+
+        // lifetimes are capped at one hour
+        let refresh = request(MessageMethod::Refresh, vec![Attribute::Lifetime(86400)]);
+        let response = exchange(&mut server, now, client, &nonce, refresh);
+        assert!(response.attributes.contains(&Attribute::Lifetime(3600)));
+        assert_eq!(server.poll_timeout(), Some(now + Duration::from_secs(3600)));
+        server.handle_timeout(now + Duration::from_secs(3599));
+        assert_eq!(server.allocations(), 1);
+        server.handle_timeout(now + Duration::from_secs(3600));
+        assert_eq!(server.allocations(), 0);
+        assert_eq!(
+            server.poll_event(),
+            Some(TurnServerEvent::Released {
+                relayed,
+                transport: Transport::Udp
+            })
+        );
+
+        // a lifetime of 0 deletes the allocation, refreshing it afterwards fails
+        let (nonce, _) = allocate(&mut server, now, client, Transport::Udp);
+        let refresh = request(MessageMethod::Refresh, vec![Attribute::Lifetime(0)]);
+        let response = exchange(&mut server, now, client, &nonce, refresh);
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+        assert_eq!(server.allocations(), 0);
+        let refresh = request(MessageMethod::Refresh, vec![]);
+        let response = exchange(&mut server, now, client, &nonce, refresh);
+        assert_eq!(error_code(&response), Some(437));
+    }
+
+    #[test]
+    pub fn test_connect_to_peer_over_tcp() {
+        let now = Instant::now();
+        let mut server = turn_server();
+        let control = connection("198.51.100.1:5000", Transport::Tcp);
+        let (nonce, relayed) = allocate(&mut server, now, control, Transport::Tcp);
+        assert_eq!(
+            server.poll_event(),
+            Some(TurnServerEvent::Allocated {
+                relayed,
+                transport: Transport::Tcp
+            })
+        );
+        let peer = addr("203.0.113.5:443");
+
+        // the response waits until the IO layer connected to the peer
+        let connect = request(
+            MessageMethod::Connect,
+            vec![Attribute::XorPeerAddress(Address::from(peer))],
+        );
+        let transaction_id = connect.transaction_id;
+        server.handle_client_input(now, control, &protect(connect, &nonce, &key()));
+        assert_eq!(server.poll_transmit(), None);
+        assert_eq!(
+            server.poll_event(),
+            Some(TurnServerEvent::Connect { relayed, peer })
+        );
+        let again = request(
+            MessageMethod::Connect,
+            vec![Attribute::XorPeerAddress(Address::from(peer))],
+        );
+        let response = exchange(&mut server, now, control, &nonce, again);
+        assert_eq!(error_code(&response), Some(446));
+
+        server.handle_peer_connected(now, relayed, peer, true);
+        let response = receive(&mut server, control);
+        assert_eq!(response.transaction_id, transaction_id);
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+        let id = match response.attributes[0] {
+            Attribute::ConnectionId(id) => id,
+            ref attribute => panic!("unexpected attribute {:?}", attribute),
+        };
+
+        // the client binds a new data connection to the peer connection
+        let data = connection("198.51.100.1:5001", Transport::Tcp);
+        let bind = request(
+            MessageMethod::ConnectionBind,
+            vec![Attribute::ConnectionId(id)],
+        );
+        let response = exchange(&mut server, now, data, &nonce, bind);
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+        assert_eq!(
+            server.poll_event(),
+            Some(TurnServerEvent::Bridge {
+                connection: data,
+                relayed,
+                peer
+            })
+        );
+        let bind = request(
+            MessageMethod::ConnectionBind,
+            vec![Attribute::ConnectionId(id)],
+        );
+        let other = connection("198.51.100.1:5002", Transport::Tcp);
+        let response = exchange(&mut server, now, other, &nonce, bind);
+        assert_eq!(error_code(&response), Some(400));
+
+        // a failed connection is reported with 447, so is a peer which does not answer
+        for (peer, connected, at) in &[
+            (addr("203.0.113.6:443"), Some(false), now),
+            (addr("203.0.113.7:443"), None, now + Duration::from_secs(30)),
+        ] {
+            let connect = request(
+                MessageMethod::Connect,
+                vec![Attribute::XorPeerAddress(Address::from(*peer))],
+            );
+            server.handle_client_input(now, control, &protect(connect, &nonce, &key()));
+            server.poll_event();
+            match connected {
+                Some(connected) => server.handle_peer_connected(now, relayed, *peer, *connected),
+                None => server.handle_timeout(*at),
+            }
+            assert_eq!(error_code(&receive(&mut server, control)), Some(447));
+        }
+
+        // closing the control connection releases the allocation and its peer connections
+        server.poll_event();
+        server.handle_client_closed(control);
+        assert_eq!(
+            server.poll_event(),
+            Some(TurnServerEvent::ClosePeer { relayed, peer })
+        );
+        assert_eq!(
+            server.poll_event(),
+            Some(TurnServerEvent::Released {
+                relayed,
+                transport: Transport::Tcp
+            })
+        );
+    }
+
+    #[test]
+    pub fn test_accept_peer_connection_over_tcp() {
+        let now = Instant::now();
+        let mut server = turn_server();
+        let control = connection("198.51.100.1:5000", Transport::Tcp);
+        let (nonce, relayed) = allocate(&mut server, now, control, Transport::Tcp);
+        server.poll_event();
+        let peer = addr("203.0.113.5:40000");
+
+        // peers without a permission are refused
+        assert!(!server.handle_peer_connection(now, relayed, peer));
+        let permission = request(
+            MessageMethod::CreatePermission,
+            vec![Attribute::XorPeerAddress(Address::from(peer))],
+        );
+        exchange(&mut server, now, control, &nonce, permission);
+
+        assert!(server.handle_peer_connection(now, relayed, peer));
+        let attempt = receive(&mut server, control);
+        assert_eq!(attempt.message_class, MessageClass::Indication);
+        assert_eq!(attempt.message_method, MessageMethod::ConnectionAttempt);
+        assert_eq!(
+            attempt.attributes[0],
+            Attribute::XorPeerAddress(Address::from(peer))
+        );
+
+        // the connection is closed when the client does not bind it within 30 seconds
+        assert_eq!(server.poll_timeout(), Some(now + Duration::from_secs(30)));
+        server.handle_timeout(now + Duration::from_secs(30));
+        assert_eq!(
+            server.poll_event(),
+            Some(TurnServerEvent::ClosePeer { relayed, peer })
+        );
+    }
+
+    #[test]
+    pub fn test_dual_stack_allocations() {
+        let now = Instant::now();
+        let mut server = turn_server();
+        let client = connection("198.51.100.1:5000", Transport::Udp);
+        let (nonce, _) = allocate(&mut server, now, client, Transport::Udp);
+        let allocate_with = |attributes: Vec<Attribute>| {
+            let mut all = vec![Attribute::RequestedTransport(17)];
+            all.extend(attributes);
+            request(MessageMethod::Allocate, all)
+        };
+        let relayed_addresses = |message: &Message| -> Vec<SocketAddr> {
+            message
+                .attributes
+                .iter()
+                .filter_map(|attribute| match attribute {
+                    Attribute::XorRelayedAddress(address) => Some(address.to_socket_addr()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // without an IPv6 relay IP only IPv4 allocations succeed
+        for (port, attributes, code) in [
+            (
+                5001,
+                vec![Attribute::RequestedAddressFamily(IPKind::IPv6)],
+                440,
+            ),
+            (
+                5002,
+                vec![Attribute::AdditionalAddressFamily(IPKind::IPv4)],
+                400,
+            ),
+            (
+                5003,
+                vec![
+                    Attribute::RequestedAddressFamily(IPKind::IPv4),
+                    Attribute::AdditionalAddressFamily(IPKind::IPv6),
+                ],
+                400,
+            ),
+        ] {
+            let client = connection(&format!("198.51.100.1:{}", port), Transport::Udp);
+            let response = exchange(&mut server, now, client, &nonce, allocate_with(attributes));
+            assert_eq!(error_code(&response), Some(code));
+        }
+        // a dual allocation gets the IPv4 relayed address and the error of the IPv6 one
+        let client = connection("198.51.100.1:5004", Transport::Udp);
+        let additional = vec![Attribute::AdditionalAddressFamily(IPKind::IPv6)];
+        let response = exchange(&mut server, now, client, &nonce, allocate_with(additional));
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+        assert_eq!(relayed_addresses(&response), vec![addr("192.0.2.1:50001")]);
+        assert!(response.attributes.contains(&Attribute::AddressErrorCode {
+            ip_kind: IPKind::IPv6,
+            code: 440,
+            reason: "Address Family not Supported".to_owned(),
+        }));
+
+        let mut server = turn_server().with_relay_ip("2001:db8::1".parse().unwrap());
+        let (nonce, _) = allocate(&mut server, now, client, Transport::Udp);
+        let dual = connection("198.51.100.1:5005", Transport::Udp);
+        let additional = vec![Attribute::AdditionalAddressFamily(IPKind::IPv6)];
+        let response = exchange(&mut server, now, dual, &nonce, allocate_with(additional));
+        let relayed = relayed_addresses(&response);
+        assert_eq!(
+            relayed,
+            vec![addr("192.0.2.1:50001"), addr("[2001:db8::1]:50000")]
+        );
+        let events: Vec<TurnServerEvent> = std::iter::from_fn(|| server.poll_event()).collect();
+        assert_eq!(events.len(), 3);
+
+        // datagrams to a peer leave from the relayed address of its family
+        let peers = [addr("203.0.113.5:7000"), addr("[2001:db8::5]:7000")];
+        let permission = request(
+            MessageMethod::CreatePermission,
+            peers
+                .iter()
+                .map(|peer| Attribute::XorPeerAddress(Address::from(*peer)))
+                .collect(),
+        );
+        let response = exchange(&mut server, now, dual, &nonce, permission);
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+        for (peer, relayed) in peers.iter().zip(relayed.iter()) {
+            let send = Message {
+                message_class: MessageClass::Indication,
+                message_method: MessageMethod::Send,
+                transaction_id: TransactionID::random(),
+                attributes: vec![
+                    Attribute::XorPeerAddress(Address::from(*peer)),
+                    Attribute::Data(b"ping".to_vec()),
+                ],
+            };
+            server.handle_client_input(now, dual, &encode(&send));
+            let transmit = server.poll_transmit().unwrap();
+            assert_eq!((transmit.source, transmit.destination), (*relayed, *peer));
+        }
+
+        // an IPv6 allocation cannot reach IPv4 peers
+        let ipv6 = connection("198.51.100.1:5006", Transport::Udp);
+        let family = vec![Attribute::RequestedAddressFamily(IPKind::IPv6)];
+        let response = exchange(&mut server, now, ipv6, &nonce, allocate_with(family));
+        assert_eq!(
+            relayed_addresses(&response),
+            vec![addr("[2001:db8::1]:50001")]
+        );
+        for method in [MessageMethod::CreatePermission, MessageMethod::ChannelBind] {
+            let attributes = vec![
+                Attribute::ChannelNumber(0x4000),
+                Attribute::XorPeerAddress(Address::from(peers[0])),
+            ];
+            let response = exchange(&mut server, now, ipv6, &nonce, request(method, attributes));
+            assert_eq!(error_code(&response), Some(443));
+        }
+        let refresh = request(
+            MessageMethod::Refresh,
+            vec![Attribute::RequestedAddressFamily(IPKind::IPv4)],
+        );
+        let response = exchange(&mut server, now, ipv6, &nonce, refresh);
+        assert_eq!(error_code(&response), Some(443));
+    }
+
+    #[test]
+    pub fn test_reserve_even_port_pairs() {
+        let now = Instant::now();
+        let allocate_on =
+            |server: &mut TurnServer, nonce: &str, port: u16, attributes: Vec<Attribute>| {
+                let mut all = vec![Attribute::RequestedTransport(17)];
+                all.extend(attributes);
+                let client = connection(&format!("198.51.100.1:{}", port), Transport::Udp);
+                exchange(
+                    server,
+                    now,
+                    client,
+                    nonce,
+                    request(MessageMethod::Allocate, all),
+                )
+            };
+        let token_of = |message: &Message| {
+            message
+                .attributes
+                .iter()
+                .find_map(|attribute| match attribute {
+                    Attribute::ReservationToken(token) => Some(*token),
+                    _ => None,
+                })
+        };
+        let mut server = turn_server().with_relay_ports(50001, 50005);
+        let client = connection("198.51.100.1:5000", Transport::Udp);
+        let (nonce, relayed) = allocate(&mut server, now, client, Transport::Udp);
+        assert_eq!(relayed, addr("192.0.2.1:50001"));
+
+        // an even port, with the next one reserved for the token
+        let response = allocate_on(&mut server, &nonce, 5001, vec![Attribute::EvenPort(true)]);
+        assert_eq!(relayed_of(&response), addr("192.0.2.1:50002"));
+        let token = token_of(&response).unwrap();
+        assert_eq!(server.poll_timeout(), Some(now + Duration::from_secs(30)));
+
+        // the reserved port is skipped, no even port is left
+        let response = allocate_on(&mut server, &nonce, 5002, vec![]);
+        assert_eq!(relayed_of(&response), addr("192.0.2.1:50004"));
+        assert_eq!(token_of(&response), None);
+        let response = allocate_on(&mut server, &nonce, 5003, vec![Attribute::EvenPort(false)]);
+        assert_eq!(error_code(&response), Some(508));
+
+        // the token cannot be combined with EVEN-PORT, and is used once
+        let attributes = vec![
+            Attribute::ReservationToken(token),
+            Attribute::EvenPort(false),
+        ];
+        let response = allocate_on(&mut server, &nonce, 5004, attributes);
+        assert_eq!(error_code(&response), Some(400));
+        let attributes = vec![Attribute::ReservationToken(token)];
+        let response = allocate_on(&mut server, &nonce, 5004, attributes);
+        assert_eq!(relayed_of(&response), addr("192.0.2.1:50003"));
+        let attributes = vec![Attribute::ReservationToken(token)];
+        let response = allocate_on(&mut server, &nonce, 5005, attributes);
+        assert_eq!(error_code(&response), Some(508));
+
+        // the reserved port is free again once the reservation expired
+        let mut server = turn_server().with_relay_ports(50000, 50003);
+        let (nonce, _) = allocate(&mut server, now, client, Transport::Udp);
+        let response = allocate_on(&mut server, &nonce, 5001, vec![Attribute::EvenPort(true)]);
+        assert_eq!(relayed_of(&response), addr("192.0.2.1:50002"));
+        let response = allocate_on(&mut server, &nonce, 5002, vec![]);
+        assert_eq!(relayed_of(&response), addr("192.0.2.1:50001"));
+        let response = allocate_on(&mut server, &nonce, 5003, vec![]);
+        assert_eq!(error_code(&response), Some(508));
+        server.handle_timeout(now + Duration::from_secs(30));
+        let response = allocate_on(&mut server, &nonce, 5003, vec![]);
+        assert_eq!(relayed_of(&response), addr("192.0.2.1:50003"));
+    }
+
+    #[test]
+    pub fn test_allocation_quotas() {
+        let now = Instant::now();
+        let mut server = turn_server()
+            .with_user("bob", "secret")
+            .with_relay_ports(50000, 50010)
+            .with_user_quota(2)
+            .with_ip_quota(2)
+            .with_max_allocations(4);
+        let alice = connection("198.51.100.1:5000", Transport::Udp);
+        let (nonce, _) = allocate(&mut server, now, alice, Transport::Udp);
+        let allocate_as = |server: &mut TurnServer, username: &str, client: &str| {
+            let client = connection(client, Transport::Udp);
+            let message = request(
+                MessageMethod::Allocate,
+                vec![
+                    Attribute::RequestedTransport(17),
+                    Attribute::UserName(username.to_owned()),
+                    Attribute::Realm(REALM.to_owned()),
+                    Attribute::Nonce(challenge(server, now, client)),
+                ],
+            );
+            let mut bytes = encode(&message);
+            append_message_integrity(&mut bytes, &long_term_key(username, REALM, "secret"));
+            server.handle_client_input(now, client, &bytes);
+            let response = receive(server, client);
+            error_code(&response)
+        };
+
+        // two allocations per user and per IP address, four in all
+        assert_eq!(allocate_as(&mut server, "alice", "198.51.100.2:5000"), None);
+        assert_eq!(
+            allocate_as(&mut server, "alice", "198.51.100.3:5000"),
+            Some(486)
+        );
+        assert_eq!(allocate_as(&mut server, "bob", "198.51.100.1:5001"), None);
+        assert_eq!(
+            allocate_as(&mut server, "bob", "198.51.100.1:5002"),
+            Some(486)
+        );
+        assert_eq!(allocate_as(&mut server, "bob", "198.51.100.4:5000"), None);
+        assert_eq!(
+            allocate_as(&mut server, "bob", "198.51.100.5:5000"),
+            Some(508)
+        );
+        assert_eq!(server.allocations(), 4);
+
+        // deleting an allocation makes room for another one
+        let delete = request(MessageMethod::Refresh, vec![Attribute::Lifetime(0)]);
+        let response = exchange(&mut server, now, alice, &nonce, delete);
+        assert_eq!(response.message_class, MessageClass::SuccessResponse);
+        assert_eq!(allocate_as(&mut server, "alice", "198.51.100.3:5000"), None);
+    }
+
+    #[test]
+    pub fn test_limit_relayed_bytes() {
+        let now = Instant::now();
+        let mut server = turn_server().with_bandwidth(1000);
+        let client = connection("198.51.100.1:5000", Transport::Udp);
+        let (nonce, relayed) = allocate(&mut server, now, client, Transport::Udp);
+        let peer = addr("203.0.113.5:7000");
+        let permission = request(
+            MessageMethod::CreatePermission,
+            vec![Attribute::XorPeerAddress(Address::from(peer))],
+        );
+        exchange(&mut server, now, client, &nonce, permission);
+        let send = Message {
+            message_class: MessageClass::Indication,
+            message_method: MessageMethod::Send,
+            transaction_id: TransactionID::random(),
+            attributes: vec![
+                Attribute::XorPeerAddress(Address::from(peer)),
+                Attribute::Data(vec![0; 600]),
+            ],
+        };
+        let relayed_bytes = |server: &mut TurnServer, at: Duration, to_peer: bool| {
+            if to_peer {
+                server.handle_client_input(now + at, client, &encode(&send));
+            } else {
+                server.handle_peer_input(now + at, relayed, peer, &[0; 600]);
+            }
+            server.poll_transmit().is_some()
+        };
+
+        // a second of bytes at once, then the rate, in both directions together
+        assert!(relayed_bytes(&mut server, Duration::from_millis(0), true));
+        assert!(!relayed_bytes(&mut server, Duration::from_millis(0), true));
+        assert!(relayed_bytes(&mut server, Duration::from_millis(500), true));
+        assert!(!relayed_bytes(
+            &mut server,
+            Duration::from_millis(500),
+            false
+        ));
+        assert!(relayed_bytes(&mut server, Duration::from_secs(2), false));
+    }
+
+    #[test]
+    pub fn test_refuse_filtered_peers() {
+        let now = Instant::now();
+        let filter = PeerFilter::new()
+            .with_denied(IpRange::new("203.0.113.0".parse().unwrap(), 24).unwrap());
+        let mut server = turn_server().with_peer_filter(filter);
+        let client = connection("198.51.100.1:5000", Transport::Udp);
+        let (nonce, relayed) = allocate(&mut server, now, client, Transport::Udp);
+
+        for peer in [
+            "127.0.0.1:22",
+            "10.0.0.1:80",
+            "169.254.169.254:80",
+            "203.0.113.5:7000",
+        ] {
+            let peer = Attribute::XorPeerAddress(Address::from(addr(peer)));
+            let permission = request(MessageMethod::CreatePermission, vec![peer]);
+            let response = exchange(&mut server, now, client, &nonce, permission);
+            assert_eq!(error_code(&response), Some(403));
+        }
+        let bind = request(
+            MessageMethod::ChannelBind,
+            vec![
+                Attribute::ChannelNumber(0x4000),
+                Attribute::XorPeerAddress(Address::from(addr("[::ffff:192.168.0.1]:80"))),
+            ],
+        );
+        let response = exchange(&mut server, now, client, &nonce, bind);
+        assert_eq!(error_code(&response), Some(403));
+
+        // a denied peer in the list refuses all of them
+        let permission = request(
+            MessageMethod::CreatePermission,
+            vec![
+                Attribute::XorPeerAddress(Address::from(addr("198.51.100.7:7000"))),
+                Attribute::XorPeerAddress(Address::from(addr("192.168.0.1:7000"))),
+            ],
+        );
+        let response = exchange(&mut server, now, client, &nonce, permission);
+        assert_eq!(error_code(&response), Some(403));
+        server.handle_peer_input(now, relayed, addr("198.51.100.7:7000"), b"pong");
+        assert_eq!(server.poll_transmit(), None);
+
+        // so are RFC 6062 connections
+        let control = connection("198.51.100.1:5001", Transport::Tcp);
+        let (nonce, _) = allocate(&mut server, now, control, Transport::Tcp);
+        let connect = request(
+            MessageMethod::Connect,
+            vec![Attribute::XorPeerAddress(Address::from(addr(
+                "10.0.0.1:80",
+            )))],
+        );
+        let response = exchange(&mut server, now, control, &nonce, connect);
+        assert_eq!(error_code(&response), Some(403));
+        assert!(std::iter::from_fn(|| server.poll_event())
+            .all(|event| !matches!(event, TurnServerEvent::Connect { .. })));
+    }
+
+    #[test]
+    pub fn test_reason_of() {
+        use super::*;
+        assert_eq!(reason_of(440), "Address Family not Supported");
+        assert_eq!(reason_of(486), "Allocation Quota Reached");
+        assert_eq!(reason_of(508), "Insufficient Capacity");
+        assert_eq!(reason_of(500), "Error");
+    }
+}
@@ -0,0 +1,128 @@
+use std::convert::TryInto;
+
+// channel numbers a client can bind (RFC 8656 section 12)
+pub const MIN_CHANNEL: u16 = 0x4000;
+pub const MAX_CHANNEL: u16 = 0x4FFF;
+
+/// The transport protocol of a connection to the TURN server or of a relayed address.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Hash)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl Transport {
+    /// The protocol number carried by REQUESTED-TRANSPORT.
+    pub fn protocol(&self) -> u8 {
+        match self {
+            Transport::Udp => 17,
+            Transport::Tcp => 6,
+        }
+    }
+
+    pub fn from_protocol(protocol: u8) -> Option<Transport> {
+        match protocol {
+            17 => Some(Transport::Udp),
+            6 => Some(Transport::Tcp),
+            _ => None,
+        }
+    }
+}
+
+/// Application data relayed through a channel, its 4 bytes header replaces the STUN header and
+/// the XOR-PEER-ADDRESS of Send and Data indications.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct ChannelData {
+    pub channel: u16,
+    pub data: Vec<u8>,
+}
+
+impl ChannelData {
+    /// Encodes the message padded to 4 bytes, as required over TCP and allowed over UDP.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.data.len() + 3);
+        bytes.extend_from_slice(&self.channel.to_be_bytes());
+        bytes.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes.resize(4 + channel_data_length(self.data.len()), 0);
+        bytes
+    }
+
+    /// Decodes a ChannelData message, None when the channel number is out of range or the data
+    /// is shorter than its length.
+    pub fn decode(bytes: &[u8]) -> Option<ChannelData> {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let channel = u16::from_be_bytes(bytes[..2].try_into().unwrap());
+        let length = u16::from_be_bytes(bytes[2..4].try_into().unwrap()) as usize;
+        if !(MIN_CHANNEL..=MAX_CHANNEL).contains(&channel) || bytes.len() < 4 + length {
+            return None;
+        }
+        Some(ChannelData {
+            channel,
+            data: bytes[4..4 + length].to_vec(),
+        })
+    }
+}
+
+/// Whether the bytes start a ChannelData message rather than a STUN message, which starts with
+/// two zero bits.
+pub fn is_channel_data(bytes: &[u8]) -> bool {
+    !bytes.is_empty() && bytes[0] & 0xC0 == 0x40
+}
+
+/// The size of the STUN or ChannelData message at the start of a stream, None until its header
+/// has been received.
+///
+/// Messages over TCP are not delimited, the IO layer splits the stream with this before passing
+/// the messages on.
+pub fn frame_length(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < 4 {
+        return None;
+    }
+    let length = u16::from_be_bytes(bytes[2..4].try_into().unwrap()) as usize;
+    if is_channel_data(bytes) {
+        Some(4 + channel_data_length(length))
+    } else {
+        Some(20 + length)
+    }
+}
+
+// ChannelData is padded to 4 bytes over TCP
+fn channel_data_length(length: usize) -> usize {
+    length.div_ceil(4) * 4
+}
+
+#[cfg(test)]
+mod test {
+    use crate::turn::transport::*;
+
+    #[test]
+    pub fn test_encode_decode_channel_data() {
+        let message = ChannelData {
+            channel: 0x4001,
+            data: b"hello".to_vec(),
+        };
+        let bytes = message.encode();
+        assert_eq!(bytes.len(), 12);
+        assert_eq!(&bytes[..4], &[0x40, 0x01, 0x00, 0x05]);
+        assert!(is_channel_data(&bytes));
+        assert_eq!(frame_length(&bytes), Some(12));
+        assert_eq!(ChannelData::decode(&bytes), Some(message));
+
+        // out of range channel numbers and truncated data
+        assert_eq!(ChannelData::decode(&[0x50, 0x00, 0x00, 0x00]), None);
+        assert_eq!(ChannelData::decode(&bytes[..8]), None);
+    }
+
+    #[test]
+    pub fn test_frame_stun_messages() {
+        // a Binding request with 8 bytes of attributes
+        let mut bytes = vec![0x00, 0x01, 0x00, 0x08, 0x21, 0x12, 0xA4, 0x42];
+        assert!(!is_channel_data(&bytes));
+        assert_eq!(frame_length(&bytes), Some(28));
+        bytes.truncate(3);
+        assert_eq!(frame_length(&bytes), None);
+    }
+}